@@ -0,0 +1,700 @@
+//! Read-only trees served straight from an archive.
+//!
+//! An `ArchiveDir` indexes an archive once, when it is opened. From then on, lookups, listings
+//! and metadata come from the index, and file contents are read from the archive by offset, so
+//! nothing is unpacked to disk. Compressed entries are inflated in memory the first time they
+//! are read, and kept for as long as the tree is open, shared by every file opened from them.
+//! Timestamps, permissions, symlinks and hard links are taken from
+//! the archive. The tree can't be changed: any attempt fails with `Rofs`.
+//!
+//! The formats supported depend on the crate features: `tar` adds `ArchiveDir::from_tar`, and
+//! `zip` adds `ArchiveDir::from_zip`.
+use crate::clocks::SystemTimeSpec;
+use crate::dir::{new_device_id, ReaddirCursor, ReaddirEntity, WasiDir};
+use crate::error::{Error, ErrorExt};
+use crate::file::{Advice, FdFlags, FileType, Filestat, OFlags, WasiFile};
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{IoSlice, IoSliceMut, Read, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "tar")]
+mod tar;
+#[cfg(feature = "zip")]
+mod zip;
+
+/// How many symlinks a path may go through, as on Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// The index of the root directory in `Archive::nodes`.
+const ROOT: usize = 0;
+
+struct Archive {
+    device_id: u64,
+    blob: Blob,
+    nodes: Vec<Node>,
+}
+
+/// Where file contents are read from.
+enum Blob {
+    /// The archive file itself.
+    File(std::fs::File),
+    /// The archive, decompressed in memory.
+    #[cfg(feature = "tar")]
+    Memory(Vec<u8>),
+}
+
+struct Node {
+    kind: Kind,
+    /// The permission bits from the archive.
+    mode: u32,
+    mtim: SystemTime,
+    nlink: u64,
+}
+
+enum Kind {
+    Dir {
+        parent: usize,
+        entries: BTreeMap<String, usize>,
+    },
+    /// A file whose contents are the `size` bytes at `offset` in the blob, or, if `deflated` is
+    /// set, a deflate stream of that many bytes at `offset` that inflates to `size` bytes.
+    File {
+        offset: u64,
+        size: u64,
+        deflated: Option<u64>,
+        /// The contents, once a deflated file has been read.
+        inflated: OnceLock<Arc<[u8]>>,
+    },
+    Symlink(String),
+}
+
+impl Node {
+    fn filetype(&self) -> FileType {
+        match self.kind {
+            Kind::Dir { .. } => FileType::Directory,
+            Kind::File { .. } => FileType::RegularFile,
+            Kind::Symlink(_) => FileType::SymbolicLink,
+        }
+    }
+
+    /// Whether anyone at all may read the node.
+    fn readable(&self) -> bool {
+        self.mode & 0o444 != 0
+    }
+}
+
+impl Blob {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Blob::File(file) => std::os::unix::fs::FileExt::read_at(file, buf, offset),
+            #[cfg(windows)]
+            Blob::File(file) => std::os::windows::fs::FileExt::seek_read(file, buf, offset),
+            #[cfg(feature = "tar")]
+            Blob::Memory(data) => {
+                let start = usize::try_from(offset).map_or(data.len(), |o| o.min(data.len()));
+                let n = buf.len().min(data.len() - start);
+                buf[..n].copy_from_slice(&data[start..start + n]);
+                Ok(n)
+            }
+        }
+    }
+
+    /// Inflate the deflate stream of `len` bytes at `offset`, which should give `size` bytes.
+    fn inflate(&self, offset: u64, len: u64, size: u64) -> Result<Vec<u8>, Error> {
+        let stream = BlobReader {
+            blob: self,
+            pos: offset,
+            end: offset.saturating_add(len),
+        };
+        let mut data = Vec::new();
+        flate2::read::DeflateDecoder::new(stream)
+            .take(size)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(Error::io().context("archive entry is truncated"));
+        }
+        Ok(data)
+    }
+}
+
+/// Reads the bytes of a blob between `pos` and `end`.
+struct BlobReader<'a> {
+    blob: &'a Blob,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for BlobReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf
+            .len()
+            .min(usize::try_from(self.end - self.pos).unwrap_or(usize::MAX));
+        let n = self.blob.read_at(&mut buf[..len], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// The components of `path`, without the empty ones between repeated slashes.
+fn split(path: &str) -> VecDeque<String> {
+    path.split('/')
+        .filter(|c| !c.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The components of the path of an archive entry, or `None` if it goes up with `..`.
+fn components(path: &str) -> Option<Vec<String>> {
+    let path = split(path)
+        .into_iter()
+        .filter(|c| c != ".")
+        .collect::<Vec<_>>();
+    if path.iter().any(|c| c == "..") {
+        return None;
+    }
+    Some(path)
+}
+
+/// Builds the index of an archive, entry by entry. Later entries replace earlier ones of the
+/// same name, as when unpacking.
+struct Builder {
+    nodes: Vec<Node>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Builder {
+            nodes: vec![Node {
+                kind: Kind::Dir {
+                    parent: ROOT,
+                    entries: BTreeMap::new(),
+                },
+                mode: 0o755,
+                mtim: SystemTime::UNIX_EPOCH,
+                nlink: 0,
+            }],
+        }
+    }
+
+    /// The directory `path` leads to, created along with its parents if needed.
+    fn dir(&mut self, path: &[String]) -> usize {
+        let mut dir = ROOT;
+        for name in path {
+            dir = match self.get(dir, name) {
+                Some(ino) if matches!(self.nodes[ino].kind, Kind::Dir { .. }) => ino,
+                _ => self.insert(
+                    dir,
+                    name,
+                    Kind::Dir {
+                        parent: dir,
+                        entries: BTreeMap::new(),
+                    },
+                    0o755,
+                    0,
+                ),
+            };
+        }
+        dir
+    }
+
+    fn get(&self, dir: usize, name: &str) -> Option<usize> {
+        match &self.nodes[dir].kind {
+            Kind::Dir { entries, .. } => entries.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, dir: usize, name: &str, kind: Kind, mode: u32, mtime: u64) -> usize {
+        let ino = self.nodes.len();
+        self.nodes.push(Node {
+            kind,
+            mode,
+            mtim: SystemTime::UNIX_EPOCH + Duration::from_secs(mtime),
+            nlink: 0,
+        });
+        self.link(dir, name, ino);
+        ino
+    }
+
+    fn link(&mut self, dir: usize, name: &str, ino: usize) {
+        if let Kind::Dir { entries, .. } = &mut self.nodes[dir].kind {
+            entries.insert(name.to_owned(), ino);
+        }
+    }
+
+    /// The parent directory and the name of the entry `path`, or `None` if the entry can't be
+    /// placed in the tree, because its path is empty or goes up with `..`.
+    fn place(&mut self, path: &str) -> Option<(usize, String)> {
+        let mut path = components(path)?;
+        let name = path.pop()?;
+        Some((self.dir(&path), name))
+    }
+
+    fn add_dir(&mut self, path: &str, mode: u32, mtime: u64) {
+        if let Some(path) = components(path) {
+            let dir = self.dir(&path);
+            let node = &mut self.nodes[dir];
+            node.mode = mode;
+            node.mtim = SystemTime::UNIX_EPOCH + Duration::from_secs(mtime);
+        }
+    }
+
+    fn add_file(&mut self, path: &str, mode: u32, mtime: u64, offset: u64, size: u64) {
+        if let Some((dir, name)) = self.place(path) {
+            let kind = Kind::File {
+                offset,
+                size,
+                deflated: None,
+                inflated: OnceLock::new(),
+            };
+            self.insert(dir, &name, kind, mode, mtime);
+        }
+    }
+
+    /// Add a file whose contents are deflated into the `len` bytes at `offset`.
+    #[cfg(feature = "zip")]
+    fn add_deflated_file(
+        &mut self,
+        path: &str,
+        mode: u32,
+        mtime: u64,
+        offset: u64,
+        len: u64,
+        size: u64,
+    ) {
+        if let Some((dir, name)) = self.place(path) {
+            let kind = Kind::File {
+                offset,
+                size,
+                deflated: Some(len),
+                inflated: OnceLock::new(),
+            };
+            self.insert(dir, &name, kind, mode, mtime);
+        }
+    }
+
+    fn add_symlink(&mut self, path: &str, target: &str, mode: u32, mtime: u64) {
+        if let Some((dir, name)) = self.place(path) {
+            self.insert(dir, &name, Kind::Symlink(target.to_owned()), mode, mtime);
+        }
+    }
+
+    /// Add `path` as another name for the file at `target`, which must come earlier in the
+    /// archive.
+    #[cfg(feature = "tar")]
+    fn add_hard_link(&mut self, path: &str, target: &str) {
+        let target = components(target)
+            .and_then(|path| path.iter().try_fold(ROOT, |dir, name| self.get(dir, name)));
+        let target = match target {
+            Some(ino) if !matches!(self.nodes[ino].kind, Kind::Dir { .. }) => ino,
+            _ => return,
+        };
+        if let Some((dir, name)) = self.place(path) {
+            self.link(dir, &name, target);
+        }
+    }
+
+    fn finish(mut self, blob: Blob) -> ArchiveDir {
+        // Count the names of each node that is still in the tree.
+        let mut stack = vec![ROOT];
+        self.nodes[ROOT].nlink = 2;
+        while let Some(dir) = stack.pop() {
+            let children = match &self.nodes[dir].kind {
+                Kind::Dir { entries, .. } => entries.values().copied().collect::<Vec<_>>(),
+                _ => continue,
+            };
+            for ino in children {
+                if let Kind::Dir { .. } = self.nodes[ino].kind {
+                    self.nodes[ino].nlink = 2;
+                    self.nodes[dir].nlink += 1;
+                    stack.push(ino);
+                } else {
+                    self.nodes[ino].nlink += 1;
+                }
+            }
+        }
+        ArchiveDir {
+            archive: Arc::new(Archive {
+                device_id: new_device_id(),
+                blob,
+                nodes: self.nodes,
+            }),
+            ino: ROOT,
+        }
+    }
+}
+
+impl Archive {
+    /// Follow `path` from the directory `base`. Symlinks along the way are followed, and so is
+    /// one in the last component if `follow` is set or the path ends in `/`.
+    fn lookup(&self, base: usize, path: &str, follow: bool) -> Result<usize, Error> {
+        if path.is_empty() {
+            return Err(Error::not_found().context("empty path"));
+        }
+        if path.starts_with('/') {
+            return Err(Error::perm().context("absolute path"));
+        }
+        let trailing_slash = path.ends_with('/');
+        let mut components = split(path);
+        // The directories walked through, so that `..` can go back up, but never above `base`.
+        let mut stack = vec![base];
+        let mut hops = 0;
+        while let Some(component) = components.pop_front() {
+            let last = components.is_empty();
+            let dir = *stack.last().unwrap();
+            let ino = match component.as_str() {
+                "." => continue,
+                ".." if stack.len() == 1 => {
+                    return Err(Error::perm().context("path leads out of the directory"))
+                }
+                ".." => {
+                    stack.pop();
+                    continue;
+                }
+                name => match &self.nodes[dir].kind {
+                    Kind::Dir { entries, .. } => *entries.get(name).ok_or_else(Error::not_found)?,
+                    _ => return Err(Error::not_dir()),
+                },
+            };
+            match &self.nodes[ino].kind {
+                Kind::Symlink(target) if !last || follow || trailing_slash => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(Error::symlink_loop());
+                    }
+                    if target.starts_with('/') {
+                        return Err(Error::perm().context("symlink to an absolute path"));
+                    }
+                    let mut target = split(target);
+                    if target.is_empty() {
+                        return Err(Error::not_found().context("empty symlink"));
+                    }
+                    target.extend(components);
+                    components = target;
+                }
+                Kind::Dir { .. } => stack.push(ino),
+                _ if !last => return Err(Error::not_dir()),
+                Kind::File { .. } if trailing_slash => return Err(Error::not_dir()),
+                _ => return Ok(ino),
+            }
+        }
+        Ok(*stack.last().unwrap())
+    }
+
+    /// The inflated contents of the file at `ino`, if it is deflated. They are inflated once and
+    /// then shared.
+    fn inflated(&self, ino: usize) -> Result<Option<Arc<[u8]>>, Error> {
+        let (offset, size, len, inflated) = match &self.nodes[ino].kind {
+            Kind::File {
+                offset,
+                size,
+                deflated: Some(len),
+                inflated,
+            } => (*offset, *size, *len, inflated),
+            _ => return Ok(None),
+        };
+        if let Some(data) = inflated.get() {
+            return Ok(Some(data.clone()));
+        }
+        let data = self.blob.inflate(offset, len, size)?;
+        Ok(Some(inflated.get_or_init(|| data.into()).clone()))
+    }
+
+    fn filestat(&self, ino: usize) -> Filestat {
+        let node = &self.nodes[ino];
+        let size = match &node.kind {
+            Kind::Dir { .. } => 0,
+            Kind::File { size, .. } => *size,
+            Kind::Symlink(target) => target.len() as u64,
+        };
+        Filestat {
+            device_id: self.device_id,
+            inode: inode(ino),
+            filetype: node.filetype(),
+            nlink: node.nlink,
+            size,
+            atim: Some(node.mtim),
+            mtim: Some(node.mtim),
+            ctim: Some(node.mtim),
+        }
+    }
+}
+
+/// The inode number of the node at `ino`. Inode 0 is avoided, as some guests take it to mean
+/// a deleted entry.
+fn inode(ino: usize) -> u64 {
+    ino as u64 + 1
+}
+
+/// A directory of an archive.
+pub struct ArchiveDir {
+    archive: Arc<Archive>,
+    ino: usize,
+}
+
+impl WasiDir for ArchiveDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        _read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        if write || oflags.intersects(OFlags::CREATE | OFlags::TRUNCATE) {
+            return Err(Error::read_only().context("open for writing"));
+        }
+        let ino = self.archive.lookup(self.ino, path, symlink_follow)?;
+        let node = &self.archive.nodes[ino];
+        let (offset, size) = match node.kind {
+            Kind::Dir { .. } => return Err(Error::is_dir()),
+            Kind::Symlink(_) => return Err(Error::symlink_loop().context("symlink not followed")),
+            Kind::File { offset, size, .. } => (offset, size),
+        };
+        if !node.readable() {
+            return Err(Error::perm().context("file is not readable"));
+        }
+        Ok(Box::new(ArchiveFile {
+            archive: self.archive.clone(),
+            ino,
+            offset,
+            size,
+            inflated: None,
+            pos: 0,
+            fdflags,
+        }))
+    }
+    fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let ino = self.archive.lookup(self.ino, path, symlink_follow)?;
+        match self.archive.nodes[ino].kind {
+            Kind::Dir { .. } => Ok(Box::new(ArchiveDir {
+                archive: self.archive.clone(),
+                ino,
+            })),
+            _ => Err(Error::not_dir()),
+        }
+    }
+    fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let node = &self.archive.nodes[self.ino];
+        if !node.readable() {
+            return Err(Error::perm().context("directory is not readable"));
+        }
+        let (parent, entries) = match &node.kind {
+            Kind::Dir { parent, entries } => (*parent, entries),
+            _ => return Err(Error::not_dir()),
+        };
+        let entities = [(".", self.ino), ("..", parent)]
+            .into_iter()
+            .chain(entries.iter().map(|(name, &ino)| (name.as_str(), ino)))
+            .enumerate()
+            .map(|(ix, (name, ino))| {
+                Ok(ReaddirEntity {
+                    next: ReaddirCursor::from(ix as u64 + 1),
+                    inode: inode(ino),
+                    name: name.to_owned(),
+                    filetype: self.archive.nodes[ino].filetype(),
+                })
+            })
+            .skip(u64::from(cursor) as usize)
+            .collect::<Vec<_>>();
+        Ok(Box::new(entities.into_iter()))
+    }
+    fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        let ino = self.archive.lookup(self.ino, path, false)?;
+        match &self.archive.nodes[ino].kind {
+            Kind::Symlink(target) => Ok(PathBuf::from(target)),
+            _ => Err(Error::invalid_argument().context("not a symlink")),
+        }
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(self.archive.filestat(self.ino))
+    }
+    fn get_path_filestat(&self, path: &str, follow_symlinks: bool) -> Result<Filestat, Error> {
+        let ino = self.archive.lookup(self.ino, path, follow_symlinks)?;
+        Ok(self.archive.filestat(ino))
+    }
+    fn rename(&self, _path: &str, _dest_dir: &dyn WasiDir, _dest_path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn WasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+}
+
+/// A file of an archive, opened through an `ArchiveDir`.
+pub struct ArchiveFile {
+    archive: Arc<Archive>,
+    ino: usize,
+    /// Where the contents start in the blob.
+    offset: u64,
+    size: u64,
+    /// The contents, once a deflated file has been read.
+    inflated: Option<Arc<[u8]>>,
+    pos: u64,
+    fdflags: FdFlags,
+}
+
+impl ArchiveFile {
+    fn read_at(&mut self, bufs: &mut [IoSliceMut<'_>], pos: u64) -> Result<u64, Error> {
+        if self.inflated.is_none() {
+            self.inflated = self.archive.inflated(self.ino)?;
+        }
+        let mut total = 0;
+        'bufs: for buf in bufs {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let at = pos.saturating_add(total);
+                if at >= self.size {
+                    break 'bufs;
+                }
+                let len = (buf.len() - filled).min(usize::try_from(self.size - at)?);
+                let buf = &mut buf[filled..filled + len];
+                let n = match &self.inflated {
+                    Some(data) => {
+                        let at = at as usize;
+                        buf.copy_from_slice(&data[at..at + len]);
+                        len
+                    }
+                    None => self.archive.blob.read_at(buf, self.offset + at)?,
+                };
+                if n == 0 {
+                    return Err(Error::io().context("archive is truncated"));
+                }
+                filled += n;
+                total += n as u64;
+            }
+        }
+        Ok(total)
+    }
+}
+
+impl WasiFile for ArchiveFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::RegularFile)
+    }
+    fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(self.fdflags)
+    }
+    fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.fdflags = flags;
+        Ok(())
+    }
+    fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        Ok(self.archive.filestat(self.ino))
+    }
+    fn set_filestat_size(&mut self, _size: u64) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn advise(&mut self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Ok(())
+    }
+    fn allocate(&mut self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn set_times(
+        &mut self,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let n = self.read_at(bufs, self.pos)?;
+        self.pos += n;
+        Ok(n)
+    }
+    fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        self.read_at(bufs, offset)
+    }
+    fn write_vectored<'a>(&mut self, _bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        Err(Error::read_only())
+    }
+    fn write_vectored_at<'a>(&mut self, _bufs: &[IoSlice<'a>], _offset: u64) -> Result<u64, Error> {
+        Err(Error::read_only())
+    }
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (0, i128::from(offset)),
+            SeekFrom::Current(delta) => (self.pos, i128::from(delta)),
+            SeekFrom::End(delta) => (self.size, i128::from(delta)),
+        };
+        self.pos = u64::try_from(i128::from(base) + delta)
+            .map_err(|_| Error::invalid_argument().context("seek before the start"))?;
+        Ok(self.pos)
+    }
+    fn offset(&self) -> Option<u64> {
+        Some(self.pos)
+    }
+    fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.read_at(&mut [IoSliceMut::new(buf)], self.pos)
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(self.size.saturating_sub(self.pos))
+    }
+    fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, any(feature = "tar", feature = "zip")))]
+pub(crate) mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    /// An open file holding `contents`, for archives built in tests. It is removed from the
+    /// host's temporary directory as soon as it is open.
+    pub(crate) fn fixture(name: &str, contents: &[u8]) -> File {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+}
@@ -0,0 +1,134 @@
+use super::{ArchiveDir, Blob, Builder};
+use crate::error::{Error, ErrorExt};
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use tar::{Archive, EntryType};
+
+/// The first bytes of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+impl ArchiveDir {
+    /// Index the tar archive `file`, which may be gzip-compressed. A plain archive is read in
+    /// place; a compressed one is decompressed into memory first, since it can't be read by
+    /// offset.
+    ///
+    /// Entries other than files, directories, symlinks and hard links, such as devices, are left
+    /// out, and so are entries whose paths go up with `..`.
+    pub fn from_tar(mut file: File) -> Result<Self, Error> {
+        let mut magic = [0; 2];
+        let gzip = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+        file.seek(SeekFrom::Start(0))?;
+        if !gzip {
+            let builder = index(Archive::new(&file))?;
+            return Ok(builder.finish(Blob::File(file)));
+        }
+        let mut data = Vec::new();
+        flate2::read::MultiGzDecoder::new(file).read_to_end(&mut data)?;
+        let builder = index(Archive::new(Cursor::new(&data[..])))?;
+        Ok(builder.finish(Blob::Memory(data)))
+    }
+}
+
+fn index<R: Read + Seek>(mut archive: Archive<R>) -> Result<Builder, Error> {
+    let mut builder = Builder::new();
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        let path = entry.path()?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| Error::illegal_byte_sequence().context("archive entry path"))?;
+        let header = entry.header();
+        let mode = header.mode()?;
+        let mtime = header.mtime()?;
+        let link_name = || -> Result<String, Error> {
+            let link = entry
+                .link_name()?
+                .ok_or_else(|| Error::invalid_argument().context("archive link without target"))?;
+            link.to_str()
+                .map(str::to_owned)
+                .ok_or_else(|| Error::illegal_byte_sequence().context("archive link target"))
+        };
+        match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                builder.add_file(path, mode, mtime, entry.raw_file_position(), entry.size())
+            }
+            EntryType::Directory => builder.add_dir(path, mode, mtime),
+            EntryType::Symlink => builder.add_symlink(path, &link_name()?, mode, mtime),
+            EntryType::Link => builder.add_hard_link(path, &link_name()?),
+            _ => {}
+        }
+    }
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::fixture;
+    use super::ArchiveDir;
+    use crate::dir::WasiDir;
+    use crate::error::{Errno, Error};
+    use crate::file::{FdFlags, FileType, OFlags};
+    use crate::memfs::tests::{errno, read};
+    use tar::{EntryType, Header};
+
+    /// A tar archive of `a/b/c`, a symlink `a/l` to it, `top`, and `x/y/z`, whose directories
+    /// have no entries of their own.
+    fn archive() -> ArchiveDir {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut add = |path: &str, kind: EntryType, data: &[u8]| {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_mode(if kind == EntryType::Directory {
+                0o755
+            } else {
+                0o644
+            });
+            header.set_size(data.len() as u64);
+            tar.append_data(&mut header, path, data).unwrap();
+        };
+        add("a/", EntryType::Directory, b"");
+        add("a/b/", EntryType::Directory, b"");
+        add("a/b/c", EntryType::Regular, b"nested");
+        add("top", EntryType::Regular, b"top");
+        add("x/y/z", EntryType::Regular, b"deep");
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_size(0);
+        tar.append_link(&mut header, "a/l", "b/c").unwrap();
+        let tar = tar.into_inner().unwrap();
+        ArchiveDir::from_tar(fixture("archive-tar", &tar)).unwrap()
+    }
+
+    fn open(dir: &dyn WasiDir, path: &str) -> Result<(), Error> {
+        dir.open_file(true, path, OFlags::empty(), true, false, FdFlags::empty())
+            .map(drop)
+    }
+
+    #[test]
+    fn nested_paths_are_found() {
+        let root = archive();
+        assert_eq!(read(&root, "a/b/c"), b"nested");
+        assert_eq!(read(&root, "a/l"), b"nested");
+        assert_eq!(read(&root, "a/./b/../b/c"), b"nested");
+        let a = root.open_dir(false, "a").unwrap();
+        assert_eq!(read(&*a, "b/c"), b"nested");
+        assert_eq!(read(&root, "x/y/z"), b"deep");
+        let stat = root.get_path_filestat("x/y", false).unwrap();
+        assert_eq!(stat.filetype, FileType::Directory);
+    }
+
+    #[test]
+    fn missing_paths_are_not_found() {
+        let root = archive();
+        assert_eq!(errno(open(&root, "a/b/d")), Some(Errno::Noent));
+        assert_eq!(errno(open(&root, "b/c")), Some(Errno::Noent));
+        assert_eq!(errno(root.open_dir(false, "a/missing")), Some(Errno::Noent));
+        assert_eq!(
+            errno(root.get_path_filestat("x/y/missing", false)),
+            Some(Errno::Noent)
+        );
+        assert_eq!(errno(open(&root, "top/c")), Some(Errno::Notdir));
+        assert_eq!(errno(open(&root, "a/b/c/")), Some(Errno::Notdir));
+    }
+}
@@ -0,0 +1,231 @@
+use super::{ArchiveDir, Blob, BlobReader, Builder};
+use crate::error::{Error, ErrorExt};
+use std::fs::File;
+use std::io::Read;
+use zip::{CompressionMethod, DateTime, ExtraField, ZipArchive};
+
+/// The permission bits of entries made on systems without them.
+const DEFAULT_FILE_MODE: u32 = 0o644;
+const DEFAULT_DIR_MODE: u32 = 0o755;
+
+impl ArchiveDir {
+    /// Index the zip archive `file` from its central directory. Stored entries are read in
+    /// place; deflated ones are inflated in memory when an open file is first read.
+    ///
+    /// Encrypted entries and entries compressed with methods other than deflate are left out,
+    /// and so are entries whose paths go up with `..`.
+    pub fn from_zip(file: File) -> Result<Self, Error> {
+        let blob = Blob::File(file.try_clone()?);
+        let mut archive = ZipArchive::new(&file).map_err(std::io::Error::from)?;
+        let mut builder = Builder::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index_raw(i).map_err(std::io::Error::from)?;
+            if entry.encrypted() {
+                continue;
+            }
+            let path = entry.name();
+            let mtime = mtime(entry.last_modified(), entry.extra_data_fields());
+            if entry.is_dir() {
+                let mode = entry.unix_mode().map_or(DEFAULT_DIR_MODE, |m| m & 0o7777);
+                builder.add_dir(path, mode, mtime);
+                continue;
+            }
+            let mode = entry.unix_mode().map_or(DEFAULT_FILE_MODE, |m| m & 0o7777);
+            let (offset, len, size) = (entry.data_start(), entry.compressed_size(), entry.size());
+            let deflated = match entry.compression() {
+                CompressionMethod::STORE => false,
+                CompressionMethod::DEFLATE => true,
+                _ => continue,
+            };
+            if entry.is_symlink() {
+                let target = if deflated {
+                    blob.inflate(offset, len, size)?
+                } else {
+                    let mut target = Vec::new();
+                    BlobReader {
+                        blob: &blob,
+                        pos: offset,
+                        end: offset.saturating_add(size),
+                    }
+                    .read_to_end(&mut target)?;
+                    target
+                };
+                let target = String::from_utf8(target)
+                    .map_err(|_| Error::illegal_byte_sequence().context("archive link target"))?;
+                builder.add_symlink(path, &target, mode, mtime);
+            } else if deflated {
+                builder.add_deflated_file(path, mode, mtime, offset, len, size);
+            } else {
+                builder.add_file(path, mode, mtime, offset, size);
+            }
+        }
+        Ok(builder.finish(blob))
+    }
+}
+
+/// The modification time of an entry, in seconds since the epoch. The extended timestamp field
+/// is preferred, as it is in UTC; the DOS date and time have no time zone, and are taken to be
+/// in UTC too.
+fn mtime<'a>(dos: Option<DateTime>, mut extra: impl Iterator<Item = &'a ExtraField>) -> u64 {
+    let extended = extra.find_map(|field| match field {
+        ExtraField::ExtendedTimestamp(ts) => ts.mod_time(),
+        _ => None,
+    });
+    if let Some(secs) = extended {
+        return u64::from(secs);
+    }
+    let Some(dos) = dos else { return 0 };
+    let days = days_from_civil(
+        i64::from(dos.year()),
+        i64::from(dos.month()),
+        i64::from(dos.day()),
+    );
+    let secs = days * 86400
+        + i64::from(dos.hour()) * 3600
+        + i64::from(dos.minute()) * 60
+        + i64::from(dos.second());
+    u64::try_from(secs).unwrap_or(0)
+}
+
+/// The number of days from 1970-01-01 to the given date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count from March, so that the leap day comes last.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::fixture;
+    use super::ArchiveDir;
+    use crate::dir::WasiDir;
+    use crate::error::Errno;
+    use crate::file::{FdFlags, OFlags, WasiFile};
+    use crate::memfs::tests::{errno, read};
+    use flate2::write::DeflateEncoder;
+    use flate2::{Compression, Crc};
+    use std::io::{IoSliceMut, Write};
+
+    /// A zip archive of `entries`, each a path, its contents, and whether they are deflated.
+    /// Paths ending in `/` are directories.
+    fn zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for &(path, contents, deflated) in entries {
+            let data = if deflated {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(contents).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                contents.to_vec()
+            };
+            let mut crc = Crc::new();
+            crc.update(contents);
+            // Version needed, flags, method, DOS time and date (1980-01-01), CRC and sizes.
+            let mut common = Vec::new();
+            common.extend_from_slice(&20u16.to_le_bytes());
+            common.extend_from_slice(&0u16.to_le_bytes());
+            common.extend_from_slice(&(if deflated { 8u16 } else { 0 }).to_le_bytes());
+            common.extend_from_slice(&0u16.to_le_bytes());
+            common.extend_from_slice(&0x21u16.to_le_bytes());
+            common.extend_from_slice(&crc.sum().to_le_bytes());
+            common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            common.extend_from_slice(&(path.len() as u16).to_le_bytes());
+            common.extend_from_slice(&0u16.to_le_bytes());
+
+            central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            central.extend_from_slice(&20u16.to_le_bytes());
+            central.extend_from_slice(&common);
+            // No comment, disk 0, no attributes, then the offset of the local header.
+            central.extend_from_slice(&[0; 10]);
+            central.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            central.extend_from_slice(path.as_bytes());
+
+            out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            out.extend_from_slice(&common);
+            out.extend_from_slice(path.as_bytes());
+            out.extend_from_slice(&data);
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    fn contents() -> Vec<u8> {
+        (0..1000u32).flat_map(|i| (i % 7).to_le_bytes()).collect()
+    }
+
+    fn archive() -> ArchiveDir {
+        let contents = contents();
+        let zip = zip(&[
+            ("d/", b"", false),
+            ("d/e/deflated", &contents, true),
+            ("d/stored", b"stored", false),
+        ]);
+        ArchiveDir::from_zip(fixture("archive-zip", &zip)).unwrap()
+    }
+
+    fn open(dir: &dyn WasiDir, path: &str) -> Box<dyn WasiFile> {
+        dir.open_file(true, path, OFlags::empty(), true, false, FdFlags::empty())
+            .unwrap()
+    }
+
+    fn read_some(file: &mut dyn WasiFile, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let n = file
+            .read_vectored(&mut [IoSliceMut::new(&mut buf)])
+            .unwrap();
+        buf.truncate(n as usize);
+        buf
+    }
+
+    #[test]
+    fn nested_and_missing_paths() {
+        let root = archive();
+        assert_eq!(read(&root, "d/stored"), b"stored");
+        assert_eq!(read(&root, "d/e/deflated"), contents());
+        let e = root.open_dir(false, "d/e").unwrap();
+        assert_eq!(read(&*e, "deflated"), contents());
+        assert_eq!(errno(root.open_dir(false, "d/missing")), Some(Errno::Noent));
+        assert_eq!(
+            errno(root.get_path_filestat("e/deflated", false)),
+            Some(Errno::Noent)
+        );
+        assert_eq!(
+            errno(root.get_path_filestat("d/stored/x", false)),
+            Some(Errno::Notdir)
+        );
+    }
+
+    #[test]
+    fn a_deflated_entry_is_shared_by_two_open_files() {
+        let root = archive();
+        let contents = contents();
+        let mut first = open(&root, "d/e/deflated");
+        let mut second = open(&root, "d/e/deflated");
+        let head = read_some(&mut *first, 10);
+        assert_eq!(head, &contents[..10]);
+        assert_eq!(read_some(&mut *second, contents.len()), contents);
+        assert_eq!(first.offset(), Some(10));
+        assert_eq!(second.offset(), Some(contents.len() as u64));
+        assert_eq!(read_some(&mut *first, contents.len()), &contents[10..]);
+        assert!(read_some(&mut *second, 10).is_empty());
+        let mut buf = [0; 4];
+        let n = first
+            .read_vectored_at(&mut [IoSliceMut::new(&mut buf)], 8)
+            .unwrap();
+        assert_eq!(&buf[..n as usize], &contents[8..12]);
+    }
+}
@@ -0,0 +1,332 @@
+//! The asynchronous counterpart of `WasiDir`.
+use crate::async_file::AsyncWasiFile;
+use crate::clocks::SystemTimeSpec;
+use crate::dir::{
+    is_dot, join, link_target, not_copyable, replaceable, temp_path, times, under, CopyPolicy,
+    DirCaps, DirEntry, ReaddirCursor, ReaddirEntity, Walk,
+};
+use crate::error::{Errno, Error, ErrorExt};
+#[cfg(unix)]
+use crate::file::copy_on_host;
+use crate::file::{FdFlags, FileType, Filestat, OFlags};
+use async_trait::async_trait;
+use std::any::Any;
+use std::path::PathBuf;
+
+#[async_trait]
+pub trait AsyncWasiDir: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+
+    async fn open_file(
+        &self,
+        _symlink_follow: bool,
+        _path: &str,
+        _oflags: OFlags,
+        _read: bool,
+        _write: bool,
+        _fdflags: FdFlags,
+    ) -> Result<Box<dyn AsyncWasiFile>, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn open_dir(
+        &self,
+        _symlink_follow: bool,
+        _path: &str,
+    ) -> Result<Box<dyn AsyncWasiDir>, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
+    /// Unlike `WasiDir::readdir`, the directory is read when the returned future resolves, so
+    /// the iterator itself never blocks.
+    async fn readdir(
+        &self,
+        _cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn read_link(&self, _path: &str) -> Result<PathBuf, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn get_path_filestat(
+        &self,
+        _path: &str,
+        _follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn rename(
+        &self,
+        _path: &str,
+        _dest_dir: &dyn AsyncWasiDir,
+        _dest_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn AsyncWasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
+    /// Whether entries may be copied out of or into this directory to stand in for a rename or
+    /// hard link.
+    /// See `WasiDir::copy_policy`.
+    fn copy_policy(&self) -> CopyPolicy {
+        CopyPolicy::Allow
+    }
+}
+
+pub(crate) type AsyncDirEntry = DirEntry<dyn AsyncWasiDir>;
+
+pub trait AsyncDirEntryExt {
+    fn get_cap(&self, caps: DirCaps) -> Result<&dyn AsyncWasiDir, Error>;
+}
+
+impl AsyncDirEntryExt for AsyncDirEntry {
+    fn get_cap(&self, caps: DirCaps) -> Result<&dyn AsyncWasiDir, Error> {
+        self.capable_of_dir(caps)?;
+        Ok(self.dir())
+    }
+}
+
+/// The asynchronous counterpart of `dir::move_across`: move `src_path` in `src_dir` to
+/// `dest_path` in `dest_dir` by copying it across under a temporary name, renaming the copy into
+/// place and then removing the source. Fails with `Xdev` if either directory's `copy_policy` is
+/// `Deny`, or for anything but regular files, symlinks and directories of them.
+pub async fn move_across(
+    src_dir: &dyn AsyncWasiDir,
+    src_path: &str,
+    dest_dir: &dyn AsyncWasiDir,
+    dest_path: &str,
+) -> Result<(), Error> {
+    if src_dir.copy_policy().and(dest_dir.copy_policy()) == CopyPolicy::Deny {
+        return Err(Error::cross_device().context("the host doesn't allow copying across"));
+    }
+    let stat = src_dir.get_path_filestat(src_path, false).await?;
+    let moving_dir = stat.filetype == FileType::Directory;
+    match dest_dir.get_path_filestat(dest_path, false).await {
+        Ok(dest) => {
+            if replaceable(&dest, moving_dir)? && !entries(dest_dir, dest_path).await?.is_empty() {
+                return Err(Error::not_empty());
+            }
+        }
+        Err(e) if Errno::from_error(&e) == Some(Errno::Noent) => {}
+        Err(e) => return Err(e),
+    }
+    let temp = temp_path(dest_path);
+    let copied = match copy_tree(src_dir, src_path, stat, dest_dir, &temp).await {
+        Ok(()) => dest_dir.rename(&temp, dest_dir, dest_path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = copied {
+        let _ = remove_all(dest_dir, &temp).await;
+        return Err(e);
+    }
+    remove_all(src_dir, src_path).await
+}
+
+/// The asynchronous counterpart of `dir::link_across`: link `path` in `dir` to `target_path` in
+/// `target_dir` by copying it across, keeping the source. Fails with `Xdev` if either
+/// directory's `copy_policy` is `Deny`.
+pub async fn link_across(
+    dir: &dyn AsyncWasiDir,
+    path: &str,
+    target_dir: &dyn AsyncWasiDir,
+    target_path: &str,
+) -> Result<(), Error> {
+    if dir.copy_policy().and(target_dir.copy_policy()) == CopyPolicy::Deny {
+        return Err(Error::cross_device().context("the host doesn't allow copying across"));
+    }
+    let stat = dir.get_path_filestat(path, false).await?;
+    if stat.filetype == FileType::Directory {
+        return Err(Error::perm().context("hard link to a directory"));
+    }
+    if exists(target_dir, target_path).await? {
+        return Err(Error::exist());
+    }
+    let temp = temp_path(target_path);
+    let copied = match copy_tree(dir, path, stat, target_dir, &temp).await {
+        Ok(()) => put_in_place(target_dir, &temp, target_path).await,
+        Err(e) => Err(e),
+    };
+    let _ = target_dir.unlink_file(&temp).await;
+    copied
+}
+
+/// Give the finished copy at `temp` the name `path`, as `dir::put_in_place` does.
+async fn put_in_place(dir: &dyn AsyncWasiDir, temp: &str, path: &str) -> Result<(), Error> {
+    match dir.hard_link(temp, dir, path).await {
+        Err(e) if Errno::from_error(&e) == Some(Errno::Notsup) => {
+            if exists(dir, path).await? {
+                return Err(Error::exist());
+            }
+            dir.rename(temp, dir, path).await
+        }
+        result => result,
+    }
+}
+
+async fn exists(dir: &dyn AsyncWasiDir, path: &str) -> Result<bool, Error> {
+    match dir.get_path_filestat(path, false).await {
+        Ok(_) => Ok(true),
+        Err(e) if Errno::from_error(&e) == Some(Errno::Noent) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copy `src_path`, whose metadata is `stat`, to the new `dest_path`, walking it as
+/// `dir::copy_tree` does.
+async fn copy_tree(
+    src_dir: &dyn AsyncWasiDir,
+    src_path: &str,
+    stat: Filestat,
+    dest_dir: &dyn AsyncWasiDir,
+    dest_path: &str,
+) -> Result<(), Error> {
+    let mut walk = Walk::new(stat);
+    while let Some((rel, stat)) = walk.next() {
+        let (from, to) = (under(src_path, &rel), under(dest_path, &rel));
+        match stat.filetype {
+            FileType::Directory => {
+                dest_dir.create_dir(&to).await?;
+                for (name, _) in entries(src_dir, &from).await? {
+                    let stat = src_dir
+                        .get_path_filestat(&join(&from, &name), false)
+                        .await?;
+                    walk.push(&rel, &name, stat);
+                }
+            }
+            FileType::SymbolicLink => {
+                let target = src_dir.read_link(&from).await?;
+                dest_dir.symlink(link_target(&target)?, &to).await?;
+            }
+            FileType::RegularFile => {
+                let none = FdFlags::empty();
+                let mut src = src_dir
+                    .open_file(false, &from, OFlags::empty(), true, false, none)
+                    .await?;
+                let exclusive = OFlags::CREATE | OFlags::EXCLUSIVE;
+                let mut dst = dest_dir
+                    .open_file(false, &to, exclusive, false, true, none)
+                    .await?;
+                copy_contents(&mut *src, &mut *dst).await?;
+            }
+            _ => return Err(not_copyable()),
+        }
+    }
+    for (rel, stat) in walk.finish() {
+        let (atim, mtim) = times(&stat);
+        dest_dir
+            .set_times(&under(dest_path, &rel), atim, mtim, false)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Copy the contents of `src`, which hasn't been read from, to the empty file `dst`, as
+/// `file::copy_contents` does. A copy between host files is made on the host, by the calling
+/// thread.
+async fn copy_contents(
+    src: &mut dyn AsyncWasiFile,
+    dst: &mut dyn AsyncWasiFile,
+) -> Result<(), Error> {
+    #[cfg(unix)]
+    if let (Some(from), Some(to)) = (src.host_fd(), dst.host_fd()) {
+        if copy_on_host(from, to)? {
+            return Ok(());
+        }
+    }
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = src
+            .read_vectored(&mut [std::io::IoSliceMut::new(&mut buf)])
+            .await? as usize;
+        if n == 0 {
+            return Ok(());
+        }
+        let mut written = 0;
+        while written < n {
+            match dst
+                .write_vectored(&[std::io::IoSlice::new(&buf[written..n])])
+                .await?
+            {
+                0 => return Err(Error::io().context("copy wrote nothing")),
+                w => written += w as usize,
+            }
+        }
+    }
+}
+
+/// The names in the directory at `path` in `dir`, other than `.` and `..`, with their types.
+async fn entries(dir: &dyn AsyncWasiDir, path: &str) -> Result<Vec<(String, FileType)>, Error> {
+    let dir = dir.open_dir(false, path).await?;
+    dir.readdir(ReaddirCursor::from(0))
+        .await?
+        .filter(|entity| entity.as_ref().map_or(true, |entity| !is_dot(&entity.name)))
+        .map(|entity| entity.map(|entity| (entity.name, entity.filetype)))
+        .collect()
+}
+
+/// Remove `path` from `dir`, with everything in it if it is a directory, walking it as
+/// `dir::remove_all` does. Nothing there is fine.
+async fn remove_all(dir: &dyn AsyncWasiDir, path: &str) -> Result<(), Error> {
+    let stat = match dir.get_path_filestat(path, false).await {
+        Ok(stat) => stat,
+        Err(e) if Errno::from_error(&e) == Some(Errno::Noent) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut walk = Walk::new(stat.filetype);
+    while let Some((rel, filetype)) = walk.next() {
+        if filetype == FileType::Directory {
+            for (name, filetype) in entries(dir, &under(path, &rel)).await? {
+                walk.push(&rel, &name, filetype);
+            }
+        }
+    }
+    for (rel, filetype) in walk.finish() {
+        if filetype == FileType::Directory {
+            dir.remove_dir(&under(path, &rel)).await?;
+        } else {
+            dir.unlink_file(&under(path, &rel)).await?;
+        }
+    }
+    Ok(())
+}
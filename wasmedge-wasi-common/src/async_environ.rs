@@ -0,0 +1,535 @@
+//! The asynchronous counterpart of `WasiEnviron`.
+//!
+//! Files and directories are `AsyncWasiFile`s and `AsyncWasiDir`s, and every syscall returns a
+//! future. Timers used by `poll_oneoff` come from the `AsyncWasiSched` the environ is built with.
+use crate::async_dir::{AsyncDirEntry, AsyncDirEntryExt, AsyncWasiDir};
+use crate::async_file::{AsyncFileEntry, AsyncFileEntryExt, AsyncWasiFile};
+use crate::cancel::CancelHandle;
+use crate::dir::DirCaps;
+use crate::environ::WasiEnviron;
+use crate::error::{Error, ErrorExt};
+use crate::file::{FdFlags, FileCaps, OFlags};
+use crate::limits::{Limits, Usage};
+use crate::metrics::Metrics;
+use crate::quota::OutputQuota;
+use crate::sched::{AsyncWasiSched, Event, EventKind, Subscription, SubscriptionKind};
+use crate::string_array::{StringArray, StringArrayError};
+use crate::table::Table;
+use crate::trace::syscall_span;
+use crate::{AsyncWasiSnapshotPreview1, Ciovec, CiovecArray, IovecArray};
+use async_trait::async_trait;
+use std::any::Any;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::Poll;
+
+/// A subscription in progress, resolving to its `Event::result`.
+type SubscriptionFuture<'a> = Pin<Box<dyn Future<Output = Result<u64, Error>> + Send + 'a>>;
+
+pub struct AsyncWasiEnviron {
+    pub args: StringArray,
+    pub env: StringArray,
+    pub table: Table,
+    pub exit_code: i32,
+    pub sched: Box<dyn AsyncWasiSched>,
+    pub metrics: Metrics,
+    cancel: CancelHandle,
+}
+impl AsyncWasiEnviron {
+    pub fn new(sched: Box<dyn AsyncWasiSched>) -> Self {
+        let mut environ = AsyncWasiEnviron {
+            args: StringArray::new(),
+            env: StringArray::new(),
+            table: Table::new(),
+            exit_code: 0,
+            sched,
+            metrics: Metrics::new(),
+            cancel: CancelHandle::new(),
+        };
+
+        environ.set_stdin(Box::new(crate::pipe::ReadPipe::new(std::io::empty())));
+        environ.set_stdout(Box::new(crate::pipe::WritePipe::new(std::io::sink())));
+        environ.set_stderr(Box::new(crate::pipe::WritePipe::new(std::io::sink())));
+
+        environ
+    }
+
+    /// A handle that interrupts the blocking syscalls of this environ, from any thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Bound the descriptors the guest can open. See `WasiEnviron::set_limits`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.table.set_limits(limits)
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.table.limits()
+    }
+
+    /// How many descriptors, sockets and derived directories are open, against their limits.
+    pub fn usage(&self) -> Usage {
+        self.table.usage()
+    }
+
+    pub fn push_arg(&mut self, arg: &str) -> Result<(), StringArrayError> {
+        self.args.push(arg.to_owned())
+    }
+
+    pub fn push_env(&mut self, var: &str, value: &str) -> Result<(), StringArrayError> {
+        self.env.push(format!("{}={}", var, value))
+    }
+
+    pub fn push_preopened_dir(
+        &mut self,
+        dir: Box<dyn AsyncWasiDir>,
+        path: impl AsRef<Path>,
+    ) -> Result<u32, Error> {
+        let caps = DirCaps::all();
+        let file_caps = FileCaps::all();
+        let fd = self.table().push(Box::new(AsyncDirEntry::new(
+            caps,
+            file_caps,
+            Some(path.as_ref().to_owned()),
+            dir,
+        )))?;
+        self.metrics.preopen(fd, path.as_ref());
+        Ok(fd)
+    }
+
+    pub fn set_stdin(&mut self, mut f: Box<dyn AsyncWasiFile>) {
+        let rights = Self::stdio_rights(&mut *f);
+        self.insert_file(0, f, rights);
+    }
+
+    pub fn set_stdout(&mut self, mut f: Box<dyn AsyncWasiFile>) {
+        let rights = Self::stdio_rights(&mut *f);
+        self.insert_file(1, f, rights);
+    }
+
+    pub fn set_stderr(&mut self, mut f: Box<dyn AsyncWasiFile>) {
+        let rights = Self::stdio_rights(&mut *f);
+        self.insert_file(2, f, rights);
+    }
+
+    fn stdio_rights(f: &mut dyn AsyncWasiFile) -> FileCaps {
+        let mut rights = FileCaps::all();
+
+        // See `WasiEnviron::stdio_rights`.
+        if f.isatty() {
+            rights &= !(FileCaps::TELL | FileCaps::SEEK);
+        }
+
+        rights
+    }
+
+    /// Put `file` at `fd`. If the file it replaces had an output quota, `file` takes it over.
+    pub fn insert_file(&mut self, fd: u32, file: Box<dyn AsyncWasiFile>, caps: FileCaps) {
+        let mut entry = AsyncFileEntry::new(caps, file);
+        entry.set_quota(self.output_quota(fd));
+        self.table().insert_at(fd, Box::new(entry));
+    }
+
+    /// Bound how many bytes the guest may write to `fd`, or lift the bound with `None`. See
+    /// `WasiEnviron::set_output_quota`.
+    pub fn set_output_quota(&mut self, fd: u32, quota: Option<OutputQuota>) -> Result<(), Error> {
+        self.table.get_mut::<AsyncFileEntry>(fd)?.set_quota(quota);
+        Ok(())
+    }
+
+    /// The output quota of `fd`, if it has one. Read the usage from it.
+    pub fn output_quota(&self, fd: u32) -> Option<OutputQuota> {
+        self.table.get::<AsyncFileEntry>(fd).ok()?.quota().cloned()
+    }
+
+    pub fn push_file(
+        &mut self,
+        file: Box<dyn AsyncWasiFile>,
+        caps: FileCaps,
+    ) -> Result<u32, Error> {
+        self.table().push(Box::new(AsyncFileEntry::new(caps, file)))
+    }
+
+    pub fn table(&mut self) -> &mut Table {
+        &mut self.table
+    }
+
+    pub fn insert_dir(
+        &mut self,
+        fd: u32,
+        dir: Box<dyn AsyncWasiDir>,
+        caps: DirCaps,
+        file_caps: FileCaps,
+        path: PathBuf,
+    ) {
+        self.metrics.preopen(fd, &path);
+        self.table().insert_at(
+            fd,
+            Box::new(AsyncDirEntry::new(caps, file_caps, Some(path), dir)),
+        );
+    }
+
+    pub fn push_dir(
+        &mut self,
+        dir: Box<dyn AsyncWasiDir>,
+        caps: DirCaps,
+        file_caps: FileCaps,
+        path: PathBuf,
+    ) -> Result<u32, Error> {
+        let fd = self.table().push(Box::new(AsyncDirEntry::new(
+            caps,
+            file_caps,
+            Some(path.clone()),
+            dir,
+        )))?;
+        self.metrics.preopen(fd, &path);
+        Ok(fd)
+    }
+
+    /// Build the future that completes when the subscription does.
+    fn subscribe<'a>(&'a self, sub: &Subscription) -> Result<SubscriptionFuture<'a>, Error> {
+        let fut: SubscriptionFuture<'a> = match sub.kind {
+            SubscriptionKind::Clock(timeout) => Box::pin(async move {
+                self.sched.sleep(timeout).await?;
+                Ok(0)
+            }),
+            SubscriptionKind::FdRead(fd) => {
+                let f = self
+                    .table
+                    .get::<AsyncFileEntry>(fd)?
+                    .get_cap(FileCaps::POLL_READWRITE)?;
+                Box::pin(async move {
+                    f.readable().await?;
+                    f.num_ready_bytes().await
+                })
+            }
+            SubscriptionKind::FdWrite(fd) => {
+                let f = self
+                    .table
+                    .get::<AsyncFileEntry>(fd)?
+                    .get_cap(FileCaps::POLL_READWRITE)?;
+                Box::pin(async move {
+                    f.writable().await?;
+                    Ok(0)
+                })
+            }
+        };
+        Ok(fut)
+    }
+
+    /// `poll_oneoff` without the tracing.
+    async fn poll_oneoff_inner(&self, subs: &[Subscription]) -> Result<Vec<Event>, Error> {
+        if subs.is_empty() {
+            return Err(Error::invalid_argument().context("no subscriptions"));
+        }
+
+        // Subscriptions that can't even be set up, such as a bad fd, complete immediately.
+        let mut events = Vec::new();
+        let mut pending = Vec::new();
+        for sub in subs {
+            match self.subscribe(sub) {
+                Ok(fut) => pending.push((sub.userdata, EventKind::from(&sub.kind), fut)),
+                Err(e) => events.push(Event {
+                    userdata: sub.userdata,
+                    kind: EventKind::from(&sub.kind),
+                    result: Err(e),
+                }),
+            }
+        }
+        if !events.is_empty() {
+            return Ok(events);
+        }
+
+        // Wait for the first subscription to complete, and report every one that is complete
+        // by then.
+        let wait = std::future::poll_fn(|cx| {
+            for (userdata, kind, fut) in pending.iter_mut() {
+                if let Poll::Ready(result) = fut.as_mut().poll(cx) {
+                    events.push(Event {
+                        userdata: *userdata,
+                        kind: *kind,
+                        result,
+                    });
+                }
+            }
+            if events.is_empty() {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        });
+        self.cancel.interruptible(wait).await?;
+
+        Ok(events)
+    }
+}
+
+#[async_trait]
+impl AsyncWasiSnapshotPreview1 for AsyncWasiEnviron {
+    async fn args_sizes_get(&self) -> (i32, i32) {
+        let _span = syscall_span!(
+            &self.metrics,
+            "args_sizes_get",
+            argc = self.args.number_elements(),
+            argv_buf_size = self.args.cumulative_size(),
+        );
+        (
+            self.args.number_elements() as i32,
+            self.args.cumulative_size() as i32,
+        )
+    }
+
+    async fn args_get(&self, out: &mut Vec<Ciovec>) {
+        let _span = syscall_span!(
+            &self.metrics,
+            "args_get",
+            argc = self.args.number_elements()
+        );
+        for arg in self.args.elements() {
+            out.push(Ciovec {
+                buf: arg.as_ptr(),
+                buf_len: arg.len(),
+            });
+        }
+    }
+
+    async fn environ_sizes_get(&self) -> (i32, i32) {
+        let _span = syscall_span!(
+            &self.metrics,
+            "environ_sizes_get",
+            environc = self.env.number_elements(),
+            environ_buf_size = self.env.cumulative_size(),
+        );
+        (
+            self.env.number_elements() as i32,
+            self.env.cumulative_size() as i32,
+        )
+    }
+
+    async fn environ_get(&self, out: &mut Vec<Ciovec>) {
+        let _span = syscall_span!(
+            &self.metrics,
+            "environ_get",
+            environc = self.env.number_elements()
+        );
+        for env in self.env.elements() {
+            out.push(Ciovec {
+                buf: env.as_ptr(),
+                buf_len: env.len(),
+            });
+        }
+    }
+
+    async fn fd_close(&mut self, fd: i32) -> Result<(), Error> {
+        let span = syscall_span!(&self.metrics, "fd_close", fd = fd);
+
+        let fd = fd as u32;
+        let table = self.table();
+        let result = if table.is::<AsyncFileEntry>(fd) || table.is::<AsyncDirEntry>(fd) {
+            table.delete(fd);
+            self.metrics.close(fd);
+            Ok(())
+        } else if table.contains_key(fd) {
+            Err(Error::badf().context("key does not refer to file or directory"))
+        } else {
+            Err(Error::badf().context("key not in table"))
+        };
+
+        span.finish(result)
+    }
+
+    async fn fd_read(&mut self, fd: i32, iovs: IovecArray<'_>) -> Result<i32, Error> {
+        let span = syscall_span!(&self.metrics, "fd_read", fd = fd, iovs_len = iovs.len(); fdflags);
+
+        let mut io_slice_vec = iovs
+            .iter()
+            .map(|iov| {
+                let buf: &mut [u8] =
+                    unsafe { std::slice::from_raw_parts_mut(iov.buf, iov.buf_len) };
+                std::io::IoSliceMut::new(buf)
+            })
+            .collect::<Vec<_>>();
+
+        let result = async {
+            let f = self
+                .table
+                .get_mut::<AsyncFileEntry>(fd as u32)?
+                .get_cap_mut(FileCaps::READ)?;
+            if span.is_enabled() {
+                if let Ok(fdflags) = f.get_fdflags().await {
+                    span.record("fdflags", fdflags);
+                }
+            }
+
+            let n_read_bytes = self
+                .cancel
+                .interruptible(f.read_vectored(&mut io_slice_vec))
+                .await?;
+            span.record_nbytes(n_read_bytes);
+            self.metrics.record_read(fd as u32, n_read_bytes);
+            Ok(i32::try_from(n_read_bytes)?)
+        }
+        .await;
+
+        span.finish(result)
+    }
+
+    async fn fd_write(&mut self, fd: i32, iovs: CiovecArray<'_>) -> Result<i32, Error> {
+        let span =
+            syscall_span!(&self.metrics, "fd_write", fd = fd, iovs_len = iovs.len(); fdflags);
+
+        let io_slice_vec = iovs
+            .iter()
+            .map(|iov| {
+                let buf: &[u8] = unsafe { std::slice::from_raw_parts(iov.buf, iov.buf_len) };
+                std::io::IoSlice::new(buf)
+            })
+            .collect::<Vec<_>>();
+
+        let result = async {
+            let entry = self.table.get_mut::<AsyncFileEntry>(fd as u32)?;
+            let quota = entry.quota().cloned();
+            let f = entry.get_cap_mut(FileCaps::WRITE)?;
+            if span.is_enabled() {
+                if let Ok(fdflags) = f.get_fdflags().await {
+                    span.record("fdflags", fdflags);
+                }
+            }
+
+            let n_written_bytes = match quota {
+                Some(quota) => {
+                    let grant = quota.reserve(&io_slice_vec)?;
+                    let written = if grant.is_empty() {
+                        Ok(0)
+                    } else {
+                        let bufs = grant.bufs(&io_slice_vec);
+                        self.cancel.interruptible(f.write_vectored(&bufs)).await
+                    };
+                    let (n, marker) = grant.finish(written)?;
+                    if let Some(marker) = marker {
+                        let _ = f.write_vectored(&[std::io::IoSlice::new(&marker)]).await;
+                    }
+                    n
+                }
+                None => {
+                    self.cancel
+                        .interruptible(f.write_vectored(&io_slice_vec))
+                        .await?
+                }
+            };
+            span.record_nbytes(n_written_bytes);
+            self.metrics.record_write(fd as u32, n_written_bytes);
+            Ok(i32::try_from(n_written_bytes)?)
+        }
+        .await;
+
+        span.finish(result)
+    }
+
+    async fn poll_oneoff(&self, subs: &[Subscription]) -> Result<Vec<Event>, Error> {
+        let span =
+            syscall_span!(&self.metrics, "poll_oneoff", nsubscriptions = subs.len(); nevents);
+        let result = self.poll_oneoff_inner(subs).await;
+        if let Ok(events) = &result {
+            span.record("nevents", events.len());
+        }
+        span.finish(result)
+    }
+
+    async fn path_open(
+        &mut self,
+        dirfd: i32,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        dir_caps: DirCaps,
+        file_caps: FileCaps,
+        fdflags: FdFlags,
+    ) -> Result<i32, Error> {
+        let span = syscall_span!(
+            &self.metrics,
+            "path_open",
+            dirfd = dirfd,
+            path = path,
+            symlink_follow = symlink_follow;
+            oflags,
+            fdflags,
+            opened_fd
+        );
+        if span.is_enabled() {
+            span.record("oflags", oflags);
+            span.record("fdflags", fdflags);
+        }
+
+        let result = async {
+            let dirfd = dirfd as u32;
+            let table = self.table();
+            if table.is::<AsyncFileEntry>(dirfd) {
+                return Err(Error::not_dir());
+            }
+            let dir_entry = table.get::<AsyncDirEntry>(dirfd)?;
+
+            let entry: Box<dyn Any + Send + Sync> = if oflags.contains(OFlags::DIRECTORY) {
+                if oflags.intersects(OFlags::CREATE | OFlags::EXCLUSIVE | OFlags::TRUNCATE) {
+                    return Err(Error::invalid_argument().context("directory oflags"));
+                }
+                let dir_caps = dir_entry.child_dir_caps(dir_caps);
+                let file_caps = dir_entry.child_file_caps(file_caps);
+                let dir = dir_entry.get_cap(DirCaps::OPEN)?;
+                let child_dir = dir.open_dir(symlink_follow, path).await?;
+                Box::new(AsyncDirEntry::new(dir_caps, file_caps, None, child_dir))
+            } else {
+                let mut required_caps = DirCaps::OPEN;
+                if oflags.contains(OFlags::CREATE) {
+                    required_caps |= DirCaps::CREATE_FILE;
+                }
+                let file_caps = dir_entry.child_file_caps(file_caps);
+                let dir = dir_entry.get_cap(required_caps)?;
+                let read = file_caps.contains(FileCaps::READ);
+                let write = file_caps
+                    .intersects(FileCaps::WRITE | FileCaps::ALLOCATE | FileCaps::FILESTAT_SET_SIZE);
+                let file = dir
+                    .open_file(symlink_follow, path, oflags, read, write, fdflags)
+                    .await?;
+                Box::new(AsyncFileEntry::new(file_caps, file))
+            };
+
+            let fd = table.push(entry)?;
+            self.metrics.inherit(fd, dirfd);
+            span.record("opened_fd", fd);
+            Ok(i32::try_from(fd)?)
+        }
+        .await;
+
+        span.finish(result)
+    }
+
+    async fn proc_exit(&mut self, code: i32) {
+        let _span = syscall_span!(&self.metrics, "proc_exit", code = code);
+        self.exit_code = code;
+    }
+
+    async fn sock_accept(&mut self, fd: i32, fdflags: FdFlags) -> Result<i32, Error> {
+        let span = syscall_span!(&self.metrics, "sock_accept", fd = fd; fdflags, accepted_fd);
+        if span.is_enabled() {
+            span.record("fdflags", fdflags);
+        }
+
+        let result = async {
+            let f = self
+                .table
+                .get_mut::<AsyncFileEntry>(fd as u32)?
+                .get_cap_mut(FileCaps::READ)?;
+            let file = self.cancel.interruptible(f.sock_accept(fdflags)).await?;
+            let entry = AsyncFileEntry::new(WasiEnviron::accepted_rights(), file).with_socket(true);
+            let fd = self.table.push(Box::new(entry))?;
+            span.record("accepted_fd", fd);
+            Ok(i32::try_from(fd)?)
+        }
+        .await;
+
+        span.finish(result)
+    }
+}
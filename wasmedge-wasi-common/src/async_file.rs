@@ -0,0 +1,175 @@
+//! The asynchronous counterpart of `WasiFile`.
+//!
+//! Every potentially blocking operation returns a future, so a host running many instances on
+//! an async runtime never parks a worker thread on guest I/O. The methods, their defaults and
+//! their error conventions are the same as on `WasiFile`.
+use crate::clocks::SystemTimeSpec;
+use crate::error::{Error, ErrorExt};
+use crate::file::{
+    Advice, FdFlags, FileCaps, FileEntry, FileType, Filestat, RiFlags, RoFlags, SdFlags, SiFlags,
+};
+use async_trait::async_trait;
+use std::any::Any;
+
+#[async_trait]
+pub trait AsyncWasiFile: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    async fn get_filetype(&mut self) -> Result<FileType, Error>;
+
+    #[cfg(unix)]
+    fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+        None
+    }
+
+    /// The host descriptor of a regular file whose contents are exactly what the guest sees. See
+    /// `WasiFile::host_fd`.
+    #[cfg(unix)]
+    fn host_fd(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+        None
+    }
+
+    fn isatty(&mut self) -> bool {
+        false
+    }
+
+    async fn sock_accept(&mut self, _fdflags: FdFlags) -> Result<Box<dyn AsyncWasiFile>, Error> {
+        Err(Error::badf())
+    }
+
+    async fn sock_recv<'a>(
+        &mut self,
+        _ri_data: &mut [std::io::IoSliceMut<'a>],
+        _ri_flags: RiFlags,
+    ) -> Result<(u64, RoFlags), Error> {
+        Err(Error::badf())
+    }
+
+    async fn sock_send<'a>(
+        &mut self,
+        _si_data: &[std::io::IoSlice<'a>],
+        _si_flags: SiFlags,
+    ) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn sock_shutdown(&mut self, _how: SdFlags) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn datasync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(FdFlags::empty())
+    }
+
+    async fn set_fdflags(&mut self, _flags: FdFlags) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        Ok(Filestat {
+            device_id: 0,
+            inode: 0,
+            filetype: self.get_filetype().await?,
+            nlink: 0,
+            size: 0, // XXX no way to get a size out of a Read :(
+            atim: None,
+            mtim: None,
+            ctim: None,
+        })
+    }
+
+    async fn set_filestat_size(&mut self, _size: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn advise(&mut self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn allocate(&mut self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn set_times(
+        &mut self,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn read_vectored<'a>(
+        &mut self,
+        _bufs: &mut [std::io::IoSliceMut<'a>],
+    ) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn read_vectored_at<'a>(
+        &mut self,
+        _bufs: &mut [std::io::IoSliceMut<'a>],
+        _offset: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn write_vectored<'a>(&mut self, _bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn write_vectored_at<'a>(
+        &mut self,
+        _bufs: &[std::io::IoSlice<'a>],
+        _offset: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn seek(&mut self, _pos: std::io::SeekFrom) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn peek(&mut self, _buf: &mut [u8]) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    /// Resolve once the file is ready for reading. Used by `poll_oneoff`.
+    async fn readable(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    /// Resolve once the file is ready for writing. Used by `poll_oneoff`.
+    async fn writable(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+}
+
+pub(crate) type AsyncFileEntry = FileEntry<dyn AsyncWasiFile>;
+
+pub trait AsyncFileEntryExt {
+    fn get_cap(&self, caps: FileCaps) -> Result<&dyn AsyncWasiFile, Error>;
+    fn get_cap_mut(&mut self, caps: FileCaps) -> Result<&mut dyn AsyncWasiFile, Error>;
+}
+
+impl AsyncFileEntryExt for AsyncFileEntry {
+    fn get_cap(&self, caps: FileCaps) -> Result<&dyn AsyncWasiFile, Error> {
+        self.capable_of(caps)?;
+        Ok(self.file())
+    }
+
+    fn get_cap_mut(&mut self, caps: FileCaps) -> Result<&mut dyn AsyncWasiFile, Error> {
+        self.capable_of(caps)?;
+        Ok(self.file_mut())
+    }
+}
@@ -0,0 +1,280 @@
+//! Interrupting a guest blocked in a syscall.
+//!
+//! Every environ owns a `CancelHandle`. Clone it before starting the guest, and call `cancel`
+//! from any other thread to make the blocking syscalls in progress, and every one after them,
+//! fail with `Errno::Intr`. Call `reset` to let the guest block again.
+//!
+//! Synchronous syscalls wait for their backend to become ready before touching it, so only
+//! backends that expose `WasiFile::pollable` can be woken once they have started waiting;
+//! others only notice the cancellation before their next syscall. Asynchronous syscalls race the
+//! backend against the cancellation.
+use crate::error::{Error, ErrorExt};
+use crate::file::WasiFile;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(unix)]
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub struct CancelHandle(Arc<CancelHandleInner>);
+
+struct CancelHandleInner {
+    cancelled: AtomicBool,
+    /// The wakers of the `Cancelled` futures being polled, by ID.
+    wakers: Mutex<BTreeMap<u64, Waker>>,
+    next_id: AtomicU64,
+    /// A byte is written to the pipe while cancelled, so that it can be polled alongside the
+    /// descriptor a syscall waits on. It is only created once a syscall first blocks, so that
+    /// environs that never block don't hold host descriptors for it.
+    #[cfg(unix)]
+    pipe: OnceLock<(rustix::fd::OwnedFd, rustix::fd::OwnedFd)>,
+    /// Held while the flag changes or the pipe is created, so that the pipe holds a byte
+    /// exactly when the handle is cancelled.
+    #[cfg(unix)]
+    pipe_lock: Mutex<()>,
+}
+
+impl Default for CancelHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl CancelHandle {
+    /// Create a handle that isn't cancelled.
+    pub fn new() -> Self {
+        CancelHandle(Arc::new(CancelHandleInner {
+            cancelled: AtomicBool::new(false),
+            wakers: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            #[cfg(unix)]
+            pipe: OnceLock::new(),
+            #[cfg(unix)]
+            pipe_lock: Mutex::new(()),
+        }))
+    }
+
+    /// Wake every syscall blocked on this handle, and make them fail until `reset`.
+    pub fn cancel(&self) {
+        #[cfg(unix)]
+        {
+            let _guard = self.0.pipe_lock.lock().unwrap();
+            if !self.0.cancelled.swap(true, Ordering::SeqCst) {
+                if let Some(pipe) = self.0.pipe.get() {
+                    let _ = rustix::io::write(&pipe.1, &[0]);
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        let wakers = std::mem::take(&mut *self.0.wakers.lock().unwrap());
+        for waker in wakers.into_values() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Let syscalls block again after `cancel`.
+    pub fn reset(&self) {
+        #[cfg(unix)]
+        {
+            let _guard = self.0.pipe_lock.lock().unwrap();
+            if self.0.cancelled.swap(false, Ordering::SeqCst) {
+                // The pipe holds exactly one byte while cancelled.
+                if let Some(pipe) = self.0.pipe.get() {
+                    let _ = rustix::io::read(&pipe.0, &mut [0]);
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        self.0.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    /// The wakeup pipe, created on first use with a byte in it if already cancelled.
+    #[cfg(unix)]
+    fn pipe(&self) -> Result<&(rustix::fd::OwnedFd, rustix::fd::OwnedFd), Error> {
+        if let Some(pipe) = self.0.pipe.get() {
+            return Ok(pipe);
+        }
+        let _guard = self.0.pipe_lock.lock().unwrap();
+        if self.0.pipe.get().is_none() {
+            let pipe = rustix::io::pipe().map_err(std::io::Error::from)?;
+            if self.is_cancelled() {
+                rustix::io::write(&pipe.1, &[0]).map_err(std::io::Error::from)?;
+            }
+            let _ = self.0.pipe.set(pipe);
+        }
+        Ok(self.0.pipe.get().unwrap())
+    }
+
+    /// Fail with `Errno::Intr` if cancelled.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            Err(interrupted())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Block until `file` is ready for reading, or fail if cancelled first, or with `Timedout`
+    /// once `timeout` has passed.
+    ///
+    /// Non-blocking files, and files that can't be polled, are only checked for cancellation.
+    pub fn wait_readable(
+        &self,
+        file: &mut dyn WasiFile,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        #[cfg(unix)]
+        return self.wait(file, rustix::io::PollFlags::IN, timeout);
+        #[cfg(not(unix))]
+        return self.wait(file, timeout);
+    }
+
+    /// Block until `file` is ready for writing, or fail if cancelled first, or with `Timedout`
+    /// once `timeout` has passed.
+    ///
+    /// Non-blocking files, and files that can't be polled, are only checked for cancellation.
+    pub fn wait_writable(
+        &self,
+        file: &mut dyn WasiFile,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        #[cfg(unix)]
+        return self.wait(file, rustix::io::PollFlags::OUT, timeout);
+        #[cfg(not(unix))]
+        return self.wait(file, timeout);
+    }
+
+    #[cfg(unix)]
+    fn wait(
+        &self,
+        file: &mut dyn WasiFile,
+        events: rustix::io::PollFlags,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        use crate::file::FdFlags;
+        use rustix::io::{PollFd, PollFlags};
+
+        self.check()?;
+        let nonblocking = file
+            .get_fdflags()
+            .is_ok_and(|fdflags| fdflags.contains(FdFlags::NONBLOCK));
+        let fd = match file.pollable() {
+            Some(fd) if !nonblocking => fd,
+            _ => return Ok(()),
+        };
+        let pipe = self.pipe()?;
+        let mut fds = [
+            PollFd::new(&fd, events),
+            PollFd::new(&pipe.0, PollFlags::IN),
+        ];
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let millis = match deadline {
+                // Round up, so that we never wake before the deadline and spin.
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_nanos()
+                    .div_ceil(1_000_000)
+                    .try_into()
+                    .unwrap_or(i32::MAX),
+                None => -1,
+            };
+            match rustix::io::poll(&mut fds, millis) {
+                Ok(0) => return Err(Error::timed_out().context("deadline passed")),
+                Ok(_) => break,
+                Err(rustix::io::Errno::INTR) => continue,
+                Err(e) => return Err(std::io::Error::from(e).into()),
+            }
+        }
+        self.check()
+    }
+
+    #[cfg(not(unix))]
+    fn wait(&self, _file: &mut dyn WasiFile, _timeout: Option<Duration>) -> Result<(), Error> {
+        self.check()
+    }
+
+    /// A future that resolves once the handle is cancelled.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            handle: self.clone(),
+            id: self.0.next_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Run `fut` to completion, unless the handle is cancelled first.
+    pub async fn interruptible<T>(
+        &self,
+        fut: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        self.check()?;
+        let mut fut = std::pin::pin!(fut);
+        let mut cancelled = self.cancelled();
+        std::future::poll_fn(|cx| {
+            if let Poll::Ready(result) = fut.as_mut().poll(cx) {
+                return Poll::Ready(result);
+            }
+            Pin::new(&mut cancelled)
+                .poll(cx)
+                .map(|()| Err(interrupted()))
+        })
+        .await
+    }
+}
+
+fn interrupted() -> Error {
+    Error::interrupted().context("cancelled by the host")
+}
+
+/// Returned by `CancelHandle::cancelled`.
+pub struct Cancelled {
+    handle: CancelHandle,
+    id: u64,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let inner = &self.handle.0;
+        if inner.cancelled.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        inner
+            .wakers
+            .lock()
+            .unwrap()
+            .insert(self.id, cx.waker().clone());
+        // `cancel` may have taken the wakers between the check and the insert.
+        if inner.cancelled.load(Ordering::SeqCst) {
+            inner.wakers.lock().unwrap().remove(&self.id);
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Cancelled {
+    fn drop(&mut self) {
+        self.handle.0.wakers.lock().unwrap().remove(&self.id);
+    }
+}
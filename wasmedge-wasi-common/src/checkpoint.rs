@@ -0,0 +1,313 @@
+//! Saving and restoring the WASI state of an environ, for checkpointing instances.
+//!
+//! `WasiEnviron::checkpoint` captures the arguments, environment, exit code and every
+//! descriptor in the table as a `Checkpoint`, which is plain data: with the `serde` feature it
+//! can be written in any format alongside a snapshot of the guest's memory.
+//!
+//! The backends themselves are opaque, so a `HostResources` implementation tells the
+//! checkpoint where each one lives on the host, and opens it again on restore. Descriptors it
+//! can't locate or reopen, such as in-memory pipes, are reported by `Checkpoint::restore` rather
+//! than failing the whole restore.
+use crate::descriptors::current_offset;
+use crate::dir::{DirCaps, DirEntry, WasiDir};
+use crate::environ::WasiEnviron;
+use crate::error::{Error, ErrorExt};
+use crate::file::{FdFlags, FileCaps, FileEntry, FileType, WasiFile};
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Where a backend lives on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Resource {
+    Stdin,
+    Stdout,
+    Stderr,
+    /// A file at an absolute host path, opened for reading, writing or both.
+    File {
+        path: PathBuf,
+        read: bool,
+        write: bool,
+    },
+    /// A directory at an absolute host path.
+    Dir {
+        path: PathBuf,
+    },
+    TcpListener {
+        local_addr: SocketAddr,
+    },
+    /// A TCP connection. It can be saved, but not reopened: the peer would see a new one.
+    TcpStream {
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    },
+    UnixListener {
+        path: Option<PathBuf>,
+    },
+    UnixStream {
+        peer_path: Option<PathBuf>,
+    },
+}
+
+/// Finds the host resources behind backends, and opens them again.
+pub trait HostResources {
+    /// Where `file` lives, or `None` if it has no home on the host, like a pipe.
+    fn locate_file(&self, file: &dyn WasiFile) -> Option<Resource>;
+
+    /// Where `dir` lives, or `None` if it has no home on the host.
+    fn locate_dir(&self, dir: &dyn WasiDir) -> Option<Resource>;
+
+    fn reopen_file(
+        &self,
+        resource: &Resource,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error>;
+
+    fn reopen_dir(&self, resource: &Resource) -> Result<Box<dyn WasiDir>, Error>;
+}
+
+/// The saved state of one descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FdState {
+    File {
+        /// The bits of the `FileCaps` the guest holds.
+        caps: u32,
+        filetype: FileType,
+        /// The bits of the file's `FdFlags`.
+        fdflags: u32,
+        /// The current offset, for files that can seek.
+        offset: Option<u64>,
+        resource: Option<Resource>,
+        /// The preopen the file was opened through, whose revocation reaches it.
+        #[cfg_attr(feature = "serde", serde(default))]
+        origin: Option<u32>,
+    },
+    Dir {
+        /// The bits of the `DirCaps` the guest holds on the directory.
+        caps: u32,
+        /// The bits of the `FileCaps` the guest holds on files opened through it.
+        file_caps: u32,
+        preopen_path: Option<PathBuf>,
+        resource: Option<Resource>,
+        /// The preopen a derived directory was opened through.
+        #[cfg_attr(feature = "serde", serde(default))]
+        origin: Option<u32>,
+    },
+}
+
+/// The saved WASI state of a `WasiEnviron`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint {
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    pub exit_code: i32,
+    pub fds: BTreeMap<u32, FdState>,
+}
+
+/// A descriptor that `Checkpoint::restore` left closed.
+#[derive(Debug)]
+pub struct Unrestored {
+    pub fd: u32,
+    pub error: Error,
+}
+
+impl WasiEnviron {
+    /// Save the WASI state of this environ. Descriptors that `host` can't locate are saved
+    /// without a resource, and reported when restoring.
+    pub fn checkpoint(&mut self, host: &dyn HostResources) -> Result<Checkpoint, Error> {
+        let mut fds = BTreeMap::new();
+        for fd in self.table.keys() {
+            let state = if self.table.is::<FileEntry>(fd) {
+                let entry: &mut FileEntry = self.table.get_mut(fd)?;
+                let (caps, origin) = (entry.caps(), entry.origin());
+                let file = entry.file_mut();
+                let filetype = file.get_filetype()?;
+                let fdflags = file.get_fdflags()?;
+                FdState::File {
+                    caps: caps.bits(),
+                    filetype,
+                    fdflags: fdflags.bits(),
+                    offset: current_offset(file, filetype),
+                    resource: host.locate_file(file),
+                    origin,
+                }
+            } else if self.table.is::<DirEntry>(fd) {
+                let entry: &DirEntry = self.table.get(fd)?;
+                let fdstat = entry.get_dir_fdstat();
+                FdState::Dir {
+                    caps: fdstat.dir_caps.bits(),
+                    file_caps: fdstat.file_caps.bits(),
+                    preopen_path: entry.preopen_path().clone(),
+                    resource: host.locate_dir(entry.dir()),
+                    origin: entry.origin(),
+                }
+            } else {
+                // Resources from other proposals sharing the table aren't ours to save.
+                continue;
+            };
+            fds.insert(fd, state);
+        }
+
+        Ok(Checkpoint {
+            args: self.args.elements().into_iter().map(String::from).collect(),
+            env: self.env.elements().into_iter().map(String::from).collect(),
+            exit_code: self.exit_code,
+            fds,
+        })
+    }
+}
+
+impl Checkpoint {
+    /// Build an environ from this checkpoint, reopening every descriptor through `host` at its
+    /// saved number, caps and offset.
+    ///
+    /// Descriptors that couldn't be restored are left closed and returned alongside the environ,
+    /// so the caller can decide whether to carry on without them or to fill them in. Every
+    /// other descriptor is closed, the stdio ones included: a guest that had closed stdout
+    /// doesn't find it open again.
+    pub fn restore(
+        &self,
+        host: &dyn HostResources,
+    ) -> Result<(WasiEnviron, Vec<Unrestored>), Error> {
+        let mut environ = WasiEnviron::new();
+        for fd in environ.table.keys() {
+            environ.table.delete(fd);
+        }
+        for arg in &self.args {
+            environ
+                .args
+                .push(arg.clone())
+                .map_err(|e| Error::trap(e.to_string()))?;
+        }
+        for var in &self.env {
+            environ
+                .env
+                .push(var.clone())
+                .map_err(|e| Error::trap(e.to_string()))?;
+        }
+        environ.exit_code = self.exit_code;
+
+        let mut unrestored = Vec::new();
+        for (&fd, state) in &self.fds {
+            if let Err(error) = Self::restore_fd(&mut environ, host, fd, state) {
+                unrestored.push(Unrestored { fd, error });
+            }
+        }
+        Ok((environ, unrestored))
+    }
+
+    fn restore_fd(
+        environ: &mut WasiEnviron,
+        host: &dyn HostResources,
+        fd: u32,
+        state: &FdState,
+    ) -> Result<(), Error> {
+        match state {
+            FdState::File {
+                caps,
+                fdflags,
+                offset,
+                resource,
+                origin,
+                ..
+            } => {
+                let resource = resource
+                    .as_ref()
+                    .ok_or_else(|| Error::not_supported().context("no host resource was saved"))?;
+                let mut file = host.reopen_file(resource, FdFlags::from_bits_truncate(*fdflags))?;
+                if let Some(offset) = offset {
+                    file.seek(SeekFrom::Start(*offset))?;
+                }
+                let entry = environ
+                    .file_entry(Some(fd), file, FileCaps::from_bits_truncate(*caps))
+                    .with_origin(*origin);
+                environ.table.insert_at(fd, Box::new(entry));
+            }
+            FdState::Dir {
+                caps,
+                file_caps,
+                preopen_path,
+                resource,
+                origin,
+            } => {
+                let resource = resource
+                    .as_ref()
+                    .ok_or_else(|| Error::not_supported().context("no host resource was saved"))?;
+                let dir = host.reopen_dir(resource)?;
+                let caps = DirCaps::from_bits_truncate(*caps);
+                let file_caps = FileCaps::from_bits_truncate(*file_caps);
+                match preopen_path {
+                    Some(path) => environ.insert_dir(fd, dir, caps, file_caps, path.clone()),
+                    None => {
+                        let entry = DirEntry::new(caps, file_caps, None, dir).with_origin(*origin);
+                        environ.table.insert_at(fd, Box::new(entry))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::{tests::Clock, MemFs};
+
+    /// Finds every directory at one host path, and reopens it as the root of `fs`.
+    struct Host {
+        fs: MemFs,
+    }
+
+    impl HostResources for Host {
+        fn locate_file(&self, _file: &dyn WasiFile) -> Option<Resource> {
+            None
+        }
+        fn locate_dir(&self, _dir: &dyn WasiDir) -> Option<Resource> {
+            Some(Resource::Dir {
+                path: PathBuf::from("/host"),
+            })
+        }
+        fn reopen_file(
+            &self,
+            _resource: &Resource,
+            _fdflags: FdFlags,
+        ) -> Result<Box<dyn WasiFile>, Error> {
+            Err(Error::not_supported())
+        }
+        fn reopen_dir(&self, _resource: &Resource) -> Result<Box<dyn WasiDir>, Error> {
+            Ok(Box::new(self.fs.root()))
+        }
+    }
+
+    #[test]
+    fn restore_keeps_origins_and_closed_slots() {
+        let host = Host {
+            fs: MemFs::new(Box::new(Clock)),
+        };
+        let mut environ = WasiEnviron::new();
+        let preopen = environ
+            .push_preopened_dir(Box::new(host.fs.root()), "/data")
+            .unwrap();
+        let dir: Box<dyn WasiDir> = Box::new(host.fs.root());
+        let derived =
+            DirEntry::new(DirCaps::all(), FileCaps::all(), None, dir).with_origin(Some(preopen));
+        let derived = environ.table.push(Box::new(derived)).unwrap();
+        // The guest closed stdout.
+        environ.table.delete(1);
+
+        let checkpoint = environ.checkpoint(&host).unwrap();
+        let (mut restored, unrestored) = checkpoint.restore(&host).unwrap();
+        let unrestored = unrestored.iter().map(|u| u.fd).collect::<Vec<_>>();
+        assert_eq!(unrestored, [0, 2]);
+        assert_eq!(restored.table.keys(), [preopen, derived]);
+
+        restored.revoke_preopen(preopen).unwrap();
+        let entry: &DirEntry = restored.table.get(derived).unwrap();
+        assert!(entry.is_revoked());
+    }
+}
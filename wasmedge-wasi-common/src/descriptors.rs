@@ -0,0 +1,166 @@
+//! Listing what a guest has open, for debugging and leak detection.
+use crate::checkpoint::{HostResources, Resource};
+use crate::dir::{DirCaps, DirEntry, WasiDir};
+use crate::environ::WasiEnviron;
+use crate::file::{FdFlags, FileCaps, FileEntry, FileType, WasiFile};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+/// A broad classification of a descriptor, for display.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FdKind {
+    /// Descriptors 0, 1 and 2, whatever backs them.
+    Stdio,
+    File,
+    Dir,
+    Socket,
+    Pipe,
+}
+
+/// What the table holds at one descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdInfo {
+    pub fd: u32,
+    pub kind: FdKind,
+    /// `Unknown` if the backend failed to report it.
+    pub filetype: FileType,
+    /// The rights on the directory itself, for directories.
+    pub dir_caps: Option<DirCaps>,
+    /// The rights on the file, or for directories, on files opened through them.
+    pub file_caps: FileCaps,
+    /// Empty for directories, and if the backend failed to report them.
+    pub fdflags: FdFlags,
+    pub preopen_path: Option<PathBuf>,
+    /// Where the backend lives on the host, if a `HostResources` was given and knows.
+    pub resource: Option<Resource>,
+    /// The current offset, for files that can seek.
+    pub offset: Option<u64>,
+}
+
+impl FdInfo {
+    /// The host path of the file, directory or Unix listener, if known.
+    pub fn host_path(&self) -> Option<&Path> {
+        match self.resource.as_ref()? {
+            Resource::File { path, .. } | Resource::Dir { path } => Some(path),
+            Resource::UnixListener { path } => path.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// The offset of `file`, without moving it. Only regular files have a meaningful one.
+pub(crate) fn current_offset(file: &mut dyn WasiFile, filetype: FileType) -> Option<u64> {
+    match filetype {
+        FileType::RegularFile => file.seek(SeekFrom::Current(0)).ok(),
+        _ => None,
+    }
+}
+
+/// What the entry at `fd` says about itself. `resource` and `offset`, which need the backend,
+/// are left for `locate_file`.
+pub(crate) fn describe_file(fd: u32, entry: &FileEntry) -> FdInfo {
+    let filetype = entry.filetype();
+    let kind = match filetype {
+        _ if fd <= 2 => FdKind::Stdio,
+        FileType::SocketDgram | FileType::SocketStream => FdKind::Socket,
+        FileType::Pipe => FdKind::Pipe,
+        FileType::Directory => FdKind::Dir,
+        _ => FdKind::File,
+    };
+    FdInfo {
+        fd,
+        kind,
+        filetype,
+        dir_caps: None,
+        file_caps: entry.caps(),
+        fdflags: entry.fdflags(),
+        preopen_path: None,
+        resource: None,
+        offset: None,
+    }
+}
+
+/// What the directory entry at `fd` says about itself, leaving `resource` for `locate_dir`.
+pub(crate) fn describe_dir(fd: u32, entry: &DirEntry) -> FdInfo {
+    let fdstat = entry.get_dir_fdstat();
+    FdInfo {
+        fd,
+        kind: FdKind::Dir,
+        filetype: FileType::Directory,
+        dir_caps: Some(fdstat.dir_caps),
+        file_caps: fdstat.file_caps,
+        fdflags: FdFlags::empty(),
+        preopen_path: entry.preopen_path().clone(),
+        resource: None,
+        offset: None,
+    }
+}
+
+/// Fill in where `file` lives on the host, and its offset if it is a regular file.
+pub(crate) fn locate_file(
+    info: &mut FdInfo,
+    file: &dyn WasiFile,
+    host: Option<&dyn HostResources>,
+) {
+    info.resource = host.and_then(|host| host.locate_file(file));
+    if info.filetype == FileType::RegularFile {
+        info.offset = file.offset();
+    }
+}
+
+pub(crate) fn locate_dir(info: &mut FdInfo, dir: &dyn WasiDir, host: Option<&dyn HostResources>) {
+    info.resource = host.and_then(|host| host.locate_dir(dir));
+}
+
+impl WasiEnviron {
+    /// List every descriptor in the table, in order. Pass `host` to also find where each one
+    /// lives on the host.
+    ///
+    /// This only asks the backends where they live and, without moving it, for their offset,
+    /// so it has no effect on them. Resources that other proposals keep in the same table are
+    /// skipped.
+    pub fn descriptors(&self, host: Option<&dyn HostResources>) -> Vec<FdInfo> {
+        let mut fds = Vec::new();
+        for fd in self.table.keys() {
+            if let Ok(entry) = self.table.get::<FileEntry>(fd) {
+                let mut info = describe_file(fd, entry);
+                locate_file(&mut info, entry.file(), host);
+                fds.push(info);
+            } else if let Ok(entry) = self.table.get::<DirEntry>(fd) {
+                let mut info = describe_dir(fd, entry);
+                locate_dir(&mut info, entry.dir(), host);
+                fds.push(info);
+            }
+        }
+        fds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dir::WasiDir;
+    use crate::file::OFlags;
+    use crate::memfs::{tests::Clock, MemFs};
+    use std::io::IoSlice;
+
+    #[test]
+    fn listing_leaves_the_offset_alone() {
+        let root = MemFs::new(Box::new(Clock)).root();
+        let mut file = root
+            .open_file(false, "f", OFlags::CREATE, true, true, FdFlags::APPEND)
+            .unwrap();
+        file.write_vectored(&[IoSlice::new(b"abc")]).unwrap();
+        let mut environ = WasiEnviron::new();
+        let fd = environ.push_file(file, FileCaps::all()).unwrap();
+
+        for _ in 0..2 {
+            let fds = environ.descriptors(None);
+            let info = fds.iter().find(|info| info.fd == fd).unwrap();
+            assert_eq!(info.kind, FdKind::File);
+            assert_eq!(info.filetype, FileType::RegularFile);
+            assert_eq!(info.fdflags, FdFlags::APPEND);
+            assert_eq!(info.offset, Some(3));
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! Virtual device files.
+//!
+//! Programs written for POSIX often open `/dev/null`, `/dev/zero`, `/dev/urandom` or `/dev/full`,
+//! which a WASI sandbox doesn't have. A `DeviceFile` behaves like one of them, and a `DevDir` is
+//! a read-only directory holding all four, for preopening at `/dev`.
+//!
+//! As on Linux, seeking always lands at offset 0, reading `null` gives end-of-file, reading
+//! `zero` or `full` gives zeros, and writing to `full` fails with `Nospc`. Anything else written
+//! is thrown away.
+use crate::clocks::SystemTimeSpec;
+use crate::dir::{new_device_id, ReaddirCursor, ReaddirEntity, WasiDir};
+use crate::error::{Errno, Error, ErrorExt};
+use crate::file::{Advice, FdFlags, FileType, Filestat, OFlags, WasiFile};
+use std::any::Any;
+use std::io::{IoSlice, IoSliceMut, Read, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The source of the bytes read from `urandom`, shared by every file opened from it.
+type Random = Arc<Mutex<dyn Read + Send>>;
+
+/// The devices of a `DevDir`, sorted by name. Their inode numbers follow the directory's.
+const DEVICES: [&str; 4] = ["full", "null", "urandom", "zero"];
+
+/// The inode number of a `DevDir` itself.
+const DIR_INODE: u64 = 1;
+
+#[derive(Clone)]
+enum Device {
+    Null,
+    Zero,
+    Full,
+    Urandom(Random),
+}
+
+/// An open device file.
+pub struct DeviceFile {
+    device: Device,
+    device_id: u64,
+    inode: u64,
+    fdflags: FdFlags,
+}
+
+impl DeviceFile {
+    /// A file that reads as empty and swallows writes, like `/dev/null`.
+    pub fn null() -> Self {
+        Self::standalone(Device::Null)
+    }
+
+    /// A file that reads as endless zeros and swallows writes, like `/dev/zero`.
+    pub fn zero() -> Self {
+        Self::standalone(Device::Zero)
+    }
+
+    /// A file that reads as endless zeros and is always full, like `/dev/full`.
+    pub fn full() -> Self {
+        Self::standalone(Device::Full)
+    }
+
+    /// A file that reads from `random` and swallows writes, like `/dev/urandom`.
+    pub fn urandom(random: impl Read + Send + 'static) -> Self {
+        Self::standalone(Device::Urandom(Arc::new(Mutex::new(random))))
+    }
+
+    fn standalone(device: Device) -> Self {
+        DeviceFile {
+            device,
+            device_id: new_device_id(),
+            inode: 1,
+            fdflags: FdFlags::empty(),
+        }
+    }
+
+    fn read(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<u64, Error> {
+        let mut total = 0;
+        for buf in bufs {
+            match &self.device {
+                Device::Null => return Ok(0),
+                Device::Zero | Device::Full => buf.fill(0),
+                Device::Urandom(random) => random.lock().unwrap().read_exact(buf)?,
+            }
+            total += buf.len() as u64;
+        }
+        Ok(total)
+    }
+
+    fn write(&mut self, bufs: &[IoSlice<'_>]) -> Result<u64, Error> {
+        match self.device {
+            Device::Full => Err(Error::no_space()),
+            _ => Ok(bufs.iter().map(|buf| buf.len() as u64).sum()),
+        }
+    }
+}
+
+impl WasiFile for DeviceFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::CharacterDevice)
+    }
+    fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(self.fdflags)
+    }
+    fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.fdflags = flags;
+        Ok(())
+    }
+    fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        Ok(device_filestat(self.device_id, self.inode))
+    }
+    fn set_filestat_size(&mut self, _size: u64) -> Result<(), Error> {
+        Err(Error::invalid_argument().context("device has no size"))
+    }
+    fn advise(&mut self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Ok(())
+    }
+    fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        self.read(bufs)
+    }
+    fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        _offset: u64,
+    ) -> Result<u64, Error> {
+        self.read(bufs)
+    }
+    fn write_vectored<'a>(&mut self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        self.write(bufs)
+    }
+    fn write_vectored_at<'a>(&mut self, bufs: &[IoSlice<'a>], _offset: u64) -> Result<u64, Error> {
+        self.write(bufs)
+    }
+    fn seek(&mut self, _pos: SeekFrom) -> Result<u64, Error> {
+        Ok(0)
+    }
+    fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.read(&mut [IoSliceMut::new(buf)])
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+    fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn device_filestat(device_id: u64, inode: u64) -> Filestat {
+    Filestat {
+        device_id,
+        inode,
+        filetype: FileType::CharacterDevice,
+        nlink: 1,
+        size: 0,
+        atim: None,
+        mtim: None,
+        ctim: None,
+    }
+}
+
+/// A directory holding `full`, `null`, `urandom` and `zero`. Nothing in it can be created,
+/// removed or renamed.
+#[derive(Clone)]
+pub struct DevDir {
+    device_id: u64,
+    random: Random,
+}
+
+impl DevDir {
+    /// The devices, with `urandom` reading from `random`.
+    pub fn new(random: impl Read + Send + 'static) -> Self {
+        DevDir {
+            device_id: new_device_id(),
+            random: Arc::new(Mutex::new(random)),
+        }
+    }
+
+    /// The index in `DEVICES` of the device `path` leads to, or `None` for the directory itself.
+    fn lookup(&self, path: &str) -> Result<Option<usize>, Error> {
+        if path.is_empty() {
+            return Err(Error::not_found().context("empty path"));
+        }
+        if path.starts_with('/') {
+            return Err(Error::perm().context("absolute path"));
+        }
+        let mut found = None;
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            if found.is_some() {
+                return Err(Error::not_dir());
+            }
+            if component == ".." {
+                return Err(Error::perm().context("path leads out of the directory"));
+            }
+            let index = DEVICES
+                .iter()
+                .position(|name| *name == component)
+                .ok_or_else(Error::not_found)?;
+            found = Some(index);
+        }
+        if found.is_some() && path.ends_with('/') {
+            return Err(Error::not_dir());
+        }
+        Ok(found)
+    }
+
+    fn inode(index: usize) -> u64 {
+        DIR_INODE + 1 + index as u64
+    }
+}
+
+impl WasiDir for DevDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn open_file(
+        &self,
+        _symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        _read: bool,
+        _write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let index = match self.lookup(path) {
+            Ok(Some(index)) => index,
+            Ok(None) => return Err(Error::is_dir()),
+            Err(e)
+                if Errno::from_error(&e) == Some(Errno::Noent)
+                    && oflags.contains(OFlags::CREATE) =>
+            {
+                return Err(Error::read_only())
+            }
+            Err(e) => return Err(e),
+        };
+        if oflags.contains(OFlags::CREATE | OFlags::EXCLUSIVE) {
+            return Err(Error::exist());
+        }
+        if oflags.contains(OFlags::DIRECTORY) {
+            return Err(Error::not_dir());
+        }
+        let device = match DEVICES[index] {
+            "full" => Device::Full,
+            "null" => Device::Null,
+            "urandom" => Device::Urandom(self.random.clone()),
+            _ => Device::Zero,
+        };
+        Ok(Box::new(DeviceFile {
+            device,
+            device_id: self.device_id,
+            inode: Self::inode(index),
+            fdflags,
+        }))
+    }
+    fn open_dir(&self, _symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        match self.lookup(path)? {
+            None => Ok(Box::new(self.clone())),
+            Some(_) => Err(Error::not_dir()),
+        }
+    }
+    fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let dots = [
+            (".", DIR_INODE, FileType::Directory),
+            ("..", DIR_INODE, FileType::Directory),
+        ];
+        let devices = DEVICES
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (*name, Self::inode(index), FileType::CharacterDevice));
+        let entities = dots
+            .into_iter()
+            .chain(devices)
+            .enumerate()
+            .map(|(ix, (name, inode, filetype))| {
+                Ok(ReaddirEntity {
+                    next: ReaddirCursor::from(ix as u64 + 1),
+                    inode,
+                    name: name.to_owned(),
+                    filetype,
+                })
+            })
+            .skip(u64::from(cursor) as usize)
+            .collect::<Vec<_>>();
+        Ok(Box::new(entities.into_iter()))
+    }
+    fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.lookup(path)?;
+        Err(Error::invalid_argument().context("not a symlink"))
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(Filestat {
+            device_id: self.device_id,
+            inode: DIR_INODE,
+            filetype: FileType::Directory,
+            nlink: 2,
+            size: 0,
+            atim: None,
+            mtim: None,
+            ctim: None,
+        })
+    }
+    fn get_path_filestat(&self, path: &str, _follow_symlinks: bool) -> Result<Filestat, Error> {
+        match self.lookup(path)? {
+            None => self.get_filestat(),
+            Some(index) => Ok(device_filestat(self.device_id, Self::inode(index))),
+        }
+    }
+    fn rename(&self, _path: &str, _dest_dir: &dyn WasiDir, _dest_path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn WasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::tests::errno;
+
+    fn open(dir: &DevDir, path: &str) -> Box<dyn WasiFile> {
+        dir.open_file(false, path, OFlags::empty(), true, true, FdFlags::empty())
+            .unwrap()
+    }
+
+    fn read(file: &mut dyn WasiFile, len: usize) -> (u64, Vec<u8>) {
+        let mut buf = vec![1; len];
+        let n = file
+            .read_vectored(&mut [IoSliceMut::new(&mut buf)])
+            .unwrap();
+        (n, buf)
+    }
+
+    #[test]
+    fn full_reads_zeros_and_refuses_writes() {
+        let dev = DevDir::new(std::io::empty());
+        let mut full = open(&dev, "full");
+        assert_eq!(read(&mut *full, 4), (4, vec![0; 4]));
+        let bufs = [IoSlice::new(b"data")];
+        assert_eq!(errno(full.write_vectored(&bufs)), Some(Errno::Nospc));
+        assert_eq!(errno(full.write_vectored_at(&bufs, 8)), Some(Errno::Nospc));
+    }
+
+    #[test]
+    fn null_reads_nothing_and_swallows_writes() {
+        let dev = DevDir::new(std::io::empty());
+        let mut null = open(&dev, "null");
+        assert_eq!(read(&mut *null, 4), (0, vec![1; 4]));
+        let bufs = [IoSlice::new(b"da"), IoSlice::new(b"ta")];
+        assert_eq!(null.write_vectored(&bufs).unwrap(), 4);
+        assert_eq!(null.seek(SeekFrom::End(10)).unwrap(), 0);
+        assert_eq!(read(&mut *null, 4).0, 0);
+    }
+
+    #[test]
+    fn urandom_reads_from_the_source_and_zero_reads_zeros() {
+        let dev = DevDir::new(std::io::repeat(7));
+        let mut urandom = open(&dev, "urandom");
+        assert_eq!(read(&mut *urandom, 3), (3, vec![7; 3]));
+        let mut zero = open(&dev, "./zero");
+        assert_eq!(read(&mut *zero, 3), (3, vec![0; 3]));
+    }
+
+    #[test]
+    fn only_the_four_devices_are_there() {
+        let dev = DevDir::new(std::io::empty());
+        let open = |path, oflags| dev.open_file(false, path, oflags, true, false, FdFlags::empty());
+        assert_eq!(errno(open("tty", OFlags::empty())), Some(Errno::Noent));
+        assert_eq!(errno(open("tty", OFlags::CREATE)), Some(Errno::Rofs));
+        assert_eq!(errno(open("null/x", OFlags::empty())), Some(Errno::Notdir));
+        assert_eq!(errno(open("../null", OFlags::empty())), Some(Errno::Perm));
+        assert_eq!(errno(dev.unlink_file("null")), Some(Errno::Rofs));
+    }
+}
@@ -1,9 +1,10 @@
 use crate::clocks::SystemTimeSpec;
-use crate::error::{Error, ErrorExt};
-use crate::file::{FdFlags, FileCaps, FileType, Filestat, OFlags, WasiFile};
+use crate::error::{Errno, Error, ErrorExt};
+use crate::file::{copy_contents, FdFlags, FileCaps, FileType, Filestat, OFlags, WasiFile};
 use bitflags::bitflags;
 use std::any::Any;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub trait WasiDir: Send + Sync {
     fn as_any(&self) -> &dyn Any;
@@ -60,10 +61,15 @@ pub trait WasiDir: Send + Sync {
         Err(Error::not_supported())
     }
 
+    /// Move `path` to `dest_path` in `dest_dir`. Backends that can't rename into `dest_dir`
+    /// directly fall back to `move_across`, unless either side's `copy_policy` denies it.
     fn rename(&self, _path: &str, _dest_dir: &dyn WasiDir, _dest_path: &str) -> Result<(), Error> {
         Err(Error::not_supported())
     }
 
+    /// Give the file at `path` the second name `target_path` in `target_dir`. Backends that
+    /// can't link into `target_dir` directly fall back to `link_across`, which copies the file,
+    /// unless either side's `copy_policy` denies it.
     fn hard_link(
         &self,
         _path: &str,
@@ -82,31 +88,88 @@ pub trait WasiDir: Send + Sync {
     ) -> Result<(), Error> {
         Err(Error::not_supported())
     }
+
+    /// Whether entries may be copied out of or into this directory to stand in for a rename or
+    /// hard link.
+    fn copy_policy(&self) -> CopyPolicy {
+        CopyPolicy::Allow
+    }
+}
+
+/// Whether `move_across` and `link_across` may copy entries out of or into a directory, set by
+/// the host where a rename or link across backends should rather fail than copy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CopyPolicy {
+    /// Renames across backends copy the entry and remove the source; hard links copy the file.
+    #[default]
+    Allow,
+    /// Renames and hard links across backends fail with `Xdev`, as they would across devices.
+    Deny,
 }
 
-pub(crate) struct DirEntry {
+impl CopyPolicy {
+    /// `Deny` if either policy is.
+    pub fn and(self, other: CopyPolicy) -> CopyPolicy {
+        if self == CopyPolicy::Deny || other == CopyPolicy::Deny {
+            CopyPolicy::Deny
+        } else {
+            CopyPolicy::Allow
+        }
+    }
+}
+
+/// A directory in the table, together with the capabilities the guest holds on it and on the
+/// files opened through it.
+///
+/// `D` is the backend trait object: `dyn WasiDir` for the synchronous `WasiEnviron`, or
+/// `dyn AsyncWasiDir` for the asynchronous one.
+pub(crate) struct DirEntry<D: ?Sized = dyn WasiDir> {
     caps: DirCaps,
     file_caps: FileCaps,
     preopen_path: Option<PathBuf>, // precondition: PathBuf is valid unicode
-    dir: Box<dyn WasiDir>,
+    dir: Box<D>,
+    /// The preopen this directory was opened under, if it isn't one itself.
+    origin: Option<u32>,
+    revoked: bool,
 }
 
-impl DirEntry {
+impl<D: ?Sized> DirEntry<D> {
     pub fn new(
         caps: DirCaps,
         file_caps: FileCaps,
         preopen_path: Option<PathBuf>,
-        dir: Box<dyn WasiDir>,
+        dir: Box<D>,
     ) -> Self {
         DirEntry {
             caps,
             file_caps,
             preopen_path,
             dir,
+            origin: None,
+            revoked: false,
+        }
+    }
+    pub(crate) fn with_origin(mut self, origin: Option<u32>) -> Self {
+        self.origin = origin;
+        self
+    }
+    pub(crate) fn origin(&self) -> Option<u32> {
+        self.origin
+    }
+    pub(crate) fn is_revoked(&self) -> bool {
+        self.revoked
+    }
+    /// The preopen that descriptors opened through this directory, found at `fd`, derive from.
+    pub(crate) fn origin_for_children(&self, fd: u32) -> Option<u32> {
+        match self.preopen_path {
+            Some(_) => Some(fd),
+            None => self.origin,
         }
     }
     pub fn capable_of_dir(&self, caps: DirCaps) -> Result<(), Error> {
-        if self.caps.contains(caps) {
+        if self.revoked {
+            Err(Error::perm().context("access to the directory was revoked"))
+        } else if self.caps.contains(caps) {
             Ok(())
         } else {
             let missing = caps & !self.caps;
@@ -118,8 +181,11 @@ impl DirEntry {
             Err(err.context(format!("desired rights {:?}, has {:?}", caps, self.caps)))
         }
     }
+    #[allow(dead_code)]
     pub fn capable_of_file(&self, caps: FileCaps) -> Result<(), Error> {
-        if self.file_caps.contains(caps) {
+        if self.revoked {
+            Err(Error::perm().context("access to the directory was revoked"))
+        } else if self.file_caps.contains(caps) {
             Ok(())
         } else {
             Err(Error::perm().context(format!(
@@ -128,6 +194,7 @@ impl DirEntry {
             )))
         }
     }
+    #[allow(dead_code)]
     pub fn drop_caps_to(&mut self, caps: DirCaps, file_caps: FileCaps) -> Result<(), Error> {
         self.capable_of_dir(caps)?;
         self.capable_of_file(file_caps)?;
@@ -150,6 +217,34 @@ impl DirEntry {
     pub fn preopen_path(&self) -> &Option<PathBuf> {
         &self.preopen_path
     }
+    pub(crate) fn dir(&self) -> &D {
+        &self.dir
+    }
+    /// Replace the backend with `f` of it, keeping the rights, preopen path and origin.
+    #[cfg(feature = "replay")]
+    pub(crate) fn map_dir(self, f: impl FnOnce(Box<D>) -> Box<D>) -> Self {
+        DirEntry {
+            dir: f(self.dir),
+            ..self
+        }
+    }
+}
+
+impl WasiDir for crate::file::Revoked {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl DirEntry {
+    /// Take away every right on the directory and close the backend. The descriptor stays in
+    /// the table until the guest closes it.
+    pub(crate) fn revoke(&mut self) {
+        self.caps = DirCaps::empty();
+        self.file_caps = FileCaps::empty();
+        self.dir = Box::new(crate::file::Revoked);
+        self.revoked = true;
+    }
 }
 
 pub trait DirEntryExt {
@@ -210,6 +305,7 @@ impl TableDirExt for crate::table::Table {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReaddirEntity {
     pub next: ReaddirCursor,
     pub inode: u64,
@@ -218,6 +314,7 @@ pub struct ReaddirEntity {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReaddirCursor(u64);
 impl From<u64> for ReaddirCursor {
     fn from(c: u64) -> ReaddirCursor {
@@ -229,3 +326,333 @@ impl From<ReaddirCursor> for u64 {
         c.0
     }
 }
+
+/// A device ID for a backend that keeps its own tree, such as `MemFs`, different from that of
+/// every other such tree.
+pub(crate) fn new_device_id() -> u64 {
+    static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Move `src_path` in `src_dir` to `dest_path` in `dest_dir`, two directories of backends that
+/// can't rename into each other, by copying it across and then removing the source. Regular
+/// files, symlinks and whole trees of them are copied along with their timestamps; anything
+/// else, such as a socket, fails with `Xdev`, as a rename across devices would.
+///
+/// The copy is made under a temporary name next to `dest_path` and renamed into place, so that
+/// the destination never shows a partial copy, and is removed again if it fails. The source is
+/// only removed once the copy is in place.
+///
+/// If either directory's `copy_policy` is `Deny`, nothing is copied and the move fails with
+/// `Xdev`.
+pub fn move_across(
+    src_dir: &dyn WasiDir,
+    src_path: &str,
+    dest_dir: &dyn WasiDir,
+    dest_path: &str,
+) -> Result<(), Error> {
+    if src_dir.copy_policy().and(dest_dir.copy_policy()) == CopyPolicy::Deny {
+        return Err(Error::cross_device().context("the host doesn't allow copying across"));
+    }
+    let stat = src_dir.get_path_filestat(src_path, false)?;
+    let moving_dir = stat.filetype == FileType::Directory;
+    match dest_dir.get_path_filestat(dest_path, false) {
+        Ok(dest) => {
+            if replaceable(&dest, moving_dir)? && !entries(dest_dir, dest_path)?.is_empty() {
+                return Err(Error::not_empty());
+            }
+        }
+        Err(e) if Errno::from_error(&e) == Some(Errno::Noent) => {}
+        Err(e) => return Err(e),
+    }
+    let temp = temp_path(dest_path);
+    let copied = copy_tree(src_dir, src_path, &stat, dest_dir, &temp)
+        .and_then(|()| dest_dir.rename(&temp, dest_dir, dest_path));
+    if let Err(e) = copied {
+        let _ = remove_all(dest_dir, &temp);
+        return Err(e);
+    }
+    remove_all(src_dir, src_path)
+}
+
+/// Link `path` in `dir` to `target_path` in `target_dir`, two directories of backends that
+/// can't link into each other, by copying it across as `move_across` does, but keeping the
+/// source. The copy is a file of its own, so it doesn't see later changes to the source.
+///
+/// As for a hard link, directories fail with `Perm`, and an existing `target_path` with `Exist`.
+/// If either directory's `copy_policy` is `Deny`, nothing is copied and the link fails with
+/// `Xdev`.
+pub fn link_across(
+    dir: &dyn WasiDir,
+    path: &str,
+    target_dir: &dyn WasiDir,
+    target_path: &str,
+) -> Result<(), Error> {
+    if dir.copy_policy().and(target_dir.copy_policy()) == CopyPolicy::Deny {
+        return Err(Error::cross_device().context("the host doesn't allow copying across"));
+    }
+    let stat = dir.get_path_filestat(path, false)?;
+    if stat.filetype == FileType::Directory {
+        return Err(Error::perm().context("hard link to a directory"));
+    }
+    if exists(target_dir, target_path)? {
+        return Err(Error::exist());
+    }
+    let temp = temp_path(target_path);
+    let copied = copy_tree(dir, path, &stat, target_dir, &temp)
+        .and_then(|()| put_in_place(target_dir, &temp, target_path));
+    let _ = target_dir.unlink_file(&temp);
+    copied
+}
+
+/// Give the finished copy at `temp` in `dir` the name `path`, failing with `Exist` rather than
+/// replace anything there. This is done with a hard link, and the caller removes `temp` after;
+/// where `dir` can't link, it is renamed instead.
+pub(crate) fn put_in_place(dir: &dyn WasiDir, temp: &str, path: &str) -> Result<(), Error> {
+    match dir.hard_link(temp, dir, path) {
+        Err(e) if Errno::from_error(&e) == Some(Errno::Notsup) => {
+            if exists(dir, path)? {
+                return Err(Error::exist());
+            }
+            dir.rename(temp, dir, path)
+        }
+        result => result,
+    }
+}
+
+/// Whether there is an entry at `path` in `dir`, without following a symlink there.
+pub(crate) fn exists(dir: &dyn WasiDir, path: &str) -> Result<bool, Error> {
+    match dir.get_path_filestat(path, false) {
+        Ok(_) => Ok(true),
+        Err(e) if Errno::from_error(&e) == Some(Errno::Noent) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `dest` may be replaced by a move, as for a rename. Returns whether it is a directory,
+/// which may only be replaced if it is empty.
+pub(crate) fn replaceable(dest: &Filestat, moving_dir: bool) -> Result<bool, Error> {
+    match (dest.filetype == FileType::Directory, moving_dir) {
+        (true, false) => Err(Error::is_dir()),
+        (false, true) => Err(Error::not_dir()),
+        (dest_is_dir, _) => Ok(dest_is_dir),
+    }
+}
+
+/// A name next to `path` for a copy in progress, that no other copy uses.
+pub(crate) fn temp_path(path: &str) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let path = path.trim_end_matches('/');
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    join(dir, &format!(".{}.moving-{}", name, n))
+}
+
+/// A walk over a tree of entries without recursion, so that a deep tree can't overflow the
+/// stack. The copies and removals here and in `async_dir` all walk with it, and only differ in
+/// how they do each step.
+///
+/// `next` hands out entries parent first, as paths relative to the root of the walk, the empty
+/// path being the root itself. The caller adds the entries of each directory it is handed with
+/// `push`. `finish` then hands back every entry walked, child first.
+pub(crate) struct Walk<T> {
+    pending: Vec<(String, T)>,
+    walked: Vec<(String, T)>,
+}
+
+impl<T: Clone> Walk<T> {
+    pub fn new(root: T) -> Self {
+        Walk {
+            pending: vec![(String::new(), root)],
+            walked: Vec::new(),
+        }
+    }
+
+    pub fn next(&mut self) -> Option<(String, T)> {
+        let (path, t) = self.pending.pop()?;
+        self.walked.push((path.clone(), t.clone()));
+        Some((path, t))
+    }
+
+    /// Add the entry `name` of the directory at `dir`, a path `next` handed out.
+    pub fn push(&mut self, dir: &str, name: &str, t: T) {
+        self.pending.push((join(dir, name), t));
+    }
+
+    pub fn finish(self) -> impl Iterator<Item = (String, T)> {
+        self.walked.into_iter().rev()
+    }
+}
+
+/// The path of `rel`, relative to the root of a `Walk`, when the root is at `root`.
+pub(crate) fn under(root: &str, rel: &str) -> String {
+    if rel.is_empty() {
+        root.to_owned()
+    } else {
+        join(root, rel)
+    }
+}
+
+/// Copy `src_path`, whose metadata is `stat`, to the new `dest_path`. Entries are copied parent
+/// first, and their times set child first, so that adding the children doesn't touch the times
+/// of the directories holding them.
+pub(crate) fn copy_tree(
+    src_dir: &dyn WasiDir,
+    src_path: &str,
+    stat: &Filestat,
+    dest_dir: &dyn WasiDir,
+    dest_path: &str,
+) -> Result<(), Error> {
+    let mut walk = Walk::new(stat.clone());
+    while let Some((rel, stat)) = walk.next() {
+        let (from, to) = (under(src_path, &rel), under(dest_path, &rel));
+        match stat.filetype {
+            FileType::Directory => {
+                dest_dir.create_dir(&to)?;
+                for (name, _) in entries(src_dir, &from)? {
+                    let stat = src_dir.get_path_filestat(&join(&from, &name), false)?;
+                    walk.push(&rel, &name, stat);
+                }
+            }
+            FileType::SymbolicLink => {
+                let target = src_dir.read_link(&from)?;
+                dest_dir.symlink(link_target(&target)?, &to)?;
+            }
+            FileType::RegularFile => {
+                let none = FdFlags::empty();
+                let mut src =
+                    src_dir.open_file(false, &from, OFlags::empty(), true, false, none)?;
+                let exclusive = OFlags::CREATE | OFlags::EXCLUSIVE;
+                let mut dst = dest_dir.open_file(false, &to, exclusive, false, true, none)?;
+                copy_contents(&mut *src, &mut *dst)?;
+            }
+            _ => return Err(not_copyable()),
+        }
+    }
+    for (rel, stat) in walk.finish() {
+        copy_times(dest_dir, &under(dest_path, &rel), &stat)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn link_target(target: &std::path::Path) -> Result<&str, Error> {
+    target
+        .to_str()
+        .ok_or_else(|| Error::illegal_byte_sequence().context("symlink target"))
+}
+
+pub(crate) fn not_copyable() -> Error {
+    Error::cross_device().context("special files can't be copied")
+}
+
+/// The access and modification times of `stat`, to set on a copy.
+pub(crate) fn times(stat: &Filestat) -> (Option<SystemTimeSpec>, Option<SystemTimeSpec>) {
+    let spec = |t: Option<std::time::SystemTime>| {
+        t.map(|t| SystemTimeSpec::Absolute(cap_std::time::SystemTime::from_std(t)))
+    };
+    (spec(stat.atim), spec(stat.mtim))
+}
+
+/// Give `path` in `dir` the access and modification times of `stat`.
+pub(crate) fn copy_times(dir: &dyn WasiDir, path: &str, stat: &Filestat) -> Result<(), Error> {
+    let (atim, mtim) = times(stat);
+    dir.set_times(path, atim, mtim, false)
+}
+
+pub(crate) fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Whether `name` is `.` or `..`, which listings for a walk leave out.
+pub(crate) fn is_dot(name: &str) -> bool {
+    name == "." || name == ".."
+}
+
+/// The names in the directory at `path` in `dir`, other than `.` and `..`, with their types.
+/// The empty path is `dir` itself.
+pub(crate) fn entries(dir: &dyn WasiDir, path: &str) -> Result<Vec<(String, FileType)>, Error> {
+    let opened;
+    let dir = if path.is_empty() {
+        dir
+    } else {
+        opened = dir.open_dir(false, path)?;
+        &*opened
+    };
+    dir.readdir(ReaddirCursor::from(0))?
+        .filter(|entity| entity.as_ref().map_or(true, |entity| !is_dot(&entity.name)))
+        .map(|entity| entity.map(|entity| (entity.name, entity.filetype)))
+        .collect()
+}
+
+/// Remove `path` from `dir`, with everything in it if it is a directory. Nothing there is fine.
+/// Directories are listed parent first and removed child first.
+pub(crate) fn remove_all(dir: &dyn WasiDir, path: &str) -> Result<(), Error> {
+    let stat = match dir.get_path_filestat(path, false) {
+        Ok(stat) => stat,
+        Err(e) if Errno::from_error(&e) == Some(Errno::Noent) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut walk = Walk::new(stat.filetype);
+    while let Some((rel, filetype)) = walk.next() {
+        if filetype == FileType::Directory {
+            for (name, filetype) in entries(dir, &under(path, &rel))? {
+                walk.push(&rel, &name, filetype);
+            }
+        }
+    }
+    for (rel, filetype) in walk.finish() {
+        if filetype == FileType::Directory {
+            dir.remove_dir(&under(path, &rel))?;
+        } else {
+            dir.unlink_file(&under(path, &rel))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::tests::{errno, read, write, Clock};
+    use crate::memfs::MemFs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn move_across_copies_a_deep_tree() {
+        let (src, dest) = (MemFs::new(Box::new(Clock)), MemFs::new(Box::new(Clock)));
+        let (src, dest) = (src.root(), dest.root());
+        let mut path = "top".to_owned();
+        src.create_dir(&path).unwrap();
+        for _ in 0..200 {
+            path = join(&path, "d");
+            src.create_dir(&path).unwrap();
+        }
+        let file = join(&path, "f");
+        write(&src, &file, b"deep");
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let (atim, mtim) = times(&Filestat {
+            atim: Some(then),
+            mtim: Some(then),
+            ..src.get_path_filestat("top", false).unwrap()
+        });
+        src.set_times("top", atim, mtim, false).unwrap();
+
+        move_across(&src, "top", &dest, "moved").unwrap();
+
+        assert_eq!(
+            errno(src.get_path_filestat("top", false)),
+            Some(Errno::Noent)
+        );
+        let moved = format!("moved{}", &file["top".len()..]);
+        assert_eq!(read(&dest, &moved), b"deep");
+        let stat = dest.get_path_filestat("moved", false).unwrap();
+        assert_eq!(stat.mtim, Some(then));
+
+        remove_all(&dest, "moved").unwrap();
+        assert!(entries(&dest, "").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,614 @@
+//! Disk quotas on preopened trees.
+//!
+//! A `QuotaDir` wraps a directory, and every file and directory opened through it, so that the
+//! guest can't grow the tree beyond a `DiskQuota`: in bytes, summed over the sizes of its files,
+//! and in inodes, counting files, directories and symlinks. Writes, truncation, allocation and
+//! creation that would go over fail with `Dquot`; deletion gives the space back.
+//!
+//! Each quota behaves like a filesystem of its own: renaming out of the tree, or into a tree
+//! under another quota, copies the entry across with `dir::move_across`, charging the
+//! destination and giving the space back here. Hard links across copy the file with
+//! `dir::link_across`, charging the destination for the copy.
+use crate::clocks::SystemTimeSpec;
+use crate::dir::{link_across, move_across, CopyPolicy, ReaddirCursor, ReaddirEntity, WasiDir};
+use crate::error::{Error, ErrorExt};
+use crate::file::{
+    Advice, FdFlags, FileType, Filestat, OFlags, RiFlags, RoFlags, SdFlags, SiFlags, Timeouts,
+    WasiFile,
+};
+use crate::quota::truncate_bufs;
+use std::any::Any;
+use std::collections::HashSet;
+use std::io::{IoSlice, IoSliceMut, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The byte and inode budget of one or more trees. Clones share the same usage, so the host can
+/// keep one to query the remaining capacity.
+#[derive(Debug, Clone)]
+pub struct DiskQuota(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    max_bytes: Option<u64>,
+    max_inodes: Option<u64>,
+    bytes: AtomicU64,
+    inodes: AtomicU64,
+}
+
+impl DiskQuota {
+    /// A quota of `max_bytes` and `max_inodes`, with nothing used yet. `None` is unlimited.
+    pub fn new(max_bytes: Option<u64>, max_inodes: Option<u64>) -> Self {
+        DiskQuota(Arc::new(Inner {
+            max_bytes,
+            max_inodes,
+            bytes: AtomicU64::new(0),
+            inodes: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.0.max_bytes
+    }
+
+    pub fn max_inodes(&self) -> Option<u64> {
+        self.0.max_inodes
+    }
+
+    pub fn bytes_used(&self) -> u64 {
+        self.0.bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn inodes_used(&self) -> u64 {
+        self.0.inodes.load(Ordering::SeqCst)
+    }
+
+    /// How many more bytes the guest may add, or `None` if unlimited.
+    pub fn bytes_remaining(&self) -> Option<u64> {
+        self.max_bytes()
+            .map(|max| max.saturating_sub(self.bytes_used()))
+    }
+
+    /// How many more files, directories and symlinks the guest may create, or `None` if
+    /// unlimited.
+    pub fn inodes_remaining(&self) -> Option<u64> {
+        self.max_inodes()
+            .map(|max| max.saturating_sub(self.inodes_used()))
+    }
+
+    fn same(&self, other: &DiskQuota) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    fn charge_bytes(&self, n: u64) -> Result<(), Error> {
+        charge(&self.0.bytes, self.0.max_bytes, n)
+            .map_err(|_| Error::dquot().context("disk quota exceeded: out of bytes"))
+    }
+
+    fn charge_inode(&self) -> Result<(), Error> {
+        charge(&self.0.inodes, self.0.max_inodes, 1)
+            .map_err(|_| Error::dquot().context("disk quota exceeded: out of inodes"))
+    }
+
+    fn release_bytes(&self, n: u64) {
+        release(&self.0.bytes, n)
+    }
+
+    fn release_inode(&self) {
+        release(&self.0.inodes, 1)
+    }
+
+    /// Give back what `stat`, about to lose a name, takes up, if that was its last name.
+    fn release_stat(&self, stat: &Filestat) {
+        if stat.nlink > 1 && stat.filetype != FileType::Directory {
+            return;
+        }
+        self.release_inode();
+        if stat.filetype == FileType::RegularFile {
+            self.release_bytes(stat.size);
+        }
+    }
+
+    /// Add what is already in `dir` to the usage, without checking it against the limits.
+    fn scan(&self, dir: &dyn WasiDir, seen: &mut HashSet<(u64, u64)>) -> Result<(), Error> {
+        for entity in dir.readdir(ReaddirCursor::from(0))? {
+            let ReaddirEntity { name, filetype, .. } = entity?;
+            if name == "." || name == ".." {
+                continue;
+            }
+            if filetype == FileType::Directory {
+                self.0.inodes.fetch_add(1, Ordering::SeqCst);
+                self.scan(&*dir.open_dir(false, &name)?, seen)?;
+                continue;
+            }
+            let stat = dir.get_path_filestat(&name, false)?;
+            // Count each hard-linked file once.
+            if stat.nlink > 1 && !seen.insert((stat.device_id, stat.inode)) {
+                continue;
+            }
+            self.0.inodes.fetch_add(1, Ordering::SeqCst);
+            if stat.filetype == FileType::RegularFile {
+                self.0.bytes.fetch_add(stat.size, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn charge(used: &AtomicU64, max: Option<u64>, n: u64) -> Result<(), ()> {
+    used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+        let new = used.checked_add(n)?;
+        match max {
+            Some(max) if new > max => None,
+            _ => Some(new),
+        }
+    })
+    .map(|_| ())
+    .map_err(|_| ())
+}
+
+fn release(used: &AtomicU64, n: u64) {
+    let _ = used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+        Some(used.saturating_sub(n))
+    });
+}
+
+/// A directory whose tree is held to a `DiskQuota`.
+pub struct QuotaDir {
+    inner: Box<dyn WasiDir>,
+    quota: DiskQuota,
+}
+
+impl QuotaDir {
+    /// Hold the tree under `dir` to `quota`, adding what it already holds to the usage. The
+    /// tree may start out over the quota, in which case the guest can only shrink it.
+    ///
+    /// Trees sharing a quota must not overlap, or the overlap is counted twice.
+    pub fn new(dir: Box<dyn WasiDir>, quota: DiskQuota) -> Result<Self, Error> {
+        quota.scan(&*dir, &mut HashSet::new())?;
+        Ok(QuotaDir { inner: dir, quota })
+    }
+
+    pub fn quota(&self) -> &DiskQuota {
+        &self.quota
+    }
+
+    /// The backend behind `dir`, if it is held to the same quota as `self`.
+    fn same_tree<'a>(&self, dir: &'a dyn WasiDir) -> Result<&'a dyn WasiDir, Error> {
+        match dir.as_any().downcast_ref::<QuotaDir>() {
+            Some(dir) if dir.quota.same(&self.quota) => Ok(&*dir.inner),
+            _ => Err(Error::cross_device().context("destination is under another disk quota")),
+        }
+    }
+}
+
+impl WasiDir for QuotaDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let existing = if oflags.intersects(OFlags::CREATE | OFlags::TRUNCATE) {
+            self.inner.get_path_filestat(path, symlink_follow).ok()
+        } else {
+            None
+        };
+        let created = oflags.contains(OFlags::CREATE) && existing.is_none();
+        if created {
+            self.quota.charge_inode()?;
+        }
+        let file = match self
+            .inner
+            .open_file(symlink_follow, path, oflags, read, write, fdflags)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                if created {
+                    self.quota.release_inode();
+                }
+                return Err(e);
+            }
+        };
+        if let Some(stat) = existing {
+            if oflags.contains(OFlags::TRUNCATE) && stat.filetype == FileType::RegularFile {
+                self.quota.release_bytes(stat.size);
+            }
+        }
+        Ok(Box::new(QuotaFile {
+            inner: file,
+            quota: self.quota.clone(),
+        }))
+    }
+    fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let dir = self.inner.open_dir(symlink_follow, path)?;
+        Ok(Box::new(QuotaDir {
+            inner: dir,
+            quota: self.quota.clone(),
+        }))
+    }
+    fn create_dir(&self, path: &str) -> Result<(), Error> {
+        self.quota.charge_inode()?;
+        self.inner.create_dir(path).inspect_err(|_| {
+            self.quota.release_inode();
+        })
+    }
+    fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        self.inner.readdir(cursor)
+    }
+    fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.quota.charge_inode()?;
+        self.inner.symlink(old_path, new_path).inspect_err(|_| {
+            self.quota.release_inode();
+        })
+    }
+    fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        self.inner.remove_dir(path)?;
+        self.quota.release_inode();
+        Ok(())
+    }
+    fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        let stat = self.inner.get_path_filestat(path, false)?;
+        self.inner.unlink_file(path)?;
+        self.quota.release_stat(&stat);
+        Ok(())
+    }
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.inner.read_link(path)
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.inner.get_filestat()
+    }
+    fn get_path_filestat(&self, path: &str, follow_symlinks: bool) -> Result<Filestat, Error> {
+        self.inner.get_path_filestat(path, follow_symlinks)
+    }
+    fn rename(&self, path: &str, dest_dir: &dyn WasiDir, dest_path: &str) -> Result<(), Error> {
+        let dest_dir = match self.same_tree(dest_dir) {
+            Ok(dest_dir) => dest_dir,
+            // The copy is charged to the destination, and removing the source releases it here.
+            Err(_) => return move_across(self, path, dest_dir, dest_path),
+        };
+        let moved = self.inner.get_path_filestat(path, false)?;
+        // Renaming onto another name of the same file leaves both in place.
+        let replaced = dest_dir
+            .get_path_filestat(dest_path, false)
+            .ok()
+            .filter(|stat| (stat.device_id, stat.inode) != (moved.device_id, moved.inode));
+        self.inner.rename(path, dest_dir, dest_path)?;
+        if let Some(stat) = replaced {
+            self.quota.release_stat(&stat);
+        }
+        Ok(())
+    }
+    fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        // Another name for the same inode takes up no more space.
+        let target_dir = match self.same_tree(target_dir) {
+            Ok(dir) => dir,
+            // The copy is charged to the destination.
+            Err(_) => return link_across(self, path, target_dir, target_path),
+        };
+        self.inner.hard_link(path, target_dir, target_path)
+    }
+    fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        self.inner.set_times(path, atime, mtime, follow_symlinks)
+    }
+    fn copy_policy(&self) -> CopyPolicy {
+        self.inner.copy_policy()
+    }
+}
+
+/// A file opened through a `QuotaDir`. Growing it is charged to the quota, shrinking it gives
+/// the bytes back.
+pub struct QuotaFile {
+    inner: Box<dyn WasiFile>,
+    quota: DiskQuota,
+}
+
+impl QuotaFile {
+    fn size(&mut self) -> Result<u64, Error> {
+        Ok(self.inner.get_filestat()?.size)
+    }
+
+    /// The size of the file, or `None` if it isn't a regular file and so takes up no space.
+    fn regular_size(&mut self) -> Result<Option<u64>, Error> {
+        let stat = self.inner.get_filestat()?;
+        Ok((stat.filetype == FileType::RegularFile).then_some(stat.size))
+    }
+
+    /// Charge a write of up to `len` bytes at `pos` into a file of `size` bytes, cutting it down
+    /// to what the quota has room for. Return how many bytes may be written, and how many bytes
+    /// were charged for them.
+    fn charge_write(&self, pos: u64, len: u64, size: u64) -> Result<(u64, u64), Error> {
+        let len = match self.quota.bytes_remaining() {
+            Some(remaining) => len.min((size + remaining).saturating_sub(pos)),
+            None => len,
+        };
+        if len == 0 {
+            return Err(Error::dquot().context("disk quota exceeded: out of bytes"));
+        }
+        let growth = (pos + len).saturating_sub(size);
+        self.quota.charge_bytes(growth)?;
+        Ok((len, growth))
+    }
+
+    /// Give back what a write of `written` bytes at `pos` into a file of `size` bytes didn't
+    /// use of the `charged` bytes.
+    fn settle_write(&self, pos: u64, written: u64, size: u64, charged: u64) {
+        let growth = (pos + written).saturating_sub(size);
+        self.quota.release_bytes(charged.saturating_sub(growth));
+    }
+
+    fn write_at<'a>(
+        &mut self,
+        bufs: &'a [IoSlice<'a>],
+        pos: u64,
+        size: u64,
+        write: impl FnOnce(&mut dyn WasiFile, &[IoSlice<'_>]) -> Result<u64, Error>,
+    ) -> Result<u64, Error> {
+        let len = bufs.iter().map(|b| b.len() as u64).sum::<u64>();
+        if len == 0 {
+            return write(&mut *self.inner, bufs);
+        }
+        let (len, charged) = self.charge_write(pos, len, size)?;
+        let result = write(&mut *self.inner, &truncate_bufs(bufs, len));
+        let written = *result.as_ref().unwrap_or(&0);
+        self.settle_write(pos, written, size, charged);
+        result
+    }
+}
+
+impl WasiFile for QuotaFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        self.inner.get_filetype()
+    }
+    #[cfg(unix)]
+    fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+        self.inner.pollable()
+    }
+    fn isatty(&mut self) -> bool {
+        self.inner.isatty()
+    }
+    fn sock_accept(&mut self, fdflags: FdFlags) -> Result<Box<dyn WasiFile>, Error> {
+        self.inner.sock_accept(fdflags)
+    }
+    fn sock_recv<'a>(
+        &mut self,
+        ri_data: &mut [IoSliceMut<'a>],
+        ri_flags: RiFlags,
+    ) -> Result<(u64, RoFlags), Error> {
+        self.inner.sock_recv(ri_data, ri_flags)
+    }
+    fn sock_send<'a>(&mut self, si_data: &[IoSlice<'a>], si_flags: SiFlags) -> Result<u64, Error> {
+        self.inner.sock_send(si_data, si_flags)
+    }
+    fn sock_shutdown(&mut self, how: SdFlags) -> Result<(), Error> {
+        self.inner.sock_shutdown(how)
+    }
+    fn datasync(&mut self) -> Result<(), Error> {
+        self.inner.datasync()
+    }
+    fn sync(&mut self) -> Result<(), Error> {
+        self.inner.sync()
+    }
+    fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        self.inner.get_fdflags()
+    }
+    fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.inner.set_fdflags(flags)
+    }
+    fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        self.inner.get_filestat()
+    }
+    fn set_filestat_size(&mut self, new_size: u64) -> Result<(), Error> {
+        let size = self.size()?;
+        if new_size > size {
+            self.quota.charge_bytes(new_size - size)?;
+            self.inner.set_filestat_size(new_size).inspect_err(|_| {
+                self.quota.release_bytes(new_size - size);
+            })
+        } else {
+            self.inner.set_filestat_size(new_size)?;
+            self.quota.release_bytes(size - new_size);
+            Ok(())
+        }
+    }
+    fn advise(&mut self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+        self.inner.advise(offset, len, advice)
+    }
+    fn allocate(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        let size = self.size()?;
+        let growth = offset.saturating_add(len).saturating_sub(size);
+        self.quota.charge_bytes(growth)?;
+        self.inner.allocate(offset, len).inspect_err(|_| {
+            self.quota.release_bytes(growth);
+        })
+    }
+    fn set_times(
+        &mut self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        self.inner.set_times(atime, mtime)
+    }
+    fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        self.inner.read_vectored(bufs)
+    }
+    fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        self.inner.read_vectored_at(bufs, offset)
+    }
+    fn write_vectored<'a>(&mut self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        let size = match self.regular_size()? {
+            Some(size) => size,
+            None => return self.inner.write_vectored(bufs),
+        };
+        let pos = if self.inner.get_fdflags()?.contains(FdFlags::APPEND) {
+            size
+        } else {
+            self.inner.seek(SeekFrom::Current(0))?
+        };
+        self.write_at(bufs, pos, size, |f, bufs| f.write_vectored(bufs))
+    }
+    fn write_vectored_at<'a>(&mut self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        let size = match self.regular_size()? {
+            Some(size) => size,
+            None => return self.inner.write_vectored_at(bufs, offset),
+        };
+        self.write_at(bufs, offset, size, |f, bufs| {
+            f.write_vectored_at(bufs, offset)
+        })
+    }
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        self.inner.seek(pos)
+    }
+    fn offset(&self) -> Option<u64> {
+        self.inner.offset()
+    }
+    fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.inner.peek(buf)
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes()
+    }
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+        self.inner.set_timeouts(timeouts)
+    }
+    fn readable(&self) -> Result<(), Error> {
+        self.inner.readable()
+    }
+    fn writable(&self) -> Result<(), Error> {
+        self.inner.writable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Errno;
+    use crate::memfs::tests::{errno, read, write, Clock};
+    use crate::memfs::MemFs;
+
+    /// A `QuotaDir` over a new MemFs that already holds the 3-byte file `a`.
+    fn quota_dir(fs: MemFs, max_bytes: u64, max_inodes: u64) -> QuotaDir {
+        let root = fs.root();
+        write(&root, "a", b"aaa");
+        let quota = DiskQuota::new(Some(max_bytes), Some(max_inodes));
+        QuotaDir::new(Box::new(root), quota).unwrap()
+    }
+
+    fn open(dir: &QuotaDir, path: &str, oflags: OFlags) -> Box<dyn WasiFile> {
+        dir.open_file(false, path, oflags, true, true, FdFlags::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn writes_are_charged_and_cut_to_fit() {
+        let dir = quota_dir(MemFs::new(Box::new(Clock)), 8, 10);
+        assert_eq!(
+            (dir.quota().bytes_used(), dir.quota().inodes_used()),
+            (3, 1)
+        );
+
+        let mut f = open(&dir, "b", OFlags::CREATE);
+        assert_eq!(dir.quota().inodes_used(), 2);
+        assert_eq!(f.write_vectored(&[IoSlice::new(b"bbbb")]).unwrap(), 4);
+        // Overwriting what is there costs nothing.
+        assert_eq!(f.write_vectored_at(&[IoSlice::new(b"BB")], 0).unwrap(), 2);
+        assert_eq!(dir.quota().bytes_used(), 7);
+        // Only one more byte fits.
+        assert_eq!(f.write_vectored(&[IoSlice::new(b"cc")]).unwrap(), 1);
+        assert_eq!(dir.quota().bytes_remaining(), Some(0));
+        let err = f.write_vectored(&[IoSlice::new(b"d")]).unwrap_err();
+        assert_eq!(Errno::from_error(&err), Some(Errno::Dquot));
+        assert_eq!(read(&dir, "b"), b"BBbbc");
+    }
+
+    #[test]
+    fn a_failed_write_is_refunded() {
+        let fs = MemFs::new(Box::new(Clock)).with_max_bytes(5);
+        let dir = quota_dir(fs, 100, 10);
+        let mut f = open(&dir, "b", OFlags::CREATE);
+        let err = f.write_vectored(&[IoSlice::new(b"too long")]).unwrap_err();
+        assert_eq!(Errno::from_error(&err), Some(Errno::Fbig));
+        assert_eq!(dir.quota().bytes_used(), 3);
+    }
+
+    #[test]
+    fn shrinking_and_removing_refund() {
+        let dir = quota_dir(MemFs::new(Box::new(Clock)), 100, 10);
+        let mut f = open(&dir, "b", OFlags::CREATE);
+        f.set_filestat_size(10).unwrap();
+        assert_eq!(dir.quota().bytes_used(), 13);
+        f.set_filestat_size(4).unwrap();
+        assert_eq!(dir.quota().bytes_used(), 7);
+        drop(open(&dir, "b", OFlags::TRUNCATE));
+        assert_eq!(dir.quota().bytes_used(), 3);
+
+        // A file with another name keeps its space until the last one goes.
+        dir.hard_link("a", &dir, "c").unwrap();
+        dir.unlink_file("a").unwrap();
+        assert_eq!(
+            (dir.quota().bytes_used(), dir.quota().inodes_used()),
+            (3, 2)
+        );
+        dir.unlink_file("c").unwrap();
+        assert_eq!(
+            (dir.quota().bytes_used(), dir.quota().inodes_used()),
+            (0, 1)
+        );
+    }
+
+    #[test]
+    fn renaming_over_a_file_refunds_it() {
+        let dir = quota_dir(MemFs::new(Box::new(Clock)), 100, 10);
+        write(&dir, "b", b"bbbbb");
+        assert_eq!(
+            (dir.quota().bytes_used(), dir.quota().inodes_used()),
+            (8, 2)
+        );
+        dir.rename("b", &dir, "a").unwrap();
+        assert_eq!(
+            (dir.quota().bytes_used(), dir.quota().inodes_used()),
+            (5, 1)
+        );
+        assert_eq!(read(&dir, "a"), b"bbbbb");
+    }
+
+    #[test]
+    fn running_out_of_inodes() {
+        let dir = quota_dir(MemFs::new(Box::new(Clock)), 100, 2);
+        dir.create_dir("d").unwrap();
+        assert_eq!(errno(dir.create_dir("e")), Some(Errno::Dquot));
+        assert_eq!(errno(dir.symlink("a", "l")), Some(Errno::Dquot));
+        dir.remove_dir("d").unwrap();
+        dir.symlink("a", "l").unwrap();
+        assert_eq!(dir.quota().inodes_used(), 2);
+    }
+}
@@ -1,20 +1,36 @@
-use crate::dir::{DirCaps, DirEntry, WasiDir};
-use crate::error::Error;
-use crate::file::{FileCaps, FileEntry, FileEntryExt, TableFileExt, WasiFile};
+use crate::cancel::CancelHandle;
+use crate::dir::{DirCaps, DirEntry, DirEntryExt, TableDirExt, WasiDir};
+use crate::error::{Error, ErrorExt};
+use crate::file::{
+    FdFlags, FileCaps, FileEntry, FileEntryExt, FileType, OFlags, TableFileExt, Timeouts, WasiFile,
+};
+use crate::limits::{Limits, Usage};
+use crate::metrics::Metrics;
+use crate::quota::OutputQuota;
 use crate::string_array::{StringArray, StringArrayError};
 use crate::table::Table;
+use crate::trace::syscall_span;
 use crate::WasiSnapshotPreview1;
-use crate::{Ciovec, CiovecArray};
-use std::{
-    io::Write,
-    path::{Path, PathBuf},
-};
+use crate::{Ciovec, CiovecArray, IovecArray};
+use std::any::Any;
+use std::path::{Path, PathBuf};
 
 pub struct WasiEnviron {
     pub args: StringArray,
     pub env: StringArray,
     pub table: Table,
     pub exit_code: i32,
+    pub metrics: Metrics,
+    pub(crate) cancel: CancelHandle,
+    pub(crate) default_timeouts: Timeouts,
+    /// What the host asked to keep until the environ is dropped. It comes after the table, so
+    /// that the descriptors are closed first.
+    pub(crate) held: Vec<Box<dyn Any + Send + Sync>>,
+}
+impl Default for WasiEnviron {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl WasiEnviron {
     pub fn new() -> Self {
@@ -23,6 +39,10 @@ impl WasiEnviron {
             env: StringArray::new(),
             table: Table::new(),
             exit_code: 0,
+            metrics: Metrics::new(),
+            cancel: CancelHandle::new(),
+            default_timeouts: Timeouts::default(),
+            held: Vec::new(),
         };
 
         environ.set_stdin(Box::new(crate::pipe::ReadPipe::new(std::io::empty())));
@@ -32,6 +52,33 @@ impl WasiEnviron {
         environ
     }
 
+    /// A handle that interrupts the blocking syscalls of this environ, from any thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Bound the descriptors the guest can open. Once a limit is reached, syscalls that would
+    /// open another descriptor fail with `Mfile` or `Nfile`; descriptors the host inserts at a
+    /// chosen index are never refused.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.table.set_limits(limits)
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.table.limits()
+    }
+
+    /// How many descriptors, sockets and derived directories are open, against their limits.
+    pub fn usage(&self) -> Usage {
+        self.table.usage()
+    }
+
+    /// Keep `resource` until the environ is dropped, after every descriptor has been closed, for
+    /// something the guest's files depend on, such as a directory to remove at the end.
+    pub fn hold(&mut self, resource: impl Any + Send + Sync) {
+        self.held.push(Box::new(resource));
+    }
+
     pub fn push_arg(&mut self, arg: &str) -> Result<(), StringArrayError> {
         self.args.push(arg.to_owned())
     }
@@ -40,19 +87,47 @@ impl WasiEnviron {
         self.env.push(format!("{}={}", var, value))
     }
 
+    /// Preopen `dir` at the guest path `path`, with all rights, and return its descriptor.
+    ///
+    /// This can be called while the guest runs, to grant it access to a new directory.
     pub fn push_preopened_dir(
         &mut self,
         dir: Box<dyn WasiDir>,
         path: impl AsRef<Path>,
-    ) -> Result<(), Error> {
+    ) -> Result<u32, Error> {
         let caps = DirCaps::all();
         let file_caps = FileCaps::all();
-        self.table().push(Box::new(DirEntry::new(
+        let fd = self.table().push(Box::new(DirEntry::new(
             caps,
             file_caps,
             Some(path.as_ref().to_owned()),
             dir,
         )))?;
+        self.metrics.preopen(fd, path.as_ref());
+        Ok(fd)
+    }
+
+    /// Take away the guest's access to the preopened directory at `fd`, and to every file and
+    /// directory it opened through it.
+    ///
+    /// The backends are closed at once, but the descriptors stay in the table, failing every
+    /// operation with `Perm`, until the guest closes them. That way a descriptor number the
+    /// guest still holds is never handed out again for something else.
+    pub fn revoke_preopen(&mut self, fd: u32) -> Result<(), Error> {
+        if !self.table.is_preopen(fd) {
+            return Err(Error::badf().context("not a preopened directory"));
+        }
+        for key in self.table.keys() {
+            if let Ok(entry) = self.table.get_mut::<FileEntry>(key) {
+                if entry.origin() == Some(fd) {
+                    entry.revoke();
+                }
+            } else if let Ok(entry) = self.table.get_mut::<DirEntry>(key) {
+                if key == fd || entry.origin() == Some(fd) {
+                    entry.revoke();
+                }
+            }
+        }
         Ok(())
     }
 
@@ -71,7 +146,7 @@ impl WasiEnviron {
         self.insert_file(2, f, rights);
     }
 
-    fn stdio_rights(f: &mut dyn WasiFile) -> FileCaps {
+    pub(crate) fn stdio_rights(f: &mut dyn WasiFile) -> FileCaps {
         let mut rights = FileCaps::all();
 
         // If `f` is a tty, restrict the `tell` and `seek` capabilities, so
@@ -84,13 +159,80 @@ impl WasiEnviron {
         rights
     }
 
+    /// The rights on a connection accepted by `sock_accept`.
+    pub(crate) fn accepted_rights() -> FileCaps {
+        FileCaps::READ
+            | FileCaps::WRITE
+            | FileCaps::FDSTAT_SET_FLAGS
+            | FileCaps::POLL_READWRITE
+            | FileCaps::FILESTAT_GET
+    }
+
+    /// Put `file` at `fd`. If the file it replaces had an output quota, `file` takes it over.
     pub fn insert_file(&mut self, fd: u32, file: Box<dyn WasiFile>, caps: FileCaps) {
-        self.table()
-            .insert_at(fd, Box::new(FileEntry::new(caps, file)));
+        let quota = self.output_quota(fd);
+        let mut entry = self.file_entry(Some(fd), file, caps);
+        entry.set_quota(quota);
+        self.table().insert_at(fd, Box::new(entry));
     }
 
     pub fn push_file(&mut self, file: Box<dyn WasiFile>, caps: FileCaps) -> Result<u32, Error> {
-        self.table().push(Box::new(FileEntry::new(caps, file)))
+        let entry = self.file_entry(None, file, caps);
+        self.table().push(Box::new(entry))
+    }
+
+    /// Set how long blocking reads and writes on the file at `fd` may wait, overriding the
+    /// default timeouts.
+    ///
+    /// They are handed to the backend, which may enforce them on its own operations, such as
+    /// `sock_recv` on a socket. Either way, `fd_read`, `fd_write` and `sock_accept` fail with
+    /// `Timedout` once they have waited for that long on a `pollable` backend.
+    pub fn set_timeouts(&mut self, fd: u32, timeouts: Timeouts) -> Result<(), Error> {
+        self.table.get_file_mut(fd)?.set_timeouts(timeouts)
+    }
+
+    /// Bound how many bytes the guest may write to `fd`, or lift the bound with `None`. The
+    /// quota stays with the descriptor when `set_stdout`, `set_stderr` or `insert_file` replace
+    /// its file.
+    pub fn set_output_quota(&mut self, fd: u32, quota: Option<OutputQuota>) -> Result<(), Error> {
+        self.table.get_file_mut(fd)?.set_quota(quota);
+        Ok(())
+    }
+
+    /// The output quota of `fd`, if it has one. Read the usage from it.
+    pub fn output_quota(&self, fd: u32) -> Option<OutputQuota> {
+        self.table.get_file(fd).ok()?.quota().cloned()
+    }
+
+    /// Set the timeouts of sockets and stdio: those added from now on, including connections
+    /// accepted by the guest, and those already in the table that are still on the old default.
+    pub fn set_default_timeouts(&mut self, timeouts: Timeouts) {
+        let timeouts = timeouts.normalized();
+        let old = std::mem::replace(&mut self.default_timeouts, timeouts);
+        for fd in self.table.keys() {
+            if let Ok(entry) = self.table.get_mut::<FileEntry>(fd) {
+                if entry.timeouts() == old && (entry.is_socket() || is_stdio(fd)) {
+                    // A backend that rejects them keeps the old ones.
+                    let _ = entry.set_timeouts(timeouts);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn file_entry(
+        &self,
+        fd: Option<u32>,
+        mut file: Box<dyn WasiFile>,
+        caps: FileCaps,
+    ) -> FileEntry {
+        let socket = is_socket(&mut *file);
+        let default = self.default_timeouts;
+        let takes_default = default != Timeouts::default() && (socket || fd.is_some_and(is_stdio));
+        let mut entry = FileEntry::new(caps, file).with_socket(socket).with_fdstat();
+        if takes_default {
+            let _ = entry.set_timeouts(default);
+        }
+        entry
     }
 
     pub fn table(&mut self) -> &mut Table {
@@ -105,6 +247,7 @@ impl WasiEnviron {
         file_caps: FileCaps,
         path: PathBuf,
     ) {
+        self.metrics.preopen(fd, &path);
         self.table().insert_at(
             fd,
             Box::new(DirEntry::new(caps, file_caps, Some(path), dir)),
@@ -118,13 +261,36 @@ impl WasiEnviron {
         file_caps: FileCaps,
         path: PathBuf,
     ) -> Result<u32, Error> {
-        self.table()
-            .push(Box::new(DirEntry::new(caps, file_caps, Some(path), dir)))
+        let fd = self.table().push(Box::new(DirEntry::new(
+            caps,
+            file_caps,
+            Some(path.clone()),
+            dir,
+        )))?;
+        self.metrics.preopen(fd, &path);
+        Ok(fd)
     }
 }
+/// Stdio and sockets get the default timeouts.
+fn is_stdio(fd: u32) -> bool {
+    fd <= 2
+}
+
+pub(crate) fn is_socket(file: &mut dyn WasiFile) -> bool {
+    matches!(
+        file.get_filetype(),
+        Ok(FileType::SocketStream | FileType::SocketDgram)
+    )
+}
+
 impl WasiSnapshotPreview1 for WasiEnviron {
     fn args_sizes_get(&self) -> (i32, i32) {
-        println!("in WasiEnviron::args_sizes_get");
+        let _span = syscall_span!(
+            &self.metrics,
+            "args_sizes_get",
+            argc = self.args.number_elements(),
+            argv_buf_size = self.args.cumulative_size(),
+        );
         (
             self.args.number_elements() as i32,
             self.args.cumulative_size() as i32,
@@ -132,18 +298,27 @@ impl WasiSnapshotPreview1 for WasiEnviron {
     }
 
     fn args_get(&self, out: &mut Vec<Ciovec>) {
-        println!("in WasiEnviron::args_get");
+        let _span = syscall_span!(
+            &self.metrics,
+            "args_get",
+            argc = self.args.number_elements()
+        );
         for arg in self.args.elements() {
             let iov = Ciovec {
                 buf: arg.as_ptr(),
-                buf_len: arg.as_bytes().len(),
+                buf_len: arg.len(),
             };
             out.push(iov);
         }
     }
 
     fn environ_sizes_get(&self) -> (i32, i32) {
-        println!("in WasiEnviron::environ_sizes_get");
+        let _span = syscall_span!(
+            &self.metrics,
+            "environ_sizes_get",
+            environc = self.env.number_elements(),
+            environ_buf_size = self.env.cumulative_size(),
+        );
         (
             self.env.number_elements() as i32,
             self.env.cumulative_size() as i32,
@@ -151,53 +326,204 @@ impl WasiSnapshotPreview1 for WasiEnviron {
     }
 
     fn environ_get(&self, out: &mut Vec<Ciovec>) {
-        println!("in WasiEnviron::environ_get");
+        let _span = syscall_span!(
+            &self.metrics,
+            "environ_get",
+            environc = self.env.number_elements()
+        );
         for env in self.env.elements() {
             let iov = Ciovec {
                 buf: env.as_ptr(),
-                buf_len: env.as_bytes().len(),
+                buf_len: env.len(),
             };
             out.push(iov);
         }
     }
 
-    fn fd_write(&mut self, fd: i32, iovs: CiovecArray) -> i32 {
-        println!("in WasiEnviron::fd_write");
+    fn fd_close(&mut self, fd: i32) -> Result<(), Error> {
+        let span = syscall_span!(&self.metrics, "fd_close", fd = fd);
 
-        let mut io_slice_vec = vec![];
-        for iov in iovs {
-            let buf: &[u8] = unsafe { std::slice::from_raw_parts(iov.buf, iov.buf_len) };
-            let io_slice = std::io::IoSlice::new(buf);
-            io_slice_vec.push(io_slice);
-        }
+        let result = (|| {
+            let fd = fd as u32;
+            let table = self.table();
+            // Only close what is ours: other proposals may share the table.
+            if !table.is::<FileEntry>(fd) && !table.is::<DirEntry>(fd) {
+                return Err(if table.contains_key(fd) {
+                    Error::badf().context("key does not refer to file or directory")
+                } else {
+                    Error::badf().context("key not in table")
+                });
+            }
+            table.delete(fd);
+            self.metrics.close(fd);
+            Ok(())
+        })();
+
+        span.finish(result)
+    }
 
-        let f = self
-            .table()
-            .get_file_mut(fd as u32)
-            .expect("[fd_write] failed to get file")
-            .get_cap_mut(FileCaps::WRITE)
-            .expect("[fd_write] failed to get file cap");
+    fn fd_read(&mut self, fd: i32, iovs: IovecArray) -> Result<i32, Error> {
+        let span = syscall_span!(&self.metrics, "fd_read", fd = fd, iovs_len = iovs.len(); fdflags);
 
-        // * for test
-        // let mut f = std::fs::File::create("foo.txt").expect("failed to create file");
+        let mut io_slice_vec = iovs
+            .iter()
+            .map(|iov| {
+                let buf: &mut [u8] =
+                    unsafe { std::slice::from_raw_parts_mut(iov.buf, iov.buf_len) };
+                std::io::IoSliceMut::new(buf)
+            })
+            .collect::<Vec<_>>();
 
-        let n_written_bytes = f
-            .write_vectored(&io_slice_vec)
-            .expect("[fd_write] failed to write to file");
+        let result = (|| {
+            let entry = self.table.get_file_mut(fd as u32)?;
+            let timeouts = entry.timeouts();
+            let f = entry.get_cap_mut(FileCaps::READ)?;
+            if span.is_enabled() {
+                if let Ok(fdflags) = f.get_fdflags() {
+                    span.record("fdflags", fdflags);
+                }
+            }
+            self.cancel.wait_readable(f, timeouts.read)?;
 
-        // let mut buffer = std::fs::File::create("foo.txt").expect("failed to create file");
-        // let nwritten = buffer
-        //     .write_vectored(&io_slice_vec)
-        //     .expect("failed to write to file");
+            let n_read_bytes = f.read_vectored(&mut io_slice_vec)?;
+            span.record_nbytes(n_read_bytes);
+            self.metrics.record_read(fd as u32, n_read_bytes);
+            Ok(i32::try_from(n_read_bytes)?)
+        })();
 
-        i32::try_from(n_written_bytes).expect("[fd_write] failed to convert to i32")
+        span.finish(result)
     }
 
-    fn proc_exit(&mut self, code: i32) {
-        println!("in WasiEnviron::proc_exit");
+    fn fd_write(&mut self, fd: i32, iovs: CiovecArray) -> Result<i32, Error> {
+        let span =
+            syscall_span!(&self.metrics, "fd_write", fd = fd, iovs_len = iovs.len(); fdflags);
+
+        let io_slice_vec = iovs
+            .iter()
+            .map(|iov| {
+                let buf: &[u8] = unsafe { std::slice::from_raw_parts(iov.buf, iov.buf_len) };
+                std::io::IoSlice::new(buf)
+            })
+            .collect::<Vec<_>>();
+
+        let result = (|| {
+            let entry = self.table.get_file_mut(fd as u32)?;
+            let timeouts = entry.timeouts();
+            let quota = entry.quota().cloned();
+            let f = entry.get_cap_mut(FileCaps::WRITE)?;
+            if span.is_enabled() {
+                if let Ok(fdflags) = f.get_fdflags() {
+                    span.record("fdflags", fdflags);
+                }
+            }
+            self.cancel.wait_writable(f, timeouts.write)?;
+
+            let n_written_bytes = match quota {
+                Some(quota) => {
+                    quota.write_vectored(&io_slice_vec, |bufs| f.write_vectored(bufs))?
+                }
+                None => f.write_vectored(&io_slice_vec)?,
+            };
+            span.record_nbytes(n_written_bytes);
+            self.metrics.record_write(fd as u32, n_written_bytes);
+            Ok(i32::try_from(n_written_bytes)?)
+        })();
+
+        span.finish(result)
+    }
+
+    fn path_open(
+        &mut self,
+        dirfd: i32,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        dir_caps: DirCaps,
+        file_caps: FileCaps,
+        fdflags: FdFlags,
+    ) -> Result<i32, Error> {
+        let span = syscall_span!(
+            &self.metrics,
+            "path_open",
+            dirfd = dirfd,
+            path = path,
+            symlink_follow = symlink_follow;
+            oflags,
+            fdflags,
+            opened_fd
+        );
+        if span.is_enabled() {
+            span.record("oflags", oflags);
+            span.record("fdflags", fdflags);
+        }
+
+        let result = (|| {
+            let dirfd = dirfd as u32;
+            let table = self.table();
+            if table.is::<FileEntry>(dirfd) {
+                return Err(Error::not_dir());
+            }
+            let dir_entry = table.get_dir(dirfd)?;
+            let origin = dir_entry.origin_for_children(dirfd);
+
+            let entry: Box<dyn Any + Send + Sync> = if oflags.contains(OFlags::DIRECTORY) {
+                if oflags.intersects(OFlags::CREATE | OFlags::EXCLUSIVE | OFlags::TRUNCATE) {
+                    return Err(Error::invalid_argument().context("directory oflags"));
+                }
+                let dir_caps = dir_entry.child_dir_caps(dir_caps);
+                let file_caps = dir_entry.child_file_caps(file_caps);
+                let dir = dir_entry.get_cap(DirCaps::OPEN)?;
+                let child_dir = dir.open_dir(symlink_follow, path)?;
+                Box::new(DirEntry::new(dir_caps, file_caps, None, child_dir).with_origin(origin))
+            } else {
+                let mut required_caps = DirCaps::OPEN;
+                if oflags.contains(OFlags::CREATE) {
+                    required_caps |= DirCaps::CREATE_FILE;
+                }
+                let file_caps = dir_entry.child_file_caps(file_caps);
+                let dir = dir_entry.get_cap(required_caps)?;
+                let read = file_caps.contains(FileCaps::READ);
+                let write = file_caps
+                    .intersects(FileCaps::WRITE | FileCaps::ALLOCATE | FileCaps::FILESTAT_SET_SIZE);
+                let file = dir.open_file(symlink_follow, path, oflags, read, write, fdflags)?;
+                Box::new(
+                    FileEntry::new(file_caps, file)
+                        .with_origin(origin)
+                        .with_fdstat(),
+                )
+            };
 
-        println!("code: {}", code);
+            let fd = table.push(entry)?;
+            self.metrics.inherit(fd, dirfd);
+            span.record("opened_fd", fd);
+            Ok(i32::try_from(fd)?)
+        })();
+
+        span.finish(result)
+    }
+
+    fn proc_exit(&mut self, code: i32) {
+        let _span = syscall_span!(&self.metrics, "proc_exit", code = code);
         self.exit_code = code;
-        println!("exit_code: {}", self.exit_code);
+    }
+
+    fn sock_accept(&mut self, fd: i32, fdflags: FdFlags) -> Result<i32, Error> {
+        let span = syscall_span!(&self.metrics, "sock_accept", fd = fd; fdflags, accepted_fd);
+        if span.is_enabled() {
+            span.record("fdflags", fdflags);
+        }
+
+        let result = (|| {
+            let entry = self.table.get_file_mut(fd as u32)?;
+            let timeouts = entry.timeouts();
+            let f = entry.get_cap_mut(FileCaps::READ)?;
+            self.cancel.wait_readable(f, timeouts.read)?;
+            let file = f.sock_accept(fdflags)?;
+            let fd = self.push_file(file, WasiEnviron::accepted_rights())?;
+            span.record("accepted_fd", fd);
+            Ok(i32::try_from(fd)?)
+        })();
+
+        span.finish(result)
     }
 }
@@ -4,13 +4,14 @@
 //! all of the logic for transforming an `Error` into the snapshot's own
 //! `Errno`. They may do so by downcasting the error into any of:
 //! * `std::io::Error` - these are thrown by `std`, `cap_std`, etc for most of
-//! the operations WASI is concerned with.
+//!   the operations WASI is concerned with.
 //! * `wasi_common::ErrorKind` - these are a subset of the Errnos, and are
-//! constructed directly by wasi-common or an impl rather than coming from the
-//! OS or some library which doesn't know about WASI.
+//!   constructed directly by wasi-common or an impl rather than coming from the
+//!   OS or some library which doesn't know about WASI.
 //! * `wiggle::GuestError`
 //! * `std::num::TryFromIntError`
 //! * `std::str::Utf8Error`
+//!
 //! and then applying specialized logic to translate each of those into
 //! `Errno`s.
 //!
@@ -44,18 +45,42 @@ pub enum ErrorKind {
     /// Errno::Badf: Bad file descriptor
     #[error("Badf: Bad file descriptor")]
     Badf,
+    /// Errno::Dquot: Disk quota exceeded.
+    #[error("Dquot: Disk quota exceeded")]
+    Dquot,
+    /// Errno::Fbig: File too large.
+    #[error("Fbig: File too large")]
+    Fbig,
     /// Errno::Ilseq: Illegal byte sequence
     #[error("Ilseq: Illegal byte sequence")]
     Ilseq,
     /// Errno::Io: I/O error
     #[error("Io: I/O error")]
     Io,
+    /// Errno::Isdir: Is a directory.
+    #[error("Isdir: Is a directory")]
+    Isdir,
+    /// Errno::Loop: Too many levels of symbolic links.
+    #[error("Loop: Too many levels of symbolic links")]
+    Loop,
+    /// Errno::Mfile: File descriptor value too large.
+    #[error("Mfile: File descriptor value too large")]
+    Mfile,
     /// Errno::Nametoolong: Filename too long
     #[error("Nametoolong: Filename too long")]
     Nametoolong,
+    /// Errno::Nfile: Too many files open in system.
+    #[error("Nfile: Too many files open in system")]
+    Nfile,
+    /// Errno::Nospc: No space left on device.
+    #[error("Nospc: No space left on device")]
+    Nospc,
     /// Errno::Notdir: Not a directory or a symbolic link to a directory.
     #[error("Notdir: Not a directory or a symbolic link to a directory")]
     Notdir,
+    /// Errno::Notempty: Directory not empty.
+    #[error("Notempty: Directory not empty")]
+    Notempty,
     /// Errno::Notsup: Not supported, or operation not supported on socket.
     #[error("Notsup: Not supported, or operation not supported on socket")]
     Notsup,
@@ -65,12 +90,18 @@ pub enum ErrorKind {
     /// Errno::Range: Result too large
     #[error("Range: Result too large")]
     Range,
+    /// Errno::Rofs: Read-only file system.
+    #[error("Rofs: Read-only file system")]
+    Rofs,
     /// Errno::Spipe: Invalid seek
     #[error("Spipe: Invalid seek")]
     Spipe,
     /// Errno::Perm: Permission denied
     #[error("Permission denied")]
     Perm,
+    /// Errno::Xdev: Cross-device link
+    #[error("Xdev: Cross-device link")]
+    Xdev,
 }
 
 pub trait ErrorExt {
@@ -78,17 +109,29 @@ pub trait ErrorExt {
     fn not_found() -> Self;
     fn too_big() -> Self;
     fn badf() -> Self;
+    fn dquot() -> Self;
     fn exist() -> Self;
+    fn fbig() -> Self;
     fn illegal_byte_sequence() -> Self;
+    fn interrupted() -> Self;
     fn invalid_argument() -> Self;
     fn io() -> Self;
+    fn is_dir() -> Self;
+    fn mfile() -> Self;
     fn name_too_long() -> Self;
+    fn nfile() -> Self;
+    fn no_space() -> Self;
     fn not_dir() -> Self;
+    fn not_empty() -> Self;
     fn not_supported() -> Self;
     fn overflow() -> Self;
     fn range() -> Self;
+    fn read_only() -> Self;
     fn seek_pipe() -> Self;
+    fn symlink_loop() -> Self;
+    fn timed_out() -> Self;
     fn perm() -> Self;
+    fn cross_device() -> Self;
 }
 
 impl ErrorExt for Error {
@@ -104,24 +147,48 @@ impl ErrorExt for Error {
     fn badf() -> Self {
         ErrorKind::Badf.into()
     }
+    fn dquot() -> Self {
+        ErrorKind::Dquot.into()
+    }
     fn exist() -> Self {
         std::io::Error::from(std::io::ErrorKind::AlreadyExists).into()
     }
+    fn fbig() -> Self {
+        ErrorKind::Fbig.into()
+    }
     fn illegal_byte_sequence() -> Self {
         ErrorKind::Ilseq.into()
     }
+    fn interrupted() -> Self {
+        std::io::Error::from(std::io::ErrorKind::Interrupted).into()
+    }
     fn invalid_argument() -> Self {
         std::io::Error::from(std::io::ErrorKind::InvalidInput).into()
     }
     fn io() -> Self {
         ErrorKind::Io.into()
     }
+    fn is_dir() -> Self {
+        ErrorKind::Isdir.into()
+    }
+    fn mfile() -> Self {
+        ErrorKind::Mfile.into()
+    }
     fn name_too_long() -> Self {
         ErrorKind::Nametoolong.into()
     }
+    fn nfile() -> Self {
+        ErrorKind::Nfile.into()
+    }
+    fn no_space() -> Self {
+        ErrorKind::Nospc.into()
+    }
     fn not_dir() -> Self {
         ErrorKind::Notdir.into()
     }
+    fn not_empty() -> Self {
+        ErrorKind::Notempty.into()
+    }
     fn not_supported() -> Self {
         ErrorKind::Notsup.into()
     }
@@ -131,10 +198,280 @@ impl ErrorExt for Error {
     fn range() -> Self {
         ErrorKind::Range.into()
     }
+    fn read_only() -> Self {
+        ErrorKind::Rofs.into()
+    }
     fn seek_pipe() -> Self {
         ErrorKind::Spipe.into()
     }
+    fn symlink_loop() -> Self {
+        ErrorKind::Loop.into()
+    }
+    fn timed_out() -> Self {
+        std::io::Error::from(std::io::ErrorKind::TimedOut).into()
+    }
     fn perm() -> Self {
         ErrorKind::Perm.into()
     }
+    fn cross_device() -> Self {
+        ErrorKind::Xdev.into()
+    }
+}
+
+/// A WASI `$errno` value, as returned to the guest.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u16)]
+pub enum Errno {
+    Success,
+    TooBig,
+    Acces,
+    Addrinuse,
+    Addrnotavail,
+    Afnosupport,
+    Again,
+    Already,
+    Badf,
+    Badmsg,
+    Busy,
+    Canceled,
+    Child,
+    Connaborted,
+    Connrefused,
+    Connreset,
+    Deadlk,
+    Destaddrreq,
+    Dom,
+    Dquot,
+    Exist,
+    Fault,
+    Fbig,
+    Hostunreach,
+    Idrm,
+    Ilseq,
+    Inprogress,
+    Intr,
+    Inval,
+    Io,
+    Isconn,
+    Isdir,
+    Loop,
+    Mfile,
+    Mlink,
+    Msgsize,
+    Multihop,
+    Nametoolong,
+    Netdown,
+    Netreset,
+    Netunreach,
+    Nfile,
+    Nobufs,
+    Nodev,
+    Noent,
+    Noexec,
+    Nolck,
+    Nolink,
+    Nomem,
+    Nomsg,
+    Noprotoopt,
+    Nospc,
+    Nosys,
+    Notconn,
+    Notdir,
+    Notempty,
+    Notrecoverable,
+    Notsock,
+    Notsup,
+    Notty,
+    Nxio,
+    Overflow,
+    Ownerdead,
+    Perm,
+    Pipe,
+    Proto,
+    Protonosupport,
+    Prototype,
+    Range,
+    Rofs,
+    Spipe,
+    Srch,
+    Stale,
+    Timedout,
+    Txtbsy,
+    Xdev,
+    Notcapable,
+}
+
+impl Errno {
+    /// Translate an `Error` into the `Errno` the guest should see, following the rules in the
+    /// module documentation. Returns `None` if the error should trap instead.
+    pub fn from_error(err: &Error) -> Option<Errno> {
+        if let Some(errno) = err.downcast_ref::<Errno>() {
+            Some(*errno)
+        } else if let Some(kind) = err.downcast_ref::<ErrorKind>() {
+            Some(Errno::from(*kind))
+        } else if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            Some(Errno::from(err))
+        } else if err.downcast_ref::<std::num::TryFromIntError>().is_some() {
+            Some(Errno::Overflow)
+        } else if err.downcast_ref::<std::str::Utf8Error>().is_some() {
+            Some(Errno::Ilseq)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for Errno {
+    /// The POSIX-style name, such as `ENOENT`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Errno::Success => write!(f, "0"),
+            Errno::TooBig => write!(f, "E2BIG"),
+            _ => write!(f, "E{}", format!("{:?}", self).to_uppercase()),
+        }
+    }
+}
+
+impl std::error::Error for Errno {}
+
+impl From<ErrorKind> for Errno {
+    fn from(kind: ErrorKind) -> Errno {
+        match kind {
+            ErrorKind::TooBig => Errno::TooBig,
+            ErrorKind::Badf => Errno::Badf,
+            ErrorKind::Dquot => Errno::Dquot,
+            ErrorKind::Fbig => Errno::Fbig,
+            ErrorKind::Ilseq => Errno::Ilseq,
+            ErrorKind::Io => Errno::Io,
+            ErrorKind::Isdir => Errno::Isdir,
+            ErrorKind::Loop => Errno::Loop,
+            ErrorKind::Mfile => Errno::Mfile,
+            ErrorKind::Nametoolong => Errno::Nametoolong,
+            ErrorKind::Nfile => Errno::Nfile,
+            ErrorKind::Nospc => Errno::Nospc,
+            ErrorKind::Notdir => Errno::Notdir,
+            ErrorKind::Notempty => Errno::Notempty,
+            ErrorKind::Notsup => Errno::Notsup,
+            ErrorKind::Overflow => Errno::Overflow,
+            ErrorKind::Range => Errno::Range,
+            ErrorKind::Rofs => Errno::Rofs,
+            ErrorKind::Spipe => Errno::Spipe,
+            ErrorKind::Perm => Errno::Perm,
+            ErrorKind::Xdev => Errno::Xdev,
+        }
+    }
+}
+
+impl From<&std::io::Error> for Errno {
+    fn from(err: &std::io::Error) -> Errno {
+        #[cfg(unix)]
+        if let Some(raw) = err.raw_os_error() {
+            return Errno::from(rustix::io::Errno::from_raw_os_error(raw));
+        }
+        match err.kind() {
+            std::io::ErrorKind::NotFound => Errno::Noent,
+            std::io::ErrorKind::PermissionDenied => Errno::Perm,
+            std::io::ErrorKind::ConnectionRefused => Errno::Connrefused,
+            std::io::ErrorKind::ConnectionReset => Errno::Connreset,
+            std::io::ErrorKind::ConnectionAborted => Errno::Connaborted,
+            std::io::ErrorKind::NotConnected => Errno::Notconn,
+            std::io::ErrorKind::AddrInUse => Errno::Addrinuse,
+            std::io::ErrorKind::AddrNotAvailable => Errno::Addrnotavail,
+            std::io::ErrorKind::BrokenPipe => Errno::Pipe,
+            std::io::ErrorKind::AlreadyExists => Errno::Exist,
+            std::io::ErrorKind::WouldBlock => Errno::Again,
+            std::io::ErrorKind::InvalidInput => Errno::Inval,
+            std::io::ErrorKind::TimedOut => Errno::Timedout,
+            std::io::ErrorKind::Interrupted => Errno::Intr,
+            std::io::ErrorKind::Unsupported => Errno::Notsup,
+            std::io::ErrorKind::OutOfMemory => Errno::Nomem,
+            _ => Errno::Io,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<rustix::io::Errno> for Errno {
+    fn from(err: rustix::io::Errno) -> Errno {
+        use rustix::io::Errno as E;
+        match err {
+            E::TOOBIG => Errno::TooBig,
+            E::ACCESS => Errno::Acces,
+            E::ADDRINUSE => Errno::Addrinuse,
+            E::ADDRNOTAVAIL => Errno::Addrnotavail,
+            E::AFNOSUPPORT => Errno::Afnosupport,
+            E::AGAIN => Errno::Again,
+            E::ALREADY => Errno::Already,
+            E::BADF => Errno::Badf,
+            E::BADMSG => Errno::Badmsg,
+            E::BUSY => Errno::Busy,
+            E::CANCELED => Errno::Canceled,
+            E::CHILD => Errno::Child,
+            E::CONNABORTED => Errno::Connaborted,
+            E::CONNREFUSED => Errno::Connrefused,
+            E::CONNRESET => Errno::Connreset,
+            E::DEADLK => Errno::Deadlk,
+            E::DESTADDRREQ => Errno::Destaddrreq,
+            E::DOM => Errno::Dom,
+            E::DQUOT => Errno::Dquot,
+            E::EXIST => Errno::Exist,
+            E::FAULT => Errno::Fault,
+            E::FBIG => Errno::Fbig,
+            E::HOSTUNREACH => Errno::Hostunreach,
+            E::IDRM => Errno::Idrm,
+            E::ILSEQ => Errno::Ilseq,
+            E::INPROGRESS => Errno::Inprogress,
+            E::INTR => Errno::Intr,
+            E::INVAL => Errno::Inval,
+            E::IO => Errno::Io,
+            E::ISCONN => Errno::Isconn,
+            E::ISDIR => Errno::Isdir,
+            E::LOOP => Errno::Loop,
+            E::MFILE => Errno::Mfile,
+            E::MLINK => Errno::Mlink,
+            E::MSGSIZE => Errno::Msgsize,
+            E::MULTIHOP => Errno::Multihop,
+            E::NAMETOOLONG => Errno::Nametoolong,
+            E::NETDOWN => Errno::Netdown,
+            E::NETRESET => Errno::Netreset,
+            E::NETUNREACH => Errno::Netunreach,
+            E::NFILE => Errno::Nfile,
+            E::NOBUFS => Errno::Nobufs,
+            E::NODEV => Errno::Nodev,
+            E::NOENT => Errno::Noent,
+            E::NOEXEC => Errno::Noexec,
+            E::NOLCK => Errno::Nolck,
+            E::NOLINK => Errno::Nolink,
+            E::NOMEM => Errno::Nomem,
+            E::NOMSG => Errno::Nomsg,
+            E::NOPROTOOPT => Errno::Noprotoopt,
+            E::NOSPC => Errno::Nospc,
+            E::NOSYS => Errno::Nosys,
+            E::NOTCONN => Errno::Notconn,
+            E::NOTDIR => Errno::Notdir,
+            E::NOTEMPTY => Errno::Notempty,
+            E::NOTRECOVERABLE => Errno::Notrecoverable,
+            E::NOTSOCK => Errno::Notsock,
+            E::NOTSUP => Errno::Notsup,
+            E::NOTTY => Errno::Notty,
+            E::NXIO => Errno::Nxio,
+            E::OVERFLOW => Errno::Overflow,
+            E::OWNERDEAD => Errno::Ownerdead,
+            E::PERM => Errno::Perm,
+            E::PIPE => Errno::Pipe,
+            E::PROTO => Errno::Proto,
+            E::PROTONOSUPPORT => Errno::Protonosupport,
+            E::PROTOTYPE => Errno::Prototype,
+            E::RANGE => Errno::Range,
+            E::ROFS => Errno::Rofs,
+            E::SPIPE => Errno::Spipe,
+            E::SRCH => Errno::Srch,
+            E::STALE => Errno::Stale,
+            E::TIMEDOUT => Errno::Timedout,
+            E::TXTBSY => Errno::Txtbsy,
+            E::XDEV => Errno::Xdev,
+            _ => Errno::Io,
+        }
+    }
 }
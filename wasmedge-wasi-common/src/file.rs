@@ -1,14 +1,24 @@
 use crate::clocks::SystemTimeSpec;
-use crate::error::{Error, ErrorExt};
+use crate::error::{Errno, Error, ErrorExt};
+use crate::quota::OutputQuota;
 use bitflags::bitflags;
 use std::any::Any;
+use std::time::Duration;
 
 pub trait WasiFile: Send + Sync {
     fn as_any(&self) -> &dyn Any;
     fn get_filetype(&mut self) -> Result<FileType, Error>;
 
     #[cfg(unix)]
-    fn pollable(&self) -> Option<rustix::fd::BorrowedFd> {
+    fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+        None
+    }
+
+    /// The host descriptor of a regular file whose contents are exactly what the guest sees, so
+    /// that they can be copied on the host. Wrappers that only restrict access may pass it on;
+    /// wrappers that account for writes or change contents must not.
+    #[cfg(unix)]
+    fn host_fd(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
         None
     }
 
@@ -117,6 +127,11 @@ pub trait WasiFile: Send + Sync {
         Err(Error::badf())
     }
 
+    /// The current offset, if the backend can tell without moving it or blocking.
+    fn offset(&self) -> Option<u64> {
+        None
+    }
+
     fn peek(&mut self, _buf: &mut [u8]) -> Result<u64, Error> {
         Err(Error::badf())
     }
@@ -125,6 +140,14 @@ pub trait WasiFile: Send + Sync {
         Ok(0)
     }
 
+    /// Make blocking reads and writes fail once they have waited for longer than `timeouts`.
+    ///
+    /// Backends that can't enforce timeouts themselves return `Notsup`; the environ still
+    /// enforces them for the syscalls it runs, if the backend is `pollable`.
+    fn set_timeouts(&mut self, _timeouts: Timeouts) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
     fn readable(&self) -> Result<(), Error> {
         Err(Error::badf())
     }
@@ -134,7 +157,28 @@ pub trait WasiFile: Send + Sync {
     }
 }
 
+/// How long a blocking read or write on a descriptor may wait. `None` waits forever, and so
+/// does a zero duration, as with `SO_RCVTIMEO` and `SO_SNDTIMEO`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+impl Timeouts {
+    /// The same timeouts, with zero durations spelled as `None`, the way backends and the
+    /// environ expect them.
+    pub(crate) fn normalized(self) -> Self {
+        let normalize = |timeout: Option<Duration>| timeout.filter(|t| !t.is_zero());
+        Timeouts {
+            read: normalize(self.read),
+            write: normalize(self.write),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FileType {
     Unknown,
     BlockDevice,
@@ -192,6 +236,7 @@ bitflags! {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Filestat {
     pub device_id: u64,
     pub inode: u64,
@@ -216,18 +261,78 @@ impl TableFileExt for crate::table::Table {
     }
 }
 
-pub(crate) struct FileEntry {
+/// A file in the table, together with the capabilities the guest holds on it.
+///
+/// `F` is the backend trait object: `dyn WasiFile` for the synchronous `WasiEnviron`, or
+/// `dyn AsyncWasiFile` for the asynchronous one.
+pub(crate) struct FileEntry<F: ?Sized = dyn WasiFile> {
     caps: FileCaps,
-    file: Box<dyn WasiFile>,
+    file: Box<F>,
+    /// The preopen this file was opened under, if any.
+    origin: Option<u32>,
+    revoked: bool,
+    timeouts: Timeouts,
+    /// Whether the entry counts against `Limits::max_sockets`.
+    socket: bool,
+    quota: Option<OutputQuota>,
+    /// What the backend reported when the entry was made, for `WasiEnviron::descriptors`.
+    filetype: FileType,
+    fdflags: FdFlags,
 }
 
-impl FileEntry {
-    pub fn new(caps: FileCaps, file: Box<dyn WasiFile>) -> Self {
-        FileEntry { caps, file }
+impl<F: ?Sized> FileEntry<F> {
+    pub fn new(caps: FileCaps, file: Box<F>) -> Self {
+        FileEntry {
+            caps,
+            file,
+            origin: None,
+            revoked: false,
+            timeouts: Timeouts::default(),
+            socket: false,
+            quota: None,
+            filetype: FileType::Unknown,
+            fdflags: FdFlags::empty(),
+        }
+    }
+
+    pub(crate) fn with_origin(mut self, origin: Option<u32>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub(crate) fn origin(&self) -> Option<u32> {
+        self.origin
+    }
+
+    pub(crate) fn with_socket(mut self, socket: bool) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    pub(crate) fn is_socket(&self) -> bool {
+        self.socket
+    }
+
+    pub(crate) fn set_quota(&mut self, quota: Option<OutputQuota>) {
+        self.quota = quota;
+    }
+
+    pub(crate) fn quota(&self) -> Option<&OutputQuota> {
+        self.quota.as_ref()
+    }
+
+    pub(crate) fn filetype(&self) -> FileType {
+        self.filetype
+    }
+
+    pub(crate) fn fdflags(&self) -> FdFlags {
+        self.fdflags
     }
 
     pub fn capable_of(&self, caps: FileCaps) -> Result<(), Error> {
-        if self.caps.contains(caps) {
+        if self.revoked {
+            Err(Error::perm().context("access to the file was revoked"))
+        } else if self.caps.contains(caps) {
             Ok(())
         } else {
             let missing = caps & !self.caps;
@@ -243,12 +348,69 @@ impl FileEntry {
         }
     }
 
+    #[allow(dead_code)]
     pub fn drop_caps_to(&mut self, caps: FileCaps) -> Result<(), Error> {
         self.capable_of(caps)?;
         self.caps = caps;
         Ok(())
     }
 
+    pub(crate) fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    pub(crate) fn caps(&self) -> FileCaps {
+        self.caps
+    }
+
+    pub(crate) fn file(&self) -> &F {
+        &self.file
+    }
+
+    pub(crate) fn file_mut(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    /// Replace the backend with `f` of it, keeping the rights, quota, timeouts and origin.
+    #[cfg(feature = "replay")]
+    pub(crate) fn map_file(self, f: impl FnOnce(Box<F>) -> Box<F>) -> Self {
+        FileEntry {
+            file: f(self.file),
+            ..self
+        }
+    }
+}
+
+impl FileEntry {
+    /// Ask the backend for its type and flags, so that listing the descriptors needn't ask it
+    /// again. `Unknown` and empty if it fails to report them.
+    pub(crate) fn with_fdstat(mut self) -> Self {
+        self.filetype = self.file.get_filetype().unwrap_or(FileType::Unknown);
+        self.fdflags = self.file.get_fdflags().unwrap_or_else(|_| FdFlags::empty());
+        self
+    }
+
+    /// Take away every right on the file and close the backend. The descriptor stays in the
+    /// table until the guest closes it, so its number isn't handed out again under its feet.
+    pub(crate) fn revoke(&mut self) {
+        self.caps = FileCaps::empty();
+        self.file = Box::new(Revoked);
+        self.revoked = true;
+    }
+
+    /// Set the timeouts the environ enforces, and hand them to the backend if it can enforce
+    /// them too.
+    pub(crate) fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+        let timeouts = timeouts.normalized();
+        match self.file.set_timeouts(timeouts) {
+            Err(e) if Errno::from_error(&e) != Some(Errno::Notsup) => return Err(e),
+            _ => {}
+        }
+        self.timeouts = timeouts;
+        Ok(())
+    }
+
+    #[allow(dead_code)]
     pub fn get_fdstat(&mut self) -> Result<FdStat, Error> {
         Ok(FdStat {
             filetype: self.file.get_filetype()?,
@@ -258,6 +420,18 @@ impl FileEntry {
     }
 }
 
+/// What a revoked descriptor is left holding. Every operation fails.
+pub(crate) struct Revoked;
+
+impl WasiFile for Revoked {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::Unknown)
+    }
+}
+
 pub trait FileEntryExt {
     fn get_cap(&self, caps: FileCaps) -> Result<&dyn WasiFile, Error>;
     fn get_cap_mut(&mut self, caps: FileCaps) -> Result<&mut dyn WasiFile, Error>;
@@ -301,6 +475,7 @@ pub struct FdStat {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Advice {
     Normal,
     Sequential,
@@ -309,3 +484,73 @@ pub enum Advice {
     DontNeed,
     NoReuse,
 }
+
+/// Copy the contents of `src`, which hasn't been read from, to the empty file `dst`. Between two
+/// host files this is done on the host with `copy_on_host`; anywhere else, it goes through a
+/// buffer.
+pub(crate) fn copy_contents(src: &mut dyn WasiFile, dst: &mut dyn WasiFile) -> Result<(), Error> {
+    #[cfg(unix)]
+    if let (Some(from), Some(to)) = (src.host_fd(), dst.host_fd()) {
+        if copy_on_host(from, to)? {
+            return Ok(());
+        }
+    }
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = src.read_vectored(&mut [std::io::IoSliceMut::new(&mut buf)])? as usize;
+        if n == 0 {
+            return Ok(());
+        }
+        let mut written = 0;
+        while written < n {
+            match dst.write_vectored(&[std::io::IoSlice::new(&buf[written..n])])? {
+                0 => return Err(Error::io().context("copy wrote nothing")),
+                w => written += w as usize,
+            }
+        }
+    }
+}
+
+/// Copy the contents of the host file `from`, which hasn't been read from, to the empty host
+/// file `to`, without going through a buffer: on Linux with a `FICLONE` reflink if the
+/// filesystem supports it, and otherwise with `copy_file_range`, which copies within the kernel.
+/// Returns `false`, having copied nothing, where neither works, so that the caller copies through
+/// a buffer instead.
+#[cfg(unix)]
+pub(crate) fn copy_on_host(
+    from: rustix::fd::BorrowedFd<'_>,
+    to: rustix::fd::BorrowedFd<'_>,
+) -> Result<bool, Error> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    {
+        use rustix::fd::AsRawFd;
+        use rustix::io::Errno;
+        // SAFETY: both descriptors are borrowed for the length of the call, and FICLONE takes
+        // the source descriptor as its argument.
+        if unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } == 0 {
+            return Ok(true);
+        }
+        // Any failure, such as the files being on different filesystems or one that can't
+        // reflink, leaves `to` untouched, so try the next way.
+        let mut copied = 0;
+        loop {
+            match rustix::fs::copy_file_range(from, None, to, None, 1 << 30) {
+                Ok(0) => return Ok(true),
+                Ok(n) => copied += n,
+                // The files are on filesystems that can't copy between each other, or the
+                // kernel is too old.
+                Err(Errno::XDEV | Errno::NOSYS | Errno::INVAL | Errno::OPNOTSUPP)
+                    if copied == 0 =>
+                {
+                    return Ok(false)
+                }
+                Err(e) => return Err(std::io::Error::from(e).into()),
+            }
+        }
+    }
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    {
+        let _ = (from, to);
+        Ok(false)
+    }
+}
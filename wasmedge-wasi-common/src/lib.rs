@@ -1,13 +1,47 @@
+#[cfg(any(feature = "tar", feature = "zip"))]
+pub mod archive;
+#[cfg(feature = "async")]
+pub mod async_dir;
+#[cfg(feature = "async")]
+pub mod async_environ;
+#[cfg(feature = "async")]
+pub mod async_file;
+pub mod cancel;
+pub mod checkpoint;
 pub mod clocks;
+pub mod descriptors;
+pub mod devices;
 pub mod dir;
+pub mod disk_quota;
 pub mod environ;
 pub mod error;
 pub mod file;
+pub mod limits;
+pub mod memfs;
+pub mod metrics;
+pub mod overlay;
 pub mod pipe;
+pub mod quota;
+pub mod read_only;
+#[cfg(feature = "replay")]
+pub mod replay;
+#[cfg(feature = "async")]
+pub mod sched;
+pub mod shared_environ;
+pub mod snapshot;
+pub mod strace;
 pub mod string_array;
 pub mod table;
+pub mod template;
+mod trace;
+pub mod transaction;
 
-pub use error::{Context, Error, ErrorExt, ErrorKind};
+#[cfg(feature = "async")]
+pub use async_trait::async_trait;
+pub use error::{Context, Errno, Error, ErrorExt, ErrorKind};
+
+use dir::DirCaps;
+use file::{FdFlags, FileCaps, OFlags};
 
 pub trait WasiSnapshotPreview1 {
     /// Return the number of command-line arguments and the size of the command-line argument data.
@@ -26,15 +60,98 @@ pub trait WasiSnapshotPreview1 {
     /// Key/value pairs are expected to be joined with `=`s, and terminated with `\0`s.
     fn environ_get(&self, out: &mut Vec<Ciovec>);
 
+    /// Close the file descriptor `fd`.
+    fn fd_close(&mut self, fd: i32) -> Result<(), Error>;
+
+    /// Read data from the file associated with the file descriptor `fd` into the buffers
+    /// described by `iovs`.
+    ///
+    /// Return the number of bytes read. Use `Errno::from_error` to turn an error into the
+    /// errno for the guest.
+    fn fd_read(&mut self, fd: i32, iovs: IovecArray) -> Result<i32, Error>;
+
     /// Write data described by `iovs` to the file associated with the file descriptor `fd`.
     ///
-    /// Return the number of bytes written.
-    fn fd_write(&mut self, fd: i32, iovs: CiovecArray) -> i32;
+    /// Return the number of bytes written. Use `Errno::from_error` to turn an error into the
+    /// errno for the guest.
+    fn fd_write(&mut self, fd: i32, iovs: CiovecArray) -> Result<i32, Error>;
+
+    /// Open the file or directory at `path`, relative to the directory `dirfd`.
+    ///
+    /// The new descriptor gets the requested rights that `dirfd` passes on: `file_caps` on a
+    /// file, or for a directory, `dir_caps` on itself and `file_caps` on the files opened
+    /// through it. Return the new descriptor.
+    #[allow(clippy::too_many_arguments)]
+    fn path_open(
+        &mut self,
+        dirfd: i32,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        dir_caps: DirCaps,
+        file_caps: FileCaps,
+        fdflags: FdFlags,
+    ) -> Result<i32, Error>;
 
     /// Terminate the process normally. An exit code of 0 indicates successful
     /// termination of the program. The meanings of other values is dependent on
     /// the environment.
     fn proc_exit(&mut self, code: i32);
+
+    /// Accept a new connection on the listening socket `fd`, with `fdflags` set on it.
+    /// Return the descriptor of the connection.
+    fn sock_accept(&mut self, fd: i32, fdflags: FdFlags) -> Result<i32, Error>;
+}
+
+/// The asynchronous counterpart of `WasiSnapshotPreview1`, implemented by `AsyncWasiEnviron`.
+///
+/// See `WasiSnapshotPreview1` for the meaning of each method.
+#[cfg(feature = "async")]
+#[async_trait]
+pub trait AsyncWasiSnapshotPreview1 {
+    async fn args_sizes_get(&self) -> (i32, i32);
+
+    async fn args_get(&self, out: &mut Vec<Ciovec>);
+
+    async fn environ_sizes_get(&self) -> (i32, i32);
+
+    async fn environ_get(&self, out: &mut Vec<Ciovec>);
+
+    async fn fd_close(&mut self, fd: i32) -> Result<(), Error>;
+
+    async fn fd_read(&mut self, fd: i32, iovs: IovecArray<'_>) -> Result<i32, Error>;
+
+    async fn fd_write(&mut self, fd: i32, iovs: CiovecArray<'_>) -> Result<i32, Error>;
+
+    #[allow(clippy::too_many_arguments)]
+    async fn path_open(
+        &mut self,
+        dirfd: i32,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        dir_caps: DirCaps,
+        file_caps: FileCaps,
+        fdflags: FdFlags,
+    ) -> Result<i32, Error>;
+
+    /// Concurrently poll for the occurrence of a set of events.
+    ///
+    /// Return once at least one subscription has completed, with an event for every
+    /// subscription that completed by then.
+    async fn poll_oneoff(&self, subs: &[sched::Subscription]) -> Result<Vec<sched::Event>, Error>;
+
+    async fn proc_exit(&mut self, code: i32);
+
+    async fn sock_accept(&mut self, fd: i32, fdflags: FdFlags) -> Result<i32, Error>;
+}
+
+/// The `wasi` `thread-spawn` import from the `wasi-threads` proposal.
+pub trait WasiThreads {
+    /// Spawn a new thread that runs the guest's `wasi_thread_start` export with `start_arg`.
+    ///
+    /// Return the ID of the new thread, or a negative value if no thread could be spawned.
+    fn thread_spawn(&self, start_arg: i32) -> i32;
 }
 
 pub type Size = usize;
@@ -46,4 +163,22 @@ pub struct Ciovec {
     /// The length of the buffer to be written.
     pub buf_len: Size,
 }
+// SAFETY: a `Ciovec` only describes a region of guest memory. Whoever builds one guarantees
+// the region stays valid for the duration of the syscall it is passed to, whichever thread
+// the syscall runs on.
+unsafe impl Send for Ciovec {}
+unsafe impl Sync for Ciovec {}
 pub type CiovecArray<'a> = &'a [Ciovec];
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Iovec {
+    /// The address of the buffer to be filled.
+    pub buf: *mut u8,
+    /// The length of the buffer to be filled.
+    pub buf_len: Size,
+}
+// SAFETY: see `Ciovec`.
+unsafe impl Send for Iovec {}
+unsafe impl Sync for Iovec {}
+pub type IovecArray<'a> = &'a [Iovec];
@@ -0,0 +1,144 @@
+//! Bounds on what a guest can hold open.
+//!
+//! A `Table` counts its entries by class as they come and go, and `Table::push` refuses to go
+//! over the `Limits` set on it: with `Mfile` for the total number of descriptors, and with
+//! `Nfile` for sockets and directories. Entries the host inserts at a chosen index are counted,
+//! but never refused.
+use crate::dir::DirEntry;
+use crate::error::{Error, ErrorExt};
+use crate::file::FileEntry;
+use std::any::Any;
+use std::collections::HashMap;
+
+/// The most descriptors of each class a table may hold. `None` is unlimited.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Descriptors of any kind, including stdio, preopens and resources of other proposals.
+    pub max_fds: Option<usize>,
+    /// Sockets accepted by the guest, and sockets the host adds to a `WasiEnviron` or a
+    /// `SharedWasiEnviron`.
+    pub max_sockets: Option<usize>,
+    /// Directories the guest opened through a preopen.
+    pub max_derived_dirs: Option<usize>,
+}
+
+/// How much of one limit is in use.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Gauge {
+    pub used: usize,
+    pub limit: Option<usize>,
+}
+
+impl Gauge {
+    /// How many more may be opened, or `None` if unlimited.
+    pub fn available(&self) -> Option<usize> {
+        self.limit.map(|limit| limit.saturating_sub(self.used))
+    }
+}
+
+/// The current usage of a table against each of its limits.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub fds: Gauge,
+    pub sockets: Gauge,
+    pub derived_dirs: Gauge,
+}
+
+/// What a table entry counts against, besides `max_fds`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Class {
+    Socket,
+    DerivedDir,
+    Other,
+}
+
+fn classify(a: &(dyn Any + Send + Sync)) -> Class {
+    if let Some(entry) = a.downcast_ref::<FileEntry>() {
+        if entry.is_socket() {
+            return Class::Socket;
+        }
+    } else if let Some(entry) = a.downcast_ref::<DirEntry>() {
+        if entry.preopen_path().is_none() {
+            return Class::DerivedDir;
+        }
+    }
+    #[cfg(feature = "async")]
+    if let Some(entry) = a.downcast_ref::<crate::async_file::AsyncFileEntry>() {
+        if entry.is_socket() {
+            return Class::Socket;
+        }
+    } else if let Some(entry) = a.downcast_ref::<crate::async_dir::AsyncDirEntry>() {
+        if entry.preopen_path().is_none() {
+            return Class::DerivedDir;
+        }
+    }
+    Class::Other
+}
+
+/// The limits of a table, and its entries counted by class.
+///
+/// The class of each counted entry is remembered by key, so that removing an entry never has to
+/// look at it: in a `SharedTable` another thread may be holding it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Counts {
+    pub limits: Limits,
+    classes: HashMap<u32, Class>,
+    sockets: usize,
+    derived_dirs: usize,
+}
+
+impl Counts {
+    /// Fail if pushing `a` onto a table of `len` entries would go over a limit.
+    pub fn check(&self, len: usize, a: &(dyn Any + Send + Sync)) -> Result<(), Error> {
+        let over = |used: usize, limit: Option<usize>| limit.is_some_and(|limit| used >= limit);
+        if over(len, self.limits.max_fds) {
+            return Err(Error::mfile().context("too many open descriptors"));
+        }
+        match classify(a) {
+            Class::Socket if over(self.sockets, self.limits.max_sockets) => {
+                Err(Error::nfile().context("too many open sockets"))
+            }
+            Class::DerivedDir if over(self.derived_dirs, self.limits.max_derived_dirs) => {
+                Err(Error::nfile().context("too many open directories"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count `a` as the entry at `key`, replacing whatever was counted there.
+    pub fn add(&mut self, key: u32, a: &(dyn Any + Send + Sync)) {
+        self.remove(key);
+        let class = classify(a);
+        match class {
+            Class::Socket => self.sockets += 1,
+            Class::DerivedDir => self.derived_dirs += 1,
+            Class::Other => return,
+        }
+        self.classes.insert(key, class);
+    }
+
+    pub fn remove(&mut self, key: u32) {
+        match self.classes.remove(&key) {
+            Some(Class::Socket) => self.sockets -= 1,
+            Some(Class::DerivedDir) => self.derived_dirs -= 1,
+            Some(Class::Other) | None => {}
+        }
+    }
+
+    pub fn usage(&self, len: usize) -> Usage {
+        Usage {
+            fds: Gauge {
+                used: len,
+                limit: self.limits.max_fds,
+            },
+            sockets: Gauge {
+                used: self.sockets,
+                limit: self.limits.max_sockets,
+            },
+            derived_dirs: Gauge {
+                used: self.derived_dirs,
+                limit: self.limits.max_derived_dirs,
+            },
+        }
+    }
+}
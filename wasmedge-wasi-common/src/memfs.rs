@@ -0,0 +1,1128 @@
+//! An in-memory filesystem.
+//!
+//! A `MemFs` is a tree of directories, regular files and symlinks that lives entirely in host
+//! memory, so a guest can be given a writable filesystem without touching the host disk. Preopen
+//! its `root` with `push_preopened_dir`. Cloning a `MemFs` gives another handle to the same tree.
+//!
+//! Inode numbers are never reused, timestamps come from the clock the tree was created with, and
+//! `readdir` cursors stay valid while entries are added and removed. As with the cap-std
+//! backend, paths can't lead out of the directory they are resolved from.
+//!
+//! The contents of files and symlinks are held to a byte limit, `DEFAULT_MAX_BYTES` unless set
+//! with `with_max_bytes`, so that a guest can't make the host allocate as much as it likes:
+//! growing a file beyond the limit fails with `Fbig`, and growing the tree beyond it with
+//! `Nospc`, as does running out of host memory.
+//! Renaming or hard linking into a directory of another backend copies the entry across with
+//! `dir::move_across` or `dir::link_across`.
+use crate::clocks::{SystemTimeSpec, WasiSystemClock};
+use crate::dir::{link_across, move_across, new_device_id, ReaddirCursor, ReaddirEntity, WasiDir};
+use crate::error::{Error, ErrorExt};
+use crate::file::{Advice, FdFlags, FileType, Filestat, OFlags, WasiFile};
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{IoSlice, IoSliceMut, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// How many symlinks a path may go through, as on Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// The cursors of `.` and `..`; the other entries of a directory come after them.
+const FIRST_COOKIE: u64 = 2;
+
+/// How many bytes the files and symlinks of a `MemFs` may hold, unless set otherwise.
+pub const DEFAULT_MAX_BYTES: u64 = 1 << 30;
+
+/// An in-memory filesystem. See the module documentation.
+#[derive(Clone)]
+pub struct MemFs(Arc<Fs>);
+
+struct Fs {
+    device_id: u64,
+    clock: Box<dyn WasiSystemClock>,
+    state: Mutex<State>,
+}
+
+struct State {
+    nodes: HashMap<u64, Node>,
+    next_ino: u64,
+    /// The bytes held by files and symlinks, and how many they may hold.
+    bytes: u64,
+    max_bytes: u64,
+}
+
+struct Node {
+    kind: Kind,
+    /// The names the node goes by. A file whose last name is removed lives on while it is open.
+    nlink: u64,
+    /// How many `MemFile`s have the node open.
+    open: u64,
+    atim: SystemTime,
+    mtim: SystemTime,
+    ctim: SystemTime,
+}
+
+enum Kind {
+    Dir(DirNode),
+    File(Vec<u8>),
+    Symlink(String),
+}
+
+struct DirNode {
+    parent: u64,
+    /// Each entry's inode, and the cookie it is listed under.
+    entries: HashMap<String, (u64, u64)>,
+    /// Entries by cookie. Cookies only grow, so a cursor still points at the right place after
+    /// entries before it are added or removed.
+    order: BTreeMap<u64, String>,
+    next_cookie: u64,
+}
+
+impl DirNode {
+    fn new(parent: u64) -> Self {
+        DirNode {
+            parent,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_cookie: FIRST_COOKIE,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<u64> {
+        self.entries.get(name).map(|&(ino, _)| ino)
+    }
+
+    fn insert(&mut self, name: &str, ino: u64) {
+        let cookie = self.next_cookie;
+        self.next_cookie += 1;
+        if let Some((_, old)) = self.entries.insert(name.to_owned(), (ino, cookie)) {
+            self.order.remove(&old);
+        }
+        self.order.insert(cookie, name.to_owned());
+    }
+
+    fn remove(&mut self, name: &str) -> Option<u64> {
+        let (ino, cookie) = self.entries.remove(name)?;
+        self.order.remove(&cookie);
+        Some(ino)
+    }
+}
+
+impl Node {
+    fn filetype(&self) -> FileType {
+        match self.kind {
+            Kind::Dir(_) => FileType::Directory,
+            Kind::File(_) => FileType::RegularFile,
+            Kind::Symlink(_) => FileType::SymbolicLink,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match &self.kind {
+            Kind::Dir(_) => 0,
+            Kind::File(data) => data.len() as u64,
+            Kind::Symlink(target) => target.len() as u64,
+        }
+    }
+}
+
+/// Where a path leads.
+struct Lookup {
+    /// The directory holding the last component, or `None` if the last component is `.` or
+    /// `..`, which can't be created, removed or renamed.
+    parent: Option<u64>,
+    name: String,
+    /// What the last component names, if it exists.
+    ino: Option<u64>,
+}
+
+impl Lookup {
+    fn existing(&self) -> Result<u64, Error> {
+        self.ino.ok_or_else(Error::not_found)
+    }
+
+    /// The directory and name of the last component, to add or remove an entry there.
+    fn entry(&self) -> Result<(u64, &str), Error> {
+        match self.parent {
+            Some(parent) => Ok((parent, &self.name)),
+            None => Err(Error::invalid_argument().context("path ends in `.` or `..`")),
+        }
+    }
+}
+
+impl State {
+    fn node(&self, ino: u64) -> Result<&Node, Error> {
+        self.nodes
+            .get(&ino)
+            .ok_or_else(|| Error::not_found().context("directory was removed"))
+    }
+
+    fn node_mut(&mut self, ino: u64) -> Result<&mut Node, Error> {
+        self.nodes
+            .get_mut(&ino)
+            .ok_or_else(|| Error::not_found().context("directory was removed"))
+    }
+
+    fn dir(&self, ino: u64) -> Result<&DirNode, Error> {
+        match &self.node(ino)?.kind {
+            Kind::Dir(dir) => Ok(dir),
+            _ => Err(Error::not_dir()),
+        }
+    }
+
+    fn dir_mut(&mut self, ino: u64) -> Result<&mut DirNode, Error> {
+        match &mut self.node_mut(ino)?.kind {
+            Kind::Dir(dir) => Ok(dir),
+            _ => Err(Error::not_dir()),
+        }
+    }
+
+    fn file_mut(&mut self, ino: u64) -> Result<&mut Vec<u8>, Error> {
+        match &mut self.node_mut(ino)?.kind {
+            Kind::File(data) => Ok(data),
+            _ => Err(Error::badf().context("file was replaced")),
+        }
+    }
+
+    /// Follow `path` from the directory `base`. Symlinks along the way are followed, and so is
+    /// one in the last component if `follow` is set or the path ends in `/`.
+    fn lookup(&self, base: u64, path: &str, follow: bool) -> Result<Lookup, Error> {
+        if path.is_empty() {
+            return Err(Error::not_found().context("empty path"));
+        }
+        if path.starts_with('/') {
+            return Err(Error::perm().context("absolute path"));
+        }
+        let trailing_slash = path.ends_with('/');
+        let mut components = split(path);
+        // The directories walked through, so that `..` can go back up, but never above `base`.
+        let mut stack = vec![base];
+        let mut hops = 0;
+        loop {
+            let dir = *stack.last().unwrap();
+            let component = match components.pop_front() {
+                Some(component) => component,
+                None => {
+                    return Ok(Lookup {
+                        parent: None,
+                        name: ".".to_owned(),
+                        ino: Some(dir),
+                    })
+                }
+            };
+            let last = components.is_empty();
+            let ino = match component.as_str() {
+                "." => {
+                    if last {
+                        return Ok(Lookup {
+                            parent: None,
+                            name: component,
+                            ino: Some(dir),
+                        });
+                    }
+                    continue;
+                }
+                ".." => {
+                    if stack.len() == 1 {
+                        return Err(Error::perm().context("path leads out of the directory"));
+                    }
+                    stack.pop();
+                    if last {
+                        return Ok(Lookup {
+                            parent: None,
+                            name: component,
+                            ino: stack.last().copied(),
+                        });
+                    }
+                    continue;
+                }
+                name => self.dir(dir)?.get(name),
+            };
+            let node = match ino {
+                Some(ino) => self.node(ino)?,
+                None if last => {
+                    return Ok(Lookup {
+                        parent: Some(dir),
+                        name: component,
+                        ino: None,
+                    })
+                }
+                None => return Err(Error::not_found()),
+            };
+            match &node.kind {
+                Kind::Symlink(target) if !last || follow || trailing_slash => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(Error::symlink_loop());
+                    }
+                    if target.starts_with('/') {
+                        return Err(Error::perm().context("symlink to an absolute path"));
+                    }
+                    let mut target = split(target);
+                    if target.is_empty() {
+                        return Err(Error::not_found().context("empty symlink"));
+                    }
+                    target.extend(components);
+                    components = target;
+                }
+                Kind::Dir(_) if !last => stack.push(ino.unwrap()),
+                _ if !last => return Err(Error::not_dir()),
+                Kind::Dir(_) | Kind::Symlink(_) => {
+                    return Ok(Lookup {
+                        parent: Some(dir),
+                        name: component,
+                        ino,
+                    })
+                }
+                Kind::File(_) if trailing_slash => return Err(Error::not_dir()),
+                Kind::File(_) => {
+                    return Ok(Lookup {
+                        parent: Some(dir),
+                        name: component,
+                        ino,
+                    })
+                }
+            }
+        }
+    }
+
+    fn add_node(&mut self, kind: Kind, now: SystemTime) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let node = Node {
+            kind,
+            nlink: 1,
+            open: 0,
+            atim: now,
+            mtim: now,
+            ctim: now,
+        };
+        self.bytes += node.size();
+        self.nodes.insert(ino, node);
+        ino
+    }
+
+    fn remove_node(&mut self, ino: u64) {
+        if let Some(node) = self.nodes.remove(&ino) {
+            self.bytes -= node.size();
+        }
+    }
+
+    /// Fail with `Nospc` unless `bytes` more fit within the limit.
+    fn check_space(&self, bytes: u64) -> Result<(), Error> {
+        match self.bytes.checked_add(bytes) {
+            Some(total) if total <= self.max_bytes => Ok(()),
+            _ => Err(Error::no_space().context("MemFs is full")),
+        }
+    }
+
+    /// Grow or shrink the file `ino` to `len` bytes, filling with zeros.
+    fn resize_file(&mut self, ino: u64, len: u64) -> Result<(), Error> {
+        if len > self.max_bytes {
+            return Err(Error::fbig().context("file would outgrow the MemFs"));
+        }
+        let old = self.file_mut(ino)?.len() as u64;
+        if len > old {
+            self.check_space(len - old)?;
+        }
+        let len = usize::try_from(len)?;
+        let data = self.file_mut(ino)?;
+        if len > data.len() {
+            data.try_reserve(len - data.len())
+                .map_err(|_| Error::no_space().context("out of host memory"))?;
+            data.resize(len, 0);
+        } else {
+            data.truncate(len);
+            data.shrink_to_fit();
+        }
+        self.bytes = self.bytes - old + len as u64;
+        Ok(())
+    }
+
+    /// Add the entry `name` for `ino` to the directory `dir`.
+    fn link(&mut self, dir: u64, name: &str, ino: u64, now: SystemTime) -> Result<(), Error> {
+        self.dir_mut(dir)?.insert(name, ino);
+        let is_dir = matches!(self.node(ino)?.kind, Kind::Dir(_));
+        let parent = self.node_mut(dir)?;
+        parent.mtim = now;
+        parent.ctim = now;
+        if is_dir {
+            parent.nlink += 1;
+        }
+        Ok(())
+    }
+
+    /// Remove the entry `name` from the directory `dir`, leaving the node it names alone.
+    fn detach(&mut self, dir: u64, name: &str, now: SystemTime) -> Result<u64, Error> {
+        let ino = self
+            .dir_mut(dir)?
+            .remove(name)
+            .ok_or_else(Error::not_found)?;
+        let is_dir = matches!(self.node(ino)?.kind, Kind::Dir(_));
+        let parent = self.node_mut(dir)?;
+        parent.mtim = now;
+        parent.ctim = now;
+        if is_dir {
+            parent.nlink -= 1;
+        }
+        Ok(ino)
+    }
+
+    /// Remove the entry `name` from the directory `dir`, and the node it names once nothing
+    /// refers to it anymore.
+    fn unlink(&mut self, dir: u64, name: &str, now: SystemTime) -> Result<(), Error> {
+        let ino = self.detach(dir, name, now)?;
+        let node = self.node_mut(ino)?;
+        node.nlink -= 1;
+        node.ctim = now;
+        if matches!(node.kind, Kind::Dir(_)) || (node.nlink == 0 && node.open == 0) {
+            self.remove_node(ino);
+        }
+        Ok(())
+    }
+
+    fn filestat(&self, device_id: u64, ino: u64) -> Result<Filestat, Error> {
+        let node = self.node(ino)?;
+        Ok(Filestat {
+            device_id,
+            inode: ino,
+            filetype: node.filetype(),
+            nlink: node.nlink,
+            size: node.size(),
+            atim: Some(node.atim),
+            mtim: Some(node.mtim),
+            ctim: Some(node.ctim),
+        })
+    }
+
+    fn set_times(
+        &mut self,
+        ino: u64,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        let node = self.node_mut(ino)?;
+        if let Some(atime) = atime {
+            node.atim = atime;
+        }
+        if let Some(mtime) = mtime {
+            node.mtim = mtime;
+        }
+        node.ctim = now;
+        Ok(())
+    }
+}
+
+/// The components of `path`, without the empty ones between repeated slashes.
+fn split(path: &str) -> VecDeque<String> {
+    path.split('/')
+        .filter(|c| !c.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+impl MemFs {
+    /// An empty filesystem, timestamped by `clock`.
+    pub fn new(clock: Box<dyn WasiSystemClock>) -> Self {
+        let fs = Fs {
+            device_id: new_device_id(),
+            clock,
+            state: Mutex::new(State {
+                nodes: HashMap::new(),
+                next_ino: 1,
+                bytes: 0,
+                max_bytes: DEFAULT_MAX_BYTES,
+            }),
+        };
+        let now = fs.now();
+        let mut state = fs.state();
+        let root = state.next_ino;
+        let root = state.add_node(Kind::Dir(DirNode::new(root)), now);
+        state.node_mut(root).unwrap().nlink = 2;
+        drop(state);
+        MemFs(Arc::new(fs))
+    }
+
+    /// Hold the contents of files and symlinks to `max_bytes`. A tree that already holds more
+    /// keeps it, but can't grow.
+    pub fn with_max_bytes(self, max_bytes: u64) -> Self {
+        self.0.state().max_bytes = max_bytes;
+        self
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.0.state().max_bytes
+    }
+
+    /// How many bytes the contents of files and symlinks take up, including those of files
+    /// that are unlinked but still open.
+    pub fn bytes_used(&self) -> u64 {
+        self.0.state().bytes
+    }
+
+    /// The root directory of the tree.
+    pub fn root(&self) -> MemDir {
+        MemDir {
+            fs: self.0.clone(),
+            ino: 1,
+        }
+    }
+}
+
+impl Fs {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn now(&self) -> SystemTime {
+        self.clock.now(Duration::ZERO).into_std()
+    }
+
+    fn time(&self, spec: Option<SystemTimeSpec>) -> Option<SystemTime> {
+        spec.map(|spec| match spec {
+            SystemTimeSpec::SymbolicNow => self.now(),
+            SystemTimeSpec::Absolute(t) => t.into_std(),
+        })
+    }
+}
+
+/// A directory of a `MemFs`.
+pub struct MemDir {
+    fs: Arc<Fs>,
+    ino: u64,
+}
+
+impl MemDir {
+    /// The directory behind `dir`, if it belongs to the same tree.
+    fn same_fs<'a>(&self, dir: &'a dyn WasiDir) -> Result<&'a MemDir, Error> {
+        match dir.as_any().downcast_ref::<MemDir>() {
+            Some(dir) if Arc::ptr_eq(&dir.fs, &self.fs) => Ok(dir),
+            _ => Err(Error::cross_device().context("destination is not in the same MemFs")),
+        }
+    }
+}
+
+impl WasiDir for MemDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        if fdflags.intersects(FdFlags::DSYNC | FdFlags::SYNC | FdFlags::RSYNC) {
+            return Err(Error::not_supported().context("SYNC family of FdFlags"));
+        }
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let lookup = state.lookup(self.ino, path, symlink_follow)?;
+        let ino = match lookup.ino {
+            Some(_) if oflags.contains(OFlags::CREATE | OFlags::EXCLUSIVE) => {
+                return Err(Error::exist())
+            }
+            Some(ino) => ino,
+            None if oflags.contains(OFlags::CREATE) => {
+                let (dir, name) = lookup.entry()?;
+                let ino = state.add_node(Kind::File(Vec::new()), now);
+                state.link(dir, name, ino, now)?;
+                ino
+            }
+            None => return Err(Error::not_found()),
+        };
+        let node = state.node_mut(ino)?;
+        match &mut node.kind {
+            Kind::Dir(_) => return Err(Error::is_dir()),
+            Kind::Symlink(_) => return Err(Error::symlink_loop().context("symlink not followed")),
+            Kind::File(data) => {
+                if oflags.contains(OFlags::TRUNCATE) && !data.is_empty() {
+                    state.resize_file(ino, 0)?;
+                    let node = state.node_mut(ino)?;
+                    node.mtim = now;
+                    node.ctim = now;
+                }
+            }
+        }
+        state.node_mut(ino)?.open += 1;
+        // As with the cap-std backend, a file is always opened for one of reading or writing,
+        // and the descriptor's caps decide what the guest may actually do with it.
+        Ok(Box::new(MemFile {
+            fs: self.fs.clone(),
+            ino,
+            pos: 0,
+            read: read || !write,
+            write: write || oflags.contains(OFlags::CREATE),
+            fdflags,
+        }))
+    }
+    fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let state = self.fs.state();
+        let ino = state.lookup(self.ino, path, symlink_follow)?.existing()?;
+        state.dir(ino)?;
+        Ok(Box::new(MemDir {
+            fs: self.fs.clone(),
+            ino,
+        }))
+    }
+    fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let lookup = state.lookup(self.ino, path, false)?;
+        if lookup.ino.is_some() {
+            return Err(Error::exist());
+        }
+        let (dir, name) = lookup.entry()?;
+        let ino = state.add_node(Kind::Dir(DirNode::new(dir)), now);
+        state.node_mut(ino)?.nlink = 2;
+        state.link(dir, name, ino, now)
+    }
+    fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let state = self.fs.state();
+        let dir = state.dir(self.ino)?;
+        let dots = [(0, ".", self.ino), (1, "..", dir.parent)]
+            .into_iter()
+            .map(|(cookie, name, ino)| (cookie, name.to_owned(), ino, FileType::Directory));
+        let entries = dir.order.iter().map(|(&cookie, name)| {
+            let ino = dir.get(name).unwrap();
+            (cookie, name.clone(), ino, state.nodes[&ino].filetype())
+        });
+        let cursor = u64::from(cursor);
+        let entities = dots
+            .chain(entries)
+            .filter(|&(cookie, ..)| cookie >= cursor)
+            .map(|(cookie, name, inode, filetype)| {
+                Ok(ReaddirEntity {
+                    next: ReaddirCursor::from(cookie + 1),
+                    inode,
+                    name,
+                    filetype,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(Box::new(entities.into_iter()))
+    }
+    fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let lookup = state.lookup(self.ino, new_path, false)?;
+        if lookup.ino.is_some() {
+            return Err(Error::exist());
+        }
+        let (dir, name) = lookup.entry()?;
+        state.check_space(old_path.len() as u64)?;
+        let ino = state.add_node(Kind::Symlink(old_path.to_owned()), now);
+        state.link(dir, name, ino, now)
+    }
+    fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let lookup = state.lookup(self.ino, path, false)?;
+        let ino = lookup.existing()?;
+        if !state.dir(ino)?.entries.is_empty() {
+            return Err(Error::not_empty());
+        }
+        let (dir, name) = lookup.entry()?;
+        state.unlink(dir, name, now)
+    }
+    fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let lookup = state.lookup(self.ino, path, false)?;
+        let ino = lookup.existing()?;
+        if let Kind::Dir(_) = state.node(ino)?.kind {
+            return Err(Error::is_dir());
+        }
+        let (dir, name) = lookup.entry()?;
+        state.unlink(dir, name, now)
+    }
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        let state = self.fs.state();
+        let ino = state.lookup(self.ino, path, false)?.existing()?;
+        match &state.node(ino)?.kind {
+            Kind::Symlink(target) => Ok(PathBuf::from(target)),
+            _ => Err(Error::invalid_argument().context("not a symlink")),
+        }
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.fs.state().filestat(self.fs.device_id, self.ino)
+    }
+    fn get_path_filestat(&self, path: &str, follow_symlinks: bool) -> Result<Filestat, Error> {
+        let state = self.fs.state();
+        let ino = state.lookup(self.ino, path, follow_symlinks)?.existing()?;
+        state.filestat(self.fs.device_id, ino)
+    }
+    fn rename(&self, path: &str, dest_dir: &dyn WasiDir, dest_path: &str) -> Result<(), Error> {
+        let dest_dir = match self.same_fs(dest_dir) {
+            Ok(dest_dir) => dest_dir,
+            Err(_) => return move_across(self, path, dest_dir, dest_path),
+        };
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let from = state.lookup(self.ino, path, false)?;
+        let ino = from.existing()?;
+        let (from_dir, from_name) = from.entry()?;
+        let to = state.lookup(dest_dir.ino, dest_path, false)?;
+        let (to_dir, to_name) = to.entry()?;
+        let moving_dir = matches!(state.node(ino)?.kind, Kind::Dir(_));
+        if let Some(replaced) = to.ino {
+            if replaced == ino {
+                return Ok(());
+            }
+            match (&state.node(replaced)?.kind, moving_dir) {
+                (Kind::Dir(dir), true) if !dir.entries.is_empty() => return Err(Error::not_empty()),
+                (Kind::Dir(_), true) => {}
+                (Kind::Dir(_), false) => return Err(Error::is_dir()),
+                (_, true) => return Err(Error::not_dir()),
+                (_, false) => {}
+            }
+        }
+        if moving_dir {
+            // A directory can't be moved into itself.
+            let mut dir = to_dir;
+            loop {
+                if dir == ino {
+                    return Err(Error::invalid_argument().context("directory moved into itself"));
+                }
+                let parent = state.dir(dir)?.parent;
+                if parent == dir {
+                    break;
+                }
+                dir = parent;
+            }
+        }
+        if to.ino.is_some() {
+            state.unlink(to_dir, to_name, now)?;
+        }
+        state.detach(from_dir, from_name, now)?;
+        state.link(to_dir, to_name, ino, now)?;
+        let node = state.node_mut(ino)?;
+        node.ctim = now;
+        if let Kind::Dir(dir) = &mut node.kind {
+            dir.parent = to_dir;
+        }
+        Ok(())
+    }
+    fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        let target_dir = match self.same_fs(target_dir) {
+            Ok(dir) => dir,
+            Err(_) => return link_across(self, path, target_dir, target_path),
+        };
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let ino = state.lookup(self.ino, path, false)?.existing()?;
+        if let Kind::Dir(_) = state.node(ino)?.kind {
+            return Err(Error::perm().context("hard link to a directory"));
+        }
+        let to = state.lookup(target_dir.ino, target_path, false)?;
+        if to.ino.is_some() {
+            return Err(Error::exist());
+        }
+        let (dir, name) = to.entry()?;
+        state.link(dir, name, ino, now)?;
+        let node = state.node_mut(ino)?;
+        node.nlink += 1;
+        node.ctim = now;
+        Ok(())
+    }
+    fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        let (atime, mtime, now) = (self.fs.time(atime), self.fs.time(mtime), self.fs.now());
+        let mut state = self.fs.state();
+        let ino = state.lookup(self.ino, path, follow_symlinks)?.existing()?;
+        state.set_times(ino, atime, mtime, now)
+    }
+}
+
+/// A regular file of a `MemFs`, opened through a `MemDir`.
+pub struct MemFile {
+    fs: Arc<Fs>,
+    ino: u64,
+    pos: u64,
+    read: bool,
+    write: bool,
+    fdflags: FdFlags,
+}
+
+impl MemFile {
+    fn read_at(&self, bufs: &mut [IoSliceMut<'_>], offset: u64) -> Result<u64, Error> {
+        if !self.read {
+            return Err(Error::badf().context("not opened for reading"));
+        }
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let data = state.file_mut(self.ino)?;
+        let mut pos = usize::try_from(offset)?.min(data.len());
+        let start = pos;
+        for buf in bufs {
+            let n = buf.len().min(data.len() - pos);
+            buf[..n].copy_from_slice(&data[pos..pos + n]);
+            pos += n;
+        }
+        state.node_mut(self.ino)?.atim = now;
+        Ok((pos - start) as u64)
+    }
+
+    /// Write `bufs` at `offset`, or at the end if `offset` is `None`. Return where the write
+    /// ended.
+    fn write_at(&self, bufs: &[IoSlice<'_>], offset: Option<u64>) -> Result<u64, Error> {
+        if !self.write {
+            return Err(Error::badf().context("not opened for writing"));
+        }
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        let size = state.file_mut(self.ino)?.len() as u64;
+        let offset = offset.unwrap_or(size);
+        let len = bufs.iter().map(|b| b.len() as u64).sum::<u64>();
+        let end = offset.checked_add(len).ok_or_else(Error::overflow)?;
+        if end > size {
+            state.resize_file(self.ino, end)?;
+        }
+        let data = state.file_mut(self.ino)?;
+        let mut pos = usize::try_from(offset)?;
+        for buf in bufs {
+            data[pos..pos + buf.len()].copy_from_slice(buf);
+            pos += buf.len();
+        }
+        let node = state.node_mut(self.ino)?;
+        node.mtim = now;
+        node.ctim = now;
+        Ok(pos as u64)
+    }
+
+    fn resize(&self, size: u64) -> Result<(), Error> {
+        if !self.write {
+            return Err(Error::badf().context("not opened for writing"));
+        }
+        let now = self.fs.now();
+        let mut state = self.fs.state();
+        state.resize_file(self.ino, size)?;
+        let node = state.node_mut(self.ino)?;
+        node.mtim = now;
+        node.ctim = now;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64, Error> {
+        Ok(self.fs.state().file_mut(self.ino)?.len() as u64)
+    }
+}
+
+impl Drop for MemFile {
+    fn drop(&mut self) {
+        let mut state = self.fs.state();
+        if let Some(node) = state.nodes.get_mut(&self.ino) {
+            node.open -= 1;
+            if node.nlink == 0 && node.open == 0 {
+                state.remove_node(self.ino);
+            }
+        }
+    }
+}
+
+impl WasiFile for MemFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::RegularFile)
+    }
+    fn datasync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(self.fdflags)
+    }
+    fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        if flags.intersects(FdFlags::DSYNC | FdFlags::SYNC | FdFlags::RSYNC) {
+            return Err(Error::not_supported().context("SYNC family of FdFlags"));
+        }
+        self.fdflags = flags;
+        Ok(())
+    }
+    fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        self.fs.state().filestat(self.fs.device_id, self.ino)
+    }
+    fn set_filestat_size(&mut self, size: u64) -> Result<(), Error> {
+        self.resize(size)
+    }
+    fn advise(&mut self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Ok(())
+    }
+    fn allocate(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        let end = offset.checked_add(len).ok_or_else(Error::overflow)?;
+        if end > self.len()? {
+            self.resize(end)?;
+        }
+        Ok(())
+    }
+    fn set_times(
+        &mut self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        let (atime, mtime, now) = (self.fs.time(atime), self.fs.time(mtime), self.fs.now());
+        self.fs.state().set_times(self.ino, atime, mtime, now)
+    }
+    fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let n = self.read_at(bufs, self.pos)?;
+        self.pos += n;
+        Ok(n)
+    }
+    fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        self.read_at(bufs, offset)
+    }
+    fn write_vectored<'a>(&mut self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        let offset = (!self.fdflags.contains(FdFlags::APPEND)).then_some(self.pos);
+        let end = self.write_at(bufs, offset)?;
+        self.pos = end;
+        Ok(bufs.iter().map(|b| b.len() as u64).sum())
+    }
+    fn write_vectored_at<'a>(&mut self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        self.write_at(bufs, Some(offset))?;
+        Ok(bufs.iter().map(|b| b.len() as u64).sum())
+    }
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (0, i128::from(offset)),
+            SeekFrom::Current(delta) => (self.pos, i128::from(delta)),
+            SeekFrom::End(delta) => (self.len()?, i128::from(delta)),
+        };
+        let pos = i128::from(base) + delta;
+        self.pos = u64::try_from(pos)
+            .map_err(|_| Error::invalid_argument().context("seek before the start"))?;
+        Ok(self.pos)
+    }
+    fn offset(&self) -> Option<u64> {
+        Some(self.pos)
+    }
+    fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.read_at(&mut [IoSliceMut::new(buf)], self.pos)
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(self.len()?.saturating_sub(self.pos))
+    }
+    fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::Errno;
+
+    /// The host's clock, for trees made in tests.
+    pub(crate) struct Clock;
+
+    impl WasiSystemClock for Clock {
+        fn resolution(&self) -> cap_std::time::Duration {
+            Duration::from_nanos(1)
+        }
+        fn now(&self, _precision: cap_std::time::Duration) -> cap_std::time::SystemTime {
+            cap_std::time::SystemTime::from_std(SystemTime::now())
+        }
+    }
+
+    pub(crate) fn errno<T>(result: Result<T, Error>) -> Option<Errno> {
+        Errno::from_error(&result.err().expect("expected an error"))
+    }
+
+    /// Write `contents` to the file at `path`, creating or truncating it.
+    pub(crate) fn write(dir: &dyn WasiDir, path: &str, contents: &[u8]) {
+        let oflags = OFlags::CREATE | OFlags::TRUNCATE;
+        let mut file = dir
+            .open_file(false, path, oflags, false, true, FdFlags::empty())
+            .unwrap();
+        file.write_vectored_at(&[IoSlice::new(contents)], 0)
+            .unwrap();
+    }
+
+    /// The contents of the file at `path`, following symlinks.
+    pub(crate) fn read(dir: &dyn WasiDir, path: &str) -> Vec<u8> {
+        let mut file = dir
+            .open_file(true, path, OFlags::empty(), true, false, FdFlags::empty())
+            .unwrap();
+        let mut contents = Vec::new();
+        let mut buf = [0; 64];
+        loop {
+            let n = file
+                .read_vectored(&mut [IoSliceMut::new(&mut buf)])
+                .unwrap();
+            if n == 0 {
+                return contents;
+            }
+            contents.extend_from_slice(&buf[..n as usize]);
+        }
+    }
+
+    /// The entries of `dir` from `cursor` on, each with the cursor that follows it.
+    pub(crate) fn names(dir: &dyn WasiDir, cursor: u64) -> Vec<(String, u64)> {
+        dir.readdir(ReaddirCursor::from(cursor))
+            .unwrap()
+            .map(|entity| {
+                let entity = entity.unwrap();
+                (entity.name, u64::from(entity.next))
+            })
+            .collect()
+    }
+
+    /// The names in `dir`, `.` and `..` included, in order.
+    pub(crate) fn sorted_names(dir: &dyn WasiDir) -> Vec<String> {
+        let mut names = names(dir, 0)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn create(dir: &MemDir, path: &str) -> Box<dyn WasiFile> {
+        dir.open_file(false, path, OFlags::CREATE, true, true, FdFlags::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn lookup_stays_inside_the_dir() {
+        let fs = MemFs::new(Box::new(Clock));
+        let root = fs.root();
+        root.create_dir("a").unwrap();
+        root.create_dir("a/b").unwrap();
+        let a = root.open_dir(false, "a").unwrap();
+        assert!(a.open_dir(false, "b/..").is_ok());
+        assert_eq!(errno(a.open_dir(false, "..")), Some(Errno::Perm));
+        assert_eq!(errno(a.open_dir(false, "b/../..")), Some(Errno::Perm));
+        assert_eq!(errno(a.open_dir(false, "/a")), Some(Errno::Perm));
+        a.symlink("../..", "up").unwrap();
+        assert_eq!(errno(a.open_dir(true, "up")), Some(Errno::Perm));
+        assert_eq!(errno(root.open_dir(false, "..")), Some(Errno::Perm));
+    }
+
+    #[test]
+    fn rename_into_self_fails() {
+        let fs = MemFs::new(Box::new(Clock));
+        let root = fs.root();
+        root.create_dir("a").unwrap();
+        root.create_dir("a/b").unwrap();
+        assert_eq!(errno(root.rename("a", &root, "a/b/c")), Some(Errno::Inval));
+        assert_eq!(errno(root.rename("a", &root, "a/c")), Some(Errno::Inval));
+        root.rename("a/b", &root, "c").unwrap();
+        assert!(root.open_dir(false, "c").is_ok());
+    }
+
+    #[test]
+    fn readdir_cursors_survive_changes() {
+        let fs = MemFs::new(Box::new(Clock));
+        let root = fs.root();
+        for name in ["a", "b", "c"] {
+            create(&root, name);
+        }
+        let first = names(&root, 0);
+        let names_of =
+            |entries: &[(String, u64)]| entries.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+        assert_eq!(names_of(&first), [".", "..", "a", "b", "c"]);
+        // Resume after "a" while "a" is removed and "d" is added.
+        let after_a = first[2].1;
+        root.unlink_file("a").unwrap();
+        create(&root, "d");
+        assert_eq!(names_of(&names(&root, after_a)), ["b", "c", "d"]);
+        // A new entry with an old name doesn't show up again before the cursor.
+        create(&root, "a");
+        let after_c = names(&root, 0)
+            .into_iter()
+            .find(|(name, _)| name == "c")
+            .unwrap()
+            .1;
+        assert_eq!(names_of(&names(&root, after_c)), ["d", "a"]);
+    }
+
+    #[test]
+    fn unlinked_files_stay_usable_while_open() {
+        let fs = MemFs::new(Box::new(Clock));
+        let root = fs.root();
+        let mut file = create(&root, "f");
+        file.write_vectored_at(&[IoSlice::new(b"hello")], 0)
+            .unwrap();
+        root.unlink_file("f").unwrap();
+        assert_eq!(
+            errno(root.get_path_filestat("f", false)),
+            Some(Errno::Noent)
+        );
+        assert_eq!(file.get_filestat().unwrap().nlink, 0);
+        file.write_vectored_at(&[IoSlice::new(b"!")], 5).unwrap();
+        let mut buf = [0; 6];
+        file.read_vectored_at(&mut [IoSliceMut::new(&mut buf)], 0)
+            .unwrap();
+        assert_eq!(&buf, b"hello!");
+        assert_eq!(fs.bytes_used(), 6);
+        drop(file);
+        assert_eq!(fs.bytes_used(), 0);
+        assert_eq!(fs.0.state().nodes.len(), 1);
+    }
+
+    #[test]
+    fn hard_links_count_nlink() {
+        let fs = MemFs::new(Box::new(Clock));
+        let root = fs.root();
+        drop(create(&root, "a"));
+        root.hard_link("a", &root, "b").unwrap();
+        assert_eq!(root.get_path_filestat("a", false).unwrap().nlink, 2);
+        let a = root.get_path_filestat("a", false).unwrap().inode;
+        assert_eq!(root.get_path_filestat("b", false).unwrap().inode, a);
+        root.unlink_file("a").unwrap();
+        assert_eq!(root.get_path_filestat("b", false).unwrap().nlink, 1);
+        root.unlink_file("b").unwrap();
+        assert_eq!(fs.0.state().nodes.len(), 1);
+    }
+
+    #[test]
+    fn contents_are_held_to_the_limit() {
+        let fs = MemFs::new(Box::new(Clock)).with_max_bytes(16);
+        let root = fs.root();
+        let mut file = create(&root, "f");
+        let far = file.write_vectored_at(&[IoSlice::new(b"x")], 1 << 40);
+        assert_eq!(errno(far), Some(Errno::Fbig));
+        assert_eq!(errno(file.set_filestat_size(u64::MAX)), Some(Errno::Fbig));
+        file.set_filestat_size(10).unwrap();
+        let mut other = create(&root, "g");
+        let full = other.write_vectored_at(&[IoSlice::new(&[0; 8])], 0);
+        assert_eq!(errno(full), Some(Errno::Nospc));
+        assert_eq!(errno(root.symlink("0123456789", "l")), Some(Errno::Nospc));
+        other
+            .write_vectored_at(&[IoSlice::new(&[0; 6])], 0)
+            .unwrap();
+        assert_eq!(fs.bytes_used(), 16);
+        drop(file);
+        root.open_file(false, "f", OFlags::TRUNCATE, false, true, FdFlags::empty())
+            .unwrap();
+        assert_eq!(fs.bytes_used(), 6);
+    }
+}
@@ -0,0 +1,250 @@
+//! Per-syscall and per-descriptor I/O metrics.
+//!
+//! Every environ keeps a `Metrics` handle that its syscalls update as they run. Clone the
+//! handle before starting the guest to take snapshots from another thread while it runs.
+//!
+//! With the `metrics` feature, every update is also forwarded to the `metrics` crate facade,
+//! as the counters `wasi_syscalls_total{syscall}`, `wasi_syscall_errors_total{syscall, errno}`
+//! and `wasi_bytes_total{direction, preopen}`, and the histogram
+//! `wasi_syscall_duration_seconds{syscall}`. `Metrics::set_fd_label` adds an `fd` label to
+//! `wasi_bytes_total`, which gives exporters a series for every descriptor number the guest
+//! uses.
+use crate::error::Errno;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The number of latency buckets. Bucket `i` counts calls that took less than `2^i`
+/// microseconds; the last bucket also counts everything slower.
+pub const LATENCY_BUCKETS: usize = 24;
+
+/// A histogram of syscall latencies, in power-of-two microsecond buckets.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    pub buckets: [u64; LATENCY_BUCKETS],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    /// The exclusive upper bound of bucket `i`, or `None` for the last bucket.
+    pub fn bucket_bound(i: usize) -> Option<Duration> {
+        if i + 1 < LATENCY_BUCKETS {
+            Some(Duration::from_micros(1 << i))
+        } else {
+            None
+        }
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as u32)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyscallMetrics {
+    pub calls: u64,
+    /// Calls that returned an errno to the guest.
+    pub errors: HashMap<Errno, u64>,
+    /// Calls that failed with an error that traps.
+    pub traps: u64,
+    pub latency: Histogram,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct IoMetrics {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// A copy of the metrics at one point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub syscalls: HashMap<&'static str, SyscallMetrics>,
+    /// I/O on each open descriptor, since it was opened.
+    pub fds: HashMap<u32, IoMetrics>,
+    /// I/O on files opened under each preopened directory, keyed by its guest path.
+    pub preopens: HashMap<PathBuf, IoMetrics>,
+}
+
+/// How a syscall ended.
+pub(crate) enum Outcome {
+    Ok,
+    Errno(Errno),
+    Trap,
+}
+
+#[derive(Default)]
+struct MetricsInner {
+    snapshot: MetricsSnapshot,
+    /// The preopen each descriptor was opened under.
+    preopen_of: HashMap<u32, PathBuf>,
+    fd_label: bool,
+}
+
+/// A shared handle to an environ's metrics.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<MetricsInner>>);
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.0.lock().unwrap().snapshot.clone()
+    }
+
+    /// Whether the `metrics` crate counters carry the descriptor as a label. Off by default.
+    pub fn set_fd_label(&self, enabled: bool) {
+        self.0.lock().unwrap().fd_label = enabled;
+    }
+
+    /// Clear every counter, keeping track of which descriptors belong to which preopen.
+    pub fn reset(&self) {
+        self.0.lock().unwrap().snapshot = MetricsSnapshot::default();
+    }
+
+    /// Attribute I/O on `fd` to the preopen at `path`.
+    pub(crate) fn preopen(&self, fd: u32, path: &Path) {
+        self.0
+            .lock()
+            .unwrap()
+            .preopen_of
+            .insert(fd, path.to_owned());
+    }
+
+    /// Attribute I/O on `fd`, just opened through the directory `dirfd`, to the same preopen.
+    pub(crate) fn inherit(&self, fd: u32, dirfd: u32) {
+        let mut inner = self.0.lock().unwrap();
+        match inner.preopen_of.get(&dirfd).cloned() {
+            Some(path) => inner.preopen_of.insert(fd, path),
+            None => inner.preopen_of.remove(&fd),
+        };
+    }
+
+    /// Forget `fd` once it is closed: its I/O counters, and the preopen it was attributed to,
+    /// so that a descriptor opened later under the same number starts from nothing.
+    pub(crate) fn close(&self, fd: u32) {
+        let mut inner = self.0.lock().unwrap();
+        inner.preopen_of.remove(&fd);
+        inner.snapshot.fds.remove(&fd);
+    }
+
+    pub(crate) fn record_syscall(&self, name: &'static str, latency: Duration, outcome: Outcome) {
+        let mut inner = self.0.lock().unwrap();
+        let syscall = inner.snapshot.syscalls.entry(name).or_default();
+        syscall.calls += 1;
+        syscall.latency.observe(latency);
+        match outcome {
+            Outcome::Ok => {}
+            Outcome::Errno(errno) => *syscall.errors.entry(errno).or_default() += 1,
+            Outcome::Trap => syscall.traps += 1,
+        }
+        drop(inner);
+
+        #[cfg(feature = "metrics")]
+        {
+            ::metrics::counter!("wasi_syscalls_total", "syscall" => name).increment(1);
+            ::metrics::histogram!("wasi_syscall_duration_seconds", "syscall" => name)
+                .record(latency.as_secs_f64());
+            match outcome {
+                Outcome::Ok => {}
+                Outcome::Errno(errno) => ::metrics::counter!(
+                    "wasi_syscall_errors_total",
+                    "syscall" => name,
+                    "errno" => errno.to_string(),
+                )
+                .increment(1),
+                Outcome::Trap => ::metrics::counter!(
+                    "wasi_syscall_errors_total",
+                    "syscall" => name,
+                    "errno" => "trap",
+                )
+                .increment(1),
+            }
+        }
+    }
+
+    pub(crate) fn record_read(&self, fd: u32, bytes: u64) {
+        self.record_io(fd, bytes, "read", |io| &mut io.bytes_read);
+    }
+
+    pub(crate) fn record_write(&self, fd: u32, bytes: u64) {
+        self.record_io(fd, bytes, "write", |io| &mut io.bytes_written);
+    }
+
+    #[allow(unused_variables)]
+    fn record_io(
+        &self,
+        fd: u32,
+        bytes: u64,
+        direction: &'static str,
+        counter: impl Fn(&mut IoMetrics) -> &mut u64,
+    ) {
+        let mut inner = self.0.lock().unwrap();
+        let inner = &mut *inner;
+        *counter(inner.snapshot.fds.entry(fd).or_default()) += bytes;
+        let preopen = inner.preopen_of.get(&fd);
+        if let Some(path) = preopen {
+            *counter(inner.snapshot.preopens.entry(path.clone()).or_default()) += bytes;
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            let mut labels = vec![
+                ("direction", direction.to_owned()),
+                (
+                    "preopen",
+                    preopen.map(|p| p.display().to_string()).unwrap_or_default(),
+                ),
+            ];
+            if inner.fd_label {
+                labels.push(("fd", fd.to_string()));
+            }
+            ::metrics::counter!("wasi_bytes_total", &labels).increment(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_reused_fd_starts_from_nothing() {
+        let metrics = Metrics::new();
+        metrics.preopen(3, Path::new("/data"));
+        metrics.inherit(5, 3);
+        metrics.record_write(5, 10);
+        metrics.close(5);
+        assert_eq!(metrics.snapshot().fds.get(&5), None);
+
+        metrics.record_read(5, 3);
+        let snapshot = metrics.snapshot();
+        let io = IoMetrics {
+            bytes_read: 3,
+            bytes_written: 0,
+        };
+        assert_eq!(snapshot.fds[&5], io);
+        // The descriptor is no longer under the preopen, which keeps what was written before.
+        let data = IoMetrics {
+            bytes_read: 0,
+            bytes_written: 10,
+        };
+        assert_eq!(snapshot.preopens[Path::new("/data")], data);
+    }
+}
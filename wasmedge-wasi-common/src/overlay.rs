@@ -0,0 +1,1154 @@
+//! Overlay filesystems.
+//!
+//! An `OverlayDir` shows a read-only lower directory with a writable upper directory on top, so
+//! that many guests can share one lower tree while each keeps its own changes. The lower layer is
+//! never written to: a file is copied up to the upper layer the first time it is modified, or
+//! replaced there by an empty one if it is opened with `TRUNCATE`, and deletions are recorded in
+//! the upper layer as whiteouts. Either layer can be any `WasiDir`.
+//!
+//! Whiteouts use the aufs format, so an upper layer can be kept and mounted again later: an empty
+//! file `.wh.<name>` hides the lower layer's `<name>`, and an empty file `.wh..wh..opq` in a
+//! directory hides all of the lower layer's entries in it. Names starting with `.wh.` are
+//! reserved, and the guest can neither see nor use them.
+//!
+//! Symlinks are resolved in the merged view, so a symlink in one layer can point into the other.
+//! Renaming a directory that has a counterpart in the lower layer copies its whole subtree up
+//! under the new name and whites out the old one, as `mv` does when Linux overlayfs refuses such
+//! a rename with `Xdev`.
+//!
+//! Renaming out of the overlay, into a directory of another backend or another overlay, copies
+//! the entry across with `dir::move_across`, and hard linking out of it copies the file with
+//! `dir::link_across`.
+use crate::clocks::SystemTimeSpec;
+use crate::dir::{
+    copy_times, link_across, link_target, move_across, put_in_place, temp_path, CopyPolicy,
+    ReaddirCursor, ReaddirEntity, WasiDir,
+};
+use crate::error::{Errno, Error, ErrorExt};
+use crate::file::{copy_contents, Advice, FdFlags, FileType, Filestat, OFlags, Timeouts, WasiFile};
+use std::any::Any;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::{IoSlice, IoSliceMut, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The prefix of whiteout files.
+pub(crate) const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// The marker that hides all of the lower layer's entries in a directory.
+pub(crate) const OPAQUE: &str = ".wh..wh..opq";
+
+/// How many symlinks a path may go through, as on Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+struct Layers {
+    lower: Arc<dyn WasiDir>,
+    upper: Box<dyn WasiDir>,
+}
+
+/// A directory of an overlay. See the module documentation.
+pub struct OverlayDir {
+    layers: Arc<Layers>,
+    /// Where the directory is, relative to the root of both layers.
+    path: Vec<String>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Layer {
+    Upper,
+    Lower,
+}
+
+/// A name in the merged view, and the layer it comes from.
+struct Entry {
+    layer: Layer,
+    stat: Filestat,
+    /// For a directory, whether the lower layer's entries show through in it.
+    merged: bool,
+}
+
+/// A directory in the merged view.
+#[derive(Clone)]
+struct Dir {
+    path: Vec<String>,
+    merged: bool,
+}
+
+/// Where a path leads.
+struct Lookup {
+    /// The directory holding the last component.
+    parent: Dir,
+    /// The last component, or `None` if the path names `parent` itself.
+    name: Option<String>,
+    entry: Option<Entry>,
+}
+
+impl Lookup {
+    fn existing(&self) -> Result<&Entry, Error> {
+        self.entry.as_ref().ok_or_else(Error::not_found)
+    }
+
+    /// The last component, to add or remove an entry there.
+    fn name(&self) -> Result<&str, Error> {
+        self.name
+            .as_deref()
+            .ok_or_else(|| Error::invalid_argument().context("path ends in `.` or `..`"))
+    }
+
+    fn components(&self) -> Vec<String> {
+        let mut components = self.parent.path.clone();
+        components.extend(self.name.clone());
+        components
+    }
+
+    fn path(&self) -> String {
+        self.components().join("/")
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(&self.entry, Some(entry) if entry.stat.filetype == FileType::Directory)
+    }
+
+    /// The directory the path leads to.
+    fn dir(&self) -> Result<Dir, Error> {
+        let entry = self.existing()?;
+        if entry.stat.filetype != FileType::Directory {
+            return Err(Error::not_dir());
+        }
+        Ok(Dir {
+            path: self.components(),
+            merged: entry.merged,
+        })
+    }
+}
+
+fn join(dir: &[String], name: &str) -> String {
+    let mut path = dir.join("/");
+    if !path.is_empty() {
+        path.push('/');
+    }
+    path.push_str(name);
+    path
+}
+
+fn whiteout(name: &str) -> String {
+    format!("{}{}", WHITEOUT_PREFIX, name)
+}
+
+/// The components of `path`, without the empty ones between repeated slashes.
+fn split(path: &str) -> VecDeque<String> {
+    path.split('/')
+        .filter(|c| !c.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The bit set in the cursors of entries that come from the lower layer.
+const LOWER_CURSOR: u64 = 1 << 63;
+
+/// `entity` from a layer, with `tag` added to its cursor.
+fn layer_entity(entity: ReaddirEntity, tag: u64) -> Result<ReaddirEntity, Error> {
+    let next = u64::from(entity.next);
+    if next & LOWER_CURSOR != 0 {
+        return Err(Error::overflow().context("layer's readdir cursor"));
+    }
+    Ok(ReaddirEntity {
+        next: ReaddirCursor::from(next | tag),
+        ..entity
+    })
+}
+
+/// The directory at `path` in `layer`, or the layer itself for the empty path.
+fn open_layer_dir(layer: &dyn WasiDir, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+    if path.is_empty() {
+        layer.open_dir(false, ".")
+    } else {
+        layer.open_dir(false, path)
+    }
+}
+
+fn is_not_found(e: &Error) -> bool {
+    Errno::from_error(e) == Some(Errno::Noent)
+}
+
+/// Stat `path` in `layer`, without following a symlink at the end. The empty path is the layer's
+/// root.
+pub(crate) fn stat(layer: &dyn WasiDir, path: &str) -> Result<Option<Filestat>, Error> {
+    let stat = if path.is_empty() {
+        layer.get_filestat()
+    } else {
+        layer.get_path_filestat(path, false)
+    };
+    match stat {
+        Ok(stat) => Ok(Some(stat)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn exists(layer: &dyn WasiDir, path: &str) -> Result<bool, Error> {
+    Ok(stat(layer, path)?.is_some())
+}
+
+/// The type of `path` in `layer`, or `None` if it doesn't exist.
+fn stat_of(layer: &dyn WasiDir, path: &str) -> Result<Option<FileType>, Error> {
+    Ok(stat(layer, path)?.map(|stat| stat.filetype))
+}
+
+/// The entries of the directory at `path` in `layer`, or `None` if the layer has no such
+/// directory.
+fn read_layer(layer: &dyn WasiDir, path: &str) -> Result<Option<Vec<ReaddirEntity>>, Error> {
+    let entries = if path.is_empty() {
+        layer.readdir(ReaddirCursor::from(0))?
+    } else {
+        match layer.open_dir(false, path) {
+            Ok(dir) => dir.readdir(ReaddirCursor::from(0))?,
+            Err(e) if is_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    };
+    entries.collect::<Result<Vec<_>, _>>().map(Some)
+}
+
+impl OverlayDir {
+    /// Show `upper` on top of `lower`. `lower` is only ever read from, so the same one can back
+    /// any number of overlays.
+    pub fn new(lower: Arc<dyn WasiDir>, upper: Box<dyn WasiDir>) -> Self {
+        OverlayDir {
+            layers: Arc::new(Layers { lower, upper }),
+            path: Vec::new(),
+        }
+    }
+
+    fn layer(&self, layer: Layer) -> &dyn WasiDir {
+        match layer {
+            Layer::Upper => self.upper(),
+            Layer::Lower => self.lower(),
+        }
+    }
+
+    fn upper(&self) -> &dyn WasiDir {
+        &*self.layers.upper
+    }
+
+    fn lower(&self) -> &dyn WasiDir {
+        &*self.layers.lower
+    }
+
+    /// The directory behind `dir`, if it belongs to the same overlay.
+    fn same_overlay<'a>(&self, dir: &'a dyn WasiDir) -> Result<&'a OverlayDir, Error> {
+        match dir.as_any().downcast_ref::<OverlayDir>() {
+            Some(dir) if Arc::ptr_eq(&dir.layers, &self.layers) => Ok(dir),
+            _ => Err(Error::cross_device().context("destination is not in the same overlay")),
+        }
+    }
+
+    /// Look `name` up in the directory `dir`.
+    fn step(&self, dir: &Dir, name: &str) -> Result<Option<Entry>, Error> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(Error::perm().context("name is reserved for whiteouts"));
+        }
+        let path = join(&dir.path, name);
+        if let Some(stat) = stat(self.upper(), &path)? {
+            let merged = stat.filetype == FileType::Directory
+                && dir.merged
+                && !exists(self.upper(), &format!("{}/{}", path, OPAQUE))?
+                && matches!(stat_of(self.lower(), &path)?, Some(FileType::Directory));
+            return Ok(Some(Entry {
+                layer: Layer::Upper,
+                stat,
+                merged,
+            }));
+        }
+        if !dir.merged || exists(self.upper(), &join(&dir.path, &whiteout(name)))? {
+            return Ok(None);
+        }
+        Ok(stat(self.lower(), &path)?.map(|stat| Entry {
+            layer: Layer::Lower,
+            stat,
+            merged: true,
+        }))
+    }
+
+    /// This directory, found again from the root, since the tree may have changed since it was
+    /// opened.
+    fn base(&self) -> Result<Dir, Error> {
+        let mut dir = Dir {
+            path: Vec::new(),
+            merged: !exists(self.upper(), OPAQUE)?,
+        };
+        for name in &self.path {
+            let lookup = Lookup {
+                entry: self.step(&dir, name)?,
+                parent: dir,
+                name: Some(name.clone()),
+            };
+            dir = lookup
+                .dir()
+                .map_err(|_| Error::not_found().context("directory was removed"))?;
+        }
+        Ok(dir)
+    }
+
+    /// The lookup of the directory `dir` itself.
+    fn lookup_dir(&self, dir: Dir) -> Result<Lookup, Error> {
+        let path = dir.path.join("/");
+        let (layer, stat) = match stat(self.upper(), &path)? {
+            Some(stat) => (Layer::Upper, stat),
+            None => (
+                Layer::Lower,
+                stat(self.lower(), &path)?.ok_or_else(Error::not_found)?,
+            ),
+        };
+        Ok(Lookup {
+            entry: Some(Entry {
+                layer,
+                stat,
+                merged: dir.merged,
+            }),
+            parent: dir,
+            name: None,
+        })
+    }
+
+    /// Follow `path` from this directory in the merged view. Symlinks along the way are
+    /// followed, and so is one in the last component if `follow` is set or the path ends in `/`.
+    fn lookup(&self, path: &str, follow: bool) -> Result<Lookup, Error> {
+        if path.is_empty() {
+            return Err(Error::not_found().context("empty path"));
+        }
+        if path.starts_with('/') {
+            return Err(Error::perm().context("absolute path"));
+        }
+        let trailing_slash = path.ends_with('/');
+        let mut components = split(path);
+        // The directories walked through, so that `..` can go back up, but never above this one.
+        let mut stack = vec![self.base()?];
+        let mut hops = 0;
+        loop {
+            let component = match components.pop_front() {
+                Some(component) => component,
+                None => return self.lookup_dir(stack.pop().unwrap()),
+            };
+            let last = components.is_empty();
+            match component.as_str() {
+                "." if last => return self.lookup_dir(stack.pop().unwrap()),
+                "." => continue,
+                ".." if stack.len() == 1 => {
+                    return Err(Error::perm().context("path leads out of the directory"))
+                }
+                ".." => {
+                    stack.pop();
+                    if last {
+                        return self.lookup_dir(stack.pop().unwrap());
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let dir = stack.last().unwrap().clone();
+            let entry = match self.step(&dir, &component)? {
+                Some(entry) => entry,
+                None if last => {
+                    return Ok(Lookup {
+                        parent: dir,
+                        name: Some(component),
+                        entry: None,
+                    })
+                }
+                None => return Err(Error::not_found()),
+            };
+            match entry.stat.filetype {
+                FileType::SymbolicLink if !last || follow || trailing_slash => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(Error::symlink_loop());
+                    }
+                    let target = self
+                        .layer(entry.layer)
+                        .read_link(&join(&dir.path, &component))?;
+                    let target = target
+                        .to_str()
+                        .ok_or_else(|| Error::illegal_byte_sequence().context("symlink target"))?;
+                    if target.starts_with('/') {
+                        return Err(Error::perm().context("symlink to an absolute path"));
+                    }
+                    let mut target = split(target);
+                    if target.is_empty() {
+                        return Err(Error::not_found().context("empty symlink"));
+                    }
+                    target.extend(components);
+                    components = target;
+                }
+                FileType::Directory if !last => {
+                    let mut path = dir.path;
+                    path.push(component);
+                    stack.push(Dir {
+                        path,
+                        merged: entry.merged,
+                    });
+                }
+                _ if !last => return Err(Error::not_dir()),
+                filetype if trailing_slash && filetype != FileType::Directory => {
+                    return Err(Error::not_dir())
+                }
+                _ => {
+                    return Ok(Lookup {
+                        parent: dir,
+                        name: Some(component),
+                        entry: Some(entry),
+                    })
+                }
+            }
+        }
+    }
+
+    /// The entries of `dir` in the merged view, by name, without `.` and `..`.
+    fn list(&self, dir: &Dir) -> Result<Vec<ReaddirEntity>, Error> {
+        let path = dir.path.join("/");
+        let mut entries = BTreeMap::new();
+        let mut hidden = HashSet::new();
+        let dot = |entity: &ReaddirEntity| entity.name == "." || entity.name == "..";
+        for entity in read_layer(self.upper(), &path)?.unwrap_or_default() {
+            if dot(&entity) {
+                continue;
+            } else if let Some(name) = entity.name.strip_prefix(WHITEOUT_PREFIX) {
+                hidden.insert(name.to_owned());
+            } else {
+                entries.insert(entity.name.clone(), entity);
+            }
+        }
+        if dir.merged {
+            for entity in read_layer(self.lower(), &path)?.unwrap_or_default() {
+                if !dot(&entity)
+                    && !entity.name.starts_with(WHITEOUT_PREFIX)
+                    && !hidden.contains(&entity.name)
+                    && !entries.contains_key(&entity.name)
+                {
+                    entries.insert(entity.name.clone(), entity);
+                }
+            }
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    /// Make sure the upper layer has the directory `dir` of the merged view, and every
+    /// directory above it, copying them up from the lower layer as needed.
+    fn copy_up_dir(&self, dir: &[String]) -> Result<(), Error> {
+        for i in 1..=dir.len() {
+            let path = dir[..i].join("/");
+            if exists(self.upper(), &path)? {
+                continue;
+            }
+            let stat = stat(self.lower(), &path)?.ok_or_else(Error::not_found)?;
+            self.upper().create_dir(&path)?;
+            copy_times(self.upper(), &path, &stat)?;
+        }
+        Ok(())
+    }
+
+    /// Copy what `lookup` leads to up to the upper layer, if it is only in the lower one.
+    fn copy_up(&self, lookup: &Lookup) -> Result<(), Error> {
+        let entry = lookup.existing()?;
+        if entry.layer == Layer::Upper {
+            return Ok(());
+        }
+        if entry.stat.filetype == FileType::Directory {
+            return self.copy_up_dir(&lookup.components());
+        }
+        self.copy_up_dir(&lookup.parent.path)?;
+        let path = lookup.path();
+        let copied = match entry.stat.filetype {
+            FileType::SymbolicLink => {
+                let target = self.lower().read_link(&path)?;
+                self.upper().symlink(link_target(&target)?, &path)
+            }
+            FileType::RegularFile => self.copy_up_file(&path, &entry.stat),
+            _ => return Err(Error::not_supported().context("copying up a special file")),
+        };
+        match copied {
+            // Another thread copied it up first.
+            Err(e) if Errno::from_error(&e) == Some(Errno::Exist) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Copy the lower file at `path`, whose metadata is `stat`, up to the upper layer. The copy
+    /// is made under a temporary name and only then put in place, so that a copy that fails
+    /// never hides the lower file behind a partial one. Putting it in place fails with `Exist`
+    /// rather than replace a copy another thread put there first.
+    fn copy_up_file(&self, path: &str, stat: &Filestat) -> Result<(), Error> {
+        let temp = temp_path(path);
+        let copied = (|| {
+            let none = FdFlags::empty();
+            let mut src =
+                self.lower()
+                    .open_file(false, path, OFlags::empty(), true, false, none)?;
+            let exclusive = OFlags::CREATE | OFlags::EXCLUSIVE;
+            let mut dst = self
+                .upper()
+                .open_file(false, &temp, exclusive, false, true, none)?;
+            copy_contents(&mut *src, &mut *dst)?;
+            drop(dst);
+            copy_times(self.upper(), &temp, stat)?;
+            put_in_place(self.upper(), &temp, path)
+        })();
+        let _ = self.upper().unlink_file(&temp);
+        copied
+    }
+
+    /// Whether the lower layer has an entry where `lookup` leads, hidden or not.
+    fn in_lower(&self, lookup: &Lookup) -> Result<bool, Error> {
+        match &lookup.entry {
+            Some(entry) if entry.layer == Layer::Lower => Ok(true),
+            _ => Ok(lookup.parent.merged && exists(self.lower(), &lookup.path())?),
+        }
+    }
+
+    /// Get the upper layer ready for a new entry where `lookup` leads.
+    fn prepare_create(&self, lookup: &Lookup) -> Result<String, Error> {
+        let name = lookup.name()?;
+        self.copy_up_dir(&lookup.parent.path)?;
+        self.remove_marker(&join(&lookup.parent.path, &whiteout(name)))?;
+        Ok(lookup.path())
+    }
+
+    fn add_marker(&self, path: &str) -> Result<(), Error> {
+        let none = FdFlags::empty();
+        self.upper()
+            .open_file(false, path, OFlags::CREATE, false, true, none)?;
+        Ok(())
+    }
+
+    fn remove_marker(&self, path: &str) -> Result<(), Error> {
+        match self.upper().unlink_file(path) {
+            Err(e) if !is_not_found(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Hide the lower layer's entry where `lookup` leads.
+    fn add_whiteout(&self, lookup: &Lookup) -> Result<(), Error> {
+        self.copy_up_dir(&lookup.parent.path)?;
+        self.add_marker(&join(&lookup.parent.path, &whiteout(lookup.name()?)))
+    }
+
+    /// Remove the markers from the upper layer's directory `path`, which is empty in the
+    /// merged view, so that the directory itself can be removed.
+    fn clear_markers(&self, path: &str) -> Result<(), Error> {
+        for entity in read_layer(self.upper(), path)?.unwrap_or_default() {
+            if entity.name.starts_with(WHITEOUT_PREFIX) {
+                self.upper()
+                    .unlink_file(&format!("{}/{}", path, entity.name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self, lookup: &Lookup) -> Result<bool, Error> {
+        Ok(self.list(&lookup.dir()?)?.is_empty())
+    }
+}
+
+impl WasiDir for OverlayDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let lookup = self.lookup(path, symlink_follow)?;
+        let path = lookup.path();
+        match &lookup.entry {
+            Some(_) if oflags.contains(OFlags::CREATE | OFlags::EXCLUSIVE) => Err(Error::exist()),
+            Some(entry) if entry.layer == Layer::Lower => {
+                if entry.stat.filetype == FileType::Directory {
+                    return Err(Error::is_dir());
+                }
+                if oflags.contains(OFlags::TRUNCATE) && entry.stat.filetype == FileType::RegularFile
+                {
+                    // Nothing of the lower file would be kept, so don't copy it up.
+                    self.copy_up_dir(&lookup.parent.path)?;
+                    let create = OFlags::CREATE | OFlags::EXCLUSIVE;
+                    match self.upper().open_file(
+                        false,
+                        &path,
+                        create,
+                        false,
+                        true,
+                        FdFlags::empty(),
+                    ) {
+                        // Another thread put it in the upper layer first.
+                        Err(e) if Errno::from_error(&e) == Some(Errno::Exist) => {}
+                        result => drop(result?),
+                    }
+                    return self
+                        .upper()
+                        .open_file(false, &path, oflags, read, write, fdflags);
+                }
+                if write || oflags.contains(OFlags::TRUNCATE) {
+                    self.copy_up(&lookup)?;
+                    return self
+                        .upper()
+                        .open_file(false, &path, oflags, read, write, fdflags);
+                }
+                let inner = self
+                    .lower()
+                    .open_file(false, &path, oflags, read, false, fdflags)?;
+                Ok(Box::new(LowerFile {
+                    inner,
+                    root: OverlayDir {
+                        layers: self.layers.clone(),
+                        path: Vec::new(),
+                    },
+                    path,
+                }))
+            }
+            Some(_) => self
+                .upper()
+                .open_file(false, &path, oflags, read, write, fdflags),
+            None if oflags.contains(OFlags::CREATE) => {
+                let path = self.prepare_create(&lookup)?;
+                self.upper()
+                    .open_file(false, &path, oflags, read, write, fdflags)
+            }
+            None => Err(Error::not_found()),
+        }
+    }
+    fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let lookup = self.lookup(path, symlink_follow)?;
+        Ok(Box::new(OverlayDir {
+            layers: self.layers.clone(),
+            path: lookup.dir()?.path,
+        }))
+    }
+    fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let lookup = self.lookup(path, false)?;
+        if lookup.entry.is_some() {
+            return Err(Error::exist());
+        }
+        let in_lower = self.in_lower(&lookup)?;
+        let path = self.prepare_create(&lookup)?;
+        self.upper().create_dir(&path)?;
+        if in_lower {
+            // The lower layer's entry was deleted, so nothing of it may show through.
+            self.add_marker(&format!("{}/{}", path, OPAQUE))?;
+        }
+        Ok(())
+    }
+    fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        // The upper layer's entries come first, then the lower layer's that show through, each
+        // with the cursor of its own layer, so that a cursor stays valid as long as the layers'
+        // own cursors do. The top bit of a cursor tells which layer it belongs to.
+        let dir = self.base()?;
+        let path = dir.path.join("/");
+        let cursor = u64::from(cursor);
+        let upper = read_layer(self.upper(), &path)?;
+        let mut entities = Vec::new();
+        let in_upper = upper.is_some();
+        let mut shadowed = HashSet::new();
+        for entity in upper.iter().flatten() {
+            match entity.name.strip_prefix(WHITEOUT_PREFIX) {
+                Some(name) => shadowed.insert(name.to_owned()),
+                None => shadowed.insert(entity.name.clone()),
+            };
+        }
+        if cursor & LOWER_CURSOR == 0 {
+            let upper_entities = match upper {
+                Some(entities) if cursor == 0 => entities,
+                Some(_) => open_layer_dir(self.upper(), &path)?
+                    .readdir(ReaddirCursor::from(cursor))?
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => Vec::new(),
+            };
+            for entity in upper_entities {
+                if !entity.name.starts_with(WHITEOUT_PREFIX) {
+                    entities.push(layer_entity(entity, 0)?);
+                }
+            }
+        }
+        if dir.merged {
+            let cursor = if cursor & LOWER_CURSOR == 0 {
+                0
+            } else {
+                cursor & !LOWER_CURSOR
+            };
+            let lower = open_layer_dir(self.lower(), &path)?;
+            for entity in lower.readdir(ReaddirCursor::from(cursor))? {
+                let entity = entity?;
+                let dot = entity.name == "." || entity.name == "..";
+                let reserved = entity.name.starts_with(WHITEOUT_PREFIX);
+                if (dot && !in_upper) || (!dot && !reserved && !shadowed.contains(&entity.name)) {
+                    entities.push(layer_entity(entity, LOWER_CURSOR)?);
+                }
+            }
+        }
+        Ok(Box::new(entities.into_iter().map(Ok)))
+    }
+    fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let lookup = self.lookup(new_path, false)?;
+        if lookup.entry.is_some() {
+            return Err(Error::exist());
+        }
+        let path = self.prepare_create(&lookup)?;
+        self.upper().symlink(old_path, &path)
+    }
+    fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        let lookup = self.lookup(path, false)?;
+        let entry = lookup.existing()?;
+        lookup.name()?;
+        if !self.is_empty(&lookup)? {
+            return Err(Error::not_empty());
+        }
+        let in_lower = self.in_lower(&lookup)?;
+        if entry.layer == Layer::Upper {
+            let path = lookup.path();
+            self.clear_markers(&path)?;
+            self.upper().remove_dir(&path)?;
+        }
+        if in_lower {
+            self.add_whiteout(&lookup)?;
+        }
+        Ok(())
+    }
+    fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        let lookup = self.lookup(path, false)?;
+        let entry = lookup.existing()?;
+        if lookup.is_dir() {
+            return Err(Error::is_dir());
+        }
+        let in_lower = self.in_lower(&lookup)?;
+        if entry.layer == Layer::Upper {
+            self.upper().unlink_file(&lookup.path())?;
+        }
+        if in_lower {
+            self.add_whiteout(&lookup)?;
+        }
+        Ok(())
+    }
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        let lookup = self.lookup(path, false)?;
+        let entry = lookup.existing()?;
+        self.layer(entry.layer).read_link(&lookup.path())
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        let lookup = self.lookup_dir(self.base()?)?;
+        Ok(lookup.existing()?.stat.clone())
+    }
+    fn get_path_filestat(&self, path: &str, follow_symlinks: bool) -> Result<Filestat, Error> {
+        Ok(self.lookup(path, follow_symlinks)?.existing()?.stat.clone())
+    }
+    fn rename(&self, path: &str, dest_dir: &dyn WasiDir, dest_path: &str) -> Result<(), Error> {
+        let dest_dir = match self.same_overlay(dest_dir) {
+            Ok(dest_dir) => dest_dir,
+            Err(_) => return move_across(self, path, dest_dir, dest_path),
+        };
+        let from = self.lookup(path, false)?;
+        let entry = from.existing()?;
+        from.name()?;
+        let to = dest_dir.lookup(dest_path, false)?;
+        to.name()?;
+        let moving_dir = from.is_dir();
+        if let Some(replaced) = &to.entry {
+            if replaced.layer == entry.layer
+                && (replaced.stat.device_id, replaced.stat.inode)
+                    == (entry.stat.device_id, entry.stat.inode)
+            {
+                return Ok(());
+            }
+            match (to.is_dir(), moving_dir) {
+                (true, true) if !self.is_empty(&to)? => return Err(Error::not_empty()),
+                (true, false) => return Err(Error::is_dir()),
+                (false, true) => return Err(Error::not_dir()),
+                _ => {}
+            }
+        }
+        if moving_dir && to.components().starts_with(&from.components()) {
+            return Err(Error::invalid_argument().context("directory moved into itself"));
+        }
+        if moving_dir && (entry.layer == Layer::Lower || entry.merged) {
+            // The lower layer's entries in it can't be moved, so copy them.
+            return move_across(self, path, dest_dir, dest_path);
+        }
+        let from_in_lower = self.in_lower(&from)?;
+        let to_in_lower = self.in_lower(&to)?;
+        self.copy_up(&from)?;
+        let to_path = self.prepare_create(&to)?;
+        if let Some(replaced) = &to.entry {
+            if replaced.layer == Layer::Upper && to.is_dir() {
+                self.clear_markers(&to_path)?;
+            }
+        }
+        self.upper().rename(&from.path(), self.upper(), &to_path)?;
+        if from_in_lower {
+            self.add_whiteout(&from)?;
+        }
+        if moving_dir && to_in_lower {
+            self.add_marker(&format!("{}/{}", to_path, OPAQUE))?;
+        }
+        Ok(())
+    }
+    fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        let target_dir = match self.same_overlay(target_dir) {
+            Ok(dir) => dir,
+            Err(_) => return link_across(self, path, target_dir, target_path),
+        };
+        let from = self.lookup(path, false)?;
+        from.existing()?;
+        if from.is_dir() {
+            return Err(Error::perm().context("hard link to a directory"));
+        }
+        let to = target_dir.lookup(target_path, false)?;
+        if to.entry.is_some() {
+            return Err(Error::exist());
+        }
+        self.copy_up(&from)?;
+        let to_path = self.prepare_create(&to)?;
+        self.upper().hard_link(&from.path(), self.upper(), &to_path)
+    }
+    fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        let lookup = self.lookup(path, follow_symlinks)?;
+        self.copy_up(&lookup)?;
+        self.upper().set_times(&lookup.path(), atime, mtime, false)
+    }
+    fn copy_policy(&self) -> CopyPolicy {
+        self.lower().copy_policy().and(self.upper().copy_policy())
+    }
+}
+
+/// A file opened from the lower layer without write access. The lower layer is shared, so the
+/// only change such a file allows, to its times, copies it up first.
+struct LowerFile {
+    inner: Box<dyn WasiFile>,
+    root: OverlayDir,
+    /// Where the file is, relative to the root of both layers.
+    path: String,
+}
+
+impl WasiFile for LowerFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        self.inner.get_filetype()
+    }
+    fn datasync(&mut self) -> Result<(), Error> {
+        self.inner.datasync()
+    }
+    fn sync(&mut self) -> Result<(), Error> {
+        self.inner.sync()
+    }
+    fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        self.inner.get_fdflags()
+    }
+    fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.inner.set_fdflags(flags)
+    }
+    fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        self.inner.get_filestat()
+    }
+    fn set_filestat_size(&mut self, _size: u64) -> Result<(), Error> {
+        Err(Error::badf().context("not opened for writing"))
+    }
+    fn advise(&mut self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+        self.inner.advise(offset, len, advice)
+    }
+    fn allocate(&mut self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::badf().context("not opened for writing"))
+    }
+    fn set_times(
+        &mut self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        self.root.set_times(&self.path, atime, mtime, false)
+    }
+    fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        self.inner.read_vectored(bufs)
+    }
+    fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        self.inner.read_vectored_at(bufs, offset)
+    }
+    fn write_vectored<'a>(&mut self, _bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        Err(Error::badf().context("not opened for writing"))
+    }
+    fn write_vectored_at<'a>(&mut self, _bufs: &[IoSlice<'a>], _offset: u64) -> Result<u64, Error> {
+        Err(Error::badf().context("not opened for writing"))
+    }
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        self.inner.seek(pos)
+    }
+    fn offset(&self) -> Option<u64> {
+        self.inner.offset()
+    }
+    fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.inner.peek(buf)
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes()
+    }
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+        self.inner.set_timeouts(timeouts)
+    }
+    fn readable(&self) -> Result<(), Error> {
+        self.inner.readable()
+    }
+    fn writable(&self) -> Result<(), Error> {
+        self.inner.writable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::tests::{errno, names, read, sorted_names, write, Clock};
+    use crate::memfs::{MemDir, MemFs};
+
+    struct Fixture {
+        lower: MemDir,
+        upper: MemDir,
+        overlay: OverlayDir,
+    }
+
+    /// An overlay over a lower layer holding `a` ("lower a"), `b` and `d/e`.
+    fn fixture() -> Fixture {
+        let lower = MemFs::new(Box::new(Clock)).root();
+        write(&lower, "a", b"lower a");
+        write(&lower, "b", b"b");
+        lower.create_dir("d").unwrap();
+        write(&lower, "d/e", b"e");
+        let upper = MemFs::new(Box::new(Clock)).root();
+        let overlay = OverlayDir::new(
+            Arc::from(lower.open_dir(false, ".").unwrap()),
+            upper.open_dir(false, ".").unwrap(),
+        );
+        Fixture {
+            lower,
+            upper,
+            overlay,
+        }
+    }
+
+    #[test]
+    fn writes_copy_up() {
+        let f = fixture();
+        let mut file = f
+            .overlay
+            .open_file(false, "d/e", OFlags::empty(), true, true, FdFlags::empty())
+            .unwrap();
+        file.write_vectored_at(&[IoSlice::new(b"E")], 1).unwrap();
+        drop(file);
+        assert_eq!(read(&f.overlay, "d/e"), b"eE");
+        assert_eq!(read(&f.upper, "d/e"), b"eE");
+        assert_eq!(read(&f.lower, "d/e"), b"e");
+        // Reading alone leaves the file in the lower layer.
+        assert_eq!(read(&f.overlay, "b"), b"b");
+        assert!(stat(&f.upper, "b").unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_copy_up_leaves_the_lower_file() {
+        let lower = MemFs::new(Box::new(Clock)).root();
+        write(&lower, "a", b"lower a");
+        // Too small for the copy, so it fails part way through.
+        let upper = MemFs::new(Box::new(Clock)).with_max_bytes(3);
+        let overlay = OverlayDir::new(
+            Arc::from(lower.open_dir(false, ".").unwrap()),
+            upper.root().open_dir(false, ".").unwrap(),
+        );
+        let open = || overlay.open_file(false, "a", OFlags::empty(), true, true, FdFlags::empty());
+        assert_eq!(errno(open()), Some(Errno::Fbig));
+        assert_eq!(read(&overlay, "a"), b"lower a");
+        assert_eq!(sorted_names(&upper.root()), [".", ".."]);
+
+        let upper = upper.with_max_bytes(64);
+        let mut file = open().unwrap();
+        file.write_vectored_at(&[IoSlice::new(b"A")], 0).unwrap();
+        drop(file);
+        assert_eq!(read(&overlay, "a"), b"Aower a");
+        assert_eq!(sorted_names(&upper.root()), [".", "..", "a"]);
+    }
+
+    #[test]
+    fn truncating_open_does_not_copy_up() {
+        let f = fixture();
+        let oflags = OFlags::TRUNCATE;
+        let mut file = f
+            .overlay
+            .open_file(false, "a", oflags, true, true, FdFlags::empty())
+            .unwrap();
+        assert_eq!(file.get_filestat().unwrap().size, 0);
+        file.write_vectored_at(&[IoSlice::new(b"new")], 0).unwrap();
+        drop(file);
+        assert_eq!(read(&f.overlay, "a"), b"new");
+        assert_eq!(read(&f.lower, "a"), b"lower a");
+    }
+
+    #[test]
+    fn whiteouts_hide_lower_entries() {
+        let f = fixture();
+        f.overlay.unlink_file("a").unwrap();
+        assert_eq!(
+            errno(f.overlay.get_path_filestat("a", false)),
+            Some(Errno::Noent)
+        );
+        assert!(stat(&f.upper, ".wh.a").unwrap().is_some());
+        assert!(stat(&f.lower, "a").unwrap().is_some());
+        assert_eq!(sorted_names(&f.overlay), [".", "..", "b", "d"]);
+        // The guest can't see or make whiteouts.
+        assert_eq!(
+            errno(f.overlay.get_path_filestat(".wh.a", false)),
+            Some(Errno::Perm)
+        );
+        // Creating the name again removes the whiteout.
+        write(&f.overlay, "a", b"again");
+        assert_eq!(read(&f.overlay, "a"), b"again");
+        assert!(stat(&f.upper, ".wh.a").unwrap().is_none());
+    }
+
+    #[test]
+    fn recreated_dirs_are_opaque() {
+        let f = fixture();
+        f.overlay.unlink_file("d/e").unwrap();
+        f.overlay.remove_dir("d").unwrap();
+        f.overlay.create_dir("d").unwrap();
+        assert!(stat(&f.upper, "d/.wh..wh..opq").unwrap().is_some());
+        assert_eq!(
+            sorted_names(&*f.overlay.open_dir(false, "d").unwrap()),
+            [".", ".."]
+        );
+        assert_eq!(
+            errno(f.overlay.get_path_filestat("d/e", false)),
+            Some(Errno::Noent)
+        );
+        write(&f.overlay, "d/f", b"f");
+        assert_eq!(
+            sorted_names(&*f.overlay.open_dir(false, "d").unwrap()),
+            [".", "..", "f"]
+        );
+    }
+
+    #[test]
+    fn renaming_a_lower_dir_copies_it_up() {
+        let f = fixture();
+        f.overlay.rename("d", &f.overlay, "moved").unwrap();
+        assert_eq!(read(&f.overlay, "moved/e"), b"e");
+        assert_eq!(
+            errno(f.overlay.get_path_filestat("d", false)),
+            Some(Errno::Noent)
+        );
+        assert_eq!(read(&f.lower, "d/e"), b"e");
+        assert_eq!(sorted_names(&f.overlay), [".", "..", "a", "b", "moved"]);
+        assert_eq!(
+            errno(f.overlay.rename("moved", &f.overlay, "moved/in")),
+            Some(Errno::Inval)
+        );
+    }
+
+    #[test]
+    fn moving_out_of_the_overlay_copies_across() {
+        let f = fixture();
+        let other = MemFs::new(Box::new(Clock)).root();
+        f.overlay.rename("d", &other, "d").unwrap();
+        assert_eq!(read(&other, "d/e"), b"e");
+        assert_eq!(
+            errno(f.overlay.get_path_filestat("d", false)),
+            Some(Errno::Noent)
+        );
+        assert_eq!(read(&f.lower, "d/e"), b"e");
+
+        /// A directory whose host doesn't allow copies.
+        struct NoCopies;
+        impl WasiDir for NoCopies {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+            fn copy_policy(&self) -> CopyPolicy {
+                CopyPolicy::Deny
+            }
+        }
+        assert_eq!(
+            errno(f.overlay.rename("a", &NoCopies, "a")),
+            Some(Errno::Xdev)
+        );
+        assert_eq!(read(&f.overlay, "a"), b"lower a");
+
+        f.overlay.hard_link("b", &other, "b").unwrap();
+        assert_eq!(read(&other, "b"), b"b");
+        assert_eq!(read(&f.overlay, "b"), b"b");
+        assert_eq!(
+            errno(f.overlay.hard_link("b", &other, "b")),
+            Some(Errno::Exist)
+        );
+        assert_eq!(
+            errno(other.hard_link("d", &f.overlay, "d")),
+            Some(Errno::Perm)
+        );
+        assert_eq!(
+            errno(f.overlay.hard_link("a", &NoCopies, "a")),
+            Some(Errno::Xdev)
+        );
+        assert_eq!(sorted_names(&other), [".", "..", "b", "d"]);
+    }
+
+    #[test]
+    fn readdir_merges_the_layers() {
+        let f = fixture();
+        write(&f.overlay, "c", b"c");
+        write(&f.overlay, "b", b"upper b");
+        f.overlay.create_dir("d/g").unwrap();
+        assert_eq!(sorted_names(&f.overlay), [".", "..", "a", "b", "c", "d"]);
+        let d = f.overlay.open_dir(false, "d").unwrap();
+        assert_eq!(sorted_names(&*d), [".", "..", "e", "g"]);
+        let b = f.overlay.get_path_filestat("b", false).unwrap();
+        assert_eq!(b.size, 7);
+    }
+
+    #[test]
+    fn readdir_cursors_survive_changes() {
+        let f = fixture();
+        write(&f.overlay, "c", b"c");
+        let first = names(&f.overlay, 0);
+        let position = |name: &str| first.iter().position(|(n, _)| n == name).unwrap();
+        // Resume after an upper entry and after a lower one, while entries change. Whether a
+        // new entry shows up is unspecified, but none of the others may be skipped or repeated.
+        let (after_c, after_b) = (first[position("c")].1, first[position("b")].1);
+        let rest_after_c = first[position("c") + 1..].to_vec();
+        let rest_after_b = first[position("b") + 1..].to_vec();
+        f.overlay.unlink_file("a").unwrap();
+        write(&f.overlay, "0", b"0");
+        let rest = |entries: Vec<(String, u64)>| {
+            entries
+                .into_iter()
+                .map(|(name, _)| name)
+                .filter(|name| name != "a" && name != "0")
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rest(names(&f.overlay, after_c)), rest(rest_after_c));
+        assert_eq!(rest(names(&f.overlay, after_b)), rest(rest_after_b));
+    }
+}
@@ -9,7 +9,10 @@
 //! Some convenience constructors are included for common backing types like `Vec<u8>` and `String`,
 //! but the virtual pipes can be instantiated with any `Read` or `Write` type.
 //!
+#[cfg(feature = "async")]
+use crate::async_file::AsyncWasiFile;
 use crate::file::{FdFlags, FileType, WasiFile};
+use crate::quota::OutputQuota;
 use crate::Error;
 use std::any::Any;
 use std::convert::TryInto;
@@ -21,15 +24,10 @@ use std::sync::{Arc, RwLock};
 /// A variety of `From` impls are provided so that common pipe types are easy to create. For example:
 ///
 /// ```no_run
-/// use wasi_common::{pipe::ReadPipe, WasiCtx, Table};
+/// use wasmedge_wasi_common::{environ::WasiEnviron, pipe::ReadPipe};
 /// let stdin = ReadPipe::from("hello from stdin!");
-/// // Brint these instances from elsewhere (e.g. wasi-cap-std-sync):
-/// let random = todo!();
-/// let clocks = todo!();
-/// let sched = todo!();
-/// let table = Table::new();
-/// let mut ctx = WasiCtx::new(random, clocks, sched, table);
-/// ctx.set_stdin(Box::new(stdin.clone()));
+/// let mut environ = WasiEnviron::new();
+/// environ.set_stdin(Box::new(stdin.clone()));
 /// ```
 #[derive(Debug)]
 pub struct ReadPipe<R: Read> {
@@ -71,7 +69,7 @@ impl<R: Read> ReadPipe<R> {
             }
         }
     }
-    fn borrow(&self) -> std::sync::RwLockWriteGuard<R> {
+    fn borrow(&self) -> std::sync::RwLockWriteGuard<'_, R> {
         RwLock::write(&self.reader).unwrap()
     }
 }
@@ -113,32 +111,60 @@ impl<R: Read + Any + Send + Sync> WasiFile for ReadPipe<R> {
     }
 }
 
+/// Implement `AsyncWasiFile` for read pipes over in-memory readers, which never block and so
+/// can be read from an async fn. Pipes over other readers can be run on a blocking thread pool
+/// instead, such as with `wasmedge_wasi::tokio::file::SyncFile`.
+#[cfg(feature = "async")]
+macro_rules! async_read_pipe_impl {
+    ($($reader:ty),*) => {
+        $(
+            #[crate::async_trait]
+            impl AsyncWasiFile for ReadPipe<$reader> {
+                fn as_any(&self) -> &dyn Any {
+                    self
+                }
+                async fn get_filetype(&mut self) -> Result<FileType, Error> {
+                    Ok(FileType::Pipe)
+                }
+                async fn read_vectored<'a>(
+                    &mut self,
+                    bufs: &mut [io::IoSliceMut<'a>],
+                ) -> Result<u64, Error> {
+                    WasiFile::read_vectored(self, bufs)
+                }
+                async fn readable(&self) -> Result<(), Error> {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+#[cfg(feature = "async")]
+async_read_pipe_impl!(io::Empty, io::Cursor<Vec<u8>>, io::Cursor<String>);
+
 /// A virtual pipe write end.
 ///
 /// ```no_run
-/// use wasi_common::{pipe::WritePipe, WasiCtx, Table};
+/// use wasmedge_wasi_common::{environ::WasiEnviron, pipe::WritePipe};
 /// let stdout = WritePipe::new_in_memory();
-/// // Brint these instances from elsewhere (e.g. wasi-cap-std-sync):
-/// let random = todo!();
-/// let clocks = todo!();
-/// let sched = todo!();
-/// let table = Table::new();
-/// let mut ctx = WasiCtx::new(random, clocks, sched, table);
-/// ctx.set_stdout(Box::new(stdout.clone()));
-/// // use ctx in an instance, then make sure it is dropped:
-/// drop(ctx);
+/// let mut environ = WasiEnviron::new();
+/// environ.set_stdout(Box::new(stdout.clone()));
+/// // use environ in an instance, then make sure it is dropped:
+/// drop(environ);
 /// let contents: Vec<u8> = stdout.try_into_inner().expect("sole remaining reference to WritePipe").into_inner();
 /// println!("contents of stdout: {:?}", contents);
 /// ```
 #[derive(Debug)]
 pub struct WritePipe<W: Write> {
     writer: Arc<RwLock<W>>,
+    quota: Option<OutputQuota>,
 }
 
 impl<W: Write> Clone for WritePipe<W> {
     fn clone(&self) -> Self {
         Self {
             writer: self.writer.clone(),
+            quota: self.quota.clone(),
         }
     }
 }
@@ -155,7 +181,17 @@ impl<W: Write> WritePipe<W> {
     ///
     /// All `Handle` write operations delegate to writing to this underlying writer.
     pub fn from_shared(writer: Arc<RwLock<W>>) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            quota: None,
+        }
+    }
+
+    /// Bound how many bytes may be written to the pipe. Clones made after this share the quota,
+    /// so the same quota can cover every pipe of an instance.
+    pub fn with_quota(mut self, quota: OutputQuota) -> Self {
+        self.quota = Some(quota);
+        self
     }
 
     /// Try to convert this `WritePipe<W>` back to the underlying `W` type.
@@ -171,7 +207,7 @@ impl<W: Write> WritePipe<W> {
         }
     }
 
-    fn borrow(&self) -> std::sync::RwLockWriteGuard<W> {
+    fn borrow(&self) -> std::sync::RwLockWriteGuard<'_, W> {
         RwLock::write(&self.writer).unwrap()
     }
 }
@@ -194,7 +230,46 @@ impl<W: Write + Any + Send + Sync> WasiFile for WritePipe<W> {
         Ok(FdFlags::APPEND)
     }
     fn write_vectored<'a>(&mut self, bufs: &[io::IoSlice<'a>]) -> Result<u64, Error> {
-        let n = self.borrow().write_vectored(bufs)?;
-        Ok(n.try_into()?)
+        let write = |bufs: &[io::IoSlice<'_>]| -> Result<u64, Error> {
+            let n = self.borrow().write_vectored(bufs)?;
+            Ok(n.try_into()?)
+        };
+        match &self.quota {
+            Some(quota) => quota.write_vectored(bufs, write),
+            None => write(bufs),
+        }
     }
 }
+
+/// Implement `AsyncWasiFile` for write pipes over in-memory writers, which never block and so
+/// can be written to from an async fn.
+#[cfg(feature = "async")]
+macro_rules! async_write_pipe_impl {
+    ($($writer:ty),*) => {
+        $(
+            #[crate::async_trait]
+            impl AsyncWasiFile for WritePipe<$writer> {
+                fn as_any(&self) -> &dyn Any {
+                    self
+                }
+                async fn get_filetype(&mut self) -> Result<FileType, Error> {
+                    Ok(FileType::Pipe)
+                }
+                async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+                    Ok(FdFlags::APPEND)
+                }
+                async fn write_vectored<'a>(
+                    &mut self,
+                    bufs: &[io::IoSlice<'a>],
+                ) -> Result<u64, Error> {
+                    WasiFile::write_vectored(self, bufs)
+                }
+                async fn writable(&self) -> Result<(), Error> {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+#[cfg(feature = "async")]
+async_write_pipe_impl!(io::Sink, io::Cursor<Vec<u8>>, Vec<u8>);
@@ -0,0 +1,260 @@
+//! Byte quotas on guest output.
+//!
+//! An `OutputQuota` bounds how many bytes may be written through the descriptors or pipes it is
+//! attached to, and the host picks what happens to the write that would go over it. The quota is
+//! a handle: clones share the same count, so the host can keep one to read the usage while the
+//! guest writes through another.
+use crate::error::{Error, ErrorExt};
+use std::io::IoSlice;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// What happens to a write that would go over an `OutputQuota`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OnExceeded {
+    /// Write what still fits, then fail every write after it with `Fbig`.
+    Fail,
+    /// Write what still fits and a marker line, then drop everything after it, while telling the
+    /// guest it was all written.
+    Truncate,
+    /// Write nothing, and trap the instance.
+    Trap,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputQuota(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    limit: u64,
+    on_exceeded: OnExceeded,
+    used: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl OutputQuota {
+    pub fn new(limit: u64, on_exceeded: OnExceeded) -> Self {
+        OutputQuota(Arc::new(Inner {
+            limit,
+            on_exceeded,
+            used: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        }))
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.0.limit
+    }
+
+    pub fn on_exceeded(&self) -> OnExceeded {
+        self.0.on_exceeded
+    }
+
+    /// How many bytes were written against the quota. The truncation marker is not counted.
+    pub fn used(&self) -> u64 {
+        self.0.used.load(Ordering::SeqCst)
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.limit().saturating_sub(self.used())
+    }
+
+    /// Whether a write went over the quota.
+    pub fn is_exceeded(&self) -> bool {
+        self.0.exceeded.load(Ordering::SeqCst)
+    }
+
+    /// Write `bufs` with `write`, within the quota.
+    pub(crate) fn write_vectored(
+        &self,
+        bufs: &[IoSlice<'_>],
+        mut write: impl FnMut(&[IoSlice<'_>]) -> Result<u64, Error>,
+    ) -> Result<u64, Error> {
+        let grant = self.reserve(bufs)?;
+        let written = if grant.is_empty() {
+            Ok(0)
+        } else {
+            write(&grant.bufs(bufs))
+        };
+        let (n, marker) = grant.finish(written)?;
+        if let Some(marker) = marker {
+            // The guest has no use for an error here: its bytes are already accounted for.
+            let _ = write(&[IoSlice::new(&marker)]);
+        }
+        Ok(n)
+    }
+
+    /// Take as much of a write of `bufs` as the quota has room for.
+    pub(crate) fn reserve(&self, bufs: &[IoSlice<'_>]) -> Result<Grant, Error> {
+        let len = bufs.iter().map(|b| b.len() as u64).sum::<u64>();
+        let limit = self.limit();
+        let mut allowed = 0;
+        let _ = self
+            .0
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                allowed = len.min(limit.saturating_sub(used));
+                if allowed < len && self.on_exceeded() == OnExceeded::Trap {
+                    allowed = 0;
+                }
+                Some(used + allowed)
+            });
+        if allowed == len {
+            return Ok(self.grant(len, allowed, false));
+        }
+
+        let first = !self.0.exceeded.swap(true, Ordering::SeqCst);
+        match self.on_exceeded() {
+            OnExceeded::Fail if allowed == 0 => {
+                Err(Error::fbig().context(format!("output quota of {} bytes exceeded", limit)))
+            }
+            OnExceeded::Fail => Ok(self.grant(len, allowed, false)),
+            OnExceeded::Truncate => Ok(self.grant(len, allowed, first)),
+            OnExceeded::Trap => Err(Error::trap(format!(
+                "output quota of {} bytes exceeded",
+                limit
+            ))),
+        }
+    }
+
+    fn grant(&self, len: u64, allowed: u64, marker: bool) -> Grant {
+        Grant {
+            quota: self.clone(),
+            len,
+            allowed,
+            marker,
+        }
+    }
+}
+
+/// The part of a write an `OutputQuota` lets through.
+pub(crate) struct Grant {
+    quota: OutputQuota,
+    len: u64,
+    allowed: u64,
+    marker: bool,
+}
+
+impl Grant {
+    pub fn is_empty(&self) -> bool {
+        self.allowed == 0
+    }
+
+    /// `bufs`, cut down to what may be written.
+    pub fn bufs<'a>(&self, bufs: &'a [IoSlice<'a>]) -> Vec<IoSlice<'a>> {
+        truncate_bufs(bufs, self.allowed)
+    }
+
+    /// Give back what wasn't written. Return what to tell the guest was written, and the marker
+    /// to write after it, if any.
+    pub fn finish(self, written: Result<u64, Error>) -> Result<(u64, Option<Vec<u8>>), Error> {
+        let n = match written {
+            Ok(n) => n.min(self.allowed),
+            Err(e) => {
+                self.quota.0.used.fetch_sub(self.allowed, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        self.quota
+            .0
+            .used
+            .fetch_sub(self.allowed - n, Ordering::SeqCst);
+        if n < self.allowed || self.quota.on_exceeded() != OnExceeded::Truncate {
+            return Ok((n, None));
+        }
+        let marker = self.marker.then(|| {
+            format!(
+                "\n[output truncated: quota of {} bytes exceeded]\n",
+                self.quota.limit()
+            )
+            .into_bytes()
+        });
+        Ok((self.len, marker))
+    }
+}
+
+/// The first `len` bytes of `bufs`.
+pub(crate) fn truncate_bufs<'a>(bufs: &'a [IoSlice<'a>], len: u64) -> Vec<IoSlice<'a>> {
+    let mut left = len;
+    let mut out = Vec::new();
+    for buf in bufs {
+        if left == 0 {
+            break;
+        }
+        let n = (buf.len() as u64).min(left);
+        out.push(IoSlice::new(&buf[..n as usize]));
+        left -= n;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Errno;
+
+    fn write(quota: &OutputQuota, data: &[u8], out: &mut Vec<u8>) -> Result<u64, Error> {
+        quota.write_vectored(&[IoSlice::new(data)], |bufs| {
+            let n = bufs.iter().map(|b| b.len()).sum::<usize>();
+            for buf in bufs {
+                out.extend_from_slice(buf);
+            }
+            Ok(n as u64)
+        })
+    }
+
+    #[test]
+    fn a_grant_gives_back_what_was_not_written() {
+        let quota = OutputQuota::new(10, OnExceeded::Fail);
+        let grant = quota.reserve(&[IoSlice::new(b"abcdef")]).unwrap();
+        assert_eq!(quota.used(), 6);
+        let bufs = [IoSlice::new(b"abc"), IoSlice::new(b"def")];
+        assert_eq!(grant.bufs(&bufs).len(), 2);
+        assert_eq!(grant.finish(Ok(2)).unwrap(), (2, None));
+        assert_eq!(quota.used(), 2);
+
+        let grant = quota.reserve(&[IoSlice::new(b"abc")]).unwrap();
+        assert_eq!(quota.used(), 5);
+        assert!(grant.finish(Err(Error::io())).is_err());
+        assert_eq!(quota.used(), 2);
+        assert!(!quota.is_exceeded());
+    }
+
+    #[test]
+    fn fail_writes_what_fits_then_fails() {
+        let quota = OutputQuota::new(8, OnExceeded::Fail);
+        let mut out = Vec::new();
+        assert_eq!(write(&quota, b"hello", &mut out).unwrap(), 5);
+        assert_eq!(write(&quota, b"world", &mut out).unwrap(), 3);
+        assert_eq!(out, b"hellowor");
+        assert_eq!(quota.remaining(), 0);
+        assert!(quota.is_exceeded());
+        let err = write(&quota, b"!", &mut out).unwrap_err();
+        assert_eq!(Errno::from_error(&err), Some(Errno::Fbig));
+        assert_eq!(quota.used(), 8);
+    }
+
+    #[test]
+    fn truncate_marks_the_cut_once() {
+        let quota = OutputQuota::new(4, OnExceeded::Truncate);
+        let mut out = Vec::new();
+        assert_eq!(write(&quota, b"abcdef", &mut out).unwrap(), 6);
+        assert_eq!(write(&quota, b"gh", &mut out).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "abcd\n[output truncated: quota of 4 bytes exceeded]\n"
+        );
+        assert_eq!(quota.used(), 4);
+    }
+
+    #[test]
+    fn trap_writes_nothing() {
+        let quota = OutputQuota::new(4, OnExceeded::Trap);
+        let mut out = Vec::new();
+        assert_eq!(write(&quota, b"ab", &mut out).unwrap(), 2);
+        let err = write(&quota, b"cde", &mut out).unwrap_err();
+        assert_eq!(Errno::from_error(&err), None);
+        assert_eq!(out, b"ab");
+        assert_eq!(quota.used(), 2);
+    }
+}
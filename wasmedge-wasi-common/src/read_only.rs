@@ -0,0 +1,208 @@
+//! Read-only trees.
+//!
+//! A `ReadOnlyDir` wraps a directory, and every file and directory opened through it, so that
+//! the guest can read the tree but not change it, whatever rights its descriptors carry.
+//! Creating, removing, renaming or linking entries, opening files for writing, and changing
+//! sizes or times all fail with `Rofs`.
+use crate::clocks::SystemTimeSpec;
+use crate::dir::{CopyPolicy, ReaddirCursor, ReaddirEntity, WasiDir};
+use crate::error::{Error, ErrorExt};
+use crate::file::{
+    Advice, FdFlags, FileType, Filestat, OFlags, RiFlags, RoFlags, SdFlags, SiFlags, Timeouts,
+    WasiFile,
+};
+use std::any::Any;
+use std::io::{IoSlice, IoSliceMut, SeekFrom};
+use std::path::PathBuf;
+
+pub struct ReadOnlyDir(Box<dyn WasiDir>);
+
+impl ReadOnlyDir {
+    pub fn new(dir: Box<dyn WasiDir>) -> Self {
+        ReadOnlyDir(dir)
+    }
+}
+
+impl WasiDir for ReadOnlyDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        if write || oflags.intersects(OFlags::CREATE | OFlags::TRUNCATE) {
+            return Err(Error::read_only().context("open for writing"));
+        }
+        let file = self
+            .0
+            .open_file(symlink_follow, path, oflags, read, false, fdflags)?;
+        Ok(Box::new(ReadOnlyFile(file)))
+    }
+    fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let dir = self.0.open_dir(symlink_follow, path)?;
+        Ok(Box::new(ReadOnlyDir(dir)))
+    }
+    fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        self.0.readdir(cursor)
+    }
+    fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.0.read_link(path)
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.0.get_filestat()
+    }
+    fn get_path_filestat(&self, path: &str, follow_symlinks: bool) -> Result<Filestat, Error> {
+        self.0.get_path_filestat(path, follow_symlinks)
+    }
+    fn rename(&self, _path: &str, _dest_dir: &dyn WasiDir, _dest_path: &str) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn WasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn copy_policy(&self) -> CopyPolicy {
+        self.0.copy_policy()
+    }
+}
+
+/// A file opened through a `ReadOnlyDir`.
+pub struct ReadOnlyFile(Box<dyn WasiFile>);
+
+impl WasiFile for ReadOnlyFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        self.0.get_filetype()
+    }
+    #[cfg(unix)]
+    fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+        self.0.pollable()
+    }
+    #[cfg(unix)]
+    fn host_fd(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+        self.0.host_fd()
+    }
+    fn isatty(&mut self) -> bool {
+        self.0.isatty()
+    }
+    fn sock_accept(&mut self, fdflags: FdFlags) -> Result<Box<dyn WasiFile>, Error> {
+        self.0.sock_accept(fdflags)
+    }
+    fn sock_recv<'a>(
+        &mut self,
+        ri_data: &mut [IoSliceMut<'a>],
+        ri_flags: RiFlags,
+    ) -> Result<(u64, RoFlags), Error> {
+        self.0.sock_recv(ri_data, ri_flags)
+    }
+    fn sock_send<'a>(&mut self, si_data: &[IoSlice<'a>], si_flags: SiFlags) -> Result<u64, Error> {
+        self.0.sock_send(si_data, si_flags)
+    }
+    fn sock_shutdown(&mut self, how: SdFlags) -> Result<(), Error> {
+        self.0.sock_shutdown(how)
+    }
+    fn datasync(&mut self) -> Result<(), Error> {
+        self.0.datasync()
+    }
+    fn sync(&mut self) -> Result<(), Error> {
+        self.0.sync()
+    }
+    fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        self.0.get_fdflags()
+    }
+    fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.0.set_fdflags(flags)
+    }
+    fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        self.0.get_filestat()
+    }
+    fn set_filestat_size(&mut self, _size: u64) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn advise(&mut self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+        self.0.advise(offset, len, advice)
+    }
+    fn allocate(&mut self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn set_times(
+        &mut self,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        Err(Error::read_only())
+    }
+    fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        self.0.read_vectored(bufs)
+    }
+    fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        self.0.read_vectored_at(bufs, offset)
+    }
+    fn write_vectored<'a>(&mut self, _bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        Err(Error::read_only())
+    }
+    fn write_vectored_at<'a>(&mut self, _bufs: &[IoSlice<'a>], _offset: u64) -> Result<u64, Error> {
+        Err(Error::read_only())
+    }
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        self.0.seek(pos)
+    }
+    fn offset(&self) -> Option<u64> {
+        self.0.offset()
+    }
+    fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.0.peek(buf)
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.0.num_ready_bytes()
+    }
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+        self.0.set_timeouts(timeouts)
+    }
+    fn readable(&self) -> Result<(), Error> {
+        self.0.readable()
+    }
+    fn writable(&self) -> Result<(), Error> {
+        self.0.writable()
+    }
+}
@@ -0,0 +1,187 @@
+use super::{hex, unhex, Recorder, Replay, Source};
+use crate::clocks::{WasiMonotonicClock, WasiSystemClock};
+use cap_std::time::{Duration, Instant, SystemTime};
+use serde_json::json;
+use std::io::{self, Read};
+
+fn precision(precision: Duration) -> serde_json::Value {
+    json!({ "precision": precision })
+}
+
+pub(super) struct RecordingSystemClock {
+    recorder: Recorder,
+    inner: Box<dyn WasiSystemClock>,
+}
+
+impl RecordingSystemClock {
+    pub(super) fn new(recorder: Recorder, inner: Box<dyn WasiSystemClock>) -> Self {
+        RecordingSystemClock { recorder, inner }
+    }
+}
+
+impl WasiSystemClock for RecordingSystemClock {
+    fn resolution(&self) -> Duration {
+        let resolution = self.inner.resolution();
+        self.recorder
+            .record(Source::SystemClock, "resolution", json!({}), Ok(resolution));
+        resolution
+    }
+    fn now(&self, precision: Duration) -> SystemTime {
+        let now = self.inner.now(precision);
+        self.recorder.record(
+            Source::SystemClock,
+            "now",
+            self::precision(precision),
+            Ok(now.into_std()),
+        );
+        now
+    }
+}
+
+/// Recorded as the time elapsed since the clock was wrapped, since an `Instant` has no meaning
+/// outside the process that took it.
+pub(super) struct RecordingMonotonicClock {
+    recorder: Recorder,
+    inner: Box<dyn WasiMonotonicClock>,
+    start: Instant,
+}
+
+impl RecordingMonotonicClock {
+    pub(super) fn new(recorder: Recorder, inner: Box<dyn WasiMonotonicClock>) -> Self {
+        let start = inner.now(Duration::ZERO);
+        RecordingMonotonicClock {
+            recorder,
+            inner,
+            start,
+        }
+    }
+}
+
+impl WasiMonotonicClock for RecordingMonotonicClock {
+    fn resolution(&self) -> Duration {
+        let resolution = self.inner.resolution();
+        self.recorder.record(
+            Source::MonotonicClock,
+            "resolution",
+            json!({}),
+            Ok(resolution),
+        );
+        resolution
+    }
+    fn now(&self, precision: Duration) -> Instant {
+        let now = self.inner.now(precision);
+        self.recorder.record(
+            Source::MonotonicClock,
+            "now",
+            self::precision(precision),
+            Ok(now.saturating_duration_since(self.start)),
+        );
+        now
+    }
+}
+
+// The clock traits can't fail, so after a divergence the replaying clocks report the epoch or
+// the moment they were created.
+
+pub(super) struct ReplaySystemClock {
+    replay: Replay,
+}
+
+impl ReplaySystemClock {
+    pub(super) fn new(replay: Replay) -> Self {
+        ReplaySystemClock { replay }
+    }
+}
+
+impl WasiSystemClock for ReplaySystemClock {
+    fn resolution(&self) -> Duration {
+        self.replay
+            .next_as(Source::SystemClock, "resolution", json!({}))
+            .unwrap_or_default()
+    }
+    fn now(&self, precision: Duration) -> SystemTime {
+        let now = self
+            .replay
+            .next_as(Source::SystemClock, "now", self::precision(precision))
+            .unwrap_or(std::time::UNIX_EPOCH);
+        SystemTime::from_std(now)
+    }
+}
+
+pub(super) struct ReplayMonotonicClock {
+    replay: Replay,
+    start: Instant,
+}
+
+impl ReplayMonotonicClock {
+    pub(super) fn new(replay: Replay) -> Self {
+        ReplayMonotonicClock {
+            replay,
+            start: Instant::from_std(std::time::Instant::now()),
+        }
+    }
+}
+
+impl WasiMonotonicClock for ReplayMonotonicClock {
+    fn resolution(&self) -> Duration {
+        self.replay
+            .next_as(Source::MonotonicClock, "resolution", json!({}))
+            .unwrap_or_default()
+    }
+    fn now(&self, precision: Duration) -> Instant {
+        let elapsed: Duration = self
+            .replay
+            .next_as(Source::MonotonicClock, "now", self::precision(precision))
+            .unwrap_or_default();
+        self.start + elapsed
+    }
+}
+
+/// A random source whose output is recorded.
+pub struct RecordingRandom<R> {
+    recorder: Recorder,
+    inner: R,
+}
+
+impl<R: Read> RecordingRandom<R> {
+    pub(super) fn new(recorder: Recorder, inner: R) -> Self {
+        RecordingRandom { recorder, inner }
+    }
+}
+
+impl<R: Read> Read for RecordingRandom<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.recorder.record(
+            Source::Random,
+            "read",
+            json!({ "len": buf.len() }),
+            Ok(hex(&buf[..n])),
+        );
+        Ok(n)
+    }
+}
+
+/// A random source that serves recorded bytes.
+pub struct ReplayRandom {
+    replay: Replay,
+}
+
+impl ReplayRandom {
+    pub(super) fn new(replay: Replay) -> Self {
+        ReplayRandom { replay }
+    }
+}
+
+impl Read for ReplayRandom {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self
+            .replay
+            .next_as::<String>(Source::Random, "read", json!({ "len": buf.len() }))
+            .and_then(|data| unhex(&data))
+            .map_err(|e| io::Error::other(format!("{:#}", e)))?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+}
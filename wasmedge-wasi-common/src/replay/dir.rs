@@ -0,0 +1,308 @@
+use super::file::{RecordingFile, ReplayFile};
+use super::{time_spec, RecordedError, Recorder, Replay, Source};
+use crate::clocks::SystemTimeSpec;
+use crate::dir::{CopyPolicy, ReaddirCursor, ReaddirEntity, WasiDir};
+use crate::error::{Error, ErrorExt};
+use crate::file::{FdFlags, Filestat, OFlags, WasiFile};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::path::PathBuf;
+
+type ReaddirIter = Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>;
+
+/// A directory whose every call is recorded. Files and directories opened through it are
+/// recorded too.
+pub(super) struct RecordingDir {
+    recorder: Recorder,
+    handle: u32,
+    inner: Box<dyn WasiDir>,
+}
+
+impl RecordingDir {
+    pub(super) fn new(recorder: Recorder, inner: Box<dyn WasiDir>) -> Self {
+        RecordingDir {
+            handle: recorder.next_handle(),
+            recorder,
+            inner,
+        }
+    }
+
+    pub(super) fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    fn record<T: Serialize>(
+        &self,
+        op: &str,
+        input: Value,
+        result: Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.recorder
+            .record(Source::Dir(self.handle), op, input, result.as_ref());
+        result
+    }
+
+    /// The recorded directory behind `dir`. Renames and links can only be recorded between
+    /// recorded directories.
+    fn unwrap_dir(dir: &dyn WasiDir) -> Result<&RecordingDir, Error> {
+        dir.as_any()
+            .downcast_ref::<RecordingDir>()
+            .ok_or_else(|| Error::badf().context("directory is not being recorded"))
+    }
+}
+
+impl WasiDir for RecordingDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let input = json!({
+            "symlink_follow": symlink_follow,
+            "path": path,
+            "oflags": oflags.bits(),
+            "read": read,
+            "write": write,
+            "fdflags": fdflags.bits(),
+        });
+        let result = self
+            .inner
+            .open_file(symlink_follow, path, oflags, read, write, fdflags)
+            .map(|f| RecordingFile::new(self.recorder.clone(), f));
+        self.recorder.record(
+            Source::Dir(self.handle),
+            "open_file",
+            input,
+            result.as_ref().map(|f| f.handle()),
+        );
+        Ok(Box::new(result?))
+    }
+    fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let input = json!({ "symlink_follow": symlink_follow, "path": path });
+        let result = self
+            .inner
+            .open_dir(symlink_follow, path)
+            .map(|d| RecordingDir::new(self.recorder.clone(), d));
+        self.recorder.record(
+            Source::Dir(self.handle),
+            "open_dir",
+            input,
+            result.as_ref().map(|d| d.handle),
+        );
+        Ok(Box::new(result?))
+    }
+    fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let result = self.inner.create_dir(path);
+        self.record("create_dir", json!({ "path": path }), result)
+    }
+    fn readdir(&self, cursor: ReaddirCursor) -> Result<ReaddirIter, Error> {
+        let input = json!({ "cursor": u64::from(cursor) });
+        let result = self.inner.readdir(cursor).map(|entries| {
+            entries
+                .map(|entry| entry.map_err(|e| RecordedError::new(&e)))
+                .collect::<Vec<_>>()
+        });
+        let entries = self.record("readdir", input, result)?;
+        Ok(Box::new(
+            entries
+                .into_iter()
+                .map(|entry| entry.map_err(|e| e.to_error())),
+        ))
+    }
+    fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let input = json!({ "old_path": old_path, "new_path": new_path });
+        let result = self.inner.symlink(old_path, new_path);
+        self.record("symlink", input, result)
+    }
+    fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        let result = self.inner.remove_dir(path);
+        self.record("remove_dir", json!({ "path": path }), result)
+    }
+    fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        let result = self.inner.unlink_file(path);
+        self.record("unlink_file", json!({ "path": path }), result)
+    }
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        let result = self.inner.read_link(path);
+        self.record("read_link", json!({ "path": path }), result)
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        let result = self.inner.get_filestat();
+        self.record("get_filestat", json!({}), result)
+    }
+    fn get_path_filestat(&self, path: &str, follow_symlinks: bool) -> Result<Filestat, Error> {
+        let input = json!({ "path": path, "follow_symlinks": follow_symlinks });
+        let result = self.inner.get_path_filestat(path, follow_symlinks);
+        self.record("get_path_filestat", input, result)
+    }
+    fn rename(&self, path: &str, dest_dir: &dyn WasiDir, dest_path: &str) -> Result<(), Error> {
+        let dest_dir = Self::unwrap_dir(dest_dir)?;
+        let input = json!({ "path": path, "dest_dir": dest_dir.handle, "dest_path": dest_path });
+        let result = self.inner.rename(path, &*dest_dir.inner, dest_path);
+        self.record("rename", input, result)
+    }
+    fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        let target_dir = Self::unwrap_dir(target_dir)?;
+        let input = json!({
+            "path": path,
+            "target_dir": target_dir.handle,
+            "target_path": target_path,
+        });
+        let result = self.inner.hard_link(path, &*target_dir.inner, target_path);
+        self.record("hard_link", input, result)
+    }
+    fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        let input = json!({
+            "path": path,
+            "atime": time_spec(&atime),
+            "mtime": time_spec(&mtime),
+            "follow_symlinks": follow_symlinks,
+        });
+        let result = self.inner.set_times(path, atime, mtime, follow_symlinks);
+        self.record("set_times", input, result)
+    }
+    fn copy_policy(&self) -> CopyPolicy {
+        self.inner.copy_policy()
+    }
+}
+
+/// A directory that serves recorded results.
+pub(super) struct ReplayDir {
+    replay: Replay,
+    handle: u32,
+}
+
+impl ReplayDir {
+    pub(super) fn new(replay: Replay, handle: u32) -> Self {
+        ReplayDir { replay, handle }
+    }
+
+    fn next<T: serde::de::DeserializeOwned>(&self, op: &str, input: Value) -> Result<T, Error> {
+        self.replay.next_as(Source::Dir(self.handle), op, input)
+    }
+
+    fn handle_of(dir: &dyn WasiDir) -> Result<u32, Error> {
+        dir.as_any()
+            .downcast_ref::<ReplayDir>()
+            .map(|d| d.handle)
+            .ok_or_else(|| Error::badf().context("directory is not being replayed"))
+    }
+}
+
+impl WasiDir for ReplayDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let input = json!({
+            "symlink_follow": symlink_follow,
+            "path": path,
+            "oflags": oflags.bits(),
+            "read": read,
+            "write": write,
+            "fdflags": fdflags.bits(),
+        });
+        let handle = self.next("open_file", input)?;
+        Ok(Box::new(ReplayFile::new(self.replay.clone(), handle)))
+    }
+    fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let input = json!({ "symlink_follow": symlink_follow, "path": path });
+        let handle = self.next("open_dir", input)?;
+        Ok(Box::new(ReplayDir::new(self.replay.clone(), handle)))
+    }
+    fn create_dir(&self, path: &str) -> Result<(), Error> {
+        self.next("create_dir", json!({ "path": path }))
+    }
+    fn readdir(&self, cursor: ReaddirCursor) -> Result<ReaddirIter, Error> {
+        let input = json!({ "cursor": u64::from(cursor) });
+        let entries: Vec<Result<ReaddirEntity, RecordedError>> = self.next("readdir", input)?;
+        Ok(Box::new(
+            entries
+                .into_iter()
+                .map(|entry| entry.map_err(|e| e.to_error())),
+        ))
+    }
+    fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.next(
+            "symlink",
+            json!({ "old_path": old_path, "new_path": new_path }),
+        )
+    }
+    fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        self.next("remove_dir", json!({ "path": path }))
+    }
+    fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        self.next("unlink_file", json!({ "path": path }))
+    }
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.next("read_link", json!({ "path": path }))
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.next("get_filestat", json!({}))
+    }
+    fn get_path_filestat(&self, path: &str, follow_symlinks: bool) -> Result<Filestat, Error> {
+        let input = json!({ "path": path, "follow_symlinks": follow_symlinks });
+        self.next("get_path_filestat", input)
+    }
+    fn rename(&self, path: &str, dest_dir: &dyn WasiDir, dest_path: &str) -> Result<(), Error> {
+        let dest_dir = Self::handle_of(dest_dir)?;
+        let input = json!({ "path": path, "dest_dir": dest_dir, "dest_path": dest_path });
+        self.next("rename", input)
+    }
+    fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        let target_dir = Self::handle_of(target_dir)?;
+        let input = json!({
+            "path": path,
+            "target_dir": target_dir,
+            "target_path": target_path,
+        });
+        self.next("hard_link", input)
+    }
+    fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        let input = json!({
+            "path": path,
+            "atime": time_spec(&atime),
+            "mtime": time_spec(&mtime),
+            "follow_symlinks": follow_symlinks,
+        });
+        self.next("set_times", input)
+    }
+}
@@ -0,0 +1,375 @@
+use super::{hex, time_spec, unhex, Recorder, Replay, Source};
+use crate::clocks::SystemTimeSpec;
+use crate::error::Error;
+use crate::file::{
+    Advice, FdFlags, FileType, Filestat, RiFlags, RoFlags, SdFlags, SiFlags, Timeouts, WasiFile,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::io::{IoSlice, IoSliceMut, SeekFrom};
+
+/// The first `n` bytes held by `bufs`.
+fn gather(bufs: &[IoSliceMut<'_>], n: u64) -> Vec<u8> {
+    bufs.iter()
+        .flat_map(|buf| buf.iter())
+        .take(n as usize)
+        .copied()
+        .collect()
+}
+
+/// Copy `data` into `bufs`, returning how much fit.
+fn scatter(bufs: &mut [IoSliceMut<'_>], data: &[u8]) -> u64 {
+    let mut rest = data;
+    for buf in bufs.iter_mut() {
+        let n = buf.len().min(rest.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        rest = &rest[n..];
+    }
+    (data.len() - rest.len()) as u64
+}
+
+fn concat(bufs: &[IoSlice<'_>]) -> Vec<u8> {
+    bufs.iter().flat_map(|buf| buf.iter()).copied().collect()
+}
+
+fn len(bufs: &[IoSliceMut<'_>]) -> usize {
+    bufs.iter().map(|buf| buf.len()).sum()
+}
+
+fn seek_from(pos: SeekFrom) -> Value {
+    match pos {
+        SeekFrom::Start(n) => json!({ "start": n }),
+        SeekFrom::End(n) => json!({ "end": n }),
+        SeekFrom::Current(n) => json!({ "current": n }),
+    }
+}
+
+/// A file whose every call is recorded.
+pub(super) struct RecordingFile {
+    recorder: Recorder,
+    handle: u32,
+    inner: Box<dyn WasiFile>,
+}
+
+impl RecordingFile {
+    pub(super) fn new(recorder: Recorder, inner: Box<dyn WasiFile>) -> Self {
+        RecordingFile {
+            handle: recorder.next_handle(),
+            recorder,
+            inner,
+        }
+    }
+
+    pub(super) fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    fn record<T: Serialize>(
+        &self,
+        op: &str,
+        input: Value,
+        result: Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.recorder
+            .record(Source::File(self.handle), op, input, result.as_ref());
+        result
+    }
+
+    /// Record a read, with the bytes read as its output.
+    fn record_read(
+        &self,
+        op: &str,
+        input: Value,
+        bufs: &[IoSliceMut<'_>],
+        result: Result<u64, Error>,
+    ) -> Result<u64, Error> {
+        let output = result.as_ref().map(|n| hex(&gather(bufs, *n)));
+        self.recorder
+            .record(Source::File(self.handle), op, input, output);
+        result
+    }
+}
+
+impl WasiFile for RecordingFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        let result = self.inner.get_filetype();
+        self.record("get_filetype", json!({}), result)
+    }
+    #[cfg(unix)]
+    fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+        self.inner.pollable()
+    }
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+        // Configuration from the host, not something the guest can observe: not recorded.
+        self.inner.set_timeouts(timeouts)
+    }
+    fn isatty(&mut self) -> bool {
+        let isatty = self.inner.isatty();
+        self.recorder
+            .record(Source::File(self.handle), "isatty", json!({}), Ok(isatty));
+        isatty
+    }
+    fn sock_accept(&mut self, fdflags: FdFlags) -> Result<Box<dyn WasiFile>, Error> {
+        let result = self
+            .inner
+            .sock_accept(fdflags)
+            .map(|f| RecordingFile::new(self.recorder.clone(), f));
+        self.recorder.record(
+            Source::File(self.handle),
+            "sock_accept",
+            json!({ "fdflags": fdflags.bits() }),
+            result.as_ref().map(|f| f.handle),
+        );
+        Ok(Box::new(result?))
+    }
+    fn sock_recv<'a>(
+        &mut self,
+        ri_data: &mut [IoSliceMut<'a>],
+        ri_flags: RiFlags,
+    ) -> Result<(u64, RoFlags), Error> {
+        let input = json!({ "len": len(ri_data), "ri_flags": ri_flags.bits() });
+        let result = self.inner.sock_recv(ri_data, ri_flags);
+        let output = result
+            .as_ref()
+            .map(|(n, ro_flags)| (hex(&gather(ri_data, *n)), ro_flags.bits()));
+        self.recorder
+            .record(Source::File(self.handle), "sock_recv", input, output);
+        result
+    }
+    fn sock_send<'a>(&mut self, si_data: &[IoSlice<'a>], si_flags: SiFlags) -> Result<u64, Error> {
+        let input = json!({ "data": hex(&concat(si_data)), "si_flags": si_flags.bits() });
+        let result = self.inner.sock_send(si_data, si_flags);
+        self.record("sock_send", input, result)
+    }
+    fn sock_shutdown(&mut self, how: SdFlags) -> Result<(), Error> {
+        let result = self.inner.sock_shutdown(how);
+        self.record("sock_shutdown", json!({ "how": how.bits() }), result)
+    }
+    fn datasync(&mut self) -> Result<(), Error> {
+        let result = self.inner.datasync();
+        self.record("datasync", json!({}), result)
+    }
+    fn sync(&mut self) -> Result<(), Error> {
+        let result = self.inner.sync();
+        self.record("sync", json!({}), result)
+    }
+    fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        let result = self.inner.get_fdflags().map(|flags| flags.bits());
+        let bits = self.record("get_fdflags", json!({}), result)?;
+        Ok(FdFlags::from_bits_truncate(bits))
+    }
+    fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        let result = self.inner.set_fdflags(flags);
+        self.record("set_fdflags", json!({ "flags": flags.bits() }), result)
+    }
+    fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        let result = self.inner.get_filestat();
+        self.record("get_filestat", json!({}), result)
+    }
+    fn set_filestat_size(&mut self, size: u64) -> Result<(), Error> {
+        let result = self.inner.set_filestat_size(size);
+        self.record("set_filestat_size", json!({ "size": size }), result)
+    }
+    fn advise(&mut self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+        let input = json!({ "offset": offset, "len": len, "advice": advice });
+        let result = self.inner.advise(offset, len, advice);
+        self.record("advise", input, result)
+    }
+    fn allocate(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        let result = self.inner.allocate(offset, len);
+        self.record("allocate", json!({ "offset": offset, "len": len }), result)
+    }
+    fn set_times(
+        &mut self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        let input = json!({ "atime": time_spec(&atime), "mtime": time_spec(&mtime) });
+        let result = self.inner.set_times(atime, mtime);
+        self.record("set_times", input, result)
+    }
+    fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let input = json!({ "len": len(bufs) });
+        let result = self.inner.read_vectored(bufs);
+        self.record_read("read_vectored", input, bufs, result)
+    }
+    fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        let input = json!({ "len": len(bufs), "offset": offset });
+        let result = self.inner.read_vectored_at(bufs, offset);
+        self.record_read("read_vectored_at", input, bufs, result)
+    }
+    fn write_vectored<'a>(&mut self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        let input = json!({ "data": hex(&concat(bufs)) });
+        let result = self.inner.write_vectored(bufs);
+        self.record("write_vectored", input, result)
+    }
+    fn write_vectored_at<'a>(&mut self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        let input = json!({ "data": hex(&concat(bufs)), "offset": offset });
+        let result = self.inner.write_vectored_at(bufs, offset);
+        self.record("write_vectored_at", input, result)
+    }
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let result = self.inner.seek(pos);
+        self.record("seek", seek_from(pos), result)
+    }
+    fn offset(&self) -> Option<u64> {
+        self.inner.offset()
+    }
+    fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        let input = json!({ "len": buf.len() });
+        let result = self.inner.peek(buf);
+        let output = result.as_ref().map(|n| hex(&buf[..*n as usize]));
+        self.recorder
+            .record(Source::File(self.handle), "peek", input, output);
+        result
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        let result = self.inner.num_ready_bytes();
+        self.record("num_ready_bytes", json!({}), result)
+    }
+    fn readable(&self) -> Result<(), Error> {
+        let result = self.inner.readable();
+        self.record("readable", json!({}), result)
+    }
+    fn writable(&self) -> Result<(), Error> {
+        let result = self.inner.writable();
+        self.record("writable", json!({}), result)
+    }
+}
+
+/// A file that serves recorded results.
+pub(super) struct ReplayFile {
+    replay: Replay,
+    handle: u32,
+}
+
+impl ReplayFile {
+    pub(super) fn new(replay: Replay, handle: u32) -> Self {
+        ReplayFile { replay, handle }
+    }
+
+    fn next<T: serde::de::DeserializeOwned>(&self, op: &str, input: Value) -> Result<T, Error> {
+        self.replay.next_as(Source::File(self.handle), op, input)
+    }
+
+    /// Replay a read, copying the recorded bytes into `bufs`.
+    fn next_read(&self, op: &str, input: Value, bufs: &mut [IoSliceMut<'_>]) -> Result<u64, Error> {
+        let data = unhex(&self.next::<String>(op, input)?)?;
+        Ok(scatter(bufs, &data))
+    }
+}
+
+impl WasiFile for ReplayFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_filetype(&mut self) -> Result<FileType, Error> {
+        self.next("get_filetype", json!({}))
+    }
+    fn isatty(&mut self) -> bool {
+        self.next("isatty", json!({})).unwrap_or(false)
+    }
+    fn sock_accept(&mut self, fdflags: FdFlags) -> Result<Box<dyn WasiFile>, Error> {
+        let handle = self.next("sock_accept", json!({ "fdflags": fdflags.bits() }))?;
+        Ok(Box::new(ReplayFile::new(self.replay.clone(), handle)))
+    }
+    fn sock_recv<'a>(
+        &mut self,
+        ri_data: &mut [IoSliceMut<'a>],
+        ri_flags: RiFlags,
+    ) -> Result<(u64, RoFlags), Error> {
+        let input = json!({ "len": len(ri_data), "ri_flags": ri_flags.bits() });
+        let (data, ro_flags): (String, u32) = self.next("sock_recv", input)?;
+        let n = scatter(ri_data, &unhex(&data)?);
+        Ok((n, RoFlags::from_bits_truncate(ro_flags)))
+    }
+    fn sock_send<'a>(&mut self, si_data: &[IoSlice<'a>], si_flags: SiFlags) -> Result<u64, Error> {
+        let input = json!({ "data": hex(&concat(si_data)), "si_flags": si_flags.bits() });
+        self.next("sock_send", input)
+    }
+    fn sock_shutdown(&mut self, how: SdFlags) -> Result<(), Error> {
+        self.next("sock_shutdown", json!({ "how": how.bits() }))
+    }
+    fn datasync(&mut self) -> Result<(), Error> {
+        self.next("datasync", json!({}))
+    }
+    fn sync(&mut self) -> Result<(), Error> {
+        self.next("sync", json!({}))
+    }
+    fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(FdFlags::from_bits_truncate(
+            self.next("get_fdflags", json!({}))?,
+        ))
+    }
+    fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.next("set_fdflags", json!({ "flags": flags.bits() }))
+    }
+    fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        self.next("get_filestat", json!({}))
+    }
+    fn set_filestat_size(&mut self, size: u64) -> Result<(), Error> {
+        self.next("set_filestat_size", json!({ "size": size }))
+    }
+    fn advise(&mut self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+        self.next(
+            "advise",
+            json!({ "offset": offset, "len": len, "advice": advice }),
+        )
+    }
+    fn allocate(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        self.next("allocate", json!({ "offset": offset, "len": len }))
+    }
+    fn set_times(
+        &mut self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        let input = json!({ "atime": time_spec(&atime), "mtime": time_spec(&mtime) });
+        self.next("set_times", input)
+    }
+    fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let input = json!({ "len": len(bufs) });
+        self.next_read("read_vectored", input, bufs)
+    }
+    fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        let input = json!({ "len": len(bufs), "offset": offset });
+        self.next_read("read_vectored_at", input, bufs)
+    }
+    fn write_vectored<'a>(&mut self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        self.next("write_vectored", json!({ "data": hex(&concat(bufs)) }))
+    }
+    fn write_vectored_at<'a>(&mut self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        let input = json!({ "data": hex(&concat(bufs)), "offset": offset });
+        self.next("write_vectored_at", input)
+    }
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        self.next("seek", seek_from(pos))
+    }
+    fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        let data = unhex(&self.next::<String>("peek", json!({ "len": buf.len() }))?)?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n as u64)
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.next("num_ready_bytes", json!({}))
+    }
+    fn readable(&self) -> Result<(), Error> {
+        self.next("readable", json!({}))
+    }
+    fn writable(&self) -> Result<(), Error> {
+        self.next("writable", json!({}))
+    }
+}
@@ -0,0 +1,751 @@
+//! Record and replay of everything a guest observes, for reproducing failures deterministically.
+//!
+//! Recording wraps a fully set up `WasiEnviron` with `Recorder::environ`. Every file and
+//! directory in its table is replaced by a decorator that logs each call into the backend,
+//! together with its inputs and its result: bytes read, readdir entries, file stats, errors and
+//! so on. Files and directories opened later are wrapped in turn. Clocks and the random source
+//! live outside the environ, so the host wraps them itself with `Recorder::system_clock`,
+//! `Recorder::monotonic_clock` and `Recorder::random`. The guest's syscalls are logged too,
+//! before any of the backend calls they make.
+//!
+//! `Replay::environ` rebuilds the environ from a `Trace` with backends that serve the recorded
+//! results back instead of touching the host. Each call is checked against the next event in
+//! the trace; at the first mismatch replay stops with a trap, and `Replay::divergence` reports
+//! the syscall during which the guest's behaviour changed.
+mod clocks;
+mod dir;
+mod file;
+
+pub use self::clocks::{RecordingRandom, ReplayRandom};
+
+use crate::clocks::{SystemTimeSpec, WasiMonotonicClock, WasiSystemClock};
+use crate::dir::{DirCaps, DirEntry};
+use crate::environ::WasiEnviron;
+use crate::error::{Errno, Error, ErrorExt};
+use crate::file::{FdFlags, FileCaps, FileEntry, OFlags};
+use crate::string_array::StringArrayError;
+use crate::{Ciovec, CiovecArray, IovecArray, WasiSnapshotPreview1};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Where an event happened. Files and directories are numbered in the order they were wrapped,
+/// which replay reproduces as long as the guest behaves the same.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Source {
+    Syscall,
+    File(u32),
+    Dir(u32),
+    SystemClock,
+    MonotonicClock,
+    Random,
+}
+
+/// One recorded call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub source: Source,
+    pub op: String,
+    pub input: Value,
+    pub output: Result<Value, RecordedError>,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {}({})", self.source, self.op, self.input)
+    }
+}
+
+/// An error returned by a recorded call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedError {
+    /// The errno the guest saw, or `None` if the error trapped.
+    pub errno: Option<Errno>,
+    /// The error's context chain.
+    pub message: String,
+}
+
+impl RecordedError {
+    fn new(err: &Error) -> Self {
+        RecordedError {
+            errno: Errno::from_error(err),
+            message: format!("{:#}", err),
+        }
+    }
+
+    fn to_error(&self) -> Error {
+        match self.errno {
+            Some(errno) => Error::new(errno).context(self.message.clone()),
+            None => Error::trap(self.message.clone()),
+        }
+    }
+}
+
+/// A file or directory in the table when recording started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Descriptor {
+    File {
+        fd: u32,
+        handle: u32,
+        caps: u32,
+    },
+    Dir {
+        fd: u32,
+        handle: u32,
+        caps: u32,
+        file_caps: u32,
+        preopen_path: Option<PathBuf>,
+    },
+}
+
+/// Everything needed to replay a run: the environ as it was set up, and the events that
+/// followed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Trace {
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    pub descriptors: Vec<Descriptor>,
+    pub events: Vec<Event>,
+}
+
+impl Trace {
+    pub fn save(&self, writer: impl Write) -> Result<(), Error> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn load(reader: impl Read) -> Result<Self, Error> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+struct RecorderInner {
+    trace: Trace,
+    next_handle: u32,
+}
+
+/// Collects a `Trace`. Clones share the same trace.
+#[derive(Clone)]
+pub struct Recorder(Arc<Mutex<RecorderInner>>);
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder(Arc::new(Mutex::new(RecorderInner {
+            trace: Trace::default(),
+            next_handle: 0,
+        })))
+    }
+
+    /// Start recording `environ`. Its args, env and descriptor table are saved as they are now,
+    /// so finish setting it up first.
+    pub fn environ(&self, mut environ: WasiEnviron) -> Recording {
+        let mut descriptors = Vec::new();
+        for fd in environ.table.keys() {
+            // The entries are wrapped where they are, so they keep their quota, timeouts and
+            // origin.
+            if environ.table.is::<FileEntry>(fd) {
+                let entry = environ.table.delete(fd).unwrap();
+                let mut handle = 0;
+                let entry = entry.downcast::<FileEntry>().unwrap().map_file(|file| {
+                    let file = file::RecordingFile::new(self.clone(), file);
+                    handle = file.handle();
+                    Box::new(file)
+                });
+                descriptors.push(Descriptor::File {
+                    fd,
+                    handle,
+                    caps: entry.caps().bits(),
+                });
+                environ.table.insert_at(fd, Box::new(entry));
+            } else if environ.table.is::<DirEntry>(fd) {
+                let entry = environ.table.delete(fd).unwrap();
+                let mut handle = 0;
+                let entry = entry.downcast::<DirEntry>().unwrap().map_dir(|dir| {
+                    let dir = dir::RecordingDir::new(self.clone(), dir);
+                    handle = dir.handle();
+                    Box::new(dir)
+                });
+                let fdstat = entry.get_dir_fdstat();
+                descriptors.push(Descriptor::Dir {
+                    fd,
+                    handle,
+                    caps: fdstat.dir_caps.bits(),
+                    file_caps: fdstat.file_caps.bits(),
+                    preopen_path: entry.preopen_path().clone(),
+                });
+                environ.table.insert_at(fd, Box::new(entry));
+            }
+        }
+
+        {
+            let mut inner = self.0.lock().unwrap();
+            inner.trace.args = environ
+                .args
+                .elements()
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            inner.trace.env = environ
+                .env
+                .elements()
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            inner.trace.descriptors = descriptors;
+        }
+
+        Recording {
+            environ,
+            recorder: self.clone(),
+        }
+    }
+
+    pub fn system_clock(&self, clock: Box<dyn WasiSystemClock>) -> Box<dyn WasiSystemClock> {
+        Box::new(clocks::RecordingSystemClock::new(self.clone(), clock))
+    }
+
+    pub fn monotonic_clock(
+        &self,
+        clock: Box<dyn WasiMonotonicClock>,
+    ) -> Box<dyn WasiMonotonicClock> {
+        Box::new(clocks::RecordingMonotonicClock::new(self.clone(), clock))
+    }
+
+    pub fn random<R: Read>(&self, random: R) -> RecordingRandom<R> {
+        RecordingRandom::new(self.clone(), random)
+    }
+
+    /// A copy of everything recorded so far.
+    pub fn trace(&self) -> Trace {
+        self.0.lock().unwrap().trace.clone()
+    }
+
+    pub fn save(&self, writer: impl Write) -> Result<(), Error> {
+        self.0.lock().unwrap().trace.save(writer)
+    }
+
+    fn next_handle(&self) -> u32 {
+        let mut inner = self.0.lock().unwrap();
+        let handle = inner.next_handle;
+        inner.next_handle += 1;
+        handle
+    }
+
+    /// Record a call that has completed.
+    fn record<T: Serialize>(
+        &self,
+        source: Source,
+        op: &str,
+        input: Value,
+        result: Result<T, &Error>,
+    ) {
+        let index = self.begin(source, op, input);
+        self.end(index, result);
+    }
+
+    /// Record the start of a call whose result is only known once the events it causes have
+    /// been recorded.
+    fn begin(&self, source: Source, op: &str, input: Value) -> usize {
+        let mut inner = self.0.lock().unwrap();
+        inner.trace.events.push(Event {
+            source,
+            op: op.to_owned(),
+            input,
+            output: Ok(Value::Null),
+        });
+        inner.trace.events.len() - 1
+    }
+
+    fn end<T: Serialize>(&self, index: usize, result: Result<T, &Error>) {
+        let output = match result {
+            Ok(value) => Ok(serde_json::to_value(value).unwrap_or(Value::Null)),
+            Err(e) => Err(RecordedError::new(e)),
+        };
+        self.0.lock().unwrap().trace.events[index].output = output;
+    }
+}
+
+/// Where a replay stopped matching its trace.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// The index of the first event that didn't match.
+    pub index: usize,
+    /// The number of the guest syscall during which it happened, counting from 0, and its
+    /// name.
+    pub syscall: Option<(usize, String)>,
+    /// The recorded event, or `None` if the trace had ended.
+    pub expected: Option<Event>,
+    /// The call made instead. Its output is meaningless.
+    pub actual: Event,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "replay diverged at event {}", self.index)?;
+        if let Some((n, name)) = &self.syscall {
+            write!(f, ", in syscall {} `{}`", n, name)?;
+        }
+        match &self.expected {
+            Some(expected) => write!(f, ": recorded {}, but got {}", expected, self.actual),
+            None => write!(f, ": the trace ended, but got {}", self.actual),
+        }
+    }
+}
+
+struct ReplayInner {
+    trace: Trace,
+    pos: usize,
+    syscalls: usize,
+    syscall: Option<(usize, String)>,
+    divergence: Option<Divergence>,
+}
+
+/// Serves a `Trace` back. Clones share the same position in the trace.
+#[derive(Clone)]
+pub struct Replay(Arc<Mutex<ReplayInner>>);
+
+impl Replay {
+    pub fn new(trace: Trace) -> Self {
+        Replay(Arc::new(Mutex::new(ReplayInner {
+            trace,
+            pos: 0,
+            syscalls: 0,
+            syscall: None,
+            divergence: None,
+        })))
+    }
+
+    pub fn load(reader: impl Read) -> Result<Self, Error> {
+        Ok(Self::new(Trace::load(reader)?))
+    }
+
+    /// Rebuild the recorded environ, with backends that replay the trace. Fails if the trace's
+    /// arguments or environment variables couldn't have been given to a guest.
+    pub fn environ(&self) -> Result<Replaying, StringArrayError> {
+        let trace = self.0.lock().unwrap().trace.clone();
+        let mut environ = WasiEnviron::new();
+        for arg in &trace.args {
+            environ.args.push(arg.clone())?;
+        }
+        for var in &trace.env {
+            environ.env.push(var.clone())?;
+        }
+        for descriptor in trace.descriptors {
+            match descriptor {
+                Descriptor::File { fd, handle, caps } => {
+                    let file = file::ReplayFile::new(self.clone(), handle);
+                    environ.insert_file(fd, Box::new(file), FileCaps::from_bits_truncate(caps));
+                }
+                Descriptor::Dir {
+                    fd,
+                    handle,
+                    caps,
+                    file_caps,
+                    preopen_path,
+                } => {
+                    let dir = dir::ReplayDir::new(self.clone(), handle);
+                    environ.table.insert_at(
+                        fd,
+                        Box::new(DirEntry::new(
+                            DirCaps::from_bits_truncate(caps),
+                            FileCaps::from_bits_truncate(file_caps),
+                            preopen_path,
+                            Box::new(dir),
+                        )),
+                    );
+                }
+            }
+        }
+
+        Ok(Replaying {
+            environ,
+            replay: self.clone(),
+        })
+    }
+
+    pub fn system_clock(&self) -> Box<dyn WasiSystemClock> {
+        Box::new(clocks::ReplaySystemClock::new(self.clone()))
+    }
+
+    pub fn monotonic_clock(&self) -> Box<dyn WasiMonotonicClock> {
+        Box::new(clocks::ReplayMonotonicClock::new(self.clone()))
+    }
+
+    pub fn random(&self) -> ReplayRandom {
+        ReplayRandom::new(self.clone())
+    }
+
+    /// The first point at which the run stopped matching the trace, if any.
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.0.lock().unwrap().divergence.clone()
+    }
+
+    /// Whether every recorded event has been replayed.
+    pub fn is_finished(&self) -> bool {
+        let inner = self.0.lock().unwrap();
+        inner.pos == inner.trace.events.len()
+    }
+
+    /// Check a call against the next recorded event and return the recorded output.
+    ///
+    /// Once replay has diverged, every call fails.
+    fn next(&self, source: Source, op: &str, input: Value) -> Result<Value, Error> {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(divergence) = &inner.divergence {
+            return Err(Error::trap(divergence.to_string()));
+        }
+
+        if source == Source::Syscall {
+            inner.syscall = Some((inner.syscalls, op.to_owned()));
+            inner.syscalls += 1;
+        }
+
+        let expected = inner.trace.events.get(inner.pos);
+        match expected {
+            Some(e) if e.source == source && e.op == op && e.input == input => {
+                let output = e.output.clone();
+                inner.pos += 1;
+                output.map_err(|e| e.to_error())
+            }
+            _ => {
+                let divergence = Divergence {
+                    index: inner.pos,
+                    syscall: inner.syscall.clone(),
+                    expected: expected.cloned(),
+                    actual: Event {
+                        source,
+                        op: op.to_owned(),
+                        input,
+                        output: Ok(Value::Null),
+                    },
+                };
+                let err = Error::trap(divergence.to_string());
+                inner.divergence = Some(divergence);
+                Err(err)
+            }
+        }
+    }
+
+    /// Check a guest syscall against the trace. This only fails if replay has diverged: the
+    /// syscall's result comes from replaying the backend calls it makes.
+    fn syscall(&self, op: &str, input: Value) -> Result<(), Error> {
+        match self.next(Source::Syscall, op, input) {
+            Err(e) if self.divergence().is_some() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like `next`, but decode the recorded output as a `T`.
+    fn next_as<T: DeserializeOwned>(
+        &self,
+        source: Source,
+        op: &str,
+        input: Value,
+    ) -> Result<T, Error> {
+        let output = self.next(source, op, input)?;
+        serde_json::from_value(output)
+            .map_err(|e| Error::trap(format!("malformed output for {}: {}", op, e)))
+    }
+}
+
+/// Hex-encode a buffer for the trace.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, Error> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| Error::trap(format!("malformed hex in trace: {:?}", s)))
+        })
+        .collect()
+}
+
+/// Encode a timestamp argument, which is part of a call's input.
+fn time_spec(spec: &Option<SystemTimeSpec>) -> Value {
+    match spec {
+        None => Value::Null,
+        Some(SystemTimeSpec::SymbolicNow) => json!("now"),
+        Some(SystemTimeSpec::Absolute(time)) => json!(time.into_std()),
+    }
+}
+
+/// Concatenate the buffers described by `iovs`.
+fn iovs_bytes(iovs: CiovecArray) -> Vec<u8> {
+    iovs.iter()
+        .flat_map(|iov| unsafe { std::slice::from_raw_parts(iov.buf, iov.buf_len) })
+        .copied()
+        .collect()
+}
+
+/// A `WasiEnviron` being recorded. Every syscall is logged before it runs.
+pub struct Recording {
+    environ: WasiEnviron,
+    recorder: Recorder,
+}
+
+impl Recording {
+    pub fn environ(&self) -> &WasiEnviron {
+        &self.environ
+    }
+
+    pub fn recorder(&self) -> &Recorder {
+        &self.recorder
+    }
+
+    pub fn into_inner(self) -> WasiEnviron {
+        self.environ
+    }
+}
+
+impl WasiSnapshotPreview1 for Recording {
+    fn args_sizes_get(&self) -> (i32, i32) {
+        let index = self
+            .recorder
+            .begin(Source::Syscall, "args_sizes_get", json!({}));
+        let sizes = self.environ.args_sizes_get();
+        self.recorder.end(index, Ok(sizes));
+        sizes
+    }
+
+    fn args_get(&self, out: &mut Vec<Ciovec>) {
+        self.recorder
+            .record(Source::Syscall, "args_get", json!({}), Ok(()));
+        self.environ.args_get(out)
+    }
+
+    fn environ_sizes_get(&self) -> (i32, i32) {
+        let index = self
+            .recorder
+            .begin(Source::Syscall, "environ_sizes_get", json!({}));
+        let sizes = self.environ.environ_sizes_get();
+        self.recorder.end(index, Ok(sizes));
+        sizes
+    }
+
+    fn environ_get(&self, out: &mut Vec<Ciovec>) {
+        self.recorder
+            .record(Source::Syscall, "environ_get", json!({}), Ok(()));
+        self.environ.environ_get(out)
+    }
+
+    fn fd_close(&mut self, fd: i32) -> Result<(), Error> {
+        let index = self
+            .recorder
+            .begin(Source::Syscall, "fd_close", json!({ "fd": fd }));
+        let result = self.environ.fd_close(fd);
+        self.recorder.end(index, result.as_ref());
+        result
+    }
+
+    fn fd_read(&mut self, fd: i32, iovs: IovecArray) -> Result<i32, Error> {
+        // The data read is recorded by the file it came from.
+        let len: usize = iovs.iter().map(|iov| iov.buf_len).sum();
+        let input = json!({ "fd": fd, "len": len });
+        let index = self.recorder.begin(Source::Syscall, "fd_read", input);
+        let result = self.environ.fd_read(fd, iovs);
+        self.recorder.end(index, result.as_ref());
+        result
+    }
+
+    fn fd_write(&mut self, fd: i32, iovs: CiovecArray) -> Result<i32, Error> {
+        let input = json!({ "fd": fd, "data": hex(&iovs_bytes(iovs)) });
+        let index = self.recorder.begin(Source::Syscall, "fd_write", input);
+        let result = self.environ.fd_write(fd, iovs);
+        self.recorder.end(index, result.as_ref());
+        result
+    }
+
+    fn path_open(
+        &mut self,
+        dirfd: i32,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        dir_caps: DirCaps,
+        file_caps: FileCaps,
+        fdflags: FdFlags,
+    ) -> Result<i32, Error> {
+        let input = json!({
+            "dirfd": dirfd,
+            "symlink_follow": symlink_follow,
+            "path": path,
+            "oflags": oflags.bits(),
+            "dir_caps": dir_caps.bits(),
+            "file_caps": file_caps.bits(),
+            "fdflags": fdflags.bits(),
+        });
+        let index = self.recorder.begin(Source::Syscall, "path_open", input);
+        let result = self.environ.path_open(
+            dirfd,
+            symlink_follow,
+            path,
+            oflags,
+            dir_caps,
+            file_caps,
+            fdflags,
+        );
+        self.recorder.end(index, result.as_ref());
+        result
+    }
+
+    fn proc_exit(&mut self, code: i32) {
+        let input = json!({ "code": code });
+        self.recorder
+            .record(Source::Syscall, "proc_exit", input, Ok(()));
+        self.environ.proc_exit(code)
+    }
+
+    fn sock_accept(&mut self, fd: i32, fdflags: FdFlags) -> Result<i32, Error> {
+        let input = json!({ "fd": fd, "fdflags": fdflags.bits() });
+        let index = self.recorder.begin(Source::Syscall, "sock_accept", input);
+        let result = self.environ.sock_accept(fd, fdflags);
+        self.recorder.end(index, result.as_ref());
+        result
+    }
+}
+
+/// A `WasiEnviron` rebuilt from a trace. Every syscall is checked against the trace before it
+/// runs against the replaying backends.
+pub struct Replaying {
+    environ: WasiEnviron,
+    replay: Replay,
+}
+
+impl Replaying {
+    pub fn environ(&self) -> &WasiEnviron {
+        &self.environ
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    pub fn into_inner(self) -> WasiEnviron {
+        self.environ
+    }
+}
+
+// Syscalls that can't fail still run after a divergence; it is reported through
+// `Replay::divergence`, and the next fallible call traps.
+impl WasiSnapshotPreview1 for Replaying {
+    fn args_sizes_get(&self) -> (i32, i32) {
+        let _ = self.replay.syscall("args_sizes_get", json!({}));
+        self.environ.args_sizes_get()
+    }
+
+    fn args_get(&self, out: &mut Vec<Ciovec>) {
+        let _ = self.replay.syscall("args_get", json!({}));
+        self.environ.args_get(out)
+    }
+
+    fn environ_sizes_get(&self) -> (i32, i32) {
+        let _ = self.replay.syscall("environ_sizes_get", json!({}));
+        self.environ.environ_sizes_get()
+    }
+
+    fn environ_get(&self, out: &mut Vec<Ciovec>) {
+        let _ = self.replay.syscall("environ_get", json!({}));
+        self.environ.environ_get(out)
+    }
+
+    fn fd_close(&mut self, fd: i32) -> Result<(), Error> {
+        self.replay.syscall("fd_close", json!({ "fd": fd }))?;
+        self.environ.fd_close(fd)
+    }
+
+    fn fd_read(&mut self, fd: i32, iovs: IovecArray) -> Result<i32, Error> {
+        let len: usize = iovs.iter().map(|iov| iov.buf_len).sum();
+        self.replay
+            .syscall("fd_read", json!({ "fd": fd, "len": len }))?;
+        self.environ.fd_read(fd, iovs)
+    }
+
+    fn fd_write(&mut self, fd: i32, iovs: CiovecArray) -> Result<i32, Error> {
+        let input = json!({ "fd": fd, "data": hex(&iovs_bytes(iovs)) });
+        self.replay.syscall("fd_write", input)?;
+        self.environ.fd_write(fd, iovs)
+    }
+
+    fn path_open(
+        &mut self,
+        dirfd: i32,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        dir_caps: DirCaps,
+        file_caps: FileCaps,
+        fdflags: FdFlags,
+    ) -> Result<i32, Error> {
+        let input = json!({
+            "dirfd": dirfd,
+            "symlink_follow": symlink_follow,
+            "path": path,
+            "oflags": oflags.bits(),
+            "dir_caps": dir_caps.bits(),
+            "file_caps": file_caps.bits(),
+            "fdflags": fdflags.bits(),
+        });
+        self.replay.syscall("path_open", input)?;
+        self.environ.path_open(
+            dirfd,
+            symlink_follow,
+            path,
+            oflags,
+            dir_caps,
+            file_caps,
+            fdflags,
+        )
+    }
+
+    fn proc_exit(&mut self, code: i32) {
+        let _ = self.replay.syscall("proc_exit", json!({ "code": code }));
+        self.environ.proc_exit(code)
+    }
+
+    fn sock_accept(&mut self, fd: i32, fdflags: FdFlags) -> Result<i32, Error> {
+        let input = json!({ "fd": fd, "fdflags": fdflags.bits() });
+        self.replay.syscall("sock_accept", input)?;
+        self.environ.sock_accept(fd, fdflags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe::WritePipe;
+    use crate::quota::{OnExceeded, OutputQuota};
+    use crate::Ciovec;
+
+    #[test]
+    fn recording_keeps_the_output_quota() {
+        let mut environ = WasiEnviron::new();
+        environ.insert_file(1, Box::new(WritePipe::new_in_memory()), FileCaps::all());
+        let quota = OutputQuota::new(4, OnExceeded::Fail);
+        environ.set_output_quota(1, Some(quota.clone())).unwrap();
+        let mut recording = Recorder::new().environ(environ);
+        assert!(recording.environ().output_quota(1).is_some());
+
+        let data = b"too long";
+        let iovs = [Ciovec {
+            buf: data.as_ptr(),
+            buf_len: data.len(),
+        }];
+        assert_eq!(recording.fd_write(1, &iovs).unwrap(), 4);
+        assert_eq!(quota.used(), 4);
+        let err = recording.fd_write(1, &iovs).unwrap_err();
+        assert_eq!(Errno::from_error(&err), Some(Errno::Fbig));
+    }
+}
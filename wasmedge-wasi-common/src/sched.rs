@@ -0,0 +1,59 @@
+//! Types for `poll_oneoff` on an `AsyncWasiEnviron`.
+//!
+//! The environ itself does the bookkeeping of waiting on many subscriptions at once; an
+//! `AsyncWasiSched` only has to provide timers and yielding for whichever runtime the host uses.
+use crate::error::Error;
+use async_trait::async_trait;
+use std::time::Duration;
+
+#[async_trait]
+pub trait AsyncWasiSched: Send + Sync {
+    /// Resolve after `duration` has elapsed.
+    async fn sleep(&self, duration: Duration) -> Result<(), Error>;
+    /// Give other tasks on the runtime a chance to run.
+    async fn sched_yield(&self) -> Result<(), Error>;
+}
+
+/// A single event a guest wants to wait for.
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    /// Opaque value returned in the corresponding `Event`.
+    pub userdata: u64,
+    pub kind: SubscriptionKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum SubscriptionKind {
+    /// Wait for a timeout, relative to the start of the `poll_oneoff` call.
+    Clock(Duration),
+    /// Wait for the file descriptor to become readable.
+    FdRead(u32),
+    /// Wait for the file descriptor to become writable.
+    FdWrite(u32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    Clock,
+    FdRead,
+    FdWrite,
+}
+
+impl From<&SubscriptionKind> for EventKind {
+    fn from(kind: &SubscriptionKind) -> EventKind {
+        match kind {
+            SubscriptionKind::Clock(_) => EventKind::Clock,
+            SubscriptionKind::FdRead(_) => EventKind::FdRead,
+            SubscriptionKind::FdWrite(_) => EventKind::FdWrite,
+        }
+    }
+}
+
+/// A subscription that has completed.
+#[derive(Debug)]
+pub struct Event {
+    pub userdata: u64,
+    pub kind: EventKind,
+    /// For `FdRead`, the number of bytes available for reading; otherwise 0.
+    pub result: Result<u64, Error>,
+}
@@ -0,0 +1,603 @@
+//! A `WasiEnviron` that can be shared between the threads of a `wasi-threads` instance.
+//!
+//! All threads spawned through `thread-spawn` see the same args, environment and descriptor
+//! table, so a file opened by one thread can be used by any other.
+use crate::cancel::CancelHandle;
+use crate::checkpoint::HostResources;
+use crate::descriptors::FdInfo;
+use crate::dir::{DirCaps, DirEntry, DirEntryExt, WasiDir};
+use crate::environ::WasiEnviron;
+use crate::error::{Error, ErrorExt};
+use crate::file::{FdFlags, FileCaps, FileEntry, FileEntryExt, OFlags, Timeouts, WasiFile};
+use crate::limits::{Limits, Usage};
+use crate::metrics::Metrics;
+use crate::quota::OutputQuota;
+use crate::string_array::StringArray;
+use crate::table::SharedTable;
+use crate::trace::syscall_span;
+use crate::{Ciovec, CiovecArray, IovecArray, WasiSnapshotPreview1, WasiThreads};
+use std::any::Any;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// The host callback run on a freshly spawned thread.
+///
+/// It receives the shared environ, the new thread ID and the `start_arg` passed to
+/// `thread-spawn`, and is expected to instantiate the module and call its `wasi_thread_start`
+/// export with `(tid, start_arg)`.
+pub type ThreadStart = dyn Fn(SharedWasiEnviron, i32, i32) + Send + Sync;
+
+/// Thread IDs are between 1 and 0x1FFFFFFF, see the `wasi-threads` proposal.
+const MAX_TID: u32 = 0x1FFF_FFFF;
+
+#[derive(Clone)]
+pub struct SharedWasiEnviron(Arc<SharedWasiEnvironInner>);
+
+struct SharedWasiEnvironInner {
+    args: StringArray,
+    env: StringArray,
+    table: SharedTable,
+    exit_code: AtomicI32,
+    thread_start: RwLock<Option<Arc<ThreadStart>>>,
+    next_tid: AtomicU32,
+    metrics: Metrics,
+    cancel: CancelHandle,
+    /// The timeouts of connections accepted by the guest.
+    default_timeouts: Timeouts,
+    /// Only kept, to be dropped after the table.
+    _held: Vec<Box<dyn Any + Send + Sync>>,
+}
+
+impl SharedWasiEnviron {
+    pub fn new(environ: WasiEnviron) -> Self {
+        SharedWasiEnviron(Arc::new(SharedWasiEnvironInner {
+            args: environ.args,
+            env: environ.env,
+            table: SharedTable::from(environ.table),
+            exit_code: AtomicI32::new(environ.exit_code),
+            thread_start: RwLock::new(None),
+            next_tid: AtomicU32::new(1),
+            metrics: environ.metrics,
+            cancel: environ.cancel,
+            default_timeouts: environ.default_timeouts,
+            _held: environ.held,
+        }))
+    }
+
+    /// Set the callback used by `thread_spawn` to start a new instance on a new thread.
+    /// Without one, `thread_spawn` always fails.
+    pub fn set_thread_start(
+        &self,
+        f: impl Fn(SharedWasiEnviron, i32, i32) + Send + Sync + 'static,
+    ) {
+        *self.0.thread_start.write().unwrap() = Some(Arc::new(f));
+    }
+
+    pub fn args(&self) -> &StringArray {
+        &self.0.args
+    }
+
+    pub fn env(&self) -> &StringArray {
+        &self.0.env
+    }
+
+    pub fn table(&self) -> &SharedTable {
+        &self.0.table
+    }
+
+    /// The metrics shared by every thread of the instance.
+    pub fn metrics(&self) -> &Metrics {
+        &self.0.metrics
+    }
+
+    /// Set how long blocking reads and writes on the file at `fd` may wait. See
+    /// `WasiEnviron::set_timeouts`.
+    pub fn set_timeouts(&self, fd: u32, timeouts: Timeouts) -> Result<(), Error> {
+        self.table()
+            .get_mut(fd, |entry: &mut FileEntry| entry.set_timeouts(timeouts))
+            .and_then(|r| r)
+    }
+
+    /// A handle that interrupts the blocking syscalls of every thread of the instance.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.0.cancel.clone()
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.0.exit_code.load(Ordering::SeqCst)
+    }
+
+    pub fn push_file(&self, mut file: Box<dyn WasiFile>, caps: FileCaps) -> Result<u32, Error> {
+        let socket = crate::environ::is_socket(&mut *file);
+        self.table().push(Box::new(
+            FileEntry::new(caps, file).with_socket(socket).with_fdstat(),
+        ))
+    }
+
+    /// Bound how many bytes the guest may write to `fd`, from every thread, or lift the bound
+    /// with `None`.
+    pub fn set_output_quota(&self, fd: u32, quota: Option<OutputQuota>) -> Result<(), Error> {
+        self.table()
+            .get_mut(fd, |entry: &mut FileEntry| entry.set_quota(quota))
+    }
+
+    /// The output quota of `fd`, if it has one. Read the usage from it.
+    pub fn output_quota(&self, fd: u32) -> Option<OutputQuota> {
+        self.table()
+            .get(fd, |entry: &FileEntry| entry.quota().cloned())
+            .ok()
+            .flatten()
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.table().limits()
+    }
+
+    /// How many descriptors, sockets and derived directories are open, against their limits.
+    pub fn usage(&self) -> Usage {
+        self.table().usage()
+    }
+
+    /// Preopen `dir` at the guest path `path`, with all rights, and return its descriptor. Every
+    /// thread of the instance sees it at once.
+    pub fn push_preopened_dir(
+        &self,
+        dir: Box<dyn WasiDir>,
+        path: impl AsRef<Path>,
+    ) -> Result<u32, Error> {
+        let fd = self.table().push(Box::new(DirEntry::new(
+            DirCaps::all(),
+            FileCaps::all(),
+            Some(path.as_ref().to_owned()),
+            dir,
+        )))?;
+        self.metrics().preopen(fd, path.as_ref());
+        Ok(fd)
+    }
+
+    /// Take away every thread's access to the preopened directory at `fd` and to everything
+    /// opened through it, as `WasiEnviron::revoke_preopen` does. This doesn't wait for
+    /// operations other threads are running on those descriptors, such as a read blocked on a
+    /// pipe; each descriptor is revoked as soon as its operation returns.
+    pub fn revoke_preopen(&self, fd: u32) -> Result<(), Error> {
+        self.table().revoke_preopen(fd)
+    }
+
+    /// List every descriptor in the table, in order, as `WasiEnviron::descriptors` does. This
+    /// doesn't wait for operations other threads are running: a descriptor in use, such as a
+    /// pipe with a read blocked on it, is listed without its `resource` and `offset`.
+    pub fn descriptors(&self, host: Option<&dyn HostResources>) -> Vec<FdInfo> {
+        self.table().descriptors(host)
+    }
+
+    fn next_tid(&self) -> Option<u32> {
+        self.0
+            .next_tid
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |tid| {
+                if tid <= MAX_TID {
+                    Some(tid + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+    }
+}
+
+impl From<WasiEnviron> for SharedWasiEnviron {
+    fn from(environ: WasiEnviron) -> Self {
+        SharedWasiEnviron::new(environ)
+    }
+}
+
+impl WasiSnapshotPreview1 for SharedWasiEnviron {
+    fn args_sizes_get(&self) -> (i32, i32) {
+        let _span = syscall_span!(
+            self.metrics(),
+            "args_sizes_get",
+            argc = self.args().number_elements(),
+            argv_buf_size = self.args().cumulative_size(),
+        );
+        (
+            self.args().number_elements() as i32,
+            self.args().cumulative_size() as i32,
+        )
+    }
+
+    fn args_get(&self, out: &mut Vec<Ciovec>) {
+        let _span = syscall_span!(
+            self.metrics(),
+            "args_get",
+            argc = self.args().number_elements()
+        );
+        for arg in self.args().elements() {
+            out.push(Ciovec {
+                buf: arg.as_ptr(),
+                buf_len: arg.len(),
+            });
+        }
+    }
+
+    fn environ_sizes_get(&self) -> (i32, i32) {
+        let _span = syscall_span!(
+            self.metrics(),
+            "environ_sizes_get",
+            environc = self.env().number_elements(),
+            environ_buf_size = self.env().cumulative_size(),
+        );
+        (
+            self.env().number_elements() as i32,
+            self.env().cumulative_size() as i32,
+        )
+    }
+
+    fn environ_get(&self, out: &mut Vec<Ciovec>) {
+        let _span = syscall_span!(
+            self.metrics(),
+            "environ_get",
+            environc = self.env().number_elements()
+        );
+        for env in self.env().elements() {
+            out.push(Ciovec {
+                buf: env.as_ptr(),
+                buf_len: env.len(),
+            });
+        }
+    }
+
+    fn fd_close(&mut self, fd: i32) -> Result<(), Error> {
+        let span = syscall_span!(self.metrics(), "fd_close", fd = fd);
+
+        let fd = fd as u32;
+        let table = self.table();
+        let result = if table.is::<FileEntry>(fd) || table.is::<DirEntry>(fd) {
+            // Operations other threads are running on it finish before the backend is dropped.
+            table.delete(fd);
+            self.metrics().close(fd);
+            Ok(())
+        } else if table.contains_key(fd) {
+            Err(Error::badf().context("key does not refer to file or directory"))
+        } else {
+            Err(Error::badf().context("key not in table"))
+        };
+
+        span.finish(result)
+    }
+
+    fn fd_read(&mut self, fd: i32, iovs: IovecArray) -> Result<i32, Error> {
+        let span =
+            syscall_span!(self.metrics(), "fd_read", fd = fd, iovs_len = iovs.len(); fdflags);
+
+        let mut io_slice_vec = iovs
+            .iter()
+            .map(|iov| {
+                let buf: &mut [u8] =
+                    unsafe { std::slice::from_raw_parts_mut(iov.buf, iov.buf_len) };
+                std::io::IoSliceMut::new(buf)
+            })
+            .collect::<Vec<_>>();
+
+        let result = self
+            .table()
+            .get_mut(fd as u32, |entry: &mut FileEntry| {
+                let timeouts = entry.timeouts();
+                let f = entry.get_cap_mut(FileCaps::READ)?;
+                if span.is_enabled() {
+                    if let Ok(fdflags) = f.get_fdflags() {
+                        span.record("fdflags", fdflags);
+                    }
+                }
+                self.0.cancel.wait_readable(f, timeouts.read)?;
+                f.read_vectored(&mut io_slice_vec)
+            })
+            .and_then(|r| r)
+            .and_then(|n_read_bytes| {
+                span.record_nbytes(n_read_bytes);
+                self.metrics().record_read(fd as u32, n_read_bytes);
+                Ok(i32::try_from(n_read_bytes)?)
+            });
+
+        span.finish(result)
+    }
+
+    fn fd_write(&mut self, fd: i32, iovs: CiovecArray) -> Result<i32, Error> {
+        let span =
+            syscall_span!(self.metrics(), "fd_write", fd = fd, iovs_len = iovs.len(); fdflags);
+
+        let io_slice_vec = iovs
+            .iter()
+            .map(|iov| {
+                let buf: &[u8] = unsafe { std::slice::from_raw_parts(iov.buf, iov.buf_len) };
+                std::io::IoSlice::new(buf)
+            })
+            .collect::<Vec<_>>();
+
+        let result = self
+            .table()
+            .get_mut(fd as u32, |entry: &mut FileEntry| {
+                let timeouts = entry.timeouts();
+                let quota = entry.quota().cloned();
+                let f = entry.get_cap_mut(FileCaps::WRITE)?;
+                if span.is_enabled() {
+                    if let Ok(fdflags) = f.get_fdflags() {
+                        span.record("fdflags", fdflags);
+                    }
+                }
+                self.0.cancel.wait_writable(f, timeouts.write)?;
+                match quota {
+                    Some(quota) => {
+                        quota.write_vectored(&io_slice_vec, |bufs| f.write_vectored(bufs))
+                    }
+                    None => f.write_vectored(&io_slice_vec),
+                }
+            })
+            .and_then(|r| r)
+            .and_then(|n_written_bytes| {
+                span.record_nbytes(n_written_bytes);
+                self.metrics().record_write(fd as u32, n_written_bytes);
+                Ok(i32::try_from(n_written_bytes)?)
+            });
+
+        span.finish(result)
+    }
+
+    fn path_open(
+        &mut self,
+        dirfd: i32,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        dir_caps: DirCaps,
+        file_caps: FileCaps,
+        fdflags: FdFlags,
+    ) -> Result<i32, Error> {
+        let span = syscall_span!(
+            self.metrics(),
+            "path_open",
+            dirfd = dirfd,
+            path = path,
+            symlink_follow = symlink_follow;
+            oflags,
+            fdflags,
+            opened_fd
+        );
+        if span.is_enabled() {
+            span.record("oflags", oflags);
+            span.record("fdflags", fdflags);
+        }
+
+        let dirfd = dirfd as u32;
+        let result = (|| {
+            if self.table().is::<FileEntry>(dirfd) {
+                return Err(Error::not_dir());
+            }
+            let entry = self
+                .table()
+                .get(dirfd, |dir_entry: &DirEntry| {
+                    let origin = dir_entry.origin_for_children(dirfd);
+                    let entry: Box<dyn Any + Send + Sync> = if oflags.contains(OFlags::DIRECTORY) {
+                        if oflags.intersects(OFlags::CREATE | OFlags::EXCLUSIVE | OFlags::TRUNCATE)
+                        {
+                            return Err(Error::invalid_argument().context("directory oflags"));
+                        }
+                        let dir_caps = dir_entry.child_dir_caps(dir_caps);
+                        let file_caps = dir_entry.child_file_caps(file_caps);
+                        let dir = dir_entry.get_cap(DirCaps::OPEN)?;
+                        let child_dir = dir.open_dir(symlink_follow, path)?;
+                        Box::new(
+                            DirEntry::new(dir_caps, file_caps, None, child_dir).with_origin(origin),
+                        )
+                    } else {
+                        let mut required_caps = DirCaps::OPEN;
+                        if oflags.contains(OFlags::CREATE) {
+                            required_caps |= DirCaps::CREATE_FILE;
+                        }
+                        let file_caps = dir_entry.child_file_caps(file_caps);
+                        let dir = dir_entry.get_cap(required_caps)?;
+                        let read = file_caps.contains(FileCaps::READ);
+                        let write = file_caps.intersects(
+                            FileCaps::WRITE | FileCaps::ALLOCATE | FileCaps::FILESTAT_SET_SIZE,
+                        );
+                        let file =
+                            dir.open_file(symlink_follow, path, oflags, read, write, fdflags)?;
+                        Box::new(
+                            FileEntry::new(file_caps, file)
+                                .with_origin(origin)
+                                .with_fdstat(),
+                        )
+                    };
+                    Ok(entry)
+                })
+                .and_then(|r| r)?;
+
+            // If the preopen was revoked while the file was being opened, `push` revokes it.
+            let fd = self.table().push(entry)?;
+            self.metrics().inherit(fd, dirfd);
+            span.record("opened_fd", fd);
+            Ok(i32::try_from(fd)?)
+        })();
+
+        span.finish(result)
+    }
+
+    fn proc_exit(&mut self, code: i32) {
+        let _span = syscall_span!(self.metrics(), "proc_exit", code = code);
+        self.0.exit_code.store(code, Ordering::SeqCst);
+    }
+
+    fn sock_accept(&mut self, fd: i32, fdflags: FdFlags) -> Result<i32, Error> {
+        let span = syscall_span!(self.metrics(), "sock_accept", fd = fd; fdflags, accepted_fd);
+        if span.is_enabled() {
+            span.record("fdflags", fdflags);
+        }
+
+        let result = (|| {
+            let file = self
+                .table()
+                .get_mut(fd as u32, |entry: &mut FileEntry| {
+                    let timeouts = entry.timeouts();
+                    let f = entry.get_cap_mut(FileCaps::READ)?;
+                    self.0.cancel.wait_readable(f, timeouts.read)?;
+                    f.sock_accept(fdflags)
+                })
+                .and_then(|r| r)?;
+            let mut entry = FileEntry::new(WasiEnviron::accepted_rights(), file)
+                .with_socket(true)
+                .with_fdstat();
+            if self.0.default_timeouts != Timeouts::default() {
+                let _ = entry.set_timeouts(self.0.default_timeouts);
+            }
+            let fd = self.table().push(Box::new(entry))?;
+            span.record("accepted_fd", fd);
+            Ok(i32::try_from(fd)?)
+        })();
+
+        span.finish(result)
+    }
+}
+
+impl WasiThreads for SharedWasiEnviron {
+    fn thread_spawn(&self, start_arg: i32) -> i32 {
+        let thread_start = match self.0.thread_start.read().unwrap().clone() {
+            Some(f) => f,
+            None => return -1,
+        };
+        let tid = match self.next_tid() {
+            Some(tid) => tid as i32,
+            None => return -1,
+        };
+
+        let environ = self.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("wasi-thread-{}", tid))
+            .spawn(move || thread_start(environ, tid, start_arg));
+        match spawned {
+            Ok(_) => tid,
+            Err(_) => -1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptors::FdKind;
+    use crate::error::Errno;
+    use crate::memfs::{tests::Clock, MemFs};
+    use crate::pipe::ReadPipe;
+    use crate::Iovec;
+    use std::io::Read;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// A reader that says when a read starts, then blocks until it is sent bytes.
+    struct Blocking {
+        started: mpsc::Sender<()>,
+        bytes: Mutex<mpsc::Receiver<Vec<u8>>>,
+    }
+
+    impl Read for Blocking {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let _ = self.started.send(());
+            let bytes = self.bytes.lock().unwrap().recv().unwrap_or_default();
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    fn read(environ: &mut SharedWasiEnviron, fd: u32) -> Result<i32, Error> {
+        let mut buf = [0u8; 16];
+        let iovs = [Iovec {
+            buf: buf.as_mut_ptr(),
+            buf_len: buf.len(),
+        }];
+        environ.fd_read(fd as i32, &iovs)
+    }
+
+    #[test]
+    fn revoking_does_not_wait_for_a_blocked_read() {
+        let environ = SharedWasiEnviron::new(WasiEnviron::new());
+        let preopen = environ
+            .push_preopened_dir(Box::new(MemFs::new(Box::new(Clock)).root()), "/data")
+            .unwrap();
+        let (started_tx, started) = mpsc::channel();
+        let (bytes, bytes_rx) = mpsc::channel();
+        let reader = Blocking {
+            started: started_tx,
+            bytes: Mutex::new(bytes_rx),
+        };
+        let file: FileEntry = FileEntry::new(FileCaps::all(), Box::new(ReadPipe::new(reader)));
+        let fd = environ
+            .table()
+            .push(Box::new(file.with_origin(Some(preopen))))
+            .unwrap();
+
+        let reading = {
+            let mut environ = environ.clone();
+            std::thread::spawn(move || read(&mut environ, fd))
+        };
+        started.recv().unwrap();
+
+        let (revoked_tx, revoked) = mpsc::channel();
+        {
+            let environ = environ.clone();
+            std::thread::spawn(move || revoked_tx.send(environ.revoke_preopen(preopen)));
+        }
+        revoked
+            .recv_timeout(Duration::from_secs(10))
+            .expect("revoke_preopen waited for the blocked read")
+            .unwrap();
+
+        // The read that was already running completes; the next one is refused.
+        bytes.send(b"late".to_vec()).unwrap();
+        assert_eq!(reading.join().unwrap().unwrap(), 4);
+        let mut environ = environ;
+        let err = read(&mut environ, fd).unwrap_err();
+        assert_eq!(Errno::from_error(&err), Some(Errno::Perm));
+
+        // Descriptors opened through the preopen from now on are revoked as well.
+        let late: FileEntry = FileEntry::new(FileCaps::all(), Box::new(ReadPipe::from("x")));
+        let fd = environ
+            .table()
+            .push(Box::new(late.with_origin(Some(preopen))))
+            .unwrap();
+        let err = read(&mut environ, fd).unwrap_err();
+        assert_eq!(Errno::from_error(&err), Some(Errno::Perm));
+    }
+
+    #[test]
+    fn descriptors_do_not_wait_for_a_blocked_read() {
+        let environ = SharedWasiEnviron::new(WasiEnviron::new());
+        let (started_tx, started) = mpsc::channel();
+        let (bytes, bytes_rx) = mpsc::channel();
+        let reader = Blocking {
+            started: started_tx,
+            bytes: Mutex::new(bytes_rx),
+        };
+        let fd = environ
+            .push_file(Box::new(ReadPipe::new(reader)), FileCaps::READ)
+            .unwrap();
+        let reading = {
+            let mut environ = environ.clone();
+            std::thread::spawn(move || read(&mut environ, fd))
+        };
+        started.recv().unwrap();
+
+        let (listed_tx, listed) = mpsc::channel();
+        {
+            let environ = environ.clone();
+            std::thread::spawn(move || listed_tx.send(environ.descriptors(None)));
+        }
+        let fds = listed
+            .recv_timeout(Duration::from_secs(10))
+            .expect("descriptors waited for the blocked read");
+        let info = fds.iter().find(|info| info.fd == fd).unwrap();
+        assert_eq!(info.kind, FdKind::Pipe);
+        assert_eq!(info.file_caps, FileCaps::READ);
+
+        bytes.send(b"done".to_vec()).unwrap();
+        assert_eq!(reading.join().unwrap().unwrap(), 4);
+    }
+}
@@ -0,0 +1,84 @@
+//! Copy-on-write snapshots.
+//!
+//! A `Snapshot` freezes a directory tree and forks any number of writable views of it, so that
+//! several guests can start from the same state and then diverge. A fork is an `OverlayDir` with
+//! the frozen tree as its lower layer and a scratch directory of its own on top: forking costs
+//! nothing, a file is only copied the first time a fork writes to it, and discarding a fork only
+//! means dropping it and its scratch directory.
+//!
+//! Freezing copies the tree into a store of the snapshot's own, either a host directory or
+//! memory, so that later changes to the tree don't reach the forks. When the tree and the store,
+//! or the store and a fork's scratch directory, are on the same host filesystem, files are
+//! copied with reflinks where it supports them, and so cost no space until they are changed.
+//! Hard links in the tree are copied as separate files, and special files such as sockets can't
+//! be copied at all.
+use crate::clocks::WasiSystemClock;
+use crate::dir::{copy_times, copy_tree, entries, WasiDir};
+use crate::error::Error;
+use crate::memfs::MemFs;
+use crate::overlay::OverlayDir;
+use crate::read_only::ReadOnlyDir;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct Snapshot {
+    tree: Arc<dyn WasiDir>,
+}
+
+impl Snapshot {
+    /// Freeze the tree at `dir` by copying it into `store`, an empty directory that nothing
+    /// else may change for as long as the snapshot and its forks are in use. If the copy fails,
+    /// `store` may be left with part of the tree.
+    pub fn new(dir: &dyn WasiDir, store: Box<dyn WasiDir>) -> Result<Self, Error> {
+        for (name, _) in entries(dir, "")? {
+            let stat = dir.get_path_filestat(&name, false)?;
+            copy_tree(dir, &name, &stat, &*store, &name)?;
+        }
+        copy_times(&*store, ".", &dir.get_filestat()?)?;
+        Ok(Snapshot {
+            tree: Arc::new(ReadOnlyDir::new(store)),
+        })
+    }
+
+    /// Freeze the tree at `dir` by copying it into memory, held to `memfs::DEFAULT_MAX_BYTES`,
+    /// with timestamps from `clock`.
+    pub fn in_memory(dir: &dyn WasiDir, clock: Box<dyn WasiSystemClock>) -> Result<Self, Error> {
+        Self::new(dir, Box::new(MemFs::new(clock).root()))
+    }
+
+    /// A writable fork whose changes go to `scratch`, which should start out empty.
+    pub fn fork(&self, scratch: Box<dyn WasiDir>) -> OverlayDir {
+        OverlayDir::new(self.tree.clone(), scratch)
+    }
+
+    /// A writable fork whose changes are kept in memory, held to `memfs::DEFAULT_MAX_BYTES`, and
+    /// are gone once it is dropped.
+    pub fn fork_in_memory(&self, clock: Box<dyn WasiSystemClock>) -> OverlayDir {
+        self.fork(Box::new(MemFs::new(clock).root()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::tests::{read, write, Clock};
+
+    #[test]
+    fn snapshots_are_frozen_and_forks_diverge() {
+        let tree = MemFs::new(Box::new(Clock)).root();
+        tree.create_dir("d").unwrap();
+        write(&tree, "d/f", b"before");
+        tree.symlink("d/f", "l").unwrap();
+        let snapshot = Snapshot::in_memory(&tree, Box::new(Clock)).unwrap();
+        write(&tree, "d/f", b"after");
+        tree.unlink_file("l").unwrap();
+        let (one, two) = (
+            snapshot.fork_in_memory(Box::new(Clock)),
+            snapshot.fork_in_memory(Box::new(Clock)),
+        );
+        assert_eq!(read(&one, "l"), b"before");
+        write(&one, "d/f", b"one");
+        assert_eq!(read(&one, "d/f"), b"one");
+        assert_eq!(read(&two, "d/f"), b"before");
+    }
+}
@@ -0,0 +1,412 @@
+//! An `strace`-like log of the syscalls a guest makes.
+//!
+//! `Strace` wraps anything implementing `WasiSnapshotPreview1`, such as a `WasiEnviron`, and
+//! writes one line per syscall to a `Write` sink:
+//!
+//! ```text
+//! args_sizes_get() = (2, 12)
+//! path_open(3, SYMLINK_FOLLOW, "out.txt", CREATE|TRUNCATE, 0, READ|WRITE, 0) = 4
+//! fd_read(0, ["ping\n"], 1) = 5
+//! fd_write(1, ["hello, world\n"], 1) = 13
+//! fd_write(7, ["oops"], 1) = EBADF (key not in table: Badf: Bad file descriptor)
+//! proc_exit(0) = ?
+//! ```
+//!
+//! Flags are decoded by name and paths are quoted. The `Display*` helpers used to do so are
+//! public, so hosts adding their own syscalls can log them the same way.
+use crate::dir::DirCaps;
+use crate::error::{Errno, Error};
+use crate::file::{FdFlags, FileCaps, OFlags};
+use crate::{Ciovec, CiovecArray, Iovec, IovecArray, WasiSnapshotPreview1};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// How buffer contents are shown in the log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Buffers {
+    /// Show at most this many bytes of each buffer as an escaped string, followed by `...` if
+    /// the buffer is longer.
+    Abbreviate(usize),
+    /// Abbreviate buffers as with `Abbreviate(32)`, and follow the line with a hexdump of every
+    /// buffer in full.
+    Hexdump,
+}
+
+impl Default for Buffers {
+    /// The same limit `strace` uses by default.
+    fn default() -> Self {
+        Buffers::Abbreviate(32)
+    }
+}
+
+/// Logs every syscall made through the wrapped environ.
+pub struct Strace<E, W: Write> {
+    inner: E,
+    out: Mutex<W>,
+    buffers: Buffers,
+}
+
+impl<E: WasiSnapshotPreview1, W: Write> Strace<E, W> {
+    pub fn new(inner: E, out: W) -> Self {
+        Strace {
+            inner,
+            out: Mutex::new(out),
+            buffers: Buffers::default(),
+        }
+    }
+
+    /// Set how buffer contents are shown.
+    pub fn buffers(mut self, buffers: Buffers) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    /// Return the wrapped environ and the log sink.
+    pub fn into_inner(self) -> (E, W) {
+        (self.inner, self.out.into_inner().unwrap())
+    }
+
+    /// Write one line. Failing to log never fails the syscall, so write errors are dropped.
+    fn log(&self, line: fmt::Arguments<'_>) {
+        let _ = self.out.lock().unwrap().write_fmt(line);
+    }
+
+    // The iovecs logged below are the ones just passed to or returned from the wrapped environ,
+    // so they satisfy `iov_bytes`'s contract.
+
+    fn strings(&self, iovs: &[Ciovec]) -> String {
+        let strings = iovs
+            .iter()
+            .map(|iov| {
+                let buf = unsafe { iov_bytes(iov) };
+                DisplayBuf::new(buf.strip_suffix(b"\0").unwrap_or(buf), usize::MAX).to_string()
+            })
+            .collect::<Vec<_>>();
+        format!("[{}]", strings.join(", "))
+    }
+
+    fn buffer_list(&self, bufs: &[&[u8]]) -> String {
+        let limit = match self.buffers {
+            Buffers::Abbreviate(limit) => limit,
+            Buffers::Hexdump => 32,
+        };
+        let bufs = bufs
+            .iter()
+            .map(|buf| DisplayBuf::new(buf, limit).to_string())
+            .collect::<Vec<_>>();
+        format!("[{}]", bufs.join(", "))
+    }
+
+    fn hexdump(&self, bufs: &[&[u8]]) {
+        if self.buffers == Buffers::Hexdump {
+            for buf in bufs {
+                self.log(format_args!("{}", Hexdump(buf)));
+            }
+        }
+    }
+}
+
+/// View the buffer an iovec describes.
+///
+/// # Safety
+///
+/// `iov` must describe valid memory, as it must for the syscall it was passed to or returned
+/// from.
+unsafe fn iov_bytes(iov: &Ciovec) -> &[u8] {
+    std::slice::from_raw_parts(iov.buf, iov.buf_len)
+}
+
+/// View the part of each buffer that a read of `n` bytes filled.
+///
+/// # Safety
+///
+/// As for `iov_bytes`.
+unsafe fn filled_bytes(iovs: &[Iovec], mut n: usize) -> Vec<&[u8]> {
+    iovs.iter()
+        .map(|iov| {
+            let len = iov.buf_len.min(n);
+            n -= len;
+            std::slice::from_raw_parts(iov.buf as *const u8, len)
+        })
+        .collect()
+}
+
+impl<E: WasiSnapshotPreview1, W: Write> WasiSnapshotPreview1 for Strace<E, W> {
+    fn args_sizes_get(&self) -> (i32, i32) {
+        let (argc, size) = self.inner.args_sizes_get();
+        self.log(format_args!("args_sizes_get() = ({}, {})\n", argc, size));
+        (argc, size)
+    }
+
+    fn args_get(&self, out: &mut Vec<Ciovec>) {
+        let start = out.len();
+        self.inner.args_get(out);
+        self.log(format_args!(
+            "args_get({}) = 0\n",
+            self.strings(&out[start..])
+        ));
+    }
+
+    fn environ_sizes_get(&self) -> (i32, i32) {
+        let (count, size) = self.inner.environ_sizes_get();
+        self.log(format_args!(
+            "environ_sizes_get() = ({}, {})\n",
+            count, size
+        ));
+        (count, size)
+    }
+
+    fn environ_get(&self, out: &mut Vec<Ciovec>) {
+        let start = out.len();
+        self.inner.environ_get(out);
+        self.log(format_args!(
+            "environ_get({}) = 0\n",
+            self.strings(&out[start..])
+        ));
+    }
+
+    fn fd_close(&mut self, fd: i32) -> Result<(), Error> {
+        let result = self.inner.fd_close(fd).map(|()| 0);
+        self.log(format_args!(
+            "fd_close({}) = {}\n",
+            fd,
+            DisplayResult(&result)
+        ));
+        result.map(|_| ())
+    }
+
+    fn fd_read(&mut self, fd: i32, iovs: IovecArray) -> Result<i32, Error> {
+        let result = self.inner.fd_read(fd, iovs);
+        // Only the bytes actually read are shown, as `strace` does.
+        let n = result.as_ref().map_or(0, |&n| n as usize);
+        let bufs = unsafe { filled_bytes(iovs, n) };
+        self.log(format_args!(
+            "fd_read({}, {}, {}) = {}\n",
+            fd,
+            self.buffer_list(&bufs),
+            iovs.len(),
+            DisplayResult(&result)
+        ));
+        self.hexdump(&bufs);
+        result
+    }
+
+    fn fd_write(&mut self, fd: i32, iovs: CiovecArray) -> Result<i32, Error> {
+        let result = self.inner.fd_write(fd, iovs);
+        let bufs = iovs
+            .iter()
+            .map(|iov| unsafe { iov_bytes(iov) })
+            .collect::<Vec<_>>();
+        self.log(format_args!(
+            "fd_write({}, {}, {}) = {}\n",
+            fd,
+            self.buffer_list(&bufs),
+            iovs.len(),
+            DisplayResult(&result)
+        ));
+        self.hexdump(&bufs);
+        result
+    }
+
+    fn path_open(
+        &mut self,
+        dirfd: i32,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        dir_caps: DirCaps,
+        file_caps: FileCaps,
+        fdflags: FdFlags,
+    ) -> Result<i32, Error> {
+        let result = self.inner.path_open(
+            dirfd,
+            symlink_follow,
+            path,
+            oflags,
+            dir_caps,
+            file_caps,
+            fdflags,
+        );
+        self.log(format_args!(
+            "path_open({}, {}, {}, {}, {}, {}, {}) = {}\n",
+            dirfd,
+            if symlink_follow {
+                "SYMLINK_FOLLOW"
+            } else {
+                "0"
+            },
+            DisplayPath(Path::new(path)),
+            DisplayFlags(oflags),
+            DisplayFlags(dir_caps),
+            DisplayFlags(file_caps),
+            DisplayFlags(fdflags),
+            DisplayResult(&result)
+        ));
+        result
+    }
+
+    fn proc_exit(&mut self, code: i32) {
+        self.log(format_args!("proc_exit({}) = ?\n", code));
+        self.inner.proc_exit(code);
+    }
+
+    fn sock_accept(&mut self, fd: i32, fdflags: FdFlags) -> Result<i32, Error> {
+        let result = self.inner.sock_accept(fd, fdflags);
+        self.log(format_args!(
+            "sock_accept({}, {}) = {}\n",
+            fd,
+            DisplayFlags(fdflags),
+            DisplayResult(&result)
+        ));
+        result
+    }
+}
+
+/// Shows a set of bitflags the way `strace` does, as `CREATE|TRUNCATE`, or `0` if empty.
+pub struct DisplayFlags<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Display for DisplayFlags<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The `Debug` output of `bitflags` types is `A | B`, or `(empty)`.
+        let flags = format!("{:?}", self.0);
+        if flags == "(empty)" {
+            write!(f, "0")
+        } else {
+            write!(f, "{}", flags.replace(" | ", "|"))
+        }
+    }
+}
+
+/// Shows a path as a quoted, escaped string.
+pub struct DisplayPath<'a>(pub &'a Path);
+
+impl fmt::Display for DisplayPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0.to_string_lossy())
+    }
+}
+
+/// Shows a buffer as a quoted string, with anything that isn't printable ASCII escaped, cut off
+/// after `limit` bytes.
+pub struct DisplayBuf<'a> {
+    buf: &'a [u8],
+    limit: usize,
+}
+
+impl<'a> DisplayBuf<'a> {
+    pub fn new(buf: &'a [u8], limit: usize) -> Self {
+        DisplayBuf { buf, limit }
+    }
+}
+
+impl fmt::Display for DisplayBuf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = &self.buf[..self.buf.len().min(self.limit)];
+        write!(f, "\"{}\"", shown.escape_ascii())?;
+        if shown.len() < self.buf.len() {
+            write!(f, "...")?;
+        }
+        Ok(())
+    }
+}
+
+/// Shows a buffer as hexdump lines, as `strace -e write=` does, each followed by a newline.
+pub struct Hexdump<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, chunk) in self.0.chunks(16).enumerate() {
+            write!(f, " | {:05x} ", i * 16)?;
+            for j in 0..16 {
+                match chunk.get(j) {
+                    Some(b) => write!(f, " {:02x}", b)?,
+                    None => write!(f, "   ")?,
+                }
+            }
+            write!(f, "  ")?;
+            for j in 0..16 {
+                match chunk.get(j) {
+                    Some(b) if b.is_ascii_graphic() || *b == b' ' => write!(f, "{}", *b as char)?,
+                    Some(_) => write!(f, ".")?,
+                    None => write!(f, " ")?,
+                }
+            }
+            writeln!(f, " |")?;
+        }
+        Ok(())
+    }
+}
+
+/// Shows the outcome of a syscall: the value, the errno name followed by the error's context
+/// chain, or `trap` for errors the guest never sees.
+pub struct DisplayResult<'a, T>(pub &'a Result<T, Error>);
+
+impl<T: fmt::Display> fmt::Display for DisplayResult<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Ok(value) => write!(f, "{}", value),
+            Err(e) => match Errno::from_error(e) {
+                Some(errno) => write!(f, "{} ({:#})", errno, e),
+                None => write!(f, "trap ({:#})", e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environ::WasiEnviron;
+
+    fn write(strace: &mut Strace<WasiEnviron, Vec<u8>>, fd: i32, data: &[u8]) {
+        let iovs = [Ciovec {
+            buf: data.as_ptr(),
+            buf_len: data.len(),
+        }];
+        let _ = strace.fd_write(fd, &iovs);
+    }
+
+    fn log(strace: Strace<WasiEnviron, Vec<u8>>) -> String {
+        String::from_utf8(strace.into_inner().1).unwrap()
+    }
+
+    #[test]
+    fn long_buffers_are_abbreviated() {
+        let mut strace =
+            Strace::new(WasiEnviron::new(), Vec::new()).buffers(Buffers::Abbreviate(4));
+        write(&mut strace, 1, b"hello\n");
+        write(&mut strace, 1, b"hi\n");
+        write(&mut strace, 7, b"x");
+        let log = log(strace);
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], r#"fd_write(1, ["hell"...], 1) = 6"#);
+        assert_eq!(lines[1], r#"fd_write(1, ["hi\n"], 1) = 3"#);
+        assert!(lines[2].starts_with(r#"fd_write(7, ["x"], 1) = EBADF ("#));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn hexdump_follows_the_line() {
+        let mut strace = Strace::new(WasiEnviron::new(), Vec::new()).buffers(Buffers::Hexdump);
+        let data = b"0123456789abcdef\x00\xff";
+        write(&mut strace, 1, data);
+        assert_eq!(
+            log(strace),
+            concat!(
+                "fd_write(1, [\"0123456789abcdef\\x00\\xff\"], 1) = 18\n",
+                " | 00000  30 31 32 33 34 35 36 37 38 39 61 62 63 64 65 66  0123456789abcdef |\n",
+                " | 00010  00 ff                                            ..               |\n",
+            )
+        );
+    }
+}
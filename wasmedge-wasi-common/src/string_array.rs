@@ -2,19 +2,27 @@
 pub struct StringArray {
     elems: Vec<String>,
 }
+impl Default for StringArray {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl StringArray {
     pub fn new() -> Self {
         StringArray { elems: Vec::new() }
     }
 
     pub fn push(&mut self, elem: String) -> Result<(), StringArrayError> {
-        if self.elems.len() + 1 > std::u32::MAX as usize {
+        if elem.contains('\0') {
+            return Err(StringArrayError::Nul);
+        }
+        if self.elems.len() + 1 > u32::MAX as usize {
             return Err(StringArrayError::NumberElements);
         }
-        if elem.as_bytes().len() + 1 > std::u32::MAX as usize {
+        if elem.len() + 1 > u32::MAX as usize {
             return Err(StringArrayError::ElementSize);
         }
-        if self.cumulative_size() as usize + elem.as_bytes().len() + 1 > std::u32::MAX as usize {
+        if self.cumulative_size() as usize + elem.len() + 1 > u32::MAX as usize {
             return Err(StringArrayError::CumulativeSize);
         }
         self.elems.push(elem);
@@ -26,10 +34,7 @@ impl StringArray {
     }
 
     pub fn cumulative_size(&self) -> u32 {
-        self.elems
-            .iter()
-            .map(|e| e.as_bytes().len() + 1)
-            .sum::<usize>() as u32
+        self.elems.iter().map(|e| e.len() + 1).sum::<usize>() as u32
     }
 
     pub fn elements(&self) -> Vec<&str> {
@@ -45,4 +50,6 @@ pub enum StringArrayError {
     ElementSize,
     #[error("Cumulative size exceeds 2^32")]
     CumulativeSize,
+    #[error("Element contains a nul byte")]
+    Nul,
 }
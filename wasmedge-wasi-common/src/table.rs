@@ -1,6 +1,13 @@
+use crate::checkpoint::HostResources;
+use crate::descriptors::{describe_dir, describe_file, locate_dir, locate_file, FdInfo};
+use crate::dir::DirEntry;
+use crate::file::FileEntry;
+use crate::limits::{Counts, Limits, Usage};
 use crate::{Error, ErrorExt};
-use std::any::Any;
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 /// The `Table` type is designed to map u32 handles to resources. The table is now part of the
 /// public interface to a `WasiCtx` - it is reference counted so that it can be shared beyond a
@@ -12,6 +19,12 @@ use std::collections::HashMap;
 pub struct Table {
     map: HashMap<u32, Box<dyn Any + Send + Sync>>,
     next_key: u32,
+    counts: Counts,
+}
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Table {
     /// Create an empty table. New insertions will begin at 3, above stdio.
@@ -19,27 +32,47 @@ impl Table {
         Table {
             map: HashMap::new(),
             next_key: 3, // 0, 1 and 2 are reserved for stdio
+            counts: Counts::default(),
         }
     }
 
-    /// Insert a resource at a certain index.
+    /// Bound what `push` accepts from now on. Entries already in the table stay, even if they
+    /// are over the new limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.counts.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.counts.limits
+    }
+
+    /// How much of each limit is in use.
+    pub fn usage(&self) -> Usage {
+        self.counts.usage(self.map.len())
+    }
+
+    /// Insert a resource at a certain index. This is never refused by the limits.
     pub fn insert_at(&mut self, key: u32, a: Box<dyn Any + Send + Sync>) {
+        self.counts.add(key, &*a);
         self.map.insert(key, a);
     }
 
-    /// Insert a resource at the next available index.
+    /// Insert a resource at the next available index. Fails with `Mfile` or `Nfile` if that
+    /// would go over the limits.
     pub fn push(&mut self, a: Box<dyn Any + Send + Sync>) -> Result<u32, Error> {
         // NOTE: The performance of this new key calculation could be very bad once keys wrap
         // around.
         if self.map.len() == u32::MAX as usize {
             return Err(Error::trap("table has no free keys"));
         }
+        self.counts.check(self.map.len(), &*a)?;
         loop {
             let key = self.next_key;
             self.next_key = self.next_key.wrapping_add(1);
             if self.map.contains_key(&key) {
                 continue;
             }
+            self.counts.add(key, &*a);
             self.map.insert(key, a);
             return Ok(key);
         }
@@ -86,10 +119,375 @@ impl Table {
     /// Remove a resource at a given index from the table. Returns the resource
     /// if it was present.
     pub fn delete(&mut self, key: u32) -> Option<Box<dyn Any + Send + Sync>> {
+        self.counts.remove(key);
         self.map.remove(&key)
     }
 
     pub fn len(&self) -> usize {
         self.map.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// The keys in use, in ascending order.
+    pub fn keys(&self) -> Vec<u32> {
+        let mut keys = self.map.keys().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+}
+
+/// A `Table` that can be shared between threads.
+///
+/// Every entry sits behind its own lock, so operations on different descriptors can proceed
+/// concurrently, while operations on the same descriptor are serialized. The map itself is only
+/// locked long enough to look an entry up, insert it or remove it. This is the table used by a
+/// `SharedWasiEnviron`, where all guest threads of an instance see the same descriptors.
+pub struct SharedTable(RwLock<SharedTableInner>);
+
+type SharedEntry = Arc<Slot>;
+
+/// One entry of a `SharedTable`. The type, what revocation needs to know and how the entry
+/// describes itself are kept outside the lock, so they can be checked while another thread
+/// holds the resource.
+struct Slot {
+    type_id: TypeId,
+    /// The description of a file or directory, brought up to date whenever it is held mutably.
+    info: Mutex<Option<FdInfo>>,
+    /// The preopen a file or directory was opened through.
+    origin: Option<u32>,
+    preopen: bool,
+    /// Set as soon as the entry is revoked. The entry itself is revoked once no thread holds it.
+    revoked: AtomicBool,
+    value: RwLock<Box<dyn Any + Send + Sync>>,
+}
+
+impl Slot {
+    fn new(key: u32, a: Box<dyn Any + Send + Sync>) -> SharedEntry {
+        let (origin, preopen, revoked) = if let Some(entry) = a.downcast_ref::<FileEntry>() {
+            (entry.origin(), false, false)
+        } else if let Some(entry) = a.downcast_ref::<DirEntry>() {
+            (
+                entry.origin(),
+                entry.preopen_path().is_some(),
+                entry.is_revoked(),
+            )
+        } else {
+            (None, false, false)
+        };
+        Arc::new(Slot {
+            type_id: (*a).type_id(),
+            info: Mutex::new(describe(key, &*a)),
+            origin,
+            preopen,
+            revoked: AtomicBool::new(revoked),
+            value: RwLock::new(a),
+        })
+    }
+
+    fn is_revoked(&self) -> bool {
+        self.revoked.load(Ordering::SeqCst)
+    }
+
+    /// Bring the description up to date with `a`, the resource at `key`.
+    fn refresh(&self, key: u32, a: &(dyn Any + Send + Sync)) {
+        *self.info.lock().unwrap() = describe(key, a);
+    }
+}
+
+fn describe(key: u32, a: &(dyn Any + Send + Sync)) -> Option<FdInfo> {
+    if let Some(entry) = a.downcast_ref::<FileEntry>() {
+        Some(describe_file(key, entry))
+    } else {
+        a.downcast_ref::<DirEntry>()
+            .map(|entry| describe_dir(key, entry))
+    }
+}
+
+fn revoke(a: &mut (dyn Any + Send + Sync)) {
+    if let Some(entry) = a.downcast_mut::<FileEntry>() {
+        entry.revoke();
+    } else if let Some(entry) = a.downcast_mut::<DirEntry>() {
+        entry.revoke();
+    }
+}
+
+struct SharedTableInner {
+    map: HashMap<u32, SharedEntry>,
+    next_key: u32,
+    counts: Counts,
+}
+
+impl Default for SharedTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl SharedTable {
+    /// Create an empty table. New insertions will begin at 3, above stdio.
+    pub fn new() -> Self {
+        SharedTable::from(Table::new())
+    }
+
+    /// Bound what `push` accepts from now on. See `Table::set_limits`.
+    pub fn set_limits(&self, limits: Limits) {
+        self.inner_mut().counts.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.inner().counts.limits
+    }
+
+    /// How much of each limit is in use.
+    pub fn usage(&self) -> Usage {
+        let inner = self.inner();
+        inner.counts.usage(inner.map.len())
+    }
+
+    /// Insert a resource at a certain index. This is never refused by the limits.
+    pub fn insert_at(&self, key: u32, a: Box<dyn Any + Send + Sync>) {
+        let mut inner = self.inner_mut();
+        inner.counts.add(key, &*a);
+        inner.map.insert(key, Slot::new(key, a));
+    }
+
+    /// Insert a resource at the next available index. Fails with `Mfile` or `Nfile` if that
+    /// would go over the limits.
+    pub fn push(&self, a: Box<dyn Any + Send + Sync>) -> Result<u32, Error> {
+        let mut inner = self.inner_mut();
+        if inner.map.len() == u32::MAX as usize {
+            return Err(Error::trap("table has no free keys"));
+        }
+        inner.counts.check(inner.map.len(), &*a)?;
+        loop {
+            let key = inner.next_key;
+            inner.next_key = inner.next_key.wrapping_add(1);
+            if inner.map.contains_key(&key) {
+                continue;
+            }
+            inner.counts.add(key, &*a);
+            let slot = Slot::new(key, a);
+            // Opened through a preopen that was revoked meanwhile.
+            if let Some(origin) = slot.origin.and_then(|origin| inner.map.get(&origin)) {
+                if origin.is_revoked() {
+                    slot.revoked.store(true, Ordering::SeqCst);
+                }
+            }
+            inner.map.insert(key, slot);
+            return Ok(key);
+        }
+    }
+
+    /// Check if the table has a resource at the given index.
+    pub fn contains_key(&self, key: u32) -> bool {
+        self.inner().map.contains_key(&key)
+    }
+
+    /// Check if the resource at a given index can be downcast to a given type.
+    pub fn is<T: Any + Sized>(&self, key: u32) -> bool {
+        match self.inner().map.get(&key) {
+            Some(r) => r.type_id == TypeId::of::<T>(),
+            None => false,
+        }
+    }
+
+    /// Call `f` with an immutable reference to a resource of a given type at a given index.
+    /// Any number of threads can read the same resource at the same time.
+    pub fn get<T: Any + Sized, R>(&self, key: u32, f: impl FnOnce(&T) -> R) -> Result<R, Error> {
+        let r = self
+            .entry(key)
+            .ok_or_else(|| Error::badf().context("key not in table"))?;
+        if r.is_revoked() {
+            let mut value = r.value.write().unwrap();
+            revoke(&mut **value);
+            r.refresh(key, &**value);
+        }
+        let r = r.value.read().unwrap();
+        let t = r
+            .downcast_ref::<T>()
+            .ok_or_else(|| Error::badf().context("element is a different type"))?;
+        Ok(f(t))
+    }
+
+    /// Call `f` with a mutable reference to a resource of a given type at a given index. Only
+    /// one thread at a time can hold a resource mutably; other threads block until `f` returns.
+    pub fn get_mut<T: Any + Sized, R>(
+        &self,
+        key: u32,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, Error> {
+        let r = self
+            .entry(key)
+            .ok_or_else(|| Error::badf().context("key not in table"))?;
+        let mut value = r.value.write().unwrap();
+        if r.is_revoked() {
+            revoke(&mut **value);
+        }
+        let t = value
+            .downcast_mut::<T>()
+            .ok_or_else(|| Error::badf().context("element is a different type"))?;
+        let result = f(t);
+        // Revoked while `f` was running.
+        if r.is_revoked() {
+            revoke(&mut **value);
+        }
+        r.refresh(key, &**value);
+        Ok(result)
+    }
+
+    /// Revoke the preopened directory at `key` and every file and directory opened through it.
+    /// This never waits for operations running on them: an entry another thread is using is
+    /// revoked as soon as that thread lets go of it, and fails every operation from then on.
+    pub(crate) fn revoke_preopen(&self, key: u32) -> Result<(), Error> {
+        let slots: Vec<(u32, SharedEntry)> = {
+            let inner = self.inner();
+            let slot = inner
+                .map
+                .get(&key)
+                .ok_or_else(|| Error::badf().context("key not in table"))?;
+            if !slot.preopen {
+                return Err(Error::badf().context("not a preopened directory"));
+            }
+            // Flag them all under the map lock, so `push` sees the flag on the preopen if it
+            // adds an entry after the scan.
+            inner
+                .map
+                .iter()
+                .filter(|(k, slot)| **k == key || slot.origin == Some(key))
+                .map(|(k, slot)| {
+                    slot.revoked.store(true, Ordering::SeqCst);
+                    (*k, slot.clone())
+                })
+                .collect()
+        };
+        for (key, slot) in slots {
+            if let Ok(mut value) = slot.value.try_write() {
+                revoke(&mut **value);
+                slot.refresh(key, &**value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a resource at a given index from the table. Returns the resource if it was
+    /// present and no other thread is using it; otherwise the resource is dropped once the
+    /// last in-flight operation on it completes.
+    pub fn delete(&self, key: u32) -> Option<Box<dyn Any + Send + Sync>> {
+        let r = {
+            let mut inner = self.inner_mut();
+            inner.counts.remove(key);
+            inner.map.remove(&key)?
+        };
+        let revoked = r.is_revoked();
+        let mut a = Arc::try_unwrap(r)
+            .ok()
+            .map(|r| RwLock::into_inner(r.value).unwrap())?;
+        if revoked {
+            revoke(&mut *a);
+        }
+        Some(a)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner().map.is_empty()
+    }
+
+    /// The keys in use, in ascending order. Other threads may change the table right after.
+    pub fn keys(&self) -> Vec<u32> {
+        let mut keys = self.inner().map.keys().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Describe every file and directory in the table, in order, as `WasiEnviron::descriptors`
+    /// does. This never waits for operations running on them: a descriptor another thread is
+    /// using is listed as it was when that thread took it, without its resource or offset.
+    pub(crate) fn descriptors(&self, host: Option<&dyn HostResources>) -> Vec<FdInfo> {
+        let mut slots = self
+            .inner()
+            .map
+            .iter()
+            .map(|(key, slot)| (*key, slot.clone()))
+            .collect::<Vec<_>>();
+        slots.sort_unstable_by_key(|(key, _)| *key);
+        let mut fds = Vec::new();
+        for (_, slot) in slots {
+            let Some(mut info) = slot.info.lock().unwrap().clone() else {
+                continue;
+            };
+            if let Ok(value) = slot.value.try_read() {
+                if let Some(entry) = value.downcast_ref::<FileEntry>() {
+                    locate_file(&mut info, entry.file(), host);
+                } else if let Some(entry) = value.downcast_ref::<DirEntry>() {
+                    locate_dir(&mut info, entry.dir(), host);
+                }
+            }
+            fds.push(info);
+        }
+        fds
+    }
+
+    fn entry(&self, key: u32) -> Option<SharedEntry> {
+        self.inner().map.get(&key).cloned()
+    }
+
+    fn inner(&self) -> RwLockReadGuard<'_, SharedTableInner> {
+        self.0.read().unwrap()
+    }
+
+    fn inner_mut(&self) -> RwLockWriteGuard<'_, SharedTableInner> {
+        self.0.write().unwrap()
+    }
+}
+
+impl From<Table> for SharedTable {
+    fn from(table: Table) -> Self {
+        let map = table
+            .map
+            .into_iter()
+            .map(|(key, a)| (key, Slot::new(key, a)))
+            .collect();
+        SharedTable(RwLock::new(SharedTableInner {
+            map,
+            next_key: table.next_key,
+            counts: table.counts,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn is_does_not_wait_for_a_busy_entry() {
+        let table = Arc::new(SharedTable::new());
+        let fd = table.push(Box::new(7u64)).unwrap();
+        let (held_tx, held_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let holder = {
+            let table = table.clone();
+            std::thread::spawn(move || {
+                table
+                    .get_mut(fd, |_: &mut u64| {
+                        held_tx.send(()).unwrap();
+                        release_rx.recv().unwrap();
+                    })
+                    .unwrap()
+            })
+        };
+        held_rx.recv().unwrap();
+        assert!(table.is::<u64>(fd));
+        assert!(!table.is::<u32>(fd));
+        assert!(!table.is::<u64>(fd + 1));
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+    }
 }
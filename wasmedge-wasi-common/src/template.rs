@@ -0,0 +1,212 @@
+//! Reusable environ definitions, for pooling instances.
+//!
+//! A `WasiEnvironTemplate` captures everything needed to build a `WasiEnviron`: the arguments,
+//! the environment, and factories for the stdio files and preopened directories. Build as many
+//! environs from it as needed with `instantiate`, or put a used environ back into its initial
+//! state with `reset`.
+use crate::dir::{DirCaps, WasiDir};
+use crate::environ::WasiEnviron;
+use crate::error::Error;
+use crate::file::{FileCaps, WasiFile};
+use crate::limits::Limits;
+use crate::string_array::{StringArray, StringArrayError};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Creates a fresh file for each environ built from a template.
+pub type FileFactory = dyn Fn() -> Box<dyn WasiFile> + Send + Sync;
+
+/// Opens a fresh directory handle for each environ built from a template.
+pub type DirFactory = dyn Fn() -> Result<Box<dyn WasiDir>, Error> + Send + Sync;
+
+#[derive(Clone)]
+enum Slot {
+    /// A file. Without explicit caps, it gets the rights `WasiEnviron` gives stdio.
+    File {
+        factory: Arc<FileFactory>,
+        caps: Option<FileCaps>,
+    },
+    Dir {
+        factory: Arc<DirFactory>,
+        caps: DirCaps,
+        file_caps: FileCaps,
+        path: PathBuf,
+    },
+}
+
+#[derive(Clone)]
+pub struct WasiEnvironTemplate {
+    args: StringArray,
+    env: StringArray,
+    slots: BTreeMap<u32, Slot>,
+    limits: Limits,
+}
+
+impl Default for WasiEnvironTemplate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WasiEnvironTemplate {
+    /// Create a template with no arguments or environment, and the same empty stdio as
+    /// `WasiEnviron::new`.
+    pub fn new() -> Self {
+        let mut template = WasiEnvironTemplate {
+            args: StringArray::new(),
+            env: StringArray::new(),
+            slots: BTreeMap::new(),
+            limits: Limits::default(),
+        };
+
+        template.set_stdin(|| Box::new(crate::pipe::ReadPipe::new(std::io::empty())));
+        template.set_stdout(|| Box::new(crate::pipe::WritePipe::new(std::io::sink())));
+        template.set_stderr(|| Box::new(crate::pipe::WritePipe::new(std::io::sink())));
+
+        template
+    }
+
+    pub fn push_arg(&mut self, arg: &str) -> Result<(), StringArrayError> {
+        self.args.push(arg.to_owned())
+    }
+
+    pub fn push_env(&mut self, var: &str, value: &str) -> Result<(), StringArrayError> {
+        self.env.push(format!("{}={}", var, value))
+    }
+
+    pub fn set_stdin(&mut self, f: impl Fn() -> Box<dyn WasiFile> + Send + Sync + 'static) {
+        self.insert_stdio(0, Arc::new(f));
+    }
+
+    pub fn set_stdout(&mut self, f: impl Fn() -> Box<dyn WasiFile> + Send + Sync + 'static) {
+        self.insert_stdio(1, Arc::new(f));
+    }
+
+    pub fn set_stderr(&mut self, f: impl Fn() -> Box<dyn WasiFile> + Send + Sync + 'static) {
+        self.insert_stdio(2, Arc::new(f));
+    }
+
+    fn insert_stdio(&mut self, fd: u32, factory: Arc<FileFactory>) {
+        self.slots.insert(
+            fd,
+            Slot::File {
+                factory,
+                caps: None,
+            },
+        );
+    }
+
+    pub fn insert_file(
+        &mut self,
+        fd: u32,
+        f: impl Fn() -> Box<dyn WasiFile> + Send + Sync + 'static,
+        caps: FileCaps,
+    ) {
+        self.slots.insert(
+            fd,
+            Slot::File {
+                factory: Arc::new(f),
+                caps: Some(caps),
+            },
+        );
+    }
+
+    /// Preopen a directory at the next free descriptor, with all rights, as
+    /// `WasiEnviron::push_preopened_dir` does.
+    pub fn push_preopened_dir(
+        &mut self,
+        f: impl Fn() -> Result<Box<dyn WasiDir>, Error> + Send + Sync + 'static,
+        path: impl AsRef<Path>,
+    ) -> u32 {
+        // Every environ's table starts handing out descriptors at 3.
+        let fd = (3..)
+            .find(|fd| !self.slots.contains_key(fd))
+            .expect("template has no free descriptors");
+        self.insert_dir(
+            fd,
+            f,
+            DirCaps::all(),
+            FileCaps::all(),
+            path.as_ref().to_owned(),
+        );
+        fd
+    }
+
+    pub fn insert_dir(
+        &mut self,
+        fd: u32,
+        f: impl Fn() -> Result<Box<dyn WasiDir>, Error> + Send + Sync + 'static,
+        caps: DirCaps,
+        file_caps: FileCaps,
+        path: PathBuf,
+    ) {
+        self.slots.insert(
+            fd,
+            Slot::Dir {
+                factory: Arc::new(f),
+                caps,
+                file_caps,
+                path,
+            },
+        );
+    }
+
+    /// Bound the descriptors of each environ built from the template. The template's own
+    /// descriptors are never refused, but count against the limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Build a fresh environ. Fails if a preopened directory can't be opened.
+    pub fn instantiate(&self) -> Result<WasiEnviron, Error> {
+        let mut environ = WasiEnviron::new();
+        self.reset(&mut environ)?;
+        Ok(environ)
+    }
+
+    /// Put `environ` back into the state `instantiate` would build.
+    ///
+    /// Every descriptor is closed, including ones the template defines, which are opened again
+    /// from their factories so that no file offset or buffered data carries over. The arguments,
+    /// environment, exit code, default timeouts and limits are restored, and the metrics and
+    /// cancellation are reset in place, so handles cloned from `environ.metrics` or
+    /// `cancel_handle` keep working.
+    ///
+    /// If a directory fails to open, `environ` is left holding only the descriptors before it.
+    pub fn reset(&self, environ: &mut WasiEnviron) -> Result<(), Error> {
+        let old = std::mem::take(&mut environ.table);
+        for fd in old.keys() {
+            environ.metrics.close(fd);
+        }
+        drop(old);
+        environ.table.set_limits(self.limits);
+        environ.metrics.reset();
+        environ.cancel.reset();
+        environ.default_timeouts = Default::default();
+
+        environ.args = self.args.clone();
+        environ.env = self.env.clone();
+        environ.exit_code = 0;
+
+        for (&fd, slot) in &self.slots {
+            match slot {
+                Slot::File { factory, caps } => {
+                    let mut file = factory();
+                    let caps = caps.unwrap_or_else(|| WasiEnviron::stdio_rights(&mut *file));
+                    environ.insert_file(fd, file, caps);
+                }
+                Slot::Dir {
+                    factory,
+                    caps,
+                    file_caps,
+                    path,
+                } => {
+                    let dir = factory()?;
+                    environ.insert_dir(fd, dir, *caps, *file_caps, path.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+//! Syscall instrumentation.
+//!
+//! Every `WasiSnapshotPreview1` method runs inside a `SyscallSpan`, which times the call and
+//! records its outcome in the environ's `Metrics`.
+//!
+//! With the `tracing` feature enabled, it is also a `debug`-level span named after the syscall.
+//! Besides the syscall's own arguments, the span records `nbytes` for I/O, the `errno` returned
+//! to the guest (or `trap`), the full `anyhow` context chain as `error`, and `duration_us`.
+use crate::error::{Errno, Error};
+use crate::metrics::{Metrics, Outcome};
+use std::fmt::Debug;
+use std::time::Instant;
+
+/// Open a `SyscallSpan` for the syscall `$name`, reporting to `$metrics` and recording the
+/// given fields.
+///
+/// Fields that are only known later, such as decoded flags, must be declared up front after a
+/// `;` and filled in with `SyscallSpan::record`.
+macro_rules! syscall_span {
+    ($metrics:expr, $name:literal $(, $field:ident = $value:expr)* $(; $($later:ident),+)? $(,)?) => {{
+        #[cfg(feature = "tracing")]
+        let span = crate::trace::SyscallSpan::new($metrics, $name, tracing::debug_span!(
+            $name,
+            $($field = $value,)*
+            $($($later = tracing::field::Empty,)+)?
+            nbytes = tracing::field::Empty,
+            errno = tracing::field::Empty,
+            error = tracing::field::Empty,
+            duration_us = tracing::field::Empty,
+        ));
+        #[cfg(not(feature = "tracing"))]
+        let span = {
+            $(let _ = &$value;)*
+            crate::trace::SyscallSpan::new($metrics, $name)
+        };
+        span
+    }};
+}
+pub(crate) use syscall_span;
+
+pub(crate) struct SyscallSpan {
+    name: &'static str,
+    start: Instant,
+    metrics: Metrics,
+    outcome: Outcome,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl SyscallSpan {
+    #[cfg(feature = "tracing")]
+    pub(crate) fn new(metrics: &Metrics, name: &'static str, span: tracing::Span) -> Self {
+        SyscallSpan {
+            name,
+            start: Instant::now(),
+            metrics: metrics.clone(),
+            outcome: Outcome::Ok,
+            span,
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn new(metrics: &Metrics, name: &'static str) -> Self {
+        SyscallSpan {
+            name,
+            start: Instant::now(),
+            metrics: metrics.clone(),
+            outcome: Outcome::Ok,
+        }
+    }
+
+    /// Whether anyone is listening. Use this to skip computing expensive field values.
+    pub(crate) fn is_enabled(&self) -> bool {
+        #[cfg(feature = "tracing")]
+        return !self.span.is_disabled();
+        #[cfg(not(feature = "tracing"))]
+        false
+    }
+
+    /// Record a field declared when the span was opened, using its `Debug` output.
+    #[allow(unused_variables)]
+    pub(crate) fn record(&self, field: &'static str, value: impl Debug) {
+        #[cfg(feature = "tracing")]
+        self.span.record(field, tracing::field::debug(value));
+    }
+
+    #[allow(unused_variables)]
+    pub(crate) fn record_nbytes(&self, nbytes: u64) {
+        #[cfg(feature = "tracing")]
+        self.span.record("nbytes", nbytes);
+    }
+
+    /// Close the span, recording the outcome of a syscall that can fail.
+    pub(crate) fn finish<T>(mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(e) = &result {
+            self.outcome = match Errno::from_error(e) {
+                Some(errno) => Outcome::Errno(errno),
+                None => Outcome::Trap,
+            };
+            #[cfg(feature = "tracing")]
+            {
+                match &self.outcome {
+                    Outcome::Errno(errno) => {
+                        self.span.record("errno", tracing::field::debug(errno))
+                    }
+                    _ => self.span.record("errno", "trap"),
+                };
+                self.span
+                    .record("error", tracing::field::display(format!("{:#}", e)));
+            }
+        }
+        result
+    }
+}
+
+impl Drop for SyscallSpan {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed();
+        #[cfg(feature = "tracing")]
+        self.span.record("duration_us", duration.as_micros() as u64);
+        let outcome = std::mem::replace(&mut self.outcome, Outcome::Ok);
+        self.metrics.record_syscall(self.name, duration, outcome);
+    }
+}
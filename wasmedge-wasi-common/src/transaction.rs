@@ -0,0 +1,426 @@
+//! Transactional directories.
+//!
+//! A `Transaction` gives the guest a view of a directory in which every change it makes, whether
+//! writes, creates, unlinks, renames or times, is staged in memory instead of being made to the
+//! directory itself. Once the guest has run, the host either commits the staged changes to the
+//! directory or rolls them back, so that a failed run leaves nothing behind.
+//!
+//! The view is an `OverlayDir` with the directory as its lower layer and the staged changes on
+//! top, and behaves as described there; in particular, renaming a directory that is already in
+//! the directory copies it into the staged changes as a whole. Hard links made by the guest are
+//! committed as copies.
+//!
+//! A commit is all or nothing, even if the host crashes part way. The new contents of files are
+//! first written to a private directory `.wh..wh.commit` in the directory, along with a journal
+//! of every change to make, and nothing else is touched until the journal is complete. Only then
+//! are the changes made, each in a way that can be repeated, so that `Transaction::recover` can
+//! finish an interrupted commit, or throw away one that hadn't got as far as its journal.
+use crate::clocks::{SystemTimeSpec, WasiSystemClock};
+use crate::dir::{copy_times, entries, join, remove_all, WasiDir};
+use crate::error::{Errno, Error, ErrorExt};
+use crate::file::{copy_contents, FdFlags, FileType, OFlags};
+use crate::memfs::MemFs;
+use crate::overlay::{stat, OverlayDir, OPAQUE, WHITEOUT_PREFIX};
+use std::collections::HashSet;
+use std::io::{IoSlice, IoSliceMut};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// The directory a commit is prepared in. Names with the whiteout prefix are reserved in an
+/// overlay, so the guest can't have made or see this.
+const COMMIT_DIR: &str = ".wh..wh.commit";
+
+/// The journal of a commit, which only exists once it is complete.
+const JOURNAL: &str = ".wh..wh.commit/journal";
+
+/// The journal while it is being written.
+const JOURNAL_TEMP: &str = ".wh..wh.commit/journal.tmp";
+
+pub struct Transaction {
+    dir: Arc<dyn WasiDir>,
+    staged: MemFs,
+}
+
+/// A change to the directory, which leaves the same result however often it is made.
+#[derive(Debug, PartialEq)]
+enum Op {
+    /// Remove the entry, with everything in it.
+    Remove(String),
+    /// Make the entry a directory, keeping it if it is one already.
+    Dir(String),
+    /// Make the entry a symlink to the target.
+    Symlink(String, String),
+    /// Move the file prepared under the number into place, unless that's done already.
+    File(String, u64),
+    /// Set the access and modification times of the entry.
+    Times(String, Option<SystemTime>, Option<SystemTime>),
+}
+
+impl Transaction {
+    /// Stage changes to `dir` in memory, with timestamps from `clock`. The staged contents are
+    /// held to `memfs::DEFAULT_MAX_BYTES`.
+    pub fn new(dir: Box<dyn WasiDir>, clock: Box<dyn WasiSystemClock>) -> Self {
+        Transaction {
+            dir: Arc::from(dir),
+            staged: MemFs::new(clock),
+        }
+    }
+
+    /// The view of the directory with the staged changes on top, to preopen for the guest. All
+    /// views share the same staged changes.
+    ///
+    /// Renaming a directory that is in `dir` itself, rather than made by the guest, copies it
+    /// with everything in it into the staged changes, which may make it fail with `Nospc` for a
+    /// large tree.
+    pub fn view(&self) -> OverlayDir {
+        OverlayDir::new(self.dir.clone(), Box::new(self.staged.root()))
+    }
+
+    /// Make the staged changes to the directory, then clear them. An earlier commit that was
+    /// interrupted is finished first.
+    ///
+    /// If this fails before the journal is complete, the directory is left as it was and the
+    /// changes stay staged. If it fails after that, the rest of the changes are made by the next
+    /// `commit` or `recover`.
+    pub fn commit(&self) -> Result<(), Error> {
+        let dir = &*self.dir;
+        Self::recover(dir)?;
+        dir.create_dir(COMMIT_DIR)?;
+        let ops = match self.prepare() {
+            Ok(ops) => ops,
+            Err(e) => {
+                let _ = remove_all(dir, COMMIT_DIR);
+                return Err(e);
+            }
+        };
+        // From here on the commit will be finished, if not by this call then by the next.
+        dir.rename(JOURNAL_TEMP, dir, JOURNAL)?;
+        apply(dir, &ops)?;
+        remove_all(dir, COMMIT_DIR)?;
+        self.rollback()
+    }
+
+    /// Finish a commit to `dir` that was interrupted after its journal was complete, or throw
+    /// away one that was interrupted before. Call this on a directory before using it again
+    /// after a crash.
+    pub fn recover(dir: &dyn WasiDir) -> Result<(), Error> {
+        match read_file(dir, JOURNAL) {
+            Ok(journal) => apply(dir, &decode(&journal)?)?,
+            Err(e) if Errno::from_error(&e) == Some(Errno::Noent) => {}
+            Err(e) => return Err(e),
+        }
+        remove_all(dir, COMMIT_DIR)
+    }
+
+    /// Throw the staged changes away. Files the guest still has open keep their contents, but
+    /// are no longer part of the view.
+    pub fn rollback(&self) -> Result<(), Error> {
+        let staged = self.staged.root();
+        for (name, _) in entries(&staged, "")? {
+            remove_all(&staged, &name)?;
+        }
+        Ok(())
+    }
+
+    /// Write the new contents of files to the commit directory, and the journal of the changes
+    /// next to them, without touching anything else.
+    fn prepare(&self) -> Result<Vec<Op>, Error> {
+        let mut ops = Vec::new();
+        let mut files = 0;
+        self.plan("", &mut ops, &mut files)?;
+        let mut journal = self.dir.open_file(
+            false,
+            JOURNAL_TEMP,
+            OFlags::CREATE | OFlags::EXCLUSIVE,
+            false,
+            true,
+            FdFlags::empty(),
+        )?;
+        let data = encode(&ops);
+        let mut written = 0;
+        while written < data.len() {
+            match journal.write_vectored(&[IoSlice::new(&data[written..])])? {
+                0 => return Err(Error::io().context("journal write wrote nothing")),
+                n => written += n as usize,
+            }
+        }
+        journal.sync()?;
+        Ok(ops)
+    }
+
+    /// Add the changes to the directory at `path` to `ops`, preparing the files among them.
+    fn plan(&self, path: &str, ops: &mut Vec<Op>, files: &mut u64) -> Result<(), Error> {
+        let dir = &*self.dir;
+        let staged = self.staged.root();
+        let entries = entries(&staged, path)?;
+        let kept = entries
+            .iter()
+            .filter(|(name, _)| !name.starts_with(WHITEOUT_PREFIX))
+            .map(|(name, _)| name.as_str())
+            .collect::<HashSet<_>>();
+        // Entries that are replaced are taken care of where they are made, so only those that
+        // are gone for good are removed.
+        if entries.iter().any(|(name, _)| name == OPAQUE)
+            && stat(dir, path)?.map(|stat| stat.filetype) == Some(FileType::Directory)
+        {
+            for (name, _) in self::entries(dir, path)? {
+                if !kept.contains(name.as_str()) && name != COMMIT_DIR {
+                    ops.push(Op::Remove(join(path, &name)));
+                }
+            }
+        }
+        for (name, _) in &entries {
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                if name != OPAQUE && !kept.contains(hidden) {
+                    ops.push(Op::Remove(join(path, hidden)));
+                }
+            }
+        }
+        for (name, filetype) in &entries {
+            if name.starts_with(WHITEOUT_PREFIX) {
+                continue;
+            }
+            let target = join(path, name);
+            match filetype {
+                FileType::Directory => {
+                    ops.push(Op::Dir(target.clone()));
+                    self.plan(&target, ops, files)?;
+                    let stat = staged.get_path_filestat(&target, false)?;
+                    ops.push(Op::Times(target, stat.atim, stat.mtim));
+                }
+                FileType::SymbolicLink => {
+                    let link = staged.read_link(&target)?;
+                    let link = link
+                        .to_str()
+                        .ok_or_else(|| Error::illegal_byte_sequence().context("symlink target"))?;
+                    ops.push(Op::Symlink(target, link.to_owned()));
+                }
+                FileType::RegularFile => {
+                    let prepared = join(COMMIT_DIR, &files.to_string());
+                    let none = FdFlags::empty();
+                    let mut src =
+                        staged.open_file(false, &target, OFlags::empty(), true, false, none)?;
+                    let mut dst = dir.open_file(
+                        false,
+                        &prepared,
+                        OFlags::CREATE | OFlags::EXCLUSIVE,
+                        false,
+                        true,
+                        none,
+                    )?;
+                    copy_contents(&mut *src, &mut *dst)?;
+                    dst.sync()?;
+                    drop(dst);
+                    copy_times(dir, &prepared, &src.get_filestat()?)?;
+                    ops.push(Op::File(target, *files));
+                    *files += 1;
+                }
+                _ => return Err(Error::not_supported().context("committing a special file")),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Make the changes in `ops` to `dir`, skipping those that were made already.
+fn apply(dir: &dyn WasiDir, ops: &[Op]) -> Result<(), Error> {
+    for op in ops {
+        match op {
+            Op::Remove(path) => remove_all(dir, path)?,
+            Op::Dir(path) => {
+                if stat(dir, path)?.map(|stat| stat.filetype) != Some(FileType::Directory) {
+                    remove_all(dir, path)?;
+                    dir.create_dir(path)?;
+                }
+            }
+            Op::Symlink(path, target) => {
+                let done = match stat(dir, path)? {
+                    Some(stat) if stat.filetype == FileType::SymbolicLink => {
+                        dir.read_link(path)?.to_str() == Some(target.as_str())
+                    }
+                    _ => false,
+                };
+                if !done {
+                    remove_all(dir, path)?;
+                    dir.symlink(target, path)?;
+                }
+            }
+            Op::File(path, n) => {
+                let prepared = join(COMMIT_DIR, &n.to_string());
+                if stat(dir, &prepared)?.is_some() {
+                    if stat(dir, path)?.map(|stat| stat.filetype) == Some(FileType::Directory) {
+                        remove_all(dir, path)?;
+                    }
+                    dir.rename(&prepared, dir, path)?;
+                }
+            }
+            Op::Times(path, atim, mtim) => {
+                let spec = |t: &Option<SystemTime>| {
+                    t.map(|t| SystemTimeSpec::Absolute(cap_std::time::SystemTime::from_std(t)))
+                };
+                dir.set_times(path, spec(atim), spec(mtim), false)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_file(dir: &dyn WasiDir, path: &str) -> Result<Vec<u8>, Error> {
+    let mut file = dir.open_file(false, path, OFlags::empty(), true, false, FdFlags::empty())?;
+    let mut data = Vec::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read_vectored(&mut [IoSliceMut::new(&mut buf)])? as usize {
+            0 => return Ok(data),
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+/// Write `ops` as a journal: one line per change, a letter for its kind followed by its
+/// fields, each as its length in bytes, a colon and the field itself.
+fn encode(ops: &[Op]) -> Vec<u8> {
+    fn time(t: &Option<SystemTime>) -> String {
+        t.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or_else(String::new, |t| t.as_nanos().to_string())
+    }
+    let mut out = String::new();
+    for op in ops {
+        let (kind, fields) = match op {
+            Op::Remove(path) => ('R', vec![path.clone()]),
+            Op::Dir(path) => ('D', vec![path.clone()]),
+            Op::Symlink(path, target) => ('S', vec![path.clone(), target.clone()]),
+            Op::File(path, n) => ('F', vec![path.clone(), n.to_string()]),
+            Op::Times(path, atim, mtim) => ('T', vec![path.clone(), time(atim), time(mtim)]),
+        };
+        out.push(kind);
+        for field in fields {
+            out.push_str(&format!("{}:{}", field.len(), field));
+        }
+        out.push('\n');
+    }
+    out.into_bytes()
+}
+
+/// Read back a journal written by `encode`.
+fn decode(journal: &[u8]) -> Result<Vec<Op>, Error> {
+    let mut journal = Journal(std::str::from_utf8(journal).map_err(|_| corrupt())?);
+    let mut ops = Vec::new();
+    while let Some(kind) = journal.kind() {
+        ops.push(match kind {
+            'R' => Op::Remove(journal.field()?),
+            'D' => Op::Dir(journal.field()?),
+            'S' => Op::Symlink(journal.field()?, journal.field()?),
+            'F' => Op::File(journal.field()?, journal.number()?),
+            'T' => Op::Times(journal.field()?, journal.time()?, journal.time()?),
+            _ => return Err(corrupt()),
+        });
+        journal.0 = journal.0.strip_prefix('\n').ok_or_else(corrupt)?;
+    }
+    Ok(ops)
+}
+
+fn corrupt() -> Error {
+    Error::io().context("commit journal is corrupt")
+}
+
+/// What is left of a journal being read.
+struct Journal<'a>(&'a str);
+
+impl Journal<'_> {
+    fn kind(&mut self) -> Option<char> {
+        let kind = self.0.chars().next()?;
+        self.0 = &self.0[kind.len_utf8()..];
+        Some(kind)
+    }
+
+    fn field(&mut self) -> Result<String, Error> {
+        let (len, rest) = self.0.split_once(':').ok_or_else(corrupt)?;
+        let len = len.parse::<usize>().map_err(|_| corrupt())?;
+        let field = rest.get(..len).ok_or_else(corrupt)?;
+        self.0 = &rest[len..];
+        Ok(field.to_owned())
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T, Error> {
+        self.field()?.parse().map_err(|_| corrupt())
+    }
+
+    fn time(&mut self) -> Result<Option<SystemTime>, Error> {
+        if self.0.starts_with("0:") {
+            self.0 = &self.0[2..];
+            return Ok(None);
+        }
+        let nanos = self.number::<u128>()?;
+        let secs = u64::try_from(nanos / 1_000_000_000).map_err(|_| corrupt())?;
+        let since_epoch = Duration::new(secs, (nanos % 1_000_000_000) as u32);
+        Ok(Some(SystemTime::UNIX_EPOCH + since_epoch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::tests::{read, sorted_names, write, Clock};
+    use crate::memfs::MemDir;
+
+    /// A directory holding `a`, `b` and `d/e`, and a transaction on it that changes `a`,
+    /// removes `b`, moves `d` to `m`, and adds the symlink `l` and the dir `n`.
+    fn changed() -> (MemDir, Transaction) {
+        let dir = MemFs::new(Box::new(Clock)).root();
+        write(&dir, "a", b"a");
+        write(&dir, "b", b"b");
+        dir.create_dir("d").unwrap();
+        write(&dir, "d/e", b"e");
+        let transaction = Transaction::new(dir.open_dir(false, ".").unwrap(), Box::new(Clock));
+        let view = transaction.view();
+        write(&view, "a", b"new a");
+        view.unlink_file("b").unwrap();
+        view.rename("d", &view, "m").unwrap();
+        view.symlink("a", "l").unwrap();
+        view.create_dir("n").unwrap();
+        write(&view, "n/f", b"f");
+        (dir, transaction)
+    }
+
+    fn assert_committed(dir: &MemDir) {
+        assert_eq!(sorted_names(dir), [".", "..", "a", "l", "m", "n"]);
+        assert_eq!(read(dir, "a"), b"new a");
+        assert_eq!(read(dir, "m/e"), b"e");
+        assert_eq!(read(dir, "n/f"), b"f");
+        assert_eq!(dir.read_link("l").unwrap().to_str(), Some("a"));
+    }
+
+    #[test]
+    fn commit_makes_the_staged_changes() {
+        let (dir, transaction) = changed();
+        transaction.commit().unwrap();
+        assert_committed(&dir);
+        assert_eq!(sorted_names(&transaction.staged.root()), [".", ".."]);
+    }
+
+    #[test]
+    fn recover_finishes_a_commit_with_a_journal() {
+        let (dir, transaction) = changed();
+        dir.create_dir(COMMIT_DIR).unwrap();
+        let ops = transaction.prepare().unwrap();
+        assert_eq!(decode(&encode(&ops)).unwrap(), ops);
+        dir.rename(JOURNAL_TEMP, &dir, JOURNAL).unwrap();
+        // Interrupted part way through making the changes.
+        apply(&dir, &ops[..ops.len() / 2]).unwrap();
+        Transaction::recover(&dir).unwrap();
+        assert_committed(&dir);
+        Transaction::recover(&dir).unwrap();
+        assert_committed(&dir);
+    }
+
+    #[test]
+    fn recover_discards_a_commit_without_a_journal() {
+        let (dir, transaction) = changed();
+        dir.create_dir(COMMIT_DIR).unwrap();
+        transaction.prepare().unwrap();
+        Transaction::recover(&dir).unwrap();
+        assert_eq!(sorted_names(&dir), [".", "..", "a", "b", "d"]);
+        assert_eq!(read(&dir, "a"), b"a");
+    }
+}
@@ -0,0 +1,156 @@
+//! `HostResources` for the backends in this crate.
+use crate::dir::Dir;
+use crate::file::File;
+use crate::net::{TcpListener, TcpStream};
+use crate::stdio::{Stderr, Stdin, Stdout};
+use cap_std::AmbientAuthority;
+use io_lifetimes::AsFilelike;
+use std::path::PathBuf;
+use system_interface::io::IsReadWrite;
+use wasmedge_wasi_common::{
+    checkpoint::{HostResources, Resource},
+    dir::WasiDir,
+    error::{Error, ErrorExt},
+    file::{FdFlags, WasiFile},
+};
+
+#[cfg(unix)]
+use crate::net::{UnixListener, UnixStream};
+
+/// Locates files and directories by their absolute host path, and reopens them with ambient
+/// authority.
+///
+/// Host paths are only known on Linux, where they are read from `/proc/self/fd`; elsewhere only
+/// stdio and sockets can be located. Files that have been unlinked can't be found again.
+/// Listeners are bound again to their old address, but connections can't be reopened.
+pub struct AmbientResources(AmbientAuthority);
+
+impl AmbientResources {
+    pub fn new(ambient_authority: AmbientAuthority) -> Self {
+        AmbientResources(ambient_authority)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn host_path(f: &impl AsFilelike) -> Option<PathBuf> {
+    use std::os::unix::io::AsRawFd;
+    let fd = f.as_filelike().as_raw_fd();
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+    // Unlinked files show up as `/path (deleted)`, and pipes as `pipe:[...]`.
+    if path.is_absolute() && path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn host_path(_f: &impl AsFilelike) -> Option<PathBuf> {
+    None
+}
+
+impl HostResources for AmbientResources {
+    fn locate_file(&self, file: &dyn WasiFile) -> Option<Resource> {
+        let file = file.as_any();
+        if file.is::<Stdin>() {
+            Some(Resource::Stdin)
+        } else if file.is::<Stdout>() {
+            Some(Resource::Stdout)
+        } else if file.is::<Stderr>() {
+            Some(Resource::Stderr)
+        } else if let Some(f) = file.downcast_ref::<File>() {
+            let (read, write) = f.0.is_read_write().ok()?;
+            Some(Resource::File {
+                path: host_path(&f.0)?,
+                read,
+                write,
+            })
+        } else if let Some(l) = file.downcast_ref::<TcpListener>() {
+            Some(Resource::TcpListener {
+                local_addr: l.0.local_addr().ok()?,
+            })
+        } else if let Some(s) = file.downcast_ref::<TcpStream>() {
+            Some(Resource::TcpStream {
+                local_addr: s.0.local_addr().ok()?,
+                peer_addr: s.0.peer_addr().ok()?,
+            })
+        } else {
+            #[cfg(unix)]
+            if let Some(l) = file.downcast_ref::<UnixListener>() {
+                let addr = l.0.local_addr().ok()?;
+                return Some(Resource::UnixListener {
+                    path: addr.as_pathname().map(PathBuf::from),
+                });
+            } else if let Some(s) = file.downcast_ref::<UnixStream>() {
+                let addr = s.0.peer_addr().ok()?;
+                return Some(Resource::UnixStream {
+                    peer_path: addr.as_pathname().map(PathBuf::from),
+                });
+            }
+            None
+        }
+    }
+
+    fn locate_dir(&self, dir: &dyn WasiDir) -> Option<Resource> {
+        let dir = dir.as_any().downcast_ref::<Dir>()?;
+        Some(Resource::Dir {
+            path: host_path(&dir.0)?,
+        })
+    }
+
+    fn reopen_file(
+        &self,
+        resource: &Resource,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        match resource {
+            Resource::Stdin => Ok(Box::new(crate::stdio::stdin())),
+            Resource::Stdout => Ok(Box::new(crate::stdio::stdout())),
+            Resource::Stderr => Ok(Box::new(crate::stdio::stderr())),
+            Resource::File { path, read, write } => {
+                let mut options = cap_std::fs::OpenOptions::new();
+                options.read(*read).write(*write);
+                if fdflags.contains(FdFlags::APPEND) {
+                    options.append(true);
+                }
+                let file = cap_std::fs::File::open_ambient_with(path, &options, self.0)?;
+                let mut file = File::from_cap_std(file);
+                // The sync flags can only be chosen when opening through a directory.
+                let fdflags = fdflags - (FdFlags::DSYNC | FdFlags::SYNC | FdFlags::RSYNC);
+                if !fdflags.is_empty() {
+                    file.set_fdflags(fdflags)?;
+                }
+                Ok(Box::new(file))
+            }
+            Resource::TcpListener { local_addr } => {
+                let listener = std::net::TcpListener::bind(local_addr)?;
+                listener.set_nonblocking(fdflags.contains(FdFlags::NONBLOCK))?;
+                Ok(Box::new(TcpListener::from_cap_std(
+                    cap_std::net::TcpListener::from_std(listener),
+                )))
+            }
+            #[cfg(unix)]
+            Resource::UnixListener { path: Some(path) } => {
+                let listener = std::os::unix::net::UnixListener::bind(path)?;
+                listener.set_nonblocking(fdflags.contains(FdFlags::NONBLOCK))?;
+                Ok(Box::new(UnixListener::from_cap_std(
+                    cap_std::os::unix::net::UnixListener::from_std(listener),
+                )))
+            }
+            Resource::TcpStream { .. } | Resource::UnixStream { .. } => {
+                Err(Error::not_supported().context("connections can't be reopened"))
+            }
+            _ => Err(Error::not_supported().context(format!("can't reopen {:?}", resource))),
+        }
+    }
+
+    fn reopen_dir(&self, resource: &Resource) -> Result<Box<dyn WasiDir>, Error> {
+        match resource {
+            Resource::Dir { path } => {
+                let dir = cap_std::fs::Dir::open_ambient_dir(path, self.0)?;
+                Ok(Box::new(Dir::from_cap_std(dir)))
+            }
+            _ => Err(Error::not_dir().context(format!("can't reopen {:?}", resource))),
+        }
+    }
+}
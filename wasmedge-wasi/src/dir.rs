@@ -5,15 +5,22 @@ use std::path::{Path, PathBuf};
 use system_interface::fs::GetSetFdFlags;
 use wasmedge_wasi_common::{
     clocks,
-    dir::{ReaddirCursor, ReaddirEntity, WasiDir},
+    dir::{self, CopyPolicy, ReaddirCursor, ReaddirEntity, WasiDir},
     error::{Error, ErrorExt},
     file::{FdFlags, FileType, Filestat, OFlags, WasiFile},
 };
 
-pub struct Dir(cap_std::fs::Dir);
+/// A host directory. Renames into directories of other backends are copied across, unless the
+/// copy policy, which directories opened through this one inherit, denies it.
+pub struct Dir(pub(crate) cap_std::fs::Dir, CopyPolicy);
 impl Dir {
     pub fn from_cap_std(dir: cap_std::fs::Dir) -> Self {
-        Dir(dir)
+        Dir(dir, CopyPolicy::Allow)
+    }
+
+    pub fn with_copy_policy(mut self, policy: CopyPolicy) -> Self {
+        self.1 = policy;
+        self
     }
 
     pub fn open_file_(
@@ -86,7 +93,7 @@ impl Dir {
         } else {
             self.0.open_dir_nofollow(Path::new(path))?
         };
-        Ok(Dir::from_cap_std(d))
+        Ok(Dir(d, self.1))
     }
 
     pub fn rename_(&self, src_path: &str, dest_dir: &Self, dest_path: &str) -> Result<(), Error> {
@@ -154,7 +161,7 @@ impl WasiDir for Dir {
         .into_iter()
         .chain({
             // Now process the `DirEntry`s:
-            let entries = self.0.entries()?.map(|entry| {
+            self.0.entries()?.map(|entry| {
                 let entry = entry?;
                 let meta = entry.full_metadata()?;
                 let inode = meta.ino();
@@ -164,9 +171,7 @@ impl WasiDir for Dir {
                     .into_string()
                     .map_err(|_| Error::illegal_byte_sequence().context("filename"))?;
                 Ok((filetype, inode, name))
-            });
-
-            entries
+            })
         })
         // Enumeration of the iterator makes it possible to define the ReaddirCursor
         .enumerate()
@@ -232,11 +237,10 @@ impl WasiDir for Dir {
         })
     }
     fn rename(&self, src_path: &str, dest_dir: &dyn WasiDir, dest_path: &str) -> Result<(), Error> {
-        let dest_dir = dest_dir
-            .as_any()
-            .downcast_ref::<Self>()
-            .ok_or(Error::badf().context("failed downcast to cap-std Dir"))?;
-        self.rename_(src_path, dest_dir, dest_path)
+        match dest_dir.as_any().downcast_ref::<Self>() {
+            Some(dest_dir) => self.rename_(src_path, dest_dir, dest_path),
+            None => dir::move_across(self, src_path, dest_dir, dest_path),
+        }
     }
     fn hard_link(
         &self,
@@ -244,11 +248,10 @@ impl WasiDir for Dir {
         target_dir: &dyn WasiDir,
         target_path: &str,
     ) -> Result<(), Error> {
-        let target_dir = target_dir
-            .as_any()
-            .downcast_ref::<Self>()
-            .ok_or(Error::badf().context("failed downcast to cap-std Dir"))?;
-        self.hard_link_(src_path, target_dir, target_path)
+        match target_dir.as_any().downcast_ref::<Self>() {
+            Some(target_dir) => self.hard_link_(src_path, target_dir, target_path),
+            None => dir::link_across(self, src_path, target_dir, target_path),
+        }
     }
     fn set_times(
         &self,
@@ -272,6 +275,9 @@ impl WasiDir for Dir {
         }
         Ok(())
     }
+    fn copy_policy(&self) -> CopyPolicy {
+        self.1
+    }
 }
 
 fn convert_systimespec(t: Option<clocks::SystemTimeSpec>) -> Option<cap_fs_ext::SystemTimeSpec> {
@@ -51,7 +51,7 @@ pub fn filetype_from(ft: &cap_std::fs::FileType) -> FileType {
     }
 }
 
-pub struct File(cap_std::fs::File);
+pub struct File(pub(crate) cap_std::fs::File);
 impl File {
     pub fn from_cap_std(file: cap_std::fs::File) -> Self {
         File(file)
@@ -62,7 +62,11 @@ impl WasiFile for File {
         self
     }
     #[cfg(unix)]
-    fn pollable(&self) -> Option<rustix::fd::BorrowedFd> {
+    fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+        Some(self.0.as_fd())
+    }
+    #[cfg(unix)]
+    fn host_fd(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
         Some(self.0.as_fd())
     }
     fn datasync(&mut self) -> Result<(), Error> {
@@ -154,6 +158,9 @@ impl WasiFile for File {
     fn seek(&mut self, pos: std::io::SeekFrom) -> Result<u64, Error> {
         Ok(self.0.seek(pos)?)
     }
+    fn offset(&self) -> Option<u64> {
+        io::Seek::stream_position(&mut &self.0).ok()
+    }
     fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
         let n = self.0.peek(buf)?;
         Ok(n.try_into()?)
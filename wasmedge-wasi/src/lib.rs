@@ -1,17 +1,42 @@
+pub mod checkpoint;
 pub mod dir;
 pub mod file;
 pub mod net;
+pub mod scratch;
 pub mod stdio;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
 use crate::net::Socket;
+use crate::scratch::ScratchDir;
+use std::io;
 use std::path::Path;
-pub use wasmedge_wasi_common::{environ::WasiEnviron, file::WasiFile};
-use wasmedge_wasi_common::{error::Error, file::FileCaps, string_array::StringArrayError};
+use wasmedge_wasi_common::{
+    devices::DevDir,
+    dir::CopyPolicy,
+    disk_quota::{DiskQuota, QuotaDir},
+    error::Error,
+    file::{FileCaps, Timeouts},
+    limits::Limits,
+    quota::OutputQuota,
+    read_only::ReadOnlyDir,
+    string_array::StringArrayError,
+};
+pub use wasmedge_wasi_common::{
+    environ::WasiEnviron, file::WasiFile, shared_environ::SharedWasiEnviron,
+};
 
-pub struct WasiEnvironBuilder(WasiEnviron);
+/// Builds a `WasiEnviron`. The second field is the copy policy given to directories as they are
+/// preopened.
+pub struct WasiEnvironBuilder(WasiEnviron, CopyPolicy);
+impl Default for WasiEnvironBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl WasiEnvironBuilder {
     pub fn new() -> Self {
-        WasiEnvironBuilder(WasiEnviron::new())
+        WasiEnvironBuilder(WasiEnviron::new(), CopyPolicy::default())
     }
     pub fn env(mut self, var: &str, value: &str) -> Result<Self, StringArrayError> {
         self.0.push_env(var, value)?;
@@ -35,7 +60,7 @@ impl WasiEnvironBuilder {
     }
     pub fn args(mut self, arg: &[String]) -> Result<Self, StringArrayError> {
         for a in arg {
-            self.0.push_arg(&a)?;
+            self.0.push_arg(a)?;
         }
         Ok(self)
     }
@@ -57,6 +82,20 @@ impl WasiEnvironBuilder {
         self.0.set_stderr(f);
         self
     }
+    /// Bound how many bytes the guest may write to stdout. Keep a clone of `quota` to read the
+    /// usage.
+    pub fn stdout_quota(mut self, quota: OutputQuota) -> Self {
+        // The builder always has stdio, so this can't fail.
+        let _ = self.0.set_output_quota(1, Some(quota));
+        self
+    }
+    /// Bound how many bytes the guest may write to stderr. Keep a clone of `quota` to read the
+    /// usage.
+    pub fn stderr_quota(mut self, quota: OutputQuota) -> Self {
+        // The builder always has stdio, so this can't fail.
+        let _ = self.0.set_output_quota(2, Some(quota));
+        self
+    }
     pub fn inherit_stdin(self) -> Self {
         self.stdin(Box::new(crate::stdio::stdin()))
     }
@@ -69,15 +108,71 @@ impl WasiEnvironBuilder {
     pub fn inherit_stdio(self) -> Self {
         self.inherit_stdin().inherit_stdout().inherit_stderr()
     }
+    /// Whether renames and hard links out of or into the directories preopened after this may
+    /// copy entries across when the backends can't link into each other. With
+    /// `CopyPolicy::Deny`, they fail with `Xdev` instead, without copying anything.
+    pub fn copy_policy(mut self, policy: CopyPolicy) -> Self {
+        self.1 = policy;
+        self
+    }
+    fn host_dir(&self, dir: cap_std::fs::Dir) -> crate::dir::Dir {
+        crate::dir::Dir::from_cap_std(dir).with_copy_policy(self.1)
+    }
     pub fn preopened_dir(
         mut self,
         dir: cap_std::fs::Dir,
         guest_path: impl AsRef<Path>,
     ) -> Result<Self, Error> {
-        let dir = Box::new(crate::dir::Dir::from_cap_std(dir));
+        let dir = Box::new(self.host_dir(dir));
         self.0.push_preopened_dir(dir, guest_path)?;
         Ok(self)
     }
+    /// Preopen `dir`, holding the guest to `quota` across its whole tree. What the tree already
+    /// holds is counted first. Keep a clone of `quota` to query the remaining capacity.
+    pub fn preopened_dir_with_quota(
+        mut self,
+        dir: cap_std::fs::Dir,
+        guest_path: impl AsRef<Path>,
+        quota: DiskQuota,
+    ) -> Result<Self, Error> {
+        let dir = Box::new(self.host_dir(dir));
+        let dir = Box::new(QuotaDir::new(dir, quota)?);
+        self.0.push_preopened_dir(dir, guest_path)?;
+        Ok(self)
+    }
+    /// Preopen `dir` so that the guest can read it but not change it, whatever rights it asks
+    /// for.
+    pub fn preopened_dir_read_only(
+        mut self,
+        dir: cap_std::fs::Dir,
+        guest_path: impl AsRef<Path>,
+    ) -> Result<Self, Error> {
+        let dir = Box::new(self.host_dir(dir));
+        self.0
+            .push_preopened_dir(Box::new(ReadOnlyDir::new(dir)), guest_path)?;
+        Ok(self)
+    }
+    /// Create a private directory under the host's temporary directory and preopen it at
+    /// `guest_path`. It is removed once the environ is dropped, and not before, unless
+    /// `scratch.keep()` has been called by then. Keep a clone of `scratch` to find the directory
+    /// on the host.
+    pub fn scratch_dir(
+        mut self,
+        guest_path: impl AsRef<Path>,
+        scratch: ScratchDir,
+    ) -> Result<Self, Error> {
+        let (dir, removal) = scratch.create(self.1)?;
+        self.0.push_preopened_dir(dir, guest_path)?;
+        self.0.hold(removal);
+        Ok(self)
+    }
+    /// Preopen a read-only `/dev` holding `null`, `zero`, `full` and `urandom`, the last of which
+    /// reads from the host's random number generator.
+    pub fn dev_dir(mut self) -> Result<Self, Error> {
+        self.0
+            .push_preopened_dir(Box::new(DevDir::new(HostRandom)), "/dev")?;
+        Ok(self)
+    }
     pub fn preopened_socket(mut self, fd: u32, socket: impl Into<Socket>) -> Result<Self, Error> {
         let socket: Socket = socket.into();
         let file: Box<dyn WasiFile> = socket.into();
@@ -90,7 +185,30 @@ impl WasiEnvironBuilder {
         self.0.insert_file(fd, file, caps);
         Ok(self)
     }
+    /// Bound how long reads and writes on sockets and stdio may block.
+    pub fn default_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.0.set_default_timeouts(timeouts);
+        self
+    }
+    /// Bound how many descriptors, sockets and directories the guest may hold open.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.0.set_limits(limits);
+        self
+    }
     pub fn build(self) -> WasiEnviron {
         self.0
     }
+    pub fn build_shared(self) -> SharedWasiEnviron {
+        SharedWasiEnviron::new(self.0)
+    }
+}
+
+/// The host's random number generator, as read through `/dev/urandom`.
+struct HostRandom;
+
+impl io::Read for HostRandom {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        getrandom::fill(buf)?;
+        Ok(buf.len())
+    }
 }
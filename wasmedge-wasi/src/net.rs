@@ -5,7 +5,7 @@ use system_interface::io::IsReadWrite;
 use system_interface::io::ReadReady;
 use wasmedge_wasi_common::{
     error::{Error, ErrorExt},
-    file::{FdFlags, FileType, RiFlags, RoFlags, SdFlags, SiFlags, WasiFile},
+    file::{FdFlags, FileType, RiFlags, RoFlags, SdFlags, SiFlags, Timeouts, WasiFile},
 };
 
 #[cfg(unix)]
@@ -62,7 +62,7 @@ macro_rules! wasi_listen_write_impl {
                 self
             }
             #[cfg(unix)]
-            fn pollable(&self) -> Option<rustix::fd::BorrowedFd> {
+            fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
                 Some(self.0.as_fd())
             }
             fn sock_accept(&mut self, fdflags: FdFlags) -> Result<Box<dyn WasiFile>, Error> {
@@ -94,6 +94,14 @@ macro_rules! wasi_listen_write_impl {
             fn num_ready_bytes(&self) -> Result<u64, Error> {
                 Ok(1)
             }
+            fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+                // Listeners have no setters of their own, but `SO_RCVTIMEO` is a socket option
+                // like any other, and it also bounds how long `accept` waits.
+                let socket = socket2::SockRef::from(&self.0);
+                socket.set_read_timeout(timeouts.read)?;
+                socket.set_write_timeout(timeouts.write)?;
+                Ok(())
+            }
         }
         #[cfg(unix)]
         impl AsFd for $ty {
@@ -104,7 +112,7 @@ macro_rules! wasi_listen_write_impl {
     };
 }
 
-pub struct TcpListener(cap_std::net::TcpListener);
+pub struct TcpListener(pub(crate) cap_std::net::TcpListener);
 
 impl TcpListener {
     pub fn from_cap_std(cap_std: cap_std::net::TcpListener) -> Self {
@@ -114,7 +122,7 @@ impl TcpListener {
 wasi_listen_write_impl!(TcpListener, TcpStream);
 
 #[cfg(unix)]
-pub struct UnixListener(cap_std::os::unix::net::UnixListener);
+pub struct UnixListener(pub(crate) cap_std::os::unix::net::UnixListener);
 
 #[cfg(unix)]
 impl UnixListener {
@@ -133,7 +141,7 @@ macro_rules! wasi_stream_write_impl {
                 self
             }
             #[cfg(unix)]
-            fn pollable(&self) -> Option<rustix::fd::BorrowedFd> {
+            fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
                 Some(self.0.as_fd())
             }
             fn get_filetype(&mut self) -> Result<FileType, Error> {
@@ -177,6 +185,11 @@ macro_rules! wasi_stream_write_impl {
                 let val = self.as_socketlike_view::<$std_ty>().num_ready_bytes()?;
                 Ok(val)
             }
+            fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+                self.0.set_read_timeout(timeouts.read)?;
+                self.0.set_write_timeout(timeouts.write)?;
+                Ok(())
+            }
             fn readable(&self) -> Result<(), Error> {
                 let (readable, _writeable) = is_read_write(&self.0)?;
                 if readable {
@@ -274,7 +287,7 @@ macro_rules! wasi_stream_write_impl {
     };
 }
 
-pub struct TcpStream(cap_std::net::TcpStream);
+pub struct TcpStream(pub(crate) cap_std::net::TcpStream);
 
 impl TcpStream {
     pub fn from_cap_std(socket: cap_std::net::TcpStream) -> Self {
@@ -285,7 +298,7 @@ impl TcpStream {
 wasi_stream_write_impl!(TcpStream, std::net::TcpStream);
 
 #[cfg(unix)]
-pub struct UnixStream(cap_std::os::unix::net::UnixStream);
+pub struct UnixStream(pub(crate) cap_std::os::unix::net::UnixStream);
 
 #[cfg(unix)]
 impl UnixStream {
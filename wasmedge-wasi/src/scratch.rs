@@ -0,0 +1,162 @@
+//! Private scratch directories.
+//!
+//! `WasiEnvironBuilder::scratch_dir` creates a fresh directory under the host's temporary
+//! directory and preopens it, typically as the guest's `/tmp`. The directory is removed, with
+//! everything in it, when the environ is dropped, unless the host has asked to keep it. Until
+//! then it stays, even if the guest closes it or the host revokes it, so that files the guest
+//! still has open in it keep working.
+use crate::dir::Dir;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+use wasmedge_wasi_common::{
+    dir::{CopyPolicy, WasiDir},
+    disk_quota::{DiskQuota, QuotaDir},
+    error::Error,
+};
+
+/// The options of a scratch directory, and the host's handle to it. Keep a clone to find where
+/// the directory was created, or to keep it after a failed run.
+#[derive(Clone, Default)]
+pub struct ScratchDir {
+    quota: Option<DiskQuota>,
+    path: Arc<OnceLock<PathBuf>>,
+    keep: Arc<AtomicBool>,
+}
+
+impl ScratchDir {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold the guest to `quota` in the directory.
+    pub fn with_quota(mut self, quota: DiskQuota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Where the directory was created on the host, once it has been preopened.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.get().map(PathBuf::as_path)
+    }
+
+    /// Leave the directory in place when the environ drops it, e.g. to look into what a failed
+    /// run left behind.
+    pub fn keep(&self) {
+        self.keep.store(true, Ordering::SeqCst);
+    }
+
+    /// Create the directory and open it for the guest. The directory is removed when the
+    /// `Removal` is dropped, which the environ should hold until it is dropped itself.
+    pub(crate) fn create(&self, policy: CopyPolicy) -> Result<(Box<dyn WasiDir>, Removal), Error> {
+        let path = create_unique_dir()?;
+        let removal = Removal {
+            path: path.clone(),
+            keep: self.keep.clone(),
+        };
+        // If the directory can't be opened, dropping `removal` takes it away again.
+        let dir = cap_std::fs::Dir::open_ambient_dir(&path, cap_std::ambient_authority())?;
+        let mut dir: Box<dyn WasiDir> = Box::new(Dir::from_cap_std(dir).with_copy_policy(policy));
+        if let Some(quota) = &self.quota {
+            dir = Box::new(QuotaDir::new(dir, quota.clone())?);
+        }
+        let _ = self.path.set(path);
+        Ok((dir, removal))
+    }
+}
+
+/// Create a directory under the host's temporary directory, readable only by its owner, with a
+/// name no other directory has.
+fn create_unique_dir() -> io::Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    loop {
+        let name = format!(
+            "wasmedge-wasi-{}-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            nanos
+        );
+        let path = std::env::temp_dir().join(name);
+        match builder.create(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Removes the directory at `path` when dropped, unless it is to be kept.
+pub(crate) struct Removal {
+    path: PathBuf,
+    keep: Arc<AtomicBool>,
+}
+
+impl Drop for Removal {
+    fn drop(&mut self) {
+        if !self.keep.load(Ordering::SeqCst) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::IoSlice;
+    use wasmedge_wasi_common::error::Errno;
+    use wasmedge_wasi_common::file::{FdFlags, OFlags};
+
+    fn write(dir: &dyn WasiDir, path: &str, contents: &[u8]) -> Result<u64, Error> {
+        let mut file = dir.open_file(false, path, OFlags::CREATE, false, true, FdFlags::empty())?;
+        file.write_vectored(&[IoSlice::new(contents)])
+    }
+
+    #[test]
+    fn the_dir_is_removed_with_its_contents() {
+        let scratch = ScratchDir::new();
+        assert!(scratch.path().is_none());
+        let (dir, removal) = scratch.create(CopyPolicy::Allow).unwrap();
+        let path = scratch.path().unwrap().to_owned();
+        dir.create_dir("sub").unwrap();
+        write(&*dir, "sub/file", b"data").unwrap();
+        assert_eq!(std::fs::read(path.join("sub/file")).unwrap(), b"data");
+        drop(dir);
+        assert!(path.exists());
+        drop(removal);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn a_kept_dir_stays() {
+        let scratch = ScratchDir::new();
+        let (dir, removal) = scratch.create(CopyPolicy::Allow).unwrap();
+        write(&*dir, "file", b"data").unwrap();
+        scratch.clone().keep();
+        drop((dir, removal));
+        let path = scratch.path().unwrap();
+        assert_eq!(std::fs::read(path.join("file")).unwrap(), b"data");
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn each_dir_is_new_and_held_to_its_quota() {
+        let quota = DiskQuota::new(Some(4), None);
+        let first = ScratchDir::new().with_quota(quota.clone());
+        let second = ScratchDir::new();
+        let (dir, _removal) = first.create(CopyPolicy::Allow).unwrap();
+        let (_other, _other_removal) = second.create(CopyPolicy::Allow).unwrap();
+        assert_ne!(first.path(), second.path());
+        assert_eq!(write(&*dir, "a", b"data").unwrap(), 4);
+        let e = write(&*dir, "b", b"x").unwrap_err();
+        assert_eq!(Errno::from_error(&e), Some(Errno::Dquot));
+        assert_eq!(quota.bytes_used(), 4);
+    }
+}
@@ -26,7 +26,7 @@ impl WasiFile for Stdin {
         self
     }
     #[cfg(unix)]
-    fn pollable(&self) -> Option<rustix::fd::BorrowedFd> {
+    fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
         Some(self.0.as_fd())
     }
     fn get_filetype(&mut self) -> Result<FileType, Error> {
@@ -38,7 +38,7 @@ impl WasiFile for Stdin {
     }
     fn read_vectored<'a>(&mut self, bufs: &mut [io::IoSliceMut<'a>]) -> Result<u64, Error> {
         let n = (&*self.0.as_filelike_view::<File>()).read_vectored(bufs)?;
-        Ok(n.try_into().map_err(|_| Error::range())?)
+        n.try_into().map_err(|_| Error::range())
     }
     fn read_vectored_at<'a>(
         &mut self,
@@ -85,7 +85,7 @@ macro_rules! wasi_file_write_impl {
                 self
             }
             #[cfg(unix)]
-            fn pollable(&self) -> Option<rustix::fd::BorrowedFd> {
+            fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
                 Some(self.0.as_fd())
             }
             fn get_filetype(&mut self) -> Result<FileType, Error> {
@@ -100,7 +100,7 @@ macro_rules! wasi_file_write_impl {
             }
             fn write_vectored<'a>(&mut self, bufs: &[io::IoSlice<'a>]) -> Result<u64, Error> {
                 let n = (&*self.0.as_filelike_view::<File>()).write_vectored(bufs)?;
-                Ok(n.try_into().map_err(|c| Error::range().context(c))?)
+                n.try_into().map_err(|c| Error::range().context(c))
             }
             fn write_vectored_at<'a>(
                 &mut self,
@@ -0,0 +1,167 @@
+use super::file::{asyncify, File};
+use std::any::Any;
+use std::path::PathBuf;
+use std::sync::Arc;
+use wasmedge_wasi_common::{
+    async_dir::{self, AsyncWasiDir},
+    async_file::AsyncWasiFile,
+    async_trait, clocks,
+    dir::{CopyPolicy, ReaddirCursor, ReaddirEntity, WasiDir},
+    error::Error,
+    file::{FdFlags, Filestat, OFlags},
+};
+
+/// A cap-std directory whose operations run on tokio's blocking thread pool.
+pub struct Dir(Arc<crate::dir::Dir>);
+impl Dir {
+    pub fn from_cap_std(dir: cap_std::fs::Dir) -> Self {
+        Self::from_sync(crate::dir::Dir::from_cap_std(dir))
+    }
+
+    pub fn from_sync(dir: crate::dir::Dir) -> Self {
+        Dir(Arc::new(dir))
+    }
+
+    /// Run `op` against the underlying synchronous `Dir` with an owned copy of `path`.
+    async fn run<T, Op>(&self, path: &str, op: Op) -> Result<T, Error>
+    where
+        Op: FnOnce(&crate::dir::Dir, &str) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let dir = self.0.clone();
+        let path = path.to_owned();
+        asyncify(move || op(&dir, &path)).await
+    }
+}
+
+#[async_trait]
+impl AsyncWasiDir for Dir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    async fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn AsyncWasiFile>, Error> {
+        let f = self
+            .run(path, move |d, path| {
+                d.open_file_(symlink_follow, path, oflags, read, write, fdflags)
+            })
+            .await?;
+        Ok(Box::new(File::from_sync(f)))
+    }
+
+    async fn open_dir(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+    ) -> Result<Box<dyn AsyncWasiDir>, Error> {
+        let d = self
+            .run(path, move |d, path| d.open_dir_(symlink_follow, path))
+            .await?;
+        Ok(Box::new(Dir(Arc::new(d))))
+    }
+
+    async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        self.run(path, |d, path| d.create_dir(path)).await
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let entries = self
+            .run("", move |d, _| Ok(d.readdir(cursor)?.collect::<Vec<_>>()))
+            .await?;
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    async fn symlink(&self, src_path: &str, dest_path: &str) -> Result<(), Error> {
+        let dest_path = dest_path.to_owned();
+        self.run(src_path, move |d, src_path| d.symlink(src_path, &dest_path))
+            .await
+    }
+
+    async fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        self.run(path, |d, path| d.remove_dir(path)).await
+    }
+
+    async fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        self.run(path, |d, path| d.unlink_file(path)).await
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.run(path, |d, path| d.read_link(path)).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.run("", |d, _| d.get_filestat()).await
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        self.run(path, move |d, path| {
+            d.get_path_filestat(path, follow_symlinks)
+        })
+        .await
+    }
+
+    async fn rename(
+        &self,
+        src_path: &str,
+        dest_dir: &dyn AsyncWasiDir,
+        dest_path: &str,
+    ) -> Result<(), Error> {
+        let dest_dir = match dest_dir.as_any().downcast_ref::<Self>() {
+            Some(dest_dir) => dest_dir.0.clone(),
+            None => return async_dir::move_across(self, src_path, dest_dir, dest_path).await,
+        };
+        let dest_path = dest_path.to_owned();
+        self.run(src_path, move |d, src_path| {
+            d.rename_(src_path, &dest_dir, &dest_path)
+        })
+        .await
+    }
+
+    async fn hard_link(
+        &self,
+        src_path: &str,
+        target_dir: &dyn AsyncWasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        let target_dir = match target_dir.as_any().downcast_ref::<Self>() {
+            Some(target_dir) => target_dir.0.clone(),
+            None => return async_dir::link_across(self, src_path, target_dir, target_path).await,
+        };
+        let target_path = target_path.to_owned();
+        self.run(src_path, move |d, src_path| {
+            d.hard_link_(src_path, &target_dir, &target_path)
+        })
+        .await
+    }
+
+    async fn set_times(
+        &self,
+        path: &str,
+        atime: Option<clocks::SystemTimeSpec>,
+        mtime: Option<clocks::SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        self.run(path, move |d, path| {
+            d.set_times(path, atime, mtime, follow_symlinks)
+        })
+        .await
+    }
+
+    fn copy_policy(&self) -> CopyPolicy {
+        self.0.copy_policy()
+    }
+}
@@ -0,0 +1,330 @@
+#[cfg(unix)]
+use rustix::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::any::Any;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(unix)]
+use tokio::io::{unix::AsyncFd, Interest};
+use wasmedge_wasi_common::{
+    async_file::AsyncWasiFile,
+    async_trait, clocks,
+    error::{Error, ErrorExt},
+    file::{Advice, FdFlags, FileType, Filestat, WasiFile},
+};
+
+/// Run a blocking closure on tokio's blocking thread pool.
+pub(crate) async fn asyncify<F, T>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    ::tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::trap(format!("blocking task failed: {}", e)))?
+}
+
+/// Adapts a synchronous `WasiFile` to `AsyncWasiFile` by running every operation on tokio's
+/// blocking thread pool, so a read that blocks, such as on stdin, never stalls a runtime worker.
+///
+/// Buffers are copied to and from the blocking thread, since they can't be borrowed across it.
+/// Reads and writes on a pollable fd first wait on the reactor until it is ready, so that
+/// cancelling them doesn't leave a thread blocked. A read cancelled once it is running on the
+/// blocking pool still completes there, and its bytes are returned by the next read.
+pub(crate) struct Blocking<F: ?Sized> {
+    unreceived: Arc<Mutex<Option<Vec<u8>>>>,
+    /// The file's `host_fd`, kept so it can be handed out without locking the file.
+    #[cfg(unix)]
+    host_fd: Option<RawFd>,
+    file: Arc<Mutex<F>>,
+}
+
+impl<F: WasiFile + 'static> Blocking<F> {
+    pub(crate) fn new(f: F) -> Self {
+        Blocking {
+            unreceived: Arc::new(Mutex::new(None)),
+            #[cfg(unix)]
+            host_fd: f.host_fd().map(|fd| fd.as_raw_fd()),
+            file: Arc::new(Mutex::new(f)),
+        }
+    }
+}
+
+impl<F: WasiFile + ?Sized + 'static> Blocking<F> {
+    /// Lock the file on the calling thread. Only for operations that never block.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, F> {
+        self.file.lock().unwrap()
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn host_fd(&self) -> Option<BorrowedFd<'_>> {
+        // SAFETY: the descriptor belongs to the file, which `self` keeps open.
+        self.host_fd.map(|fd| unsafe { BorrowedFd::borrow_raw(fd) })
+    }
+
+    pub(crate) async fn run<T, Op>(&self, op: Op) -> Result<T, Error>
+    where
+        Op: FnOnce(&mut F) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let f = self.file.clone();
+        asyncify(move || op(&mut f.lock().unwrap())).await
+    }
+
+    pub(crate) async fn read_vectored(
+        &self,
+        bufs: &mut [io::IoSliceMut<'_>],
+        offset: Option<u64>,
+    ) -> Result<u64, Error> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+        let data = match offset {
+            Some(offset) => {
+                self.run(move |f| {
+                    let mut data = vec![0; len];
+                    let n = f.read_vectored_at(&mut [io::IoSliceMut::new(&mut data)], offset)?;
+                    data.truncate(n as usize);
+                    Ok(data)
+                })
+                .await?
+            }
+            None => {
+                if self.unreceived.lock().unwrap().is_none() {
+                    self.ready(readable()).await?;
+                }
+                // The bytes go through `unreceived`, so they aren't lost if this future is
+                // dropped while the read runs.
+                let unreceived = self.unreceived.clone();
+                self.run(move |f| {
+                    if unreceived.lock().unwrap().is_some() {
+                        return Ok(());
+                    }
+                    let mut data = vec![0; len];
+                    let n = f.read_vectored(&mut [io::IoSliceMut::new(&mut data)])?;
+                    data.truncate(n as usize);
+                    *unreceived.lock().unwrap() = Some(data);
+                    Ok(())
+                })
+                .await?;
+                let mut unreceived = self.unreceived.lock().unwrap();
+                let mut data = unreceived.take().unwrap_or_default();
+                if data.len() > len {
+                    *unreceived = Some(data.split_off(len));
+                }
+                data
+            }
+        };
+
+        let mut rest = &data[..];
+        for buf in bufs.iter_mut() {
+            let n = buf.len().min(rest.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            rest = &rest[n..];
+        }
+        Ok(data.len() as u64)
+    }
+
+    pub(crate) async fn write_vectored(
+        &self,
+        bufs: &[io::IoSlice<'_>],
+        offset: Option<u64>,
+    ) -> Result<u64, Error> {
+        let data = bufs
+            .iter()
+            .flat_map(|b| b.iter().copied())
+            .collect::<Vec<u8>>();
+        if offset.is_none() {
+            self.ready(writable()).await?;
+        }
+        self.run(move |f| match offset {
+            Some(offset) => f.write_vectored_at(&[io::IoSlice::new(&data)], offset),
+            None => f.write_vectored(&[io::IoSlice::new(&data)]),
+        })
+        .await
+    }
+
+    /// Wait on the tokio reactor until the file's pollable fd reports `interest`, so that
+    /// dropping the future stops the wait. Files without a pollable fd, and files the reactor
+    /// can't watch, such as regular files, are always ready.
+    ///
+    /// While an earlier operation still holds the file on the blocking pool, the file counts as
+    /// ready: the next operation queues behind it there.
+    #[cfg(unix)]
+    pub(crate) async fn ready(&self, interest: Interest) -> Result<(), Error> {
+        // Watch a duplicate of the fd, so that the file isn't locked while waiting.
+        let fd = match self.file.try_lock() {
+            Ok(f) => match f.pollable() {
+                Some(fd) => rustix::io::dup(fd)?,
+                None => return Ok(()),
+            },
+            Err(_) => return Ok(()),
+        };
+        // SAFETY: the `AsyncFd` owns the duplicate, which stays open and unchanged until it is
+        // dropped.
+        let fd = match unsafe { AsyncFd::register_with_interest(fd, interest) } {
+            Ok(fd) => fd,
+            Err(e) => {
+                let e = io::Error::from(e);
+                if e.raw_os_error() == Some(rustix::io::Errno::PERM.raw_os_error()) {
+                    return Ok(());
+                }
+                return Err(e.into());
+            }
+        };
+        let _ready = fd.ready(interest).await?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) async fn ready(&self, _events: ()) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn readable() -> Interest {
+    Interest::READABLE
+}
+
+#[cfg(unix)]
+pub(crate) fn writable() -> Interest {
+    Interest::WRITABLE
+}
+
+#[cfg(not(unix))]
+pub(crate) fn readable() {}
+
+#[cfg(not(unix))]
+pub(crate) fn writable() {}
+
+/// Implement `AsyncWasiFile` for a newtype around `Blocking<F>`, delegating every method to
+/// `F`'s `WasiFile` implementation. Expects the same imports as this module at the call site.
+macro_rules! wasi_file_blocking_impl {
+    ($ty:ty) => {
+        #[async_trait]
+        impl AsyncWasiFile for $ty {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+            async fn get_filetype(&mut self) -> Result<FileType, Error> {
+                self.0.run(|f| f.get_filetype()).await
+            }
+            #[cfg(unix)]
+            fn host_fd(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+                self.0.host_fd()
+            }
+            fn isatty(&mut self) -> bool {
+                self.0.lock().isatty()
+            }
+            async fn datasync(&mut self) -> Result<(), Error> {
+                self.0.run(|f| f.datasync()).await
+            }
+            async fn sync(&mut self) -> Result<(), Error> {
+                self.0.run(|f| f.sync()).await
+            }
+            async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+                self.0.run(|f| f.get_fdflags()).await
+            }
+            async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+                self.0.run(move |f| f.set_fdflags(flags)).await
+            }
+            async fn get_filestat(&mut self) -> Result<Filestat, Error> {
+                self.0.run(|f| f.get_filestat()).await
+            }
+            async fn set_filestat_size(&mut self, size: u64) -> Result<(), Error> {
+                self.0.run(move |f| f.set_filestat_size(size)).await
+            }
+            async fn advise(&mut self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+                self.0.run(move |f| f.advise(offset, len, advice)).await
+            }
+            async fn allocate(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+                self.0.run(move |f| f.allocate(offset, len)).await
+            }
+            async fn set_times(
+                &mut self,
+                atime: Option<clocks::SystemTimeSpec>,
+                mtime: Option<clocks::SystemTimeSpec>,
+            ) -> Result<(), Error> {
+                self.0.run(move |f| f.set_times(atime, mtime)).await
+            }
+            async fn read_vectored<'a>(
+                &mut self,
+                bufs: &mut [std::io::IoSliceMut<'a>],
+            ) -> Result<u64, Error> {
+                self.0.read_vectored(bufs, None).await
+            }
+            async fn read_vectored_at<'a>(
+                &mut self,
+                bufs: &mut [std::io::IoSliceMut<'a>],
+                offset: u64,
+            ) -> Result<u64, Error> {
+                self.0.read_vectored(bufs, Some(offset)).await
+            }
+            async fn write_vectored<'a>(
+                &mut self,
+                bufs: &[std::io::IoSlice<'a>],
+            ) -> Result<u64, Error> {
+                self.0.write_vectored(bufs, None).await
+            }
+            async fn write_vectored_at<'a>(
+                &mut self,
+                bufs: &[std::io::IoSlice<'a>],
+                offset: u64,
+            ) -> Result<u64, Error> {
+                self.0.write_vectored(bufs, Some(offset)).await
+            }
+            async fn seek(&mut self, pos: std::io::SeekFrom) -> Result<u64, Error> {
+                self.0.run(move |f| f.seek(pos)).await
+            }
+            async fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+                let len = buf.len();
+                let data = self
+                    .0
+                    .run(move |f| {
+                        let mut data = vec![0; len];
+                        let n = f.peek(&mut data)?;
+                        data.truncate(n as usize);
+                        Ok(data)
+                    })
+                    .await?;
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len() as u64)
+            }
+            async fn num_ready_bytes(&self) -> Result<u64, Error> {
+                self.0.run(|f| f.num_ready_bytes()).await
+            }
+            async fn readable(&self) -> Result<(), Error> {
+                self.0.ready($crate::tokio::file::readable()).await
+            }
+            async fn writable(&self) -> Result<(), Error> {
+                self.0.ready($crate::tokio::file::writable()).await
+            }
+        }
+    };
+}
+pub(crate) use wasi_file_blocking_impl;
+
+pub struct File(Blocking<crate::file::File>);
+impl File {
+    pub fn from_cap_std(file: cap_std::fs::File) -> Self {
+        Self::from_sync(crate::file::File::from_cap_std(file))
+    }
+
+    pub(crate) fn from_sync(file: crate::file::File) -> Self {
+        File(Blocking::new(file))
+    }
+}
+wasi_file_blocking_impl!(File);
+
+/// Runs any synchronous `WasiFile` on tokio's blocking thread pool, such as a `ReadPipe` or
+/// `WritePipe` whose reader or writer may block.
+pub struct SyncFile(Blocking<dyn WasiFile>);
+impl SyncFile {
+    pub fn new(file: impl WasiFile + 'static) -> Self {
+        SyncFile(Blocking {
+            unreceived: Arc::new(Mutex::new(None)),
+            #[cfg(unix)]
+            host_fd: file.host_fd().map(|fd| fd.as_raw_fd()),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+}
+wasi_file_blocking_impl!(SyncFile);
@@ -0,0 +1,145 @@
+//! Asynchronous counterparts of this crate's backends for hosts running on a tokio runtime.
+//!
+//! Files, directories and stdio run their blocking host calls on tokio's blocking thread pool,
+//! while sockets are driven by the tokio reactor. Use `WasiEnvironBuilder` here to build an
+//! `AsyncWasiEnviron` out of them.
+pub mod dir;
+pub mod file;
+#[cfg(unix)]
+pub mod net;
+pub mod sched;
+pub mod stdio;
+
+use crate::net::Socket;
+use std::path::Path;
+pub use wasmedge_wasi_common::{async_environ::AsyncWasiEnviron, async_file::AsyncWasiFile};
+use wasmedge_wasi_common::{
+    dir::CopyPolicy, error::Error, file::FileCaps, limits::Limits, quota::OutputQuota,
+    string_array::StringArrayError,
+};
+
+/// Builds an `AsyncWasiEnviron`. The second field is the copy policy given to directories as
+/// they are preopened.
+pub struct WasiEnvironBuilder(AsyncWasiEnviron, CopyPolicy);
+impl Default for WasiEnvironBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl WasiEnvironBuilder {
+    pub fn new() -> Self {
+        WasiEnvironBuilder(
+            AsyncWasiEnviron::new(Box::new(sched::TokioSched)),
+            CopyPolicy::default(),
+        )
+    }
+    pub fn env(mut self, var: &str, value: &str) -> Result<Self, StringArrayError> {
+        self.0.push_env(var, value)?;
+        Ok(self)
+    }
+    pub fn envs(mut self, env: &[(String, String)]) -> Result<Self, StringArrayError> {
+        for (k, v) in env {
+            self.0.push_env(k, v)?;
+        }
+        Ok(self)
+    }
+    pub fn inherit_env(mut self) -> Result<Self, StringArrayError> {
+        for (key, value) in std::env::vars() {
+            self.0.push_env(&key, &value)?;
+        }
+        Ok(self)
+    }
+    pub fn arg(mut self, arg: &str) -> Result<Self, StringArrayError> {
+        self.0.push_arg(arg)?;
+        Ok(self)
+    }
+    pub fn args(mut self, arg: &[String]) -> Result<Self, StringArrayError> {
+        for a in arg {
+            self.0.push_arg(a)?;
+        }
+        Ok(self)
+    }
+    pub fn inherit_args(mut self) -> Result<Self, StringArrayError> {
+        for arg in std::env::args() {
+            self.0.push_arg(&arg)?;
+        }
+        Ok(self)
+    }
+    pub fn stdin(mut self, f: Box<dyn AsyncWasiFile>) -> Self {
+        self.0.set_stdin(f);
+        self
+    }
+    pub fn stdout(mut self, f: Box<dyn AsyncWasiFile>) -> Self {
+        self.0.set_stdout(f);
+        self
+    }
+    pub fn stderr(mut self, f: Box<dyn AsyncWasiFile>) -> Self {
+        self.0.set_stderr(f);
+        self
+    }
+    /// Bound how many bytes the guest may write to stdout. Keep a clone of `quota` to read the
+    /// usage.
+    pub fn stdout_quota(mut self, quota: OutputQuota) -> Self {
+        // The builder always has stdio, so this can't fail.
+        let _ = self.0.set_output_quota(1, Some(quota));
+        self
+    }
+    /// Bound how many bytes the guest may write to stderr. Keep a clone of `quota` to read the
+    /// usage.
+    pub fn stderr_quota(mut self, quota: OutputQuota) -> Self {
+        // The builder always has stdio, so this can't fail.
+        let _ = self.0.set_output_quota(2, Some(quota));
+        self
+    }
+    pub fn inherit_stdin(self) -> Self {
+        self.stdin(Box::new(stdio::stdin()))
+    }
+    pub fn inherit_stdout(self) -> Self {
+        self.stdout(Box::new(stdio::stdout()))
+    }
+    pub fn inherit_stderr(self) -> Self {
+        self.stderr(Box::new(stdio::stderr()))
+    }
+    pub fn inherit_stdio(self) -> Self {
+        self.inherit_stdin().inherit_stdout().inherit_stderr()
+    }
+    /// Whether renames and hard links out of or into the directories preopened after this may
+    /// copy entries across when the backends can't link into each other. With
+    /// `CopyPolicy::Deny`, they fail with `Xdev` instead, without copying anything.
+    pub fn copy_policy(mut self, policy: CopyPolicy) -> Self {
+        self.1 = policy;
+        self
+    }
+    pub fn preopened_dir(
+        mut self,
+        dir: cap_std::fs::Dir,
+        guest_path: impl AsRef<Path>,
+    ) -> Result<Self, Error> {
+        let dir = crate::dir::Dir::from_cap_std(dir).with_copy_policy(self.1);
+        let dir = Box::new(dir::Dir::from_sync(dir));
+        self.0.push_preopened_dir(dir, guest_path)?;
+        Ok(self)
+    }
+    /// Must be called from within a tokio runtime, which the socket is registered with.
+    #[cfg(unix)]
+    pub fn preopened_socket(mut self, fd: u32, socket: impl Into<Socket>) -> Result<Self, Error> {
+        let socket: Socket = socket.into();
+        let file: Box<dyn AsyncWasiFile> = socket.try_into()?;
+
+        let caps = FileCaps::FDSTAT_SET_FLAGS
+            | FileCaps::FILESTAT_GET
+            | FileCaps::READ
+            | FileCaps::POLL_READWRITE;
+
+        self.0.insert_file(fd, file, caps);
+        Ok(self)
+    }
+    /// Bound how many descriptors, sockets and directories the guest may hold open.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.0.set_limits(limits);
+        self
+    }
+    pub fn build(self) -> AsyncWasiEnviron {
+        self.0
+    }
+}
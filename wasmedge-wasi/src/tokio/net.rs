@@ -0,0 +1,281 @@
+//! Sockets driven by the tokio reactor.
+//!
+//! The host sockets are always nonblocking and registered with the reactor, so blocking guest
+//! operations wait for readiness instead of parking a thread. When the guest sets `NONBLOCK`,
+//! operations that would block fail with `Again` instead of waiting.
+use crate::net::Socket;
+use ::tokio::io::unix::AsyncFd;
+use io_lifetimes::AsSocketlike;
+use std::any::Any;
+use std::io;
+use system_interface::io::{IoExt, ReadReady};
+use wasmedge_wasi_common::{
+    async_file::AsyncWasiFile,
+    async_trait,
+    error::{Error, ErrorExt},
+    file::{FdFlags, FileType, RiFlags, RoFlags, SdFlags, SiFlags},
+};
+
+impl TryFrom<Socket> for Box<dyn AsyncWasiFile> {
+    type Error = Error;
+
+    /// Register the socket with the current tokio runtime.
+    fn try_from(socket: Socket) -> Result<Self, Error> {
+        Ok(match socket {
+            Socket::TcpListener(l) => Box::new(TcpListener::from_cap_std(l)?),
+            Socket::UnixListener(l) => Box::new(UnixListener::from_cap_std(l)?),
+            Socket::TcpStream(l) => Box::new(TcpStream::from_cap_std(l)?),
+            Socket::UnixStream(l) => Box::new(UnixStream::from_cap_std(l)?),
+        })
+    }
+}
+
+/// Wait for `ready` on `inner` and run `op`, retrying until it doesn't report `WouldBlock`. If
+/// the guest asked for nonblocking I/O, run `op` once without waiting.
+macro_rules! async_io {
+    ($self:ident, $ready:ident, |$inner:ident| $op:expr) => {{
+        if $self.fdflags.contains(FdFlags::NONBLOCK) {
+            let $inner = $self.inner.get_ref();
+            $op
+        } else {
+            loop {
+                let mut guard = $self.inner.$ready().await?;
+                match guard.try_io(|$inner| {
+                    let $inner = $inner.get_ref();
+                    $op
+                }) {
+                    Ok(result) => break result,
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }};
+}
+
+macro_rules! wasi_listen_write_impl {
+    ($ty:ident, $cap_std_ty:ty, $stream:ident) => {
+        pub struct $ty {
+            inner: AsyncFd<$cap_std_ty>,
+            fdflags: FdFlags,
+        }
+
+        impl $ty {
+            /// Must be called from within a tokio runtime.
+            pub fn from_cap_std(listener: $cap_std_ty) -> Result<Self, Error> {
+                listener.set_nonblocking(true)?;
+                // SAFETY: the cap-std type owns its fd, which therefore stays open and
+                // unchanged until the `AsyncFd` drops it.
+                let inner = unsafe { AsyncFd::register(listener) }.map_err(io::Error::from)?;
+                Ok($ty {
+                    inner,
+                    fdflags: FdFlags::empty(),
+                })
+            }
+        }
+
+        #[async_trait]
+        impl AsyncWasiFile for $ty {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+            #[cfg(unix)]
+            fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+                Some(io_lifetimes::AsFd::as_fd(self.inner.get_ref()))
+            }
+            async fn sock_accept(
+                &mut self,
+                fdflags: FdFlags,
+            ) -> Result<Box<dyn AsyncWasiFile>, Error> {
+                let (stream, _) = async_io!(self, readable, |inner| inner.accept())?;
+                let mut stream = $stream::from_cap_std(stream)?;
+                stream.set_fdflags(fdflags).await?;
+                Ok(Box::new(stream))
+            }
+            async fn get_filetype(&mut self) -> Result<FileType, Error> {
+                Ok(FileType::SocketStream)
+            }
+            async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+                Ok(self.fdflags)
+            }
+            async fn set_fdflags(&mut self, fdflags: FdFlags) -> Result<(), Error> {
+                if fdflags == FdFlags::NONBLOCK || fdflags.is_empty() {
+                    self.fdflags = fdflags;
+                } else {
+                    return Err(
+                        Error::invalid_argument().context("cannot set anything else than NONBLOCK")
+                    );
+                }
+                Ok(())
+            }
+            async fn num_ready_bytes(&self) -> Result<u64, Error> {
+                Ok(1)
+            }
+            async fn readable(&self) -> Result<(), Error> {
+                let _guard = self.inner.readable().await?;
+                Ok(())
+            }
+        }
+    };
+}
+
+wasi_listen_write_impl!(TcpListener, cap_std::net::TcpListener, TcpStream);
+wasi_listen_write_impl!(
+    UnixListener,
+    cap_std::os::unix::net::UnixListener,
+    UnixStream
+);
+
+macro_rules! wasi_stream_write_impl {
+    ($ty:ident, $cap_std_ty:ty, $std_ty:ty) => {
+        pub struct $ty {
+            inner: AsyncFd<$cap_std_ty>,
+            fdflags: FdFlags,
+        }
+
+        impl $ty {
+            /// Must be called from within a tokio runtime.
+            pub fn from_cap_std(socket: $cap_std_ty) -> Result<Self, Error> {
+                socket.set_nonblocking(true)?;
+                // SAFETY: the cap-std type owns its fd, which therefore stays open and
+                // unchanged until the `AsyncFd` drops it.
+                let inner = unsafe { AsyncFd::register(socket) }.map_err(io::Error::from)?;
+                Ok($ty {
+                    inner,
+                    fdflags: FdFlags::empty(),
+                })
+            }
+        }
+
+        #[async_trait]
+        impl AsyncWasiFile for $ty {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+            #[cfg(unix)]
+            fn pollable(&self) -> Option<rustix::fd::BorrowedFd<'_>> {
+                Some(io_lifetimes::AsFd::as_fd(self.inner.get_ref()))
+            }
+            async fn get_filetype(&mut self) -> Result<FileType, Error> {
+                Ok(FileType::SocketStream)
+            }
+            async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+                Ok(self.fdflags)
+            }
+            async fn set_fdflags(&mut self, fdflags: FdFlags) -> Result<(), Error> {
+                if fdflags == FdFlags::NONBLOCK || fdflags.is_empty() {
+                    self.fdflags = fdflags;
+                } else {
+                    return Err(
+                        Error::invalid_argument().context("cannot set anything else than NONBLOCK")
+                    );
+                }
+                Ok(())
+            }
+            async fn read_vectored<'a>(
+                &mut self,
+                bufs: &mut [io::IoSliceMut<'a>],
+            ) -> Result<u64, Error> {
+                use std::io::Read;
+                let n = async_io!(self, readable, |inner| {
+                    Read::read_vectored(&mut &*inner.as_socketlike_view::<$std_ty>(), bufs)
+                })?;
+                Ok(n.try_into()?)
+            }
+            async fn write_vectored<'a>(&mut self, bufs: &[io::IoSlice<'a>]) -> Result<u64, Error> {
+                use std::io::Write;
+                let n = async_io!(self, writable, |inner| {
+                    Write::write_vectored(&mut &*inner.as_socketlike_view::<$std_ty>(), bufs)
+                })?;
+                Ok(n.try_into()?)
+            }
+            async fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+                let n = async_io!(self, readable, |inner| inner.peek(buf))?;
+                Ok(n.try_into()?)
+            }
+            async fn num_ready_bytes(&self) -> Result<u64, Error> {
+                let val = self
+                    .inner
+                    .get_ref()
+                    .as_socketlike_view::<$std_ty>()
+                    .num_ready_bytes()?;
+                Ok(val)
+            }
+            async fn readable(&self) -> Result<(), Error> {
+                let _guard = self.inner.readable().await?;
+                Ok(())
+            }
+            async fn writable(&self) -> Result<(), Error> {
+                let _guard = self.inner.writable().await?;
+                Ok(())
+            }
+
+            async fn sock_recv<'a>(
+                &mut self,
+                ri_data: &mut [io::IoSliceMut<'a>],
+                ri_flags: RiFlags,
+            ) -> Result<(u64, RoFlags), Error> {
+                if (ri_flags & !(RiFlags::RECV_PEEK | RiFlags::RECV_WAITALL)) != RiFlags::empty() {
+                    return Err(Error::not_supported());
+                }
+
+                if ri_flags.contains(RiFlags::RECV_PEEK) {
+                    if let Some(first) = ri_data.iter_mut().next() {
+                        let n = self.peek(first).await?;
+                        return Ok((n, RoFlags::empty()));
+                    } else {
+                        return Ok((0, RoFlags::empty()));
+                    }
+                }
+
+                if ri_flags.contains(RiFlags::RECV_WAITALL) {
+                    let n: usize = ri_data.iter().map(|buf| buf.len()).sum();
+                    let mut bufs = &mut ri_data[..];
+                    while !bufs.is_empty() {
+                        let read = self.read_vectored(bufs).await? as usize;
+                        if read == 0 {
+                            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                        }
+                        io::IoSliceMut::advance_slices(&mut bufs, read);
+                    }
+                    return Ok((n as u64, RoFlags::empty()));
+                }
+
+                let n = self.read_vectored(ri_data).await?;
+                Ok((n, RoFlags::empty()))
+            }
+
+            async fn sock_send<'a>(
+                &mut self,
+                si_data: &[io::IoSlice<'a>],
+                si_flags: SiFlags,
+            ) -> Result<u64, Error> {
+                if si_flags != SiFlags::empty() {
+                    return Err(Error::not_supported());
+                }
+
+                self.write_vectored(si_data).await
+            }
+
+            async fn sock_shutdown(&mut self, how: SdFlags) -> Result<(), Error> {
+                let how = if how == SdFlags::RD | SdFlags::WR {
+                    std::net::Shutdown::Both
+                } else if how == SdFlags::RD {
+                    std::net::Shutdown::Read
+                } else if how == SdFlags::WR {
+                    std::net::Shutdown::Write
+                } else {
+                    return Err(Error::invalid_argument());
+                };
+                self.inner.get_ref().shutdown(how)?;
+                Ok(())
+            }
+        }
+    };
+}
+
+wasi_stream_write_impl!(TcpStream, cap_std::net::TcpStream, std::net::TcpStream);
+wasi_stream_write_impl!(
+    UnixStream,
+    cap_std::os::unix::net::UnixStream,
+    std::os::unix::net::UnixStream
+);
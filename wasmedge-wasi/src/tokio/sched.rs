@@ -0,0 +1,17 @@
+use std::time::Duration;
+use wasmedge_wasi_common::{async_trait, error::Error, sched::AsyncWasiSched};
+
+/// Timers and yielding for `poll_oneoff`, backed by the current tokio runtime.
+pub struct TokioSched;
+
+#[async_trait]
+impl AsyncWasiSched for TokioSched {
+    async fn sleep(&self, duration: Duration) -> Result<(), Error> {
+        ::tokio::time::sleep(duration).await;
+        Ok(())
+    }
+    async fn sched_yield(&self) -> Result<(), Error> {
+        ::tokio::task::yield_now().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+use super::file::{wasi_file_blocking_impl, Blocking};
+use std::any::Any;
+use wasmedge_wasi_common::{
+    async_file::AsyncWasiFile,
+    async_trait, clocks,
+    error::Error,
+    file::{Advice, FdFlags, FileType, Filestat, WasiFile},
+};
+
+pub fn stdin() -> Stdin {
+    Stdin(Blocking::new(crate::stdio::stdin()))
+}
+
+pub struct Stdin(Blocking<crate::stdio::Stdin>);
+wasi_file_blocking_impl!(Stdin);
+
+pub fn stdout() -> Stdout {
+    Stdout(Blocking::new(crate::stdio::stdout()))
+}
+
+pub struct Stdout(Blocking<crate::stdio::Stdout>);
+wasi_file_blocking_impl!(Stdout);
+
+pub fn stderr() -> Stderr {
+    Stderr(Blocking::new(crate::stdio::stderr()))
+}
+
+pub struct Stderr(Blocking<crate::stdio::Stderr>);
+wasi_file_blocking_impl!(Stderr);